@@ -0,0 +1,275 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use cloud_openapi::models::TokenInfo;
+use futures::Stream;
+use uuid::Uuid;
+
+use crate::client::{Client, ConnectionConfig};
+use spin_secret::Secret;
+
+/// How often to check whether the device has been authorized. The platform
+/// asks clients not to poll more often than this.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The longest interval to back off to when the platform is struggling.
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a device code remains usable.
+const DEFAULT_AUTHORIZATION_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// How long before a token expires to refresh it.
+const DEFAULT_REFRESH_MARGIN: Duration = Duration::from_secs(10 * 60);
+
+/// How long to wait before retrying a failed refresh.
+const REFRESH_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Errors authenticating with the platform.
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    /// The platform did not respond in time.
+    #[error("Timed out waiting for the platform to respond to {endpoint}")]
+    Timeout { endpoint: String },
+    /// The platform rejected the login token.
+    #[error("The platform did not accept the login token")]
+    Unauthorized,
+    /// The user did not authorize the device before the device code expired.
+    #[error("Timed out waiting for the device to be authorized")]
+    AuthorizationTimeout,
+    /// The user has not authorized the device yet.
+    #[error("The device has not been authorized yet")]
+    AuthorizationPending,
+    /// The platform asked the client to check the device less often.
+    #[error("The platform asked for device authorization to be checked less often")]
+    SlowDown,
+}
+
+/// A status update while waiting for the user to authorize a device.
+#[derive(Debug)]
+pub enum DeviceFlowEvent {
+    /// The device has not been authorized yet.
+    Waiting,
+    /// The platform asked for fewer checks, so subsequent checks will be
+    /// less frequent.
+    SlowDown,
+    /// The device has been authorized.
+    Authorized(TokenInfo),
+}
+
+/// Logs in using the device authorization flow: the user visits a
+/// verification URL and enters a one-time code, while the authenticator
+/// polls the platform until they have done so.
+pub struct DeviceFlowAuthenticator {
+    client: Client,
+    device_code: String,
+    user_code: String,
+    verification_url: String,
+    poll_interval: Duration,
+    timeout: Duration,
+}
+
+impl DeviceFlowAuthenticator {
+    /// Creates a device code for the given client application.
+    pub async fn new(client: Client, client_id: Uuid) -> Result<Self> {
+        let item = client.create_device_code(client_id).await?;
+        Ok(Self {
+            client,
+            device_code: item
+                .device_code
+                .context("Server did not return a device code")?,
+            user_code: item
+                .user_code
+                .context("Server did not return a user code")?,
+            verification_url: item
+                .verification_url
+                .context("Server did not return a verification URL")?,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            timeout: DEFAULT_AUTHORIZATION_TIMEOUT,
+        })
+    }
+
+    /// The one-time code which the user must enter.
+    pub fn user_code(&self) -> &str {
+        &self.user_code
+    }
+
+    /// The page at which the user enters the one-time code.
+    pub fn verification_url(&self) -> &str {
+        &self.verification_url
+    }
+
+    /// Polls the platform until the device is authorized, reporting each
+    /// check as an event. The stream ends after an `Authorized` event, with
+    /// an `AuthError::AuthorizationTimeout` error if the user does not
+    /// authorize the device in time, or with the error if a check fails
+    /// other than by the platform asking to wait or to slow down.
+    pub fn wait_for_authorization(&self) -> impl Stream<Item = Result<DeviceFlowEvent>> + '_ {
+        let state = PollState {
+            interval: self.poll_interval,
+            elapsed: Duration::ZERO,
+            finished: false,
+        };
+
+        futures::stream::unfold(state, move |mut state| async move {
+            if state.finished {
+                return None;
+            }
+            if state.elapsed > self.timeout {
+                state.finished = true;
+                return Some((Err(AuthError::AuthorizationTimeout.into()), state));
+            }
+            if state.elapsed > Duration::ZERO {
+                tokio::time::sleep(state.interval).await;
+            }
+            state.elapsed += state.interval;
+
+            let event = match self.client.login(self.device_code.clone()).await {
+                Ok(token_info) if token_info.token.is_some() => {
+                    state.finished = true;
+                    DeviceFlowEvent::Authorized(token_info)
+                }
+                Ok(_) => DeviceFlowEvent::Waiting,
+                Err(e) => match e.downcast_ref::<AuthError>() {
+                    Some(AuthError::AuthorizationPending) => DeviceFlowEvent::Waiting,
+                    Some(AuthError::SlowDown) => {
+                        state.interval = (state.interval * 2).min(MAX_POLL_INTERVAL);
+                        DeviceFlowEvent::SlowDown
+                    }
+                    _ => {
+                        state.finished = true;
+                        return Some((Err(e), state));
+                    }
+                },
+            };
+            Some((Ok(event), state))
+        })
+    }
+}
+
+struct PollState {
+    interval: Duration,
+    elapsed: Duration,
+    finished: bool,
+}
+
+/// Keeps a login token fresh by exchanging it for a new one shortly before
+/// it expires, for processes which outlive a single token.
+pub struct TokenRefresher {
+    connection: ConnectionConfig,
+    margin: Duration,
+}
+
+impl TokenRefresher {
+    /// Creates a refresher for the token in the given connection.
+    pub fn new(connection: ConnectionConfig) -> Self {
+        Self {
+            connection,
+            margin: DEFAULT_REFRESH_MARGIN,
+        }
+    }
+
+    /// Sets how long before the token expires to refresh it.
+    pub fn with_margin(self, margin: Duration) -> Self {
+        Self { margin, ..self }
+    }
+
+    /// Refreshes the token each time it nears expiration, yielding every new
+    /// token. A failed refresh is reported as an error and retried, so the
+    /// stream only ends when it is dropped.
+    pub fn refreshed_tokens(self) -> impl Stream<Item = Result<TokenInfo>> {
+        futures::stream::unfold(self, |mut refresher| async move {
+            tokio::time::sleep(refresher.time_until_refresh()).await;
+
+            // The fingerprint identifies the token being replaced, and is
+            // not known for the new one until the caller computes it.
+            let result = Client::new(refresher.connection.clone())
+                .refresh_token()
+                .await
+                .and_then(|token| {
+                    token
+                        .token
+                        .as_ref()
+                        .context("Server did not return a token")?;
+                    Ok(token)
+                });
+            match &result {
+                Ok(token) => {
+                    refresher.connection.token = Secret::new(token.clone());
+                    refresher.connection.token_fingerprint = None;
+                }
+                Err(e) => {
+                    tracing::debug!("Refreshing login token failed: {:?}", e);
+                    refresher.connection.token = Secret::new(TokenInfo {
+                        expiration: None,
+                        ..refresher.connection.token.expose().clone()
+                    });
+                }
+            }
+            Some((result, refresher))
+        })
+    }
+
+    fn time_until_refresh(&self) -> Duration {
+        // A token with no known expiration, including one whose refresh just
+        // failed, is retried after a short interval.
+        let expiration = match &self.connection.token.expose().expiration {
+            Some(expiration) => expiration,
+            None => return REFRESH_RETRY_INTERVAL,
+        };
+        match chrono::DateTime::parse_from_rfc3339(expiration) {
+            Ok(expiration) => (expiration.with_timezone(&chrono::Utc) - chrono::Utc::now())
+                .to_std()
+                .unwrap_or_default()
+                .saturating_sub(self.margin),
+            Err(e) => {
+                tracing::debug!("Ignoring invalid token expiration {:?}: {}", expiration, e);
+                REFRESH_RETRY_INTERVAL
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn refresher(expiration: Option<String>) -> TokenRefresher {
+        TokenRefresher::new(ConnectionConfig {
+            token: Secret::new(TokenInfo {
+                token: Some("t0ken".to_owned()),
+                expiration,
+            }),
+            ..Default::default()
+        })
+    }
+
+    fn expires_in(duration: chrono::Duration) -> Option<String> {
+        Some((chrono::Utc::now() + duration).to_rfc3339())
+    }
+
+    #[test]
+    fn refreshes_the_margin_before_expiration() {
+        let wait = refresher(expires_in(chrono::Duration::hours(1)))
+            .with_margin(Duration::from_secs(10 * 60))
+            .time_until_refresh();
+        assert!(wait <= Duration::from_secs(50 * 60));
+        assert!(wait > Duration::from_secs(49 * 60));
+    }
+
+    #[test]
+    fn refreshes_at_once_within_the_margin_or_after_expiration() {
+        let soon = refresher(expires_in(chrono::Duration::minutes(5)));
+        assert_eq!(Duration::ZERO, soon.time_until_refresh());
+        let expired = refresher(expires_in(chrono::Duration::minutes(-5)));
+        assert_eq!(Duration::ZERO, expired.time_until_refresh());
+    }
+
+    #[test]
+    fn retries_tokens_without_a_valid_expiration() {
+        assert_eq!(REFRESH_RETRY_INTERVAL, refresher(None).time_until_refresh());
+        assert_eq!(
+            REFRESH_RETRY_INTERVAL,
+            refresher(Some("next tuesday".to_owned())).time_until_refresh()
+        );
+    }
+}
@@ -5,7 +5,7 @@ use cloud_openapi::{
         apps_api::{api_apps_get, api_apps_id_delete, api_apps_post},
         channels_api::{
             api_channels_get, api_channels_id_delete, api_channels_id_get,
-            api_channels_id_logs_get, api_channels_post, ApiChannelsIdPatchError,
+            api_channels_id_logs_get, api_channels_post,
         },
         configuration::{ApiKey, Configuration},
         device_codes_api::api_device_codes_post,
@@ -15,34 +15,232 @@ use cloud_openapi::{
     models::{
         AppItemPage, ChannelItem, ChannelItemPage, ChannelRevisionSelectionStrategy,
         CreateAppCommand, CreateChannelCommand, CreateDeviceCodeCommand, DeviceCodeItem,
-        GetChannelLogsVm, RegisterRevisionCommand, RevisionItemPage, TokenInfo,
-        UpdateEnvironmentVariableDto,
+        EnvironmentVariableItem, GetChannelLogsVm, RegisterRevisionCommand, RevisionItemPage,
+        TokenInfo, UpdateEnvironmentVariableDto,
     },
 };
 use reqwest::header;
+
+pub use crate::auth::AuthError;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
 use uuid::Uuid;
 
+use spin_secret::Secret;
+
 const JSON_MIME_TYPE: &str = "application/json";
 
+/// The path, relative to the platform URL, at which registry tokens are
+/// issued.
+pub const REGISTRY_TOKENS_PATH: &str = "api/registry-tokens";
+/// How long to wait for any platform request to complete.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait for authentication requests, which are small and, when
+/// polling for device authorization, retried anyway.
+const AUTH_REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long to wait for the platform to answer a health check.
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Header identifying the organization on whose behalf a request is made.
+const ORGANIZATION_HEADER: &str = "Fermyon-Organization";
+
+/// Headers identifying the machine and login token a request comes from, so
+/// that the platform can explain why it rejects a token.
+const MACHINE_ID_HEADER: &str = "Spin-Machine-Id";
+const TOKEN_FINGERPRINT_HEADER: &str = "Spin-Token-Fingerprint";
+
 pub struct Client {
     configuration: Configuration,
+    lookups: Mutex<Lookups>,
+}
+
+/// Results of lookups which cannot change once they succeed, such as the ID
+/// of a named app, remembered for the lifetime of the client so that a
+/// single command does not repeat identical list calls.
+#[derive(Default)]
+struct Lookups {
+    apps: HashMap<String, Uuid>,
+    channels: HashMap<(Uuid, String), Uuid>,
+    revisions: HashMap<(Uuid, String), Uuid>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct ConnectionConfig {
     pub insecure: bool,
-    pub token: TokenInfo,
+    pub token: Secret<TokenInfo>,
     pub url: String,
+    /// The ID of the organization to act on behalf of. If omitted, the
+    /// server uses the user's default organization.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub organization: Option<String>,
+    /// An identifier for the machine making requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub machine_id: Option<String>,
+    /// A fingerprint of the token, which identifies it without revealing it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_fingerprint: Option<String>,
 }
 
-impl Client {
-    pub fn new(conn_info: ConnectionConfig) -> Self {
+/// An organization to which the logged-in user belongs.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Organization {
+    pub id: Uuid,
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+struct OrganizationItemPage {
+    items: Vec<Organization>,
+}
+
+/// A revision of an app, with the provenance the platform recorded when it
+/// was registered. The provenance fields are not yet part of the OpenAPI
+/// specification, and are absent for revisions registered before the
+/// platform recorded them.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RevisionHistoryItem {
+    pub id: Uuid,
+    pub app_id: Uuid,
+    pub revision_number: String,
+    /// When the revision was registered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    /// The user or token which registered the revision.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deployed_by: Option<String>,
+    /// The digest of the revision's application in the registry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registry_digest: Option<String>,
+    /// Build metadata recorded by the deploying client, such as the source
+    /// commit.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub build_metadata: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RevisionHistoryPage {
+    items: Vec<RevisionHistoryItem>,
+    page_index: i32,
+    page_size: i32,
+    is_last_page: bool,
+}
+
+/// An HTTP route served by a deployed app.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DeployedRoute {
+    pub app_id: Uuid,
+    pub app_name: String,
+    /// The route pattern, as in `spin.toml`, including any base path.
+    pub route: String,
+}
+
+#[derive(Deserialize)]
+struct DeployedRouteList {
+    items: Vec<DeployedRoute>,
+}
+
+/// A channel and the labels it was given when deployed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LabelledChannel {
+    pub id: Uuid,
+    pub name: String,
+    pub app_id: Uuid,
+    pub app_name: String,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl LabelledChannel {
+    /// Whether the channel has all of the given labels, with exactly the
+    /// given values.
+    pub fn has_labels(&self, labels: &BTreeMap<String, String>) -> bool {
+        labels.iter().all(|(k, v)| self.labels.get(k) == Some(v))
+    }
+}
+
+#[derive(Deserialize)]
+struct LabelledChannelList {
+    items: Vec<LabelledChannel>,
+}
+
+#[derive(Deserialize)]
+struct ChannelLabels {
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+/// A short-lived token granting access to the platform's registry.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RegistryToken {
+    pub token: Secret<String>,
+    #[serde(default)]
+    pub expiration: Option<String>,
+}
+
+impl ConnectionConfig {
+    /// The headers sent with every request made over this connection,
+    /// which identify the organization, machine and token it is for.
+    /// Callers sharing an HTTP client with [`Client::with_http_client`]
+    /// should set these as its default headers.
+    pub fn headers(&self) -> header::HeaderMap {
         let mut headers = header::HeaderMap::new();
         headers.insert(header::ACCEPT, JSON_MIME_TYPE.parse().unwrap());
         headers.insert(header::CONTENT_TYPE, JSON_MIME_TYPE.parse().unwrap());
+        let identity_headers = [
+            (ORGANIZATION_HEADER, &self.organization),
+            (MACHINE_ID_HEADER, &self.machine_id),
+            (TOKEN_FINGERPRINT_HEADER, &self.token_fingerprint),
+        ];
+        for (name, value) in identity_headers {
+            if let Some(value) = value {
+                match header::HeaderValue::from_str(value) {
+                    Ok(value) => {
+                        headers.insert(name, value);
+                    }
+                    Err(_) => tracing::warn!("Ignoring invalid {} {:?}", name, value),
+                }
+            }
+        }
+        headers
+    }
+}
 
+impl Client {
+    pub fn new(conn_info: ConnectionConfig) -> Self {
+        Self::with_redirect_policy(conn_info, reqwest::redirect::Policy::default())
+    }
+
+    /// Creates a client which follows redirects as the given policy says,
+    /// so that callers can see where platform requests are redirected.
+    pub fn with_redirect_policy(
+        conn_info: ConnectionConfig,
+        redirect: reqwest::redirect::Policy,
+    ) -> Self {
+        let http = reqwest::Client::builder()
+            .danger_accept_invalid_certs(conn_info.insecure)
+            .default_headers(conn_info.headers())
+            .timeout(REQUEST_TIMEOUT)
+            .redirect(redirect)
+            .build()
+            .unwrap();
+        Self::with_http_client(conn_info, http)
+    }
+
+    /// Creates a client which makes all platform requests with the given
+    /// HTTP client, so that proxy, TLS and connection pool settings can be
+    /// shared with other clients. The HTTP client is used as is: the
+    /// connection's `insecure` setting is ignored, and its
+    /// [`headers`](ConnectionConfig::headers) are only sent if the HTTP
+    /// client sets them.
+    pub fn with_http_client(conn_info: ConnectionConfig, http: reqwest::Client) -> Self {
         let base_path = match conn_info.url.strip_suffix('/') {
             Some(s) => s.to_owned(),
             None => conn_info.url,
@@ -55,21 +253,20 @@ impl Client {
                 env!("CARGO_PKG_NAME"),
                 env!("CARGO_PKG_VERSION")
             )),
-            client: reqwest::Client::builder()
-                .danger_accept_invalid_certs(conn_info.insecure)
-                .default_headers(headers)
-                .build()
-                .unwrap(),
+            client: http,
             basic_auth: None,
             oauth_access_token: None,
             bearer_access_token: None,
-            api_key: conn_info.token.token.map(|t| ApiKey {
+            api_key: conn_info.token.into_inner().token.map(|t| ApiKey {
                 prefix: Some("Bearer".to_owned()),
                 key: t,
             }),
         };
 
-        Self { configuration }
+        Self {
+            configuration,
+            lookups: Default::default(),
+        }
     }
 
     pub async fn create_device_code(&self, client_id: Uuid) -> Result<DeviceCodeItem> {
@@ -78,7 +275,13 @@ impl Client {
             Some(CreateDeviceCodeCommand { client_id }),
         )
         .await
-        .map_err(format_response_error)
+        .map_err(|e| match e {
+            Error::Reqwest(e) if e.is_timeout() => AuthError::Timeout {
+                endpoint: "api/device-codes".to_owned(),
+            }
+            .into(),
+            e => format_response_error(e),
+        })
     }
 
     pub async fn login(&self, token: String) -> Result<TokenInfo> {
@@ -98,11 +301,110 @@ impl Client {
                 )
                 .to_string(),
             )
+            .timeout(AUTH_REQUEST_TIMEOUT)
             .send()
-            .await?;
+            .await
+            .map_err(|e| auth_request_error("api/auth-tokens", e))?;
+        let status = response.status();
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| auth_request_error("api/auth-tokens", e))?;
 
-        serde_json::from_reader(response.bytes().await?.as_ref())
-            .context("Failed to parse response")
+        if !status.is_success() {
+            if let Some(e) = device_flow_error(status, &body) {
+                return Err(e.into());
+            }
+            if status == reqwest::StatusCode::UNAUTHORIZED {
+                return Err(AuthError::Unauthorized.into());
+            }
+            anyhow::bail!("Failed to log in: response status code {}", status);
+        }
+        serde_json::from_reader(body.as_ref()).context("Failed to parse response")
+    }
+
+    /// Checks that the platform is reachable and healthy, failing with an
+    /// error which says which of those it is not.
+    pub async fn ping(&self) -> Result<()> {
+        let url = format!("{}/healthz", self.configuration.base_path);
+        let response = self
+            .configuration
+            .client
+            .get(&url)
+            .timeout(PING_TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| {
+                let reason = if e.is_timeout() {
+                    format!("no response within {} seconds", PING_TIMEOUT.as_secs())
+                } else if e.is_connect() {
+                    "could not connect".to_owned()
+                } else {
+                    e.to_string()
+                };
+                anyhow::anyhow!("Cannot reach {}: {}", self.configuration.base_path, reason)
+            })?;
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!(
+                "Server {} is unhealthy: health check returned {}",
+                self.configuration.base_path,
+                status
+            );
+        }
+        Ok(())
+    }
+
+    /// Exchanges the client's token for a new one with a later expiration.
+    /// The old token remains valid until it expires.
+    pub async fn refresh_token(&self) -> Result<TokenInfo> {
+        // The token refresh API is not yet part of the OpenAPI specification.
+        let request = self
+            .unspecified_request(reqwest::Method::POST, "api/auth-tokens/refresh")
+            .timeout(AUTH_REQUEST_TIMEOUT);
+        let content = send_unspecified_request(request)
+            .await
+            .context("Failed to refresh login token")?;
+        parse_unspecified_response(&content)
+    }
+
+    pub async fn list_organizations(&self) -> Result<Vec<Organization>> {
+        // The organizations API is not yet part of the OpenAPI specification.
+        let request = self.unspecified_request(reqwest::Method::GET, "api/organizations");
+        let content = send_unspecified_request(request)
+            .await
+            .context("Failed to list organizations")?;
+        let page: OrganizationItemPage = parse_unspecified_response(&content)?;
+        Ok(page.items)
+    }
+
+    /// Mints a short-lived token for pushing to the platform's registry, so
+    /// that long-lived registry credentials need not be stored locally.
+    pub async fn create_registry_token(&self) -> Result<RegistryToken> {
+        // The registry tokens API is not yet part of the OpenAPI specification.
+        let request = self.unspecified_request(reqwest::Method::POST, REGISTRY_TOKENS_PATH);
+        let content = send_unspecified_request(request)
+            .await
+            .context("Failed to create registry token")?;
+        parse_unspecified_response(&content)
+    }
+
+    fn unspecified_request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let configuration = &self.configuration;
+        let mut request = configuration
+            .client
+            .request(method, format!("{}/{}", configuration.base_path, path));
+        if let Some(ref user_agent) = configuration.user_agent {
+            request = request.header(reqwest::header::USER_AGENT, user_agent.clone());
+        }
+        if let Some(ref apikey) = configuration.api_key {
+            let value = match apikey.prefix {
+                Some(ref prefix) => format!("{} {}", prefix, apikey.key),
+                None => apikey.key.clone(),
+            };
+            request = request.header(reqwest::header::AUTHORIZATION, value);
+        }
+        request
     }
 
     pub async fn add_app(&self, name: &str, storage_id: &str) -> Result<Uuid> {
@@ -120,7 +422,13 @@ impl Client {
     pub async fn remove_app(&self, id: String) -> Result<()> {
         api_apps_id_delete(&self.configuration, &id)
             .await
-            .map_err(format_response_error)
+            .map_err(format_response_error)?;
+        let mut lookups = self.lookups.lock().unwrap();
+        lookups.apps.retain(|_, app_id| app_id.to_string() != id);
+        lookups
+            .channels
+            .retain(|(app_id, _), _| app_id.to_string() != id);
+        Ok(())
     }
 
     pub async fn list_apps(&self) -> Result<AppItemPage> {
@@ -129,6 +437,39 @@ impl Client {
             .map_err(format_response_error)
     }
 
+    /// Finds the ID of an app by name, using the server's search rather than
+    /// listing every app.
+    pub async fn get_app_id(&self, name: &str) -> Result<Option<Uuid>> {
+        if let Some(id) = self.lookups.lock().unwrap().apps.get(name) {
+            return Ok(Some(*id));
+        }
+
+        let mut page = api_apps_get(&self.configuration, Some(name), None, None, None, None)
+            .await
+            .map_err(format_response_error)?;
+
+        loop {
+            if let Some(app) = page.items.iter().find(|a| a.name == name) {
+                let mut lookups = self.lookups.lock().unwrap();
+                lookups.apps.insert(name.to_owned(), app.id);
+                return Ok(Some(app.id));
+            }
+            if page.is_last_page {
+                return Ok(None);
+            }
+            page = api_apps_get(
+                &self.configuration,
+                Some(name),
+                Some(page.page_index + 1),
+                Some(page.page_size),
+                None,
+                None,
+            )
+            .await
+            .map_err(format_response_error)?;
+        }
+    }
+
     pub async fn get_channel_by_id(&self, id: &str) -> Result<ChannelItem> {
         api_channels_id_get(&self.configuration, id)
             .await
@@ -148,6 +489,60 @@ impl Client {
         .map_err(format_response_error)
     }
 
+    /// Finds the ID of an app's channel by name, using the server's search
+    /// rather than listing every channel.
+    pub async fn get_channel_id(&self, app_id: Uuid, name: &str) -> Result<Option<Uuid>> {
+        let key = (app_id, name.to_owned());
+        if let Some(id) = self.lookups.lock().unwrap().channels.get(&key) {
+            return Ok(Some(*id));
+        }
+
+        let mut page = api_channels_get(&self.configuration, Some(name), None, None, None, None)
+            .await
+            .map_err(format_response_error)?;
+
+        loop {
+            if let Some(channel) = page
+                .items
+                .iter()
+                .find(|c| c.app_id == app_id && c.name == name)
+            {
+                self.lookups
+                    .lock()
+                    .unwrap()
+                    .channels
+                    .insert(key, channel.id);
+                return Ok(Some(channel.id));
+            }
+            if page.is_last_page {
+                return Ok(None);
+            }
+            page = api_channels_get(
+                &self.configuration,
+                Some(name),
+                Some(page.page_index + 1),
+                Some(page.page_size),
+                None,
+                None,
+            )
+            .await
+            .map_err(format_response_error)?;
+        }
+    }
+
+    /// Lists the channels of an app.
+    pub async fn list_app_channels(&self, app_id: Uuid) -> Result<Vec<ChannelItem>> {
+        let mut channels = vec![];
+        let mut page = self.list_channels().await?;
+        loop {
+            channels.extend(page.items.iter().filter(|c| c.app_id == app_id).cloned());
+            if page.is_last_page {
+                return Ok(channels);
+            }
+            page = self.list_channels_next(&page).await?;
+        }
+    }
+
     pub async fn list_channels_next(&self, previous: &ChannelItemPage) -> Result<ChannelItemPage> {
         api_channels_get(
             &self.configuration,
@@ -181,74 +576,147 @@ impl Client {
             .map_err(format_response_error)
     }
 
-    pub async fn patch_channel(
-        &self,
-        id: Uuid,
-        name: Option<String>,
-        revision_selection_strategy: Option<ChannelRevisionSelectionStrategy>,
-        range_rule: Option<String>,
-        active_revision_id: Option<Uuid>,
-        environment_variables: Option<Vec<UpdateEnvironmentVariableDto>>,
-    ) -> anyhow::Result<()> {
-        let patch_channel_command = PatchChannelCommand {
+    pub async fn patch_channel(&self, id: Uuid, command: PatchChannelCommand) -> Result<()> {
+        // The generated client sends `null` for omitted fields, which the
+        // server treats as clearing them, so the request is made by hand.
+        let command = PatchChannelCommand {
             channel_id: Some(id),
-            name,
-            revision_selection_strategy,
-            range_rule,
-            active_revision_id,
-            environment_variables,
+            ..command
         };
+        let request = self
+            .unspecified_request(
+                reqwest::Method::PATCH,
+                &format!("api/channels/{}", apis::urlencode(id.to_string())),
+            )
+            .json(&command);
+        send_unspecified_request(request).await?;
+        Ok(())
+    }
 
-        let local_var_configuration = &self.configuration;
+    /// Stops a channel serving requests, without deleting it.
+    pub async fn deactivate_channel(&self, id: Uuid) -> Result<()> {
+        // The deactivation API is not yet part of the OpenAPI specification.
+        let request = self.unspecified_request(
+            reqwest::Method::POST,
+            &format!(
+                "api/channels/{}/deactivate",
+                apis::urlencode(id.to_string())
+            ),
+        );
+        send_unspecified_request(request).await?;
+        Ok(())
+    }
 
-        let local_var_client = &local_var_configuration.client;
+    pub async fn delete_channel(&self, id: Uuid) -> Result<()> {
+        api_channels_id_delete(&self.configuration, &id.to_string())
+            .await
+            .map_err(format_response_error)?;
+        let mut lookups = self.lookups.lock().unwrap();
+        lookups.channels.retain(|_, channel_id| *channel_id != id);
+        Ok(())
+    }
 
-        let local_var_uri_str = format!(
-            "{}/api/channels/{id}",
-            local_var_configuration.base_path,
-            id = apis::urlencode(id.to_string())
+    /// Gets the labels of a channel.
+    pub async fn get_channel_labels(&self, id: Uuid) -> Result<BTreeMap<String, String>> {
+        // The labels API is not yet part of the OpenAPI specification.
+        let request = self.unspecified_request(
+            reqwest::Method::GET,
+            &format!("api/channels/{}/labels", apis::urlencode(id.to_string())),
         );
-        let mut local_var_req_builder =
-            local_var_client.request(reqwest::Method::PATCH, local_var_uri_str.as_str());
+        let content = send_unspecified_request(request)
+            .await
+            .context("Failed to get channel labels")?;
+        let labels: ChannelLabels = parse_unspecified_response(&content)?;
+        Ok(labels.labels)
+    }
 
-        if let Some(ref local_var_user_agent) = local_var_configuration.user_agent {
-            local_var_req_builder = local_var_req_builder
-                .header(reqwest::header::USER_AGENT, local_var_user_agent.clone());
-        }
-        if let Some(ref local_var_apikey) = local_var_configuration.api_key {
-            let local_var_key = local_var_apikey.key.clone();
-            let local_var_value = match local_var_apikey.prefix {
-                Some(ref local_var_prefix) => format!("{} {}", local_var_prefix, local_var_key),
-                None => local_var_key,
-            };
-            local_var_req_builder = local_var_req_builder.header("Authorization", local_var_value);
-        };
-        local_var_req_builder = local_var_req_builder.json(&patch_channel_command);
-
-        let local_var_req = local_var_req_builder.build()?;
-        let local_var_resp = local_var_client.execute(local_var_req).await?;
-
-        let local_var_status = local_var_resp.status();
-        let local_var_content = local_var_resp.text().await?;
-
-        if !local_var_status.is_client_error() && !local_var_status.is_server_error() {
-            Ok(())
-        } else {
-            let local_var_entity: Option<ApiChannelsIdPatchError> =
-                serde_json::from_str(&local_var_content).ok();
-            let local_var_error = ResponseContent {
-                status: local_var_status,
-                content: local_var_content,
-                entity: local_var_entity,
-            };
-            Err(format_response_error(Error::ResponseError(local_var_error)))
-        }
+    /// Replaces the labels of a channel, by which it can be found with
+    /// [`find_labelled_channels`](Self::find_labelled_channels).
+    pub async fn set_channel_labels(
+        &self,
+        id: Uuid,
+        labels: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        // The labels API is not yet part of the OpenAPI specification.
+        let request = self
+            .unspecified_request(
+                reqwest::Method::PUT,
+                &format!("api/channels/{}/labels", apis::urlencode(id.to_string())),
+            )
+            .json(&serde_json::json!({ "labels": labels }));
+        send_unspecified_request(request)
+            .await
+            .context("Failed to set channel labels")?;
+        Ok(())
     }
 
-    pub async fn remove_channel(&self, id: String) -> Result<()> {
-        api_channels_id_delete(&self.configuration, &id)
+    /// Lists the channels which have all of the given labels.
+    pub async fn find_labelled_channels(
+        &self,
+        labels: &BTreeMap<String, String>,
+    ) -> Result<Vec<LabelledChannel>> {
+        // The labels API is not yet part of the OpenAPI specification.
+        let selector: Vec<_> = labels
+            .iter()
+            .map(|(name, value)| ("label", format!("{}={}", name, value)))
+            .collect();
+        let request = self
+            .unspecified_request(reqwest::Method::GET, "api/channels/labels")
+            .query(&selector);
+        let content = send_unspecified_request(request)
             .await
-            .map_err(format_response_error)
+            .context("Failed to find labelled channels")?;
+        let list: LabelledChannelList = parse_unspecified_response(&content)?;
+        // Only exact matches are wanted, whatever the server's matching rules
+        Ok(list
+            .items
+            .into_iter()
+            .filter(|c| c.has_labels(labels))
+            .collect())
+    }
+
+    /// Gets the environment variables of a channel.
+    pub async fn get_environment_variables(
+        &self,
+        channel_id: Uuid,
+    ) -> Result<Vec<EnvironmentVariableItem>> {
+        let channel = self.get_channel_by_id(&channel_id.to_string()).await?;
+        Ok(channel.environment_variables)
+    }
+
+    /// Replaces the environment variables of a channel. The change takes
+    /// effect without deploying a new revision.
+    pub async fn set_environment_variables(
+        &self,
+        channel_id: Uuid,
+        variables: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<()> {
+        let variables = variables
+            .into_iter()
+            .map(|(key, value)| UpdateEnvironmentVariableDto::new(key, value))
+            .collect();
+        self.patch_channel(
+            channel_id,
+            PatchChannelCommand::new().with_environment_variables(variables),
+        )
+        .await
+    }
+
+    /// Sets environment variables of a channel, keeping those of its other
+    /// variables which are not given.
+    pub async fn merge_environment_variables(
+        &self,
+        channel_id: Uuid,
+        variables: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<()> {
+        let mut merged: BTreeMap<_, _> = self
+            .get_environment_variables(channel_id)
+            .await?
+            .into_iter()
+            .map(|v| (v.key, v.value))
+            .collect();
+        merged.extend(variables);
+        self.set_environment_variables(channel_id, merged).await
     }
 
     pub async fn channel_logs(&self, id: String) -> Result<GetChannelLogsVm> {
@@ -291,6 +759,134 @@ impl Client {
         .await
         .map_err(format_response_error)
     }
+
+    /// Lists the revisions of an app with their provenance, in the order
+    /// the platform returns them.
+    pub async fn list_revision_history(&self, app_id: Uuid) -> Result<Vec<RevisionHistoryItem>> {
+        // Revision provenance is not yet part of the OpenAPI specification.
+        let mut revisions = vec![];
+        let mut page_index = 0;
+        loop {
+            let request = self
+                .unspecified_request(reqwest::Method::GET, "api/revisions")
+                .query(&[("pageIndex", page_index)]);
+            let content = send_unspecified_request(request)
+                .await
+                .context("Failed to list revisions")?;
+            let page: RevisionHistoryPage = parse_unspecified_response(&content)?;
+            revisions.extend(page.items.into_iter().filter(|r| r.app_id == app_id));
+            if page.is_last_page || page.page_size <= 0 {
+                return Ok(revisions);
+            }
+            page_index = page.page_index + 1;
+        }
+    }
+
+    /// Lists the HTTP routes served on a domain by the active revisions of
+    /// all apps deployed to it.
+    pub async fn list_domain_routes(&self, domain: &str) -> Result<Vec<DeployedRoute>> {
+        // The routes API is not yet part of the OpenAPI specification.
+        let request = self
+            .unspecified_request(reqwest::Method::GET, "api/routes")
+            .query(&[("domain", domain)]);
+        let content = send_unspecified_request(request)
+            .await
+            .context("Failed to list routes")?;
+        let list: DeployedRouteList = parse_unspecified_response(&content)?;
+        Ok(list.items)
+    }
+
+    /// Finds the ID of an app's revision by revision number. Every revision
+    /// seen along the way is remembered, so later lookups for revisions
+    /// which already existed need not list revisions again.
+    pub async fn get_revision_id(
+        &self,
+        app_id: Uuid,
+        revision_number: &str,
+    ) -> anyhow::Result<Option<Uuid>> {
+        let key = (app_id, revision_number.to_owned());
+        if let Some(id) = self.lookups.lock().unwrap().revisions.get(&key) {
+            return Ok(Some(*id));
+        }
+
+        let mut page = self.list_revisions().await?;
+
+        loop {
+            {
+                let mut lookups = self.lookups.lock().unwrap();
+                for revision in &page.items {
+                    lookups.revisions.insert(
+                        (revision.app_id, revision.revision_number.clone()),
+                        revision.id,
+                    );
+                }
+                if let Some(id) = lookups.revisions.get(&key) {
+                    return Ok(Some(*id));
+                }
+            }
+
+            if page.is_last_page {
+                return Ok(None);
+            }
+
+            page = self.list_revisions_next(&page).await?;
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DeviceFlowErrorResponse {
+    error: String,
+}
+
+/// The device flow state which a failed login response reports, if it is
+/// one in which the client should keep polling: the platform may ask it to
+/// poll less often, or report that the user has not authorized the device
+/// yet, in the manner of RFC 8628.
+fn device_flow_error(status: reqwest::StatusCode, body: &[u8]) -> Option<AuthError> {
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return Some(AuthError::SlowDown);
+    }
+    match serde_json::from_slice::<DeviceFlowErrorResponse>(body) {
+        Ok(response) if response.error == "slow_down" => Some(AuthError::SlowDown),
+        Ok(response) if response.error == "authorization_pending" => {
+            Some(AuthError::AuthorizationPending)
+        }
+        _ => None,
+    }
+}
+
+fn auth_request_error(endpoint: &str, e: reqwest::Error) -> anyhow::Error {
+    if e.is_timeout() {
+        AuthError::Timeout {
+            endpoint: endpoint.to_owned(),
+        }
+        .into()
+    } else {
+        e.into()
+    }
+}
+
+async fn send_unspecified_request(request: reqwest::RequestBuilder) -> Result<String> {
+    let response = request.send().await?;
+    let status = response.status();
+    let content = response.text().await?;
+
+    if status.is_client_error() || status.is_server_error() {
+        return Err(format_response_error(Error::ResponseError(
+            ResponseContent::<()> {
+                status,
+                content,
+                entity: None,
+            },
+        )));
+    }
+
+    Ok(content)
+}
+
+fn parse_unspecified_response<T: serde::de::DeserializeOwned>(content: &str) -> Result<T> {
+    serde_json::from_str(content).context("Failed to parse response")
 }
 
 #[derive(Deserialize, Debug)]
@@ -306,6 +902,9 @@ struct CloudProblemDetails {
 
 fn format_response_error<T>(e: Error<T>) -> anyhow::Error {
     match e {
+        Error::ResponseError(r) if r.status == reqwest::StatusCode::UNAUTHORIZED => {
+            AuthError::Unauthorized.into()
+        }
         Error::ResponseError(r) => {
             // Validation failures are distinguished by the presence of `errors` so try that first
             if let Ok(m) = serde_json::from_str::<ValidationExceptionMessage>(&r.content) {
@@ -356,4 +955,91 @@ impl PatchChannelCommand {
             active_revision_id: None,
         }
     }
+
+    pub fn with_name(self, name: impl Into<String>) -> Self {
+        Self {
+            name: Some(name.into()),
+            ..self
+        }
+    }
+
+    pub fn with_environment_variables(
+        self,
+        environment_variables: Vec<UpdateEnvironmentVariableDto>,
+    ) -> Self {
+        Self {
+            environment_variables: Some(environment_variables),
+            ..self
+        }
+    }
+
+    pub fn with_range_rule(self, range_rule: impl Into<String>) -> Self {
+        Self {
+            revision_selection_strategy: Some(ChannelRevisionSelectionStrategy::UseRangeRule),
+            range_rule: Some(range_rule.into()),
+            ..self
+        }
+    }
+
+    pub fn with_active_revision(self, active_revision_id: Uuid) -> Self {
+        Self {
+            revision_selection_strategy: Some(
+                ChannelRevisionSelectionStrategy::UseSpecifiedRevision,
+            ),
+            active_revision_id: Some(active_revision_id),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_pending_and_slow_down_responses_keep_the_device_flow_polling() {
+        let bad_request = reqwest::StatusCode::BAD_REQUEST;
+        assert!(matches!(
+            device_flow_error(bad_request, br#"{"error":"authorization_pending"}"#),
+            Some(AuthError::AuthorizationPending)
+        ));
+        assert!(matches!(
+            device_flow_error(bad_request, br#"{"error":"slow_down"}"#),
+            Some(AuthError::SlowDown)
+        ));
+        assert!(matches!(
+            device_flow_error(reqwest::StatusCode::TOO_MANY_REQUESTS, b""),
+            Some(AuthError::SlowDown)
+        ));
+        assert!(device_flow_error(bad_request, br#"{"error":"expired_token"}"#).is_none());
+        assert!(device_flow_error(reqwest::StatusCode::INTERNAL_SERVER_ERROR, b"oops").is_none());
+    }
+
+    #[test]
+    fn labelled_channels_match_only_exact_labels() {
+        let list: LabelledChannelList = serde_json::from_str(
+            r#"{"items": [
+                {"id": "00000000-0000-0000-0000-000000000001", "name": "pr-1",
+                 "appId": "00000000-0000-0000-0000-000000000002", "appName": "app",
+                 "labels": {"pr": "1", "env": "preview"}},
+                {"id": "00000000-0000-0000-0000-000000000003", "name": "main",
+                 "appId": "00000000-0000-0000-0000-000000000002", "appName": "app"}
+            ]}"#,
+        )
+        .unwrap();
+        let labels = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let (preview, main) = (&list.items[0], &list.items[1]);
+
+        assert!(preview.has_labels(&labels(&[("pr", "1")])));
+        assert!(preview.has_labels(&labels(&[("pr", "1"), ("env", "preview")])));
+        assert!(!preview.has_labels(&labels(&[("pr", "10")])));
+        assert!(!preview.has_labels(&labels(&[("pr", "1"), ("team", "a")])));
+        assert!(main.labels.is_empty());
+        assert!(!main.has_labels(&labels(&[("pr", "1")])));
+    }
 }
@@ -77,6 +77,19 @@ impl RoutePattern {
         }
     }
 
+    /// Returns true if some path would be handled by both route patterns,
+    /// so that one shadows the other wherever they are served together.
+    pub fn overlaps(&self, other: &RoutePattern) -> bool {
+        match (self, other) {
+            (RoutePattern::Exact(path), other) | (other, RoutePattern::Exact(path)) => {
+                other.matches(path.as_str())
+            }
+            (RoutePattern::Wildcard(a), RoutePattern::Wildcard(b)) => {
+                a == b || a.starts_with(&format!("{}/", b)) || b.starts_with(&format!("{}/", a))
+            }
+        }
+    }
+
     /// Resolves a relative path from the end of the matched path to the end of the string.
     pub(crate) fn relative(&self, uri: &str) -> Result<String> {
         let base = match self {
@@ -198,6 +211,24 @@ mod route_tests {
         assert!(rp.matches("/base"));
     }
 
+    #[test]
+    fn test_overlapping_routes() {
+        let overlaps = |a: &str, b: &str| {
+            let (a, b) = (RoutePattern::from("/", a), RoutePattern::from("/", b));
+            assert_eq!(a.overlaps(&b), b.overlaps(&a));
+            a.overlaps(&b)
+        };
+        assert!(overlaps("/foo", "/foo/"));
+        assert!(!overlaps("/foo", "/foo/bar"));
+        assert!(overlaps("/foo/...", "/foo/bar"));
+        assert!(overlaps("/foo/...", "/foo"));
+        assert!(!overlaps("/foo/...", "/foobar"));
+        assert!(overlaps("/foo/...", "/foo/bar/..."));
+        assert!(!overlaps("/foo/...", "/bar/..."));
+        assert!(overlaps("/...", "/anything"));
+        assert!(overlaps("/...", "/"));
+    }
+
     #[test]
     fn test_relative() -> Result<()> {
         assert_eq!(
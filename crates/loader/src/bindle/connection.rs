@@ -1,10 +1,14 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
+use anyhow::Context;
 use bindle::client::{
     tokens::{HttpBasic, LongLivedToken, NoToken, TokenManager},
     Client, ClientBuilder,
 };
 
+/// How long to wait for the Bindle server to answer a ping.
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// BindleConnectionInfo holds the details of a connection to a
 /// Bindle server, including url, insecure configuration and an
 /// auth token manager
@@ -66,6 +70,28 @@ impl BindleConnectionInfo {
     pub fn base_url(&self) -> &str {
         self.base_url.as_ref()
     }
+
+    /// Checks that the Bindle server is reachable and accepts these
+    /// credentials, by making the smallest possible query.
+    pub async fn ping(&self) -> anyhow::Result<()> {
+        let client = self
+            .client()
+            .with_context(|| format!("Invalid Bindle server URL {}", self.base_url))?;
+        let query = bindle::QueryOptions {
+            limit: Some(1),
+            ..Default::default()
+        };
+        match tokio::time::timeout(PING_TIMEOUT, client.query_invoices(query)).await {
+            Ok(result) => result
+                .map(|_| ())
+                .with_context(|| format!("Cannot reach Bindle server at {}", self.base_url)),
+            Err(_) => anyhow::bail!(
+                "Cannot reach Bindle server at {}: no response within {} seconds",
+                self.base_url,
+                PING_TIMEOUT.as_secs()
+            ),
+        }
+    }
 }
 
 /// AnyAuth wraps an authentication token manager which applies
@@ -1,6 +1,6 @@
 //! Various digest functions.
 
-use sha2::{Digest, Sha256};
+use sha2::{Digest, Sha256, Sha512};
 use std::path::Path;
 
 /// Return the hex-encoded SHA256 digest of the given bytes.
@@ -10,6 +10,13 @@ pub fn bytes_sha256_string(bytes: &[u8]) -> String {
     to_hex_string(digest_value)
 }
 
+/// Return the hex-encoded SHA512 digest of the given bytes.
+pub fn bytes_sha512_string(bytes: &[u8]) -> String {
+    let digest_value = Sha512::digest(bytes);
+
+    to_hex_string(digest_value)
+}
+
 /// Return the hex-encoded SHA256 digest of the given file.
 pub fn file_sha256_string(path: impl AsRef<Path>) -> std::io::Result<String> {
     let mut file = std::fs::File::open(path)?;
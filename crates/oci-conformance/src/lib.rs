@@ -0,0 +1,309 @@
+//! Harness for checking that the Spin OCI artifact format conforms to the
+//! distribution spec and round-trips through real registry implementations.
+//!
+//! Registries are started in containers using `docker`. Harbor cannot
+//! reasonably be started per test run, so it is only tested if
+//! `SPIN_CONFORMANCE_HARBOR_REGISTRY` names an existing instance (for
+//! example `localhost:8080/library`), to which `docker login` has
+//! already been run.
+
+use std::{collections::HashMap, process::Command, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use spin_publish::oci::{
+    MediaTypeProfile, DATA_LAYER_MEDIA_TYPE, SPIN_CONFIG_MEDIA_TYPE, WASM_LAYER_MEDIA_TYPE,
+};
+
+/// Environment variable naming an existing Harbor project to test against.
+pub const HARBOR_REGISTRY_ENV: &str = "SPIN_CONFORMANCE_HARBOR_REGISTRY";
+
+const OCI_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// The smallest valid Wasm module.
+pub const TEST_WASM: &[u8] = b"\0asm\x01\0\0\0";
+/// A static asset.
+pub const TEST_ASSET: &[u8] = b"Hello, Fermyon!\n";
+/// An annotation added to every test manifest.
+pub const TEST_ANNOTATION: (&str, &str) = ("dev.fermyon.spin.conformance", "true");
+
+/// A registry implementation under test.
+#[derive(Clone, Copy, Debug)]
+pub enum RegistryKind {
+    /// The CNCF distribution reference registry
+    Distribution,
+    /// The zot registry
+    Zot,
+    /// An existing Harbor instance
+    Harbor,
+}
+
+impl RegistryKind {
+    /// All the registry implementations under test.
+    pub const ALL: &'static [Self] = &[Self::Distribution, Self::Zot, Self::Harbor];
+
+    fn image(&self) -> Option<&'static str> {
+        match self {
+            Self::Distribution => Some("registry:2"),
+            Self::Zot => Some("ghcr.io/project-zot/zot-linux-amd64:latest"),
+            Self::Harbor => None,
+        }
+    }
+}
+
+/// A running registry, which is removed when dropped if it was started by
+/// the harness.
+pub struct RegistryController {
+    /// The kind of registry
+    pub kind: RegistryKind,
+    /// The registry host, including the port
+    pub host: String,
+    /// The namespace under which test repositories are created
+    pub namespace: Option<String>,
+    container_id: Option<String>,
+}
+
+impl RegistryController {
+    /// Starts a registry of the given kind. Returns `None` if the kind
+    /// cannot be tested in this environment.
+    pub async fn start(kind: RegistryKind) -> Result<Option<Self>> {
+        let image = match kind.image() {
+            Some(image) => image,
+            None => return Ok(Self::existing(kind)),
+        };
+
+        let output = Command::new("docker")
+            .args([
+                "run",
+                "--detach",
+                "--rm",
+                "--publish",
+                "127.0.0.1::5000",
+                image,
+            ])
+            .output()
+            .context("executing docker: is it installed and on PATH?")?;
+        if !output.status.success() {
+            bail!(
+                "Failed to start {}: {}",
+                image,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        let container_id = String::from_utf8(output.stdout)?.trim().to_owned();
+
+        let output = Command::new("docker")
+            .args(["port", &container_id, "5000/tcp"])
+            .output()?;
+        let host = String::from_utf8(output.stdout)?
+            .lines()
+            .next()
+            .context("Registry container has no published port")?
+            .trim()
+            .to_owned();
+
+        let controller = Self {
+            kind,
+            host,
+            namespace: None,
+            container_id: Some(container_id),
+        };
+        controller.wait_ready().await?;
+        Ok(Some(controller))
+    }
+
+    fn existing(kind: RegistryKind) -> Option<Self> {
+        let location = std::env::var(HARBOR_REGISTRY_ENV).ok()?;
+        let (host, namespace) = match location.split_once('/') {
+            Some((host, namespace)) => (host.to_owned(), Some(namespace.to_owned())),
+            None => (location, None),
+        };
+        Some(Self {
+            kind,
+            host,
+            namespace,
+            container_id: None,
+        })
+    }
+
+    async fn wait_ready(&self) -> Result<()> {
+        let url = format!("http://{}/v2/", self.host);
+        for _ in 0..50 {
+            if let Ok(response) = reqwest::get(&url).await {
+                if response.status().is_success() || response.status().as_u16() == 401 {
+                    return Ok(());
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        bail!(
+            "{:?} registry at {} did not become ready",
+            self.kind,
+            self.host
+        )
+    }
+
+    /// The full name of a test repository.
+    pub fn repository(&self, name: &str) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{}/{}", namespace, name),
+            None => name.to_owned(),
+        }
+    }
+
+    /// Pushes a test Spin application using the raw distribution API, so
+    /// that the format can be checked independently of the Spin client.
+    /// Returns the manifest that was pushed and its digest.
+    pub async fn push_test_app(
+        &self,
+        repository: &str,
+        tag: &str,
+        profile: MediaTypeProfile,
+    ) -> Result<PushedManifest> {
+        let http = reqwest::Client::new();
+        let config = json!({
+            "spin_lock_version": 0,
+            "triggers": [],
+            "components": [{
+                "id": "hello",
+                "source": { "content_type": "application/wasm", "digest": sha256_digest(TEST_WASM) },
+                "files": [{ "digest": sha256_digest(TEST_ASSET), "path": "hello.txt" }]
+            }]
+        });
+        let config = serde_json::to_vec(&config)?;
+
+        let config_descriptor = self
+            .push_blob(&http, repository, &config, SPIN_CONFIG_MEDIA_TYPE, profile)
+            .await?;
+        let layers = vec![
+            self.push_blob(&http, repository, TEST_WASM, WASM_LAYER_MEDIA_TYPE, profile)
+                .await?,
+            self.push_blob(
+                &http,
+                repository,
+                TEST_ASSET,
+                DATA_LAYER_MEDIA_TYPE,
+                profile,
+            )
+            .await?,
+        ];
+
+        let mut annotations = profile.manifest_annotations();
+        annotations.insert(TEST_ANNOTATION.0.to_owned(), TEST_ANNOTATION.1.to_owned());
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_MANIFEST_MEDIA_TYPE,
+            "config": config_descriptor,
+            "layers": layers,
+            "annotations": annotations,
+        });
+        let data = serde_json::to_vec(&manifest)?;
+
+        let url = format!("http://{}/v2/{}/manifests/{}", self.host, repository, tag);
+        let response = http
+            .put(&url)
+            .header(reqwest::header::CONTENT_TYPE, OCI_MANIFEST_MEDIA_TYPE)
+            .body(data.clone())
+            .send()
+            .await?;
+        let status = response.status().as_u16();
+        if !response.status().is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(PushRejected { status, body }.into());
+        }
+
+        Ok(PushedManifest {
+            digest: sha256_digest(&data),
+            manifest,
+        })
+    }
+
+    async fn push_blob(
+        &self,
+        http: &reqwest::Client,
+        repository: &str,
+        data: &[u8],
+        spin_media_type: &str,
+        profile: MediaTypeProfile,
+    ) -> Result<Value> {
+        let digest = sha256_digest(data);
+        let url = format!("http://{}/v2/{}/blobs/uploads/", self.host, repository);
+        let response = http.post(&url).send().await?.error_for_status()?;
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .context("Upload response has no location")?;
+        let location = if location.starts_with('/') {
+            format!("http://{}{}", self.host, location)
+        } else {
+            location.to_owned()
+        };
+        let separator = if location.contains('?') { '&' } else { '?' };
+        http.put(format!("{}{}digest={}", location, separator, digest))
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .body(data.to_vec())
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let (media_type, annotations) = profile.describe(spin_media_type);
+        let mut descriptor = json!({
+            "mediaType": media_type,
+            "digest": digest,
+            "size": data.len(),
+        });
+        if let Some(annotations) = annotations {
+            descriptor["annotations"] = json!(annotations);
+        }
+        Ok(descriptor)
+    }
+}
+
+impl Drop for RegistryController {
+    fn drop(&mut self) {
+        if let Some(id) = &self.container_id {
+            let _ = Command::new("docker").args(["rm", "--force", id]).output();
+        }
+    }
+}
+
+/// A manifest pushed by the harness.
+pub struct PushedManifest {
+    /// The digest of the manifest
+    pub digest: String,
+    /// The manifest content
+    pub manifest: Value,
+}
+
+/// A registry refused a manifest.
+#[derive(Debug)]
+pub struct PushRejected {
+    /// The response status
+    pub status: u16,
+    /// The response body
+    pub body: String,
+}
+
+impl std::fmt::Display for PushRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "registry rejected manifest with status {}: {}",
+            self.status, self.body
+        )
+    }
+}
+
+impl std::error::Error for PushRejected {}
+
+/// The annotations of a descriptor or manifest.
+pub fn annotations(value: &Value) -> HashMap<String, String> {
+    serde_json::from_value(value["annotations"].clone()).unwrap_or_default()
+}
+
+/// Computes an OCI digest.
+pub fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
@@ -0,0 +1,114 @@
+#![cfg(feature = "registry-tests")]
+
+use anyhow::Result;
+use serde_json::Value;
+use spin_oci_conformance::*;
+use spin_publish::oci::{
+    is_media_type_rejection, spin_media_type, Client, MediaTypeProfile, PROFILE_ANNOTATION,
+    SPIN_CONFIG_MEDIA_TYPE, WASM_LAYER_MEDIA_TYPE,
+};
+
+#[tokio::test]
+async fn spin_artifacts_round_trip_through_registries() -> Result<()> {
+    for kind in RegistryKind::ALL {
+        let registry = match RegistryController::start(*kind).await? {
+            Some(registry) => registry,
+            None => {
+                eprintln!("Skipping {:?}: not available", kind);
+                continue;
+            }
+        };
+        check_round_trip(&registry).await?;
+    }
+    Ok(())
+}
+
+async fn check_round_trip(registry: &RegistryController) -> Result<()> {
+    let repository = registry.repository("spin-conformance");
+    let profile = match registry
+        .push_test_app(&repository, "v1", MediaTypeProfile::Native)
+        .await
+    {
+        Ok(_) => MediaTypeProfile::Native,
+        Err(e) => match e.downcast_ref::<PushRejected>() {
+            Some(rejected) if is_media_type_rejection(rejected.status, &rejected.body) => {
+                eprintln!(
+                    "{:?} rejected native media types; checking compatible profile",
+                    registry.kind
+                );
+                MediaTypeProfile::Compatible
+            }
+            _ => return Err(e),
+        },
+    };
+    let pushed = registry.push_test_app(&repository, "v1", profile).await?;
+
+    let client = Client::new(true)?.with_wasm_validation(true);
+    let fetched = client
+        .fetch_manifest(&registry.host, &repository, "v1")
+        .await?;
+    assert_eq!(pushed.digest, fetched.digest, "{:?}: digest", registry.kind);
+
+    let manifest: Value = serde_json::from_slice(&fetched.data)?;
+    assert_eq!(pushed.manifest, manifest, "{:?}: manifest", registry.kind);
+
+    let annotations = annotations(&manifest);
+    assert_eq!(
+        Some(&TEST_ANNOTATION.1.to_owned()),
+        annotations.get(TEST_ANNOTATION.0),
+        "{:?}: manifest annotations",
+        registry.kind
+    );
+    assert_eq!(
+        Some(&profile.name().to_owned()),
+        annotations.get(PROFILE_ANNOTATION),
+        "{:?}: profile annotation",
+        registry.kind
+    );
+
+    let config = &manifest["config"];
+    let config_annotations = annotations_of(config);
+    assert_eq!(
+        SPIN_CONFIG_MEDIA_TYPE,
+        spin_media_type(
+            config["mediaType"].as_str().unwrap(),
+            config_annotations.as_ref()
+        ),
+        "{:?}: config media type",
+        registry.kind
+    );
+
+    let wasm = &manifest["layers"][0];
+    let wasm_annotations = annotations_of(wasm);
+    let wasm_media_type = spin_media_type(
+        wasm["mediaType"].as_str().unwrap(),
+        wasm_annotations.as_ref(),
+    );
+    assert_eq!(WASM_LAYER_MEDIA_TYPE, wasm_media_type);
+    let wasm_data = client
+        .fetch_layer(
+            &registry.host,
+            &repository,
+            wasm["digest"].as_str().unwrap(),
+            wasm_media_type,
+        )
+        .await?;
+    assert_eq!(TEST_WASM, wasm_data.as_slice());
+
+    let by_digest = client
+        .fetch_manifest(&registry.host, &repository, &pushed.digest)
+        .await?;
+    assert_eq!(
+        fetched.data, by_digest.data,
+        "{:?}: pull by digest",
+        registry.kind
+    );
+
+    Ok(())
+}
+
+fn annotations_of(descriptor: &Value) -> Option<std::collections::HashMap<String, String>> {
+    descriptor
+        .get("annotations")
+        .map(|_| annotations(descriptor))
+}
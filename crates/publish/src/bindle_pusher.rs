@@ -1,23 +1,225 @@
 #![deny(missing_docs)]
 
-use crate::{PublishError, PublishResult};
-use bindle::{standalone::StandaloneRead, Id};
-use std::path::Path;
+use crate::{
+    expander::group_name_for,
+    throttle::{Throttle, Throttled},
+    PublishError, PublishResult,
+};
+use bindle::{client::tokens::TokenManager, standalone::StandaloneRead, Id, Invoice, Label};
+use spin_loader::bindle::{config as bindle_schema, SPIN_MANIFEST_MEDIA_TYPE};
+use std::{collections::HashSet, path::Path, sync::Arc};
+use tokio_util::io::ReaderStream;
 
-/// Pushes a standalone bindle to a Bindle server.
+/// The outcome of pushing a bindle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PushOutcome {
+    /// The bindle was pushed to the server.
+    Pushed,
+    /// A bindle with the same ID already exists on the server with all its
+    /// parcels, so nothing was pushed.
+    AlreadyExists,
+}
+
+/// Options controlling how a bindle is pushed.
+#[derive(Clone, Debug, Default)]
+pub struct PushOptions {
+    /// If set, parcels are uploaded one at a time at no more than this many
+    /// bytes per second.
+    pub upload_limit: Option<u64>,
+    /// If set, only the parcels used by these components are uploaded. The
+    /// parcels of other components must already exist on the server.
+    pub components: Option<Vec<String>>,
+}
+
+/// Pushes a standalone bindle to a Bindle server. If the bindle's invoice
+/// is already on the server, as after pushing only some components, the
+/// parcels the server is missing are uploaded.
 pub async fn push_all(
     path: impl AsRef<Path>,
     bindle_id: &Id,
     bindle_connection_info: spin_loader::bindle::BindleConnectionInfo,
-) -> PublishResult<()> {
+    options: &PushOptions,
+) -> PublishResult<PushOutcome> {
     let reader = StandaloneRead::new(&path, bindle_id).await?;
     let client = &bindle_connection_info.client()?;
 
-    if client.get_yanked_invoice(bindle_id).await.is_ok() {
-        return Err(PublishError::BindleAlreadyExists(bindle_id.to_string()));
-    }
+    let missing = if client.get_yanked_invoice(bindle_id).await.is_ok() {
+        let missing = client.get_missing_parcels(bindle_id).await?;
+        if missing.is_empty() {
+            return Ok(PushOutcome::AlreadyExists);
+        }
+        missing
+    } else if options.upload_limit.is_none() && options.components.is_none() {
+        reader.push(client).await?;
+        return Ok(PushOutcome::Pushed);
+    } else {
+        client
+            .create_invoice_from_file(&reader.invoice_file)
+            .await?
+            .missing
+            .unwrap_or_default()
+    };
+
+    push_parcels(&reader, bindle_id, client, options, missing).await?;
+    Ok(PushOutcome::Pushed)
+}
 
-    reader.push(client).await?;
+/// Uploads the parcels of a bindle which the server is missing, or only
+/// those used by the selected components if some are selected.
+async fn push_parcels<T: TokenManager + Send + Sync + 'static>(
+    reader: &StandaloneRead,
+    bindle_id: &Id,
+    client: &bindle::client::Client<T>,
+    options: &PushOptions,
+    missing: Vec<Label>,
+) -> PublishResult<()> {
+    let selected = match &options.components {
+        Some(components) => Some(component_parcels(reader, components).await?),
+        None => None,
+    };
+    let throttle = options
+        .upload_limit
+        .map(|limit| Arc::new(Throttle::new(limit)));
+
+    let (upload, skipped) = select_parcels(missing, selected.as_ref());
+    for label in upload {
+        let path = reader.parcel_data_path(&label.sha256);
+        let file = tokio::fs::File::open(&path)
+            .await
+            .map_err(|e| PublishError::Io {
+                source: e,
+                description: format!("Failed to open parcel {}", path.display()),
+            })?;
+        let stream = ReaderStream::new(file);
+        match &throttle {
+            Some(throttle) => {
+                let stream = Throttled::new(stream, throttle.clone());
+                client
+                    .create_parcel_from_stream(bindle_id.clone(), &label.sha256, stream)
+                    .await?
+            }
+            None => {
+                client
+                    .create_parcel_from_stream(bindle_id.clone(), &label.sha256, stream)
+                    .await?
+            }
+        }
+    }
 
+    if skipped > 0 {
+        return Err(PublishError::IncompleteBindle {
+            bindle_id: bindle_id.to_string(),
+            missing: skipped,
+        });
+    }
     Ok(())
 }
+
+/// Splits the parcels a server is missing into those to upload, which are
+/// all of them unless only some parcels are selected, and the number which
+/// are left missing.
+fn select_parcels(missing: Vec<Label>, selected: Option<&HashSet<String>>) -> (Vec<Label>, usize) {
+    let total = missing.len();
+    let upload: Vec<_> = match selected {
+        Some(selected) => missing
+            .into_iter()
+            .filter(|label| selected.contains(&label.sha256))
+            .collect(),
+        None => missing,
+    };
+    let skipped = total - upload.len();
+    (upload, skipped)
+}
+
+/// Finds the parcels used by the given components: their Wasm sources and
+/// assets, plus the application manifest.
+async fn component_parcels(
+    reader: &StandaloneRead,
+    components: &[String],
+) -> PublishResult<HashSet<String>> {
+    let invoice_text = tokio::fs::read_to_string(&reader.invoice_file)
+        .await
+        .map_err(|e| PublishError::Io {
+            source: e,
+            description: format!("Failed to read invoice {}", reader.invoice_file.display()),
+        })?;
+    let invoice: Invoice = toml::from_str(&invoice_text)
+        .map_err(|e| anyhow::anyhow!("Invalid invoice {}: {}", reader.invoice_file.display(), e))?;
+    let parcels = invoice.parcel.unwrap_or_default();
+
+    let manifest_parcel = parcels
+        .iter()
+        .find(|p| p.label.media_type == SPIN_MANIFEST_MEDIA_TYPE)
+        .ok_or_else(|| anyhow::anyhow!("Bindle has no application manifest"))?;
+    let manifest_bytes = reader.get_parcel(&manifest_parcel.label.sha256).await?;
+    let manifest: bindle_schema::RawAppManifest = toml::from_slice(&manifest_bytes)
+        .map_err(|e| anyhow::anyhow!("Invalid application manifest in bindle: {}", e))?;
+
+    let mut selected = HashSet::from([manifest_parcel.label.sha256.clone()]);
+    for id in components {
+        let component = manifest
+            .components
+            .iter()
+            .find(|c| &c.id == id)
+            .ok_or_else(|| PublishError::UnknownComponent(id.clone()))?;
+        selected.insert(component.source.clone());
+
+        let group = group_name_for(id);
+        selected.extend(
+            parcels
+                .iter()
+                .filter(|p| {
+                    p.conditions
+                        .as_ref()
+                        .and_then(|c| c.member_of.as_ref())
+                        .map_or(false, |groups| groups.contains(&group))
+                })
+                .map(|p| p.label.sha256.clone()),
+        );
+    }
+    Ok(selected)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn label(sha256: &str) -> Label {
+        Label {
+            sha256: sha256.to_owned(),
+            name: sha256.to_owned(),
+            size: 1,
+            media_type: "application/octet-stream".to_owned(),
+            annotations: None,
+            feature: None,
+            origin: None,
+        }
+    }
+
+    fn digests(labels: &[Label]) -> Vec<&str> {
+        labels.iter().map(|l| l.sha256.as_str()).collect()
+    }
+
+    #[test]
+    fn partial_push_uploads_only_selected_parcels() {
+        let missing = vec![label("manifest"), label("web"), label("api")];
+        let selected = HashSet::from(["manifest".to_owned(), "web".to_owned()]);
+        let (upload, skipped) = select_parcels(missing, Some(&selected));
+        assert_eq!(vec!["manifest", "web"], digests(&upload));
+        assert_eq!(1, skipped);
+    }
+
+    #[test]
+    fn repush_after_partial_push_uploads_the_rest() {
+        // After a partial push, the server is missing only the parcels of
+        // the components which were not selected.
+        let (upload, skipped) = select_parcels(vec![label("api")], None);
+        assert_eq!(vec!["api"], digests(&upload));
+        assert_eq!(0, skipped);
+
+        let selected = HashSet::from(["manifest".to_owned(), "api".to_owned()]);
+        let (upload, skipped) = select_parcels(vec![label("api")], Some(&selected));
+        assert_eq!(vec!["api"], digests(&upload));
+        assert_eq!(0, skipped);
+    }
+}
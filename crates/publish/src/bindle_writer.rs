@@ -1,6 +1,6 @@
 #![deny(missing_docs)]
 
-use crate::{expander::expand_manifest, PublishError, PublishResult};
+use crate::{expander::expand_manifest, PublishError, PublishFilter, PublishResult, Staging};
 use bindle::{Invoice, Parcel};
 use spin_loader::local::parent_dir;
 use std::{
@@ -9,13 +9,17 @@ use std::{
 };
 
 /// Expands a file-based application manifest to a Bindle invoice and writes it
-/// as a standalone bindle.
+/// as a standalone bindle, staging the application's files on the way. Only
+/// the asset files admitted by the filter are included.
 pub async fn prepare_bindle(
     app_file: impl AsRef<Path>,
     buildinfo: Option<semver::BuildMetadata>,
     dest_dir: impl AsRef<Path>,
+    staging: &Staging,
+    filter: &PublishFilter,
 ) -> PublishResult<bindle::Id> {
-    let (invoice, sources) = expand_manifest(&app_file, buildinfo, &dest_dir).await?;
+    let (invoice, sources) =
+        expand_manifest(&app_file, buildinfo, &dest_dir, staging, filter).await?;
     let source_dir = parent_dir(&app_file)?;
 
     write(&source_dir, &dest_dir, &invoice, &sources).await?;
@@ -100,22 +104,27 @@ impl BindleWriter {
     }
 
     async fn write_one_parcel(&self, parcels_dir: &Path, parcel: &Parcel) -> PublishResult<()> {
-        let source_file = match self.parcel_sources.source(&parcel.label.sha256) {
-            Some(path) => path.clone(),
-            None => self.source_dir.join(&parcel.label.name),
+        let (source_file, staged) = match self.parcel_sources.source(&parcel.label.sha256) {
+            Some(path) => (path.clone(), true),
+            None => (self.source_dir.join(&parcel.label.name), false),
         };
         let hash = &parcel.label.sha256;
         let dest_file = parcels_dir.join(format!("{}.dat", hash));
-        tokio::fs::copy(&source_file, &dest_file)
-            .await
-            .map_err(|e| PublishError::Io {
-                description: format!(
-                    "Failed to copy parcel from {} to '{}'",
-                    source_file.display(),
-                    dest_file.display()
-                ),
-                source: e,
-            })?;
+        // Staged content never changes, so the parcel can share its storage.
+        // Application files must be copied in case they are later modified.
+        let linked = staged && tokio::fs::hard_link(&source_file, &dest_file).await.is_ok();
+        if !linked {
+            tokio::fs::copy(&source_file, &dest_file)
+                .await
+                .map_err(|e| PublishError::Io {
+                    description: format!(
+                        "Failed to copy parcel from {} to '{}'",
+                        source_file.display(),
+                        dest_file.display()
+                    ),
+                    source: e,
+                })?;
+        }
 
         if has_annotation(parcel, DELETE_ON_WRITE) {
             tokio::fs::remove_file(&source_file).await.ignore_errors(); // Leaking a temp file is sad but not a reason to fail
@@ -19,6 +19,78 @@ pub enum PublishError {
     /// Publishing of components whose sources are already bindles is not supported
     #[error("This version of Spin can't publish components whose sources are already bindles")]
     BindlePushingNotImplemented,
+    /// A registry operation did not finish within its deadline
+    #[error("{operation} did not finish within {deadline:?}")]
+    DeadlineExceeded {
+        /// The operation which was abandoned
+        operation: String,
+        /// How long the operation was allowed to take
+        deadline: std::time::Duration,
+    },
+    /// Content downloaded from a registry does not have the expected digest
+    #[error("Content from {location} has digest {actual}, but {expected} was expected. It may have been corrupted or tampered with")]
+    DigestMismatch {
+        /// Where the content was downloaded from
+        location: String,
+        /// The digest by which the content was requested
+        expected: String,
+        /// The digest of the content actually received
+        actual: String,
+    },
+    /// A digest names an unsupported algorithm or is malformed
+    #[error("Invalid digest {digest}: {reason}")]
+    InvalidDigest {
+        /// The digest
+        digest: String,
+        /// Why it is invalid
+        reason: String,
+    },
+    /// The registry refused to replace an existing tag, as its tags are
+    /// immutable
+    #[error("The registry refused to replace the existing tag {reference}, as its tags are immutable: {message}")]
+    ImmutableTag {
+        /// The reference whose tag exists
+        reference: String,
+        /// The registry's response
+        message: String,
+    },
+    /// A reference no longer resolves to the digest it was pinned to
+    #[error("{reference} resolves to {actual}, but is pinned to {expected}. It may have been pushed again since it was pinned")]
+    PinnedDigestMismatch {
+        /// The reference
+        reference: String,
+        /// The digest the reference was pinned to
+        expected: String,
+        /// The digest the reference now resolves to
+        actual: String,
+    },
+    /// Parcels of components which were not pushed are missing from the server
+    #[error("Bindle {bindle_id} is missing {missing} parcel(s) used by components which were not pushed. Push all components to complete it")]
+    IncompleteBindle {
+        /// The bindle which was pushed
+        bindle_id: String,
+        /// The number of parcels which are missing from the server
+        missing: usize,
+    },
+    /// A reference or version template could not be expanded
+    #[error("Invalid template '{template}': {reason}")]
+    InvalidTemplate {
+        /// The template
+        template: String,
+        /// What is wrong with the template
+        reason: String,
+    },
+    /// An OCI reference could not be parsed
+    #[error("Invalid reference '{reference}': {reason}")]
+    InvalidReference {
+        /// The reference
+        reference: String,
+        /// What is wrong with the reference
+        reason: String,
+    },
+    /// A key for signing pushed applications could not be loaded
+    #[error("Invalid signing key: {0}")]
+    InvalidSigningKey(String),
     /// IO errors from interacting with the file system
     #[error("{description}")]
     Io {
@@ -27,9 +99,69 @@ pub enum PublishError {
         /// Underlying lower level error that caused your error
         source: std::io::Error,
     },
+    /// A layer whose media type says it is Wasm is not a valid Wasm module
+    /// or component
+    #[error("Layer {digest} is not a valid Wasm module or component: {reason}")]
+    InvalidWasmLayer {
+        /// The digest of the layer
+        digest: String,
+        /// What is wrong with the layer content
+        reason: String,
+    },
     /// Build artifact is missing
     #[error("Missing build artifact: '{0}'")]
     MissingBuildArtifact(String),
+    /// A registry reference is to a container image, or an index of
+    /// container images, rather than a Spin application
+    #[error("{reference} is a container image{}, not a Spin application", for_platforms(.platforms))]
+    NotSpinApplication {
+        /// The reference
+        reference: String,
+        /// The platforms of the images, if the reference is an image index,
+        /// as in `linux/amd64`
+        platforms: Vec<String>,
+    },
+    /// A push failed part way, leaving some of its layers in the registry
+    #[error("Push to {} failed after {} layer(s) were pushed: {source}", .token.reference, .token.pushed.len())]
+    PushIncomplete {
+        /// How far the push got, from which it can be resumed
+        token: Box<crate::oci::ResumeToken>,
+        /// Why the push failed
+        source: Box<PublishError>,
+    },
+    /// Request to an OCI registry failed
+    #[error("Error communicating with registry")]
+    RegistryRequest(#[from] reqwest::Error),
+    /// OCI registry returned an unexpected response
+    #[error("Registry request to {url} failed with status {status}: {message}")]
+    RegistryResponse {
+        /// The URL that was requested
+        url: String,
+        /// The HTTP status code of the response
+        status: u16,
+        /// The response body or a description of the problem with it
+        message: String,
+    },
+    /// The OCI registry could not be reached
+    #[error("Cannot reach registry {registry}")]
+    RegistryUnreachable {
+        /// The registry host
+        registry: String,
+        /// The underlying connection error
+        source: reqwest::Error,
+    },
+    /// Access to the OCI registry was refused
+    #[error("Registry authentication failed: {0}")]
+    RegistryUnauthorized(String),
+    /// Registry content does not satisfy the content trust policy
+    #[error("Content trust policy violation: {0}")]
+    PolicyViolation(#[from] crate::oci::PolicyViolation),
+    /// The registry does not support listing repositories
+    #[error("Cannot list repositories: {0}")]
+    RepositoryListingUnsupported(String),
+    /// A change refers to a component the application does not contain
+    #[error("The application has no component '{0}'")]
+    UnknownComponent(String),
     /// Invalid TOML serialization that can occur when serializing an object to a request
     #[error("{description}")]
     TomlSerialization {
@@ -43,3 +175,11 @@ pub enum PublishError {
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
+
+fn for_platforms(platforms: &[String]) -> String {
+    if platforms.is_empty() {
+        String::new()
+    } else {
+        format!(" index for {}", platforms.join(", "))
+    }
+}
@@ -1,21 +1,25 @@
 #![deny(missing_docs)]
 
 use crate::bindle_writer::{self, ParcelSources};
-use crate::{PublishError, PublishResult};
+use crate::{guest_path::guest_path, PublishError, PublishFilter, PublishResult, Staging};
 use bindle::{BindleSpec, Condition, Group, Invoice, Label, Parcel};
 use semver::BuildMetadata;
 use spin_loader::{
     bindle::config as bindle_schema,
-    digest::{bytes_sha256_string, file_sha256_string},
+    digest::bytes_sha256_string,
     local::{absolutize, config as local_schema, parent_dir, validate_raw_app_manifest, UrlSource},
 };
 use std::path::{Path, PathBuf};
 
-/// Expands a file-based application manifest to a Bindle invoice.
+/// Expands a file-based application manifest to a Bindle invoice. The
+/// application's files are staged, and the parcel sources refer to the
+/// staged copies. Asset files which the filter does not admit are left out.
 pub async fn expand_manifest(
     app_file: impl AsRef<Path>,
     buildinfo: Option<BuildMetadata>,
     scratch_dir: impl AsRef<Path>,
+    staging: &Staging,
+    filter: &PublishFilter,
 ) -> PublishResult<(Invoice, ParcelSources)> {
     let app_file = absolutize(app_file)?;
     let manifest = spin_loader::local::raw_manifest_from_file(&app_file).await?;
@@ -26,7 +30,7 @@ pub async fn expand_manifest(
     // * create a new spin.toml-like document where
     //   - each component changes its `files` entry to a group name
     //   - each component changes its `source` entry to a parcel SHA
-    let dest_manifest = bindle_manifest(&manifest, &app_dir).await?;
+    let dest_manifest = bindle_manifest(&manifest, &app_dir, staging).await?;
 
     // * create an invoice where
     //   - the metadata is copied from the app manifest
@@ -38,10 +42,10 @@ pub async fn expand_manifest(
     //   - there is a parcel for the spin.toml-a-like and it has the magic media type
 
     // - n parcels for the Wasm modules at their locations
-    let wasm_parcels = wasm_parcels(&manifest, &app_dir, &scratch_dir).await?;
+    let wasm_parcels = wasm_parcels(&manifest, &app_dir, &scratch_dir, staging).await?;
     let wasm_parcels = consolidate_wasm_parcels(wasm_parcels);
     // - n parcels for the assets under the base directory
-    let asset_parcels = asset_parcels(&manifest, &app_dir).await?;
+    let asset_parcels = asset_parcels(&manifest, &app_dir, staging, filter).await?;
     let asset_parcels = consolidate_asset_parcels(asset_parcels);
     // - one parcel to rule them all, and in the Spin app bind them
     let manifest_parcel = manifest_parcel(&dest_manifest, &scratch_dir).await?;
@@ -73,11 +77,12 @@ pub async fn expand_manifest(
 async fn bindle_manifest(
     local: &local_schema::RawAppManifest,
     base_dir: &Path,
+    staging: &Staging,
 ) -> PublishResult<bindle_schema::RawAppManifest> {
     let futures = local
         .components
         .iter()
-        .map(|c| async { bindle_component_manifest(c, base_dir).await });
+        .map(|c| async { bindle_component_manifest(c, base_dir, staging).await });
     let components = futures::future::join_all(futures)
         .await
         .into_iter()
@@ -95,6 +100,7 @@ async fn bindle_manifest(
 async fn bindle_component_manifest(
     local: &local_schema::RawComponentManifest,
     base_dir: &Path,
+    staging: &Staging,
 ) -> PublishResult<bindle_schema::RawComponentManifest> {
     let source_digest = match &local.source {
         local_schema::RawModuleSource::FileReference(path) => {
@@ -106,7 +112,7 @@ async fn bindle_component_manifest(
                 ));
             }
 
-            sha256_digest(&full_path)?
+            staging.stage(&full_path).await?.sha256
         }
         local_schema::RawModuleSource::Bindle(_) => {
             return Err(PublishError::BindlePushingNotImplemented);
@@ -135,11 +141,12 @@ async fn wasm_parcels(
     manifest: &local_schema::RawAppManifest,
     base_dir: &Path,
     scratch_dir: impl AsRef<Path>,
+    staging: &Staging,
 ) -> PublishResult<Vec<SourcedParcel>> {
     let parcel_futures = manifest
         .components
         .iter()
-        .map(|c| wasm_parcel(c, base_dir, scratch_dir.as_ref()));
+        .map(|c| wasm_parcel(c, base_dir, scratch_dir.as_ref(), staging));
     let parcels = futures::future::join_all(parcel_futures).await;
     parcels.into_iter().collect()
 }
@@ -148,6 +155,7 @@ async fn wasm_parcel(
     component: &local_schema::RawComponentManifest,
     base_dir: &Path,
     scratch_dir: impl AsRef<Path>,
+    staging: &Staging,
 ) -> PublishResult<SourcedParcel> {
     let (wasm_file, absolute_wasm_file) = match &component.source {
         local_schema::RawModuleSource::FileReference(path) => {
@@ -167,22 +175,31 @@ async fn wasm_parcel(
         }
     };
 
-    file_parcel(&absolute_wasm_file, wasm_file, None, "application/wasm").await
+    file_parcel(
+        &absolute_wasm_file,
+        wasm_file,
+        None,
+        "application/wasm",
+        staging,
+    )
+    .await
 }
 
 async fn asset_parcels(
     manifest: &local_schema::RawAppManifest,
     base_dir: impl AsRef<Path>,
+    staging: &Staging,
+    filter: &PublishFilter,
 ) -> PublishResult<Vec<SourcedParcel>> {
     let assets_by_component: Vec<Vec<_>> = manifest
         .components
         .iter()
-        .map(|c| collect_assets(c, &base_dir))
+        .map(|c| collect_assets(c, &base_dir, filter))
         .collect::<PublishResult<_>>()?;
     let parcel_futures = assets_by_component
         .iter()
         .flatten()
-        .map(|(fm, s)| file_parcel_from_mount(fm, s));
+        .map(|(fm, s)| file_parcel_from_mount(fm, s, staging));
     let parcel_results = futures::future::join_all(parcel_futures).await;
     let parcels = parcel_results.into_iter().collect::<PublishResult<_>>()?;
     Ok(parcels)
@@ -191,12 +208,20 @@ async fn asset_parcels(
 fn collect_assets(
     component: &local_schema::RawComponentManifest,
     base_dir: impl AsRef<Path>,
+    filter: &PublishFilter,
 ) -> PublishResult<Vec<(spin_loader::local::assets::FileMount, String)>> {
     let patterns = component.wasm.files.clone().unwrap_or_default();
     let exclude_files = component.wasm.exclude_files.clone().unwrap_or_default();
     let file_mounts = spin_loader::local::assets::collect(&patterns, &exclude_files, &base_dir)?;
     let annotated = file_mounts
         .into_iter()
+        .filter(|fm| {
+            let admitted = filter.admits(base_dir.as_ref(), &fm.src);
+            if !admitted {
+                tracing::debug!("Not publishing {}: excluded by filter", fm.src.display());
+            }
+            admitted
+        })
         .map(|v| (v, component.id.clone()))
         .collect();
     Ok(annotated)
@@ -205,6 +230,7 @@ fn collect_assets(
 async fn file_parcel_from_mount(
     file_mount: &spin_loader::local::assets::FileMount,
     component_id: &str,
+    staging: &Staging,
 ) -> PublishResult<SourcedParcel> {
     let source_file = &file_mount.src;
 
@@ -217,6 +243,7 @@ async fn file_parcel_from_mount(
         &file_mount.relative_dst,
         Some(component_id),
         &media_type,
+        staging,
     )
     .await
 }
@@ -226,12 +253,14 @@ async fn file_parcel(
     dest_relative_path: impl AsRef<Path>,
     component_id: Option<&str>,
     media_type: impl Into<String>,
+    staging: &Staging,
 ) -> PublishResult<SourcedParcel> {
+    let staged = staging.stage(abs_src).await?;
     let parcel = Parcel {
         label: Label {
-            sha256: sha256_digest(abs_src)?,
-            name: dest_relative_path.as_ref().display().to_string(),
-            size: file_metadata(&abs_src).await?.len(),
+            sha256: staged.sha256,
+            name: guest_path(dest_relative_path.as_ref()),
+            size: staged.size,
             media_type: media_type.into(),
             annotations: None,
             feature: None,
@@ -245,7 +274,7 @@ async fn file_parcel(
 
     Ok(SourcedParcel {
         parcel,
-        source: abs_src.to_owned(),
+        source: staged.path,
     })
 }
 
@@ -354,7 +383,7 @@ fn build_groups(manifest: &local_schema::RawAppManifest) -> Vec<Group> {
         .collect()
 }
 
-fn group_name_for(component_id: &str) -> String {
+pub(crate) fn group_name_for(component_id: &str) -> String {
     format!("files-{}", component_id)
 }
 
@@ -409,16 +438,6 @@ fn split_sources(sourced_parcels: Vec<SourcedParcel>) -> (Vec<Parcel>, ParcelSou
     (parcels.collect(), parcel_sources)
 }
 
-fn sha256_digest(file: impl AsRef<Path>) -> PublishResult<String> {
-    file_sha256_string(&file).map_err(|e| PublishError::Io {
-        source: e,
-        description: format!(
-            "Failed to calculate digest for '{}'",
-            file.as_ref().display()
-        ),
-    })
-}
-
 async fn write_file(dir: &PathBuf, filename: &String, data: &[u8]) -> PublishResult<PathBuf> {
     let file = dir.join(filename);
 
@@ -0,0 +1,218 @@
+#![deny(missing_docs)]
+
+//! Selection of the application files which are published, as controlled
+//! by a `.spinignore` file.
+
+use std::path::{Component, Path, PathBuf};
+
+use glob::{MatchOptions, Pattern};
+
+use crate::{PublishError, PublishResult};
+
+/// The name of the file, in the application directory, listing the files
+/// which should not be published.
+pub const SPINIGNORE_FILE: &str = ".spinignore";
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Decides which of an application's asset files are published, whether to
+/// Bindle or to an OCI registry. Files matched by the `.spinignore` file,
+/// which uses gitignore syntax, are left out, as are hidden files and files
+/// reached through symbolic links unless those are explicitly included.
+#[derive(Clone, Debug, Default)]
+pub struct PublishFilter {
+    rules: Vec<IgnoreRule>,
+    include_hidden: bool,
+    follow_symlinks: bool,
+}
+
+#[derive(Clone, Debug)]
+struct IgnoreRule {
+    pattern: Pattern,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+impl PublishFilter {
+    /// Loads the `.spinignore` file in the given application directory. If
+    /// there is no such file, only hidden files and symbolic links are
+    /// excluded.
+    pub fn load(app_dir: &Path) -> PublishResult<Self> {
+        let path = app_dir.join(SPINIGNORE_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(PublishError::Io {
+                source: e,
+                description: format!("Failed to read {}", path.display()),
+            }),
+        }
+    }
+
+    /// Parses the content of a `.spinignore` file.
+    pub fn parse(text: &str) -> PublishResult<Self> {
+        let rules = text
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(IgnoreRule::parse)
+            .collect::<PublishResult<_>>()?;
+        Ok(Self {
+            rules,
+            ..Default::default()
+        })
+    }
+
+    /// Sets whether hidden files, and files in hidden directories, are
+    /// published. They are not by default.
+    pub fn include_hidden(mut self, include_hidden: bool) -> Self {
+        self.include_hidden = include_hidden;
+        self
+    }
+
+    /// Sets whether files reached through symbolic links are published.
+    /// They are not by default.
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Whether files reached through symbolic links are published.
+    pub fn follows_symlinks(&self) -> bool {
+        self.follow_symlinks
+    }
+
+    /// Whether the file at the given path, relative to the application
+    /// directory, is excluded from publishing by being hidden or by the
+    /// ignore rules. A file in an excluded directory is excluded, even if a
+    /// later rule would include it, as with gitignore.
+    pub fn excludes(&self, relative_path: &Path) -> bool {
+        let names: Vec<_> = relative_path
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+
+        let mut prefix = PathBuf::new();
+        for (index, name) in names.iter().enumerate() {
+            if !self.include_hidden && name.to_string_lossy().starts_with('.') {
+                return true;
+            }
+            prefix.push(name);
+            let is_dir = index + 1 < names.len();
+            if self.ignores(&prefix, &name.to_string_lossy(), is_dir) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Whether the file at `path`, within the application directory `root`,
+    /// is published: it is not excluded, and it is not reached through a
+    /// symbolic link unless links are followed.
+    pub fn admits(&self, root: &Path, path: &Path) -> bool {
+        let relative_path = match path.strip_prefix(root) {
+            Ok(relative_path) => relative_path,
+            Err(_) => return !self.is_symlink(path),
+        };
+        let symlinked = path
+            .ancestors()
+            .take_while(|ancestor| *ancestor != root)
+            .any(|ancestor| self.is_symlink(ancestor));
+        !symlinked && !self.excludes(relative_path)
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        !self.follow_symlinks
+            && std::fs::symlink_metadata(path)
+                .map(|m| m.file_type().is_symlink())
+                .unwrap_or(false)
+    }
+
+    /// Whether the last rule matching the path ignores it.
+    fn ignores(&self, path: &Path, name: &str, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for rule in &self.rules {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            let matched = if rule.anchored {
+                rule.pattern.matches_path_with(path, MATCH_OPTIONS)
+            } else {
+                rule.pattern.matches_with(name, MATCH_OPTIONS)
+            };
+            if matched {
+                ignored = !rule.negated;
+            }
+        }
+        ignored
+    }
+}
+
+impl IgnoreRule {
+    fn parse(line: &str) -> PublishResult<Self> {
+        let (negated, pattern) = match line.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, line),
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        // As in gitignore, a pattern containing a slash is matched against
+        // the whole path from the application directory, and any other
+        // pattern against file and directory names at any level.
+        let anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+        let pattern = Pattern::new(pattern).map_err(|e| {
+            PublishError::Other(anyhow::anyhow!(
+                "Invalid pattern '{}' in {}: {}",
+                line,
+                SPINIGNORE_FILE,
+                e
+            ))
+        })?;
+        Ok(Self {
+            pattern,
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn follows_gitignore_rules() {
+        let filter = PublishFilter::parse(
+            "# build output\ntarget/\n*.log\n!keep.log\n/docs/*.md\nsecrets/\n!secrets/public.txt\n",
+        )
+        .unwrap();
+
+        assert!(filter.excludes(Path::new("target/app.wasm")));
+        assert!(filter.excludes(Path::new("static/debug.log")));
+        assert!(!filter.excludes(Path::new("static/keep.log")));
+        assert!(filter.excludes(Path::new("docs/readme.md")));
+        assert!(!filter.excludes(Path::new("static/docs/readme.md")));
+        assert!(filter.excludes(Path::new("secrets/public.txt")));
+        assert!(!filter.excludes(Path::new("static/index.html")));
+    }
+
+    #[test]
+    fn excludes_hidden_files_unless_included() {
+        let filter = PublishFilter::default();
+        assert!(filter.excludes(Path::new(".env")));
+        assert!(filter.excludes(Path::new("static/.git/config")));
+        assert!(!filter.include_hidden(true).excludes(Path::new(".env")));
+    }
+}
@@ -0,0 +1,70 @@
+//! Paths in the file systems of components, which are separated by forward
+//! slashes whatever platform an application is published from, so that an
+//! application published on Windows runs on other hosts.
+
+use std::path::{Component, Path};
+
+/// The guest path with the components of a host path, separated by forward
+/// slashes. On Windows, host paths may use either separator, and a drive
+/// prefix is dropped.
+pub(crate) fn guest_path(path: &Path) -> String {
+    let mut guest = String::new();
+    for component in path.components() {
+        let segment = match component {
+            Component::RootDir => {
+                guest.push('/');
+                continue;
+            }
+            Component::Prefix(_) | Component::CurDir => continue,
+            Component::ParentDir => "..".into(),
+            Component::Normal(name) => name.to_string_lossy(),
+        };
+        if !guest.is_empty() && !guest.ends_with('/') {
+            guest.push('/');
+        }
+        guest.push_str(&segment);
+    }
+    guest
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn guest_paths_use_forward_slashes() {
+        let joined = Path::new("/static").join("css").join("site.css");
+        assert_eq!("/static/css/site.css", guest_path(&joined));
+        assert_eq!(
+            "static/index.html",
+            guest_path(Path::new("static/index.html"))
+        );
+        assert_eq!("/", guest_path(Path::new("/")));
+        assert_eq!(
+            "../shared/logo.png",
+            guest_path(Path::new("./../shared/logo.png"))
+        );
+    }
+
+    #[test]
+    fn guest_paths_round_trip() {
+        for guest in ["/static/css/site.css", "static/index.html", "/"] {
+            assert_eq!(guest, guest_path(&PathBuf::from(guest)));
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_separators_are_replaced() {
+        assert_eq!(
+            "static/css/site.css",
+            guest_path(Path::new(r"static\css\site.css"))
+        );
+        assert_eq!(
+            "/static/css/site.css",
+            guest_path(Path::new(r"C:\static/css\site.css"))
+        );
+    }
+}
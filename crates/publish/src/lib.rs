@@ -1,13 +1,30 @@
 #![deny(missing_docs)]
 
-//! Functions for publishing Spin applications to Bindle.
+//! Functions for publishing Spin applications to Bindle and OCI registries.
 
 mod bindle_pusher;
 mod bindle_writer;
 mod error;
 mod expander;
+mod filter;
+mod guest_path;
+pub mod oci;
+mod patcher;
+mod seams;
+mod staging;
+mod template;
+mod throttle;
+mod warning;
 
-pub use bindle_pusher::push_all;
+pub use bindle_pusher::{push_all, PushOptions, PushOutcome};
 pub use bindle_writer::{prepare_bindle, write};
 pub use error::{PublishError, PublishResult};
 pub use expander::expand_manifest;
+pub use filter::{PublishFilter, SPINIGNORE_FILE};
+pub use patcher::LockedAppPatcher;
+#[cfg(feature = "testing")]
+pub use seams::testing;
+pub use spin_secret::Secret;
+pub use staging::{StagedFile, Staging};
+pub use template::TemplateContext;
+pub use warning::PublishWarning;
@@ -0,0 +1,102 @@
+//! Archive layers, which pack all the static asset files of a component
+//! into a single tar layer.
+
+use std::{
+    fs::File,
+    io::Write,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{PublishError, PublishResult};
+
+/// The media type of layers containing a tar archive of a component's
+/// static asset files, at their paths in the component's file system.
+pub const ARCHIVE_LAYER_MEDIA_TYPE: &str = "application/vnd.wasm.content.layer.v1+tar";
+
+/// Builds an archive of asset files, given as pairs of guest path and host
+/// file, writing it to `out`, which is returned. Entries are sorted and
+/// carry no timestamps or ownership, so that the same files always produce
+/// the same archive, and the same digest. Files are copied into the archive
+/// a block at a time, so that none is held in memory.
+pub fn build_archive<W: Write>(assets: &[(PathBuf, PathBuf)], out: W) -> PublishResult<W> {
+    let mut assets: Vec<_> = assets.iter().collect();
+    assets.sort();
+
+    let mut builder = tar::Builder::new(out);
+    for (guest_path, host_file) in assets {
+        let entry_path = archive_path(guest_path);
+        let read_error = |e| PublishError::Io {
+            source: e,
+            description: format!("Failed to read {}", host_file.display()),
+        };
+        let file = File::open(host_file).map_err(read_error)?;
+        let size = file.metadata().map_err(read_error)?.len();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        builder
+            .append_data(&mut header, &entry_path, file)
+            .map_err(|e| PublishError::Io {
+                source: e,
+                description: format!("Failed to archive {}", host_file.display()),
+            })?;
+    }
+    builder.into_inner().map_err(|e| PublishError::Io {
+        source: e,
+        description: "Failed to finish asset archive".to_owned(),
+    })
+}
+
+/// Unpacks an archive of asset files into a directory. Entries which would
+/// be written outside the directory are skipped.
+pub fn unpack_archive(data: &[u8], dest: &Path) -> PublishResult<()> {
+    tar::Archive::new(data)
+        .unpack(dest)
+        .map_err(|e| PublishError::Io {
+            source: e,
+            description: format!("Failed to unpack asset archive into {}", dest.display()),
+        })
+}
+
+/// The path of an entry in the archive: the guest path, relative to the
+/// root of the component's file system.
+fn archive_path(guest_path: &Path) -> PathBuf {
+    guest_path
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn archives_round_trip_guest_paths() {
+        let source = tempfile::tempdir().unwrap();
+        let index = source.path().join("index.html");
+        let style = source.path().join("style.css");
+        std::fs::write(&index, "<html/>").unwrap();
+        std::fs::write(&style, "body {}").unwrap();
+        let assets = vec![
+            (PathBuf::from("/static/index.html"), index.clone()),
+            (PathBuf::from("/static/css/style.css"), style.clone()),
+        ];
+
+        let archive = build_archive(&assets, vec![]).unwrap();
+        let reversed: Vec<_> = assets.iter().rev().cloned().collect();
+        assert_eq!(archive, build_archive(&reversed, vec![]).unwrap());
+
+        let dest = tempfile::tempdir().unwrap();
+        unpack_archive(&archive, dest.path()).unwrap();
+        assert_eq!(
+            "<html/>",
+            std::fs::read_to_string(dest.path().join("static/index.html")).unwrap()
+        );
+        assert_eq!(
+            "body {}",
+            std::fs::read_to_string(dest.path().join("static/css/style.css")).unwrap()
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! OCI 1.1 artifact manifests, which classify content by an artifact type
+//! rather than by the media type of an image config.
+
+use std::collections::HashMap;
+
+use oci_distribution::manifest::{OciDescriptor, OciImageManifest, OCI_IMAGE_MEDIA_TYPE};
+use serde::{Deserialize, Serialize};
+
+use super::SPIN_CONFIG_MEDIA_TYPE;
+
+/// The media type of OCI artifact manifests.
+pub const ARTIFACT_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.artifact.manifest.v1+json";
+/// The artifact type of Spin applications pushed with an artifact manifest.
+pub const SPIN_ARTIFACT_TYPE: &str = "application/vnd.fermyon.spin.application.v1";
+
+/// An artifact manifest. A Spin application's config, the locked
+/// application, is the first of its blobs, followed by its layers.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ArtifactManifest {
+    pub media_type: String,
+    pub artifact_type: String,
+    #[serde(default)]
+    pub blobs: Vec<OciDescriptor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+impl ArtifactManifest {
+    /// An artifact manifest of a Spin application with the given config and
+    /// layers.
+    pub fn spin(
+        config: OciDescriptor,
+        layers: Vec<OciDescriptor>,
+        annotations: Option<HashMap<String, String>>,
+    ) -> Self {
+        Self {
+            media_type: ARTIFACT_MANIFEST_MEDIA_TYPE.to_owned(),
+            artifact_type: SPIN_ARTIFACT_TYPE.to_owned(),
+            blobs: std::iter::once(config).chain(layers).collect(),
+            annotations,
+        }
+    }
+
+    /// The equivalent image manifest, so that an application pushed either
+    /// way is pulled the same way. Returns `None` if the manifest has no
+    /// Spin config blob.
+    pub fn into_image(self) -> Option<OciImageManifest> {
+        let position = self
+            .blobs
+            .iter()
+            .position(|b| b.media_type == SPIN_CONFIG_MEDIA_TYPE)?;
+        let mut layers = self.blobs;
+        let config = layers.remove(position);
+        Some(OciImageManifest {
+            schema_version: 2,
+            media_type: Some(OCI_IMAGE_MEDIA_TYPE.to_owned()),
+            config,
+            layers,
+            annotations: self.annotations,
+        })
+    }
+}
+
+/// Whether manifest content is an artifact manifest, going by the media
+/// type reported for it or the one it declares.
+pub(super) fn is_artifact_manifest(media_type: &str, data: &[u8]) -> bool {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct MediaType {
+        media_type: Option<String>,
+    }
+
+    media_type == ARTIFACT_MANIFEST_MEDIA_TYPE
+        || serde_json::from_slice::<MediaType>(data)
+            .map(|m| m.media_type.as_deref() == Some(ARTIFACT_MANIFEST_MEDIA_TYPE))
+            .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::oci::WASM_LAYER_MEDIA_TYPE;
+
+    fn descriptor(media_type: &str, digest: &str) -> OciDescriptor {
+        OciDescriptor {
+            media_type: media_type.to_owned(),
+            digest: digest.to_owned(),
+            size: 1,
+            urls: None,
+            annotations: None,
+        }
+    }
+
+    #[test]
+    fn artifact_manifests_convert_to_images() {
+        let manifest = ArtifactManifest::spin(
+            descriptor(SPIN_CONFIG_MEDIA_TYPE, "sha256:config"),
+            vec![descriptor(WASM_LAYER_MEDIA_TYPE, "sha256:wasm")],
+            None,
+        );
+        let data = serde_json::to_vec(&manifest).unwrap();
+        assert!(is_artifact_manifest("application/json", &data));
+
+        let parsed: ArtifactManifest = serde_json::from_slice(&data).unwrap();
+        assert_eq!(SPIN_ARTIFACT_TYPE, parsed.artifact_type);
+        let image = parsed.into_image().unwrap();
+        assert_eq!("sha256:config", image.config.digest);
+        assert_eq!(1, image.layers.len());
+        assert_eq!("sha256:wasm", image.layers[0].digest);
+    }
+}
@@ -0,0 +1,459 @@
+//! Registry authentication helpers.
+
+use std::collections::HashMap;
+
+use docker_credential::DockerCredential;
+use reqwest::header::WWW_AUTHENTICATE;
+use serde::Deserialize;
+
+use super::credentials::{docker_key, normalize_registry, CredentialStore};
+use crate::{PublishError, PublishResult, PublishWarning, Secret};
+
+/// The username with which Docker sends an identity token to registries
+/// which ask for basic authentication.
+const IDENTITY_TOKEN_USERNAME: &str = "00000000-0000-0000-0000-000000000000";
+/// The client ID sent when exchanging an identity token for an access token.
+const OAUTH_CLIENT_ID: &str = "spin";
+
+/// The GitHub Container Registry, which accepts GitHub tokens.
+const GITHUB_CONTAINER_REGISTRY: &str = "ghcr.io";
+const GITHUB_TOKEN_ENV: &str = "GITHUB_TOKEN";
+const GH_TOKEN_ENV: &str = "GH_TOKEN";
+/// The user whose workflow run a GitHub Actions token was issued for.
+const GITHUB_ACTOR_ENV: &str = "GITHUB_ACTOR";
+/// The username sent with a GitHub token if the actor is not known.
+const GITHUB_TOKEN_USERNAME: &str = "x-access-token";
+
+/// The environment variable giving the registry to which the credentials in
+/// the other `SPIN_REGISTRY_*` variables are sent. They are not sent to any
+/// other registry.
+pub const REGISTRY_HOST_ENV: &str = "SPIN_REGISTRY_HOST";
+/// The environment variable giving the username with which to access the
+/// registry given by [`REGISTRY_HOST_ENV`], with [`REGISTRY_PASSWORD_ENV`].
+pub const REGISTRY_USERNAME_ENV: &str = "SPIN_REGISTRY_USERNAME";
+/// The environment variable giving the password with which to access the
+/// registry given by [`REGISTRY_HOST_ENV`], with [`REGISTRY_USERNAME_ENV`].
+pub const REGISTRY_PASSWORD_ENV: &str = "SPIN_REGISTRY_PASSWORD";
+/// The environment variable giving an access token, which is sent as is to
+/// the registry given by [`REGISTRY_HOST_ENV`].
+pub const REGISTRY_TOKEN_ENV: &str = "SPIN_REGISTRY_TOKEN";
+
+/// Credentials with which to access a registry.
+#[derive(Clone, Debug)]
+pub(crate) enum RegistryAuth {
+    Anonymous,
+    Basic(String, Secret<String>),
+    /// An OAuth2 refresh token, as `docker login` stores for registries such
+    /// as Azure Container Registry, which is exchanged for access tokens.
+    IdentityToken(Secret<String>),
+    /// An access token, which is sent to the registry instead of one
+    /// requested from its token service.
+    Token(Secret<String>),
+}
+
+/// Looks up the credentials given in the environment or, failing that,
+/// stored for the given registry by `spin oci login`, issued by ECR if the
+/// `ecr` feature is enabled and the registry is in ECR, configured in the
+/// Docker configuration or stored by `podman login`, falling back to the
+/// GitHub token in the environment for the GitHub Container Registry and to
+/// anonymous access otherwise. Credentials which cannot be read are logged
+/// and skipped.
+pub(crate) fn registry_auth(registry: &str) -> RegistryAuth {
+    let (auth, warnings) = registry_auth_with_warnings(registry);
+    for warning in warnings {
+        tracing::warn!("{}", warning);
+    }
+    auth
+}
+
+/// Looks up the credentials for a registry as [`registry_auth`] does,
+/// returning warnings about the credentials which could not be read instead
+/// of logging them.
+pub(crate) fn registry_auth_with_warnings(registry: &str) -> (RegistryAuth, Vec<PublishWarning>) {
+    let mut warnings = vec![];
+    let degraded = |reason: String| PublishWarning::DegradedAuth {
+        registry: registry.to_owned(),
+        reason,
+    };
+    if let Some(auth) = env_auth(registry, |name| std::env::var(name).ok()) {
+        tracing::trace!("Using credentials from the environment for {}", registry);
+        return (auth, warnings);
+    }
+    match CredentialStore::spin().and_then(|store| store.get(registry)) {
+        Ok(Some((username, password))) => {
+            tracing::trace!("Found Spin credentials for {}", registry);
+            return (RegistryAuth::Basic(username, password), warnings);
+        }
+        Ok(None) => {}
+        Err(e) => warnings.push(degraded(format!("cannot read Spin credentials: {}", e))),
+    }
+    #[cfg(feature = "ecr")]
+    match super::ecr::ecr_auth(registry) {
+        Ok(Some(auth)) => {
+            tracing::trace!("Obtained an ECR token for {}", registry);
+            return (auth, warnings);
+        }
+        Ok(None) => {}
+        Err(e) => warnings.push(degraded(e.to_string())),
+    }
+    // Runs the credential helper named in `credHelpers` or `credsStore` if
+    // there is one, and otherwise reads the credentials in `auths`
+    match docker_credential::get_credential(docker_key(registry)) {
+        Ok(DockerCredential::UsernamePassword(username, password)) => {
+            tracing::trace!("Found Docker credentials for {}", registry);
+            return (
+                RegistryAuth::Basic(username, Secret::new(password)),
+                warnings,
+            );
+        }
+        Ok(DockerCredential::IdentityToken(token)) => {
+            tracing::trace!("Found Docker identity token for {}", registry);
+            return (RegistryAuth::IdentityToken(Secret::new(token)), warnings);
+        }
+        Err(e) => tracing::trace!("No Docker credentials for {}: {}", registry, e),
+    }
+    for store in CredentialStore::podman() {
+        match store.get(registry) {
+            Ok(Some((username, password))) => {
+                tracing::trace!(
+                    "Found podman credentials for {} in {}",
+                    registry,
+                    store.path().display()
+                );
+                return (RegistryAuth::Basic(username, password), warnings);
+            }
+            Ok(None) => {}
+            Err(e) => warnings.push(degraded(format!("cannot read podman credentials: {}", e))),
+        }
+    }
+    let auth = match github_token_auth(registry, |name| std::env::var(name).ok()) {
+        Some(auth) => {
+            tracing::trace!("Using the GitHub token in the environment for {}", registry);
+            auth
+        }
+        None => RegistryAuth::Anonymous,
+    };
+    (auth, warnings)
+}
+
+/// The GitHub token in the environment, as GitHub Actions and the GitHub
+/// CLI provide, with which to access the GitHub Container Registry when no
+/// other credentials are found. The registry accepts the token as the
+/// password of any username, and issues access tokens in exchange for it.
+fn github_token_auth(registry: &str, var: impl Fn(&str) -> Option<String>) -> Option<RegistryAuth> {
+    if registry != GITHUB_CONTAINER_REGISTRY {
+        return None;
+    }
+    let var = |name| var(name).filter(|value| !value.is_empty());
+    let token = var(GITHUB_TOKEN_ENV).or_else(|| var(GH_TOKEN_ENV))?;
+    let username = var(GITHUB_ACTOR_ENV).unwrap_or_else(|| GITHUB_TOKEN_USERNAME.to_owned());
+    Some(RegistryAuth::Basic(username, Secret::new(token)))
+}
+
+/// The credentials given in the environment variables, so that CI systems
+/// can pass credentials for a single invocation without writing them to
+/// disk. They are only used for the registry the variables name, so that
+/// they are never sent to other registries. A token takes precedence over a
+/// username and password.
+fn env_auth(registry: &str, var: impl Fn(&str) -> Option<String>) -> Option<RegistryAuth> {
+    let var = |name| var(name).filter(|value| !value.is_empty());
+    let has_credentials = [
+        REGISTRY_TOKEN_ENV,
+        REGISTRY_USERNAME_ENV,
+        REGISTRY_PASSWORD_ENV,
+    ]
+    .into_iter()
+    .any(|name| var(name).is_some());
+    if !has_credentials {
+        return None;
+    }
+    match var(REGISTRY_HOST_ENV) {
+        Some(host) if normalize_registry(&host) == normalize_registry(registry) => {}
+        Some(_) => return None,
+        None => {
+            tracing::warn!(
+                "Ignoring registry credentials in the environment: {} must be set to the registry they are for",
+                REGISTRY_HOST_ENV
+            );
+            return None;
+        }
+    }
+    if let Some(token) = var(REGISTRY_TOKEN_ENV) {
+        return Some(RegistryAuth::Token(Secret::new(token)));
+    }
+    match (var(REGISTRY_USERNAME_ENV), var(REGISTRY_PASSWORD_ENV)) {
+        (Some(username), Some(password)) => {
+            Some(RegistryAuth::Basic(username, Secret::new(password)))
+        }
+        (None, None) => None,
+        _ => {
+            tracing::warn!(
+                "Ignoring registry credentials in the environment: {} and {} must both be set",
+                REGISTRY_USERNAME_ENV,
+                REGISTRY_PASSWORD_ENV
+            );
+            None
+        }
+    }
+}
+
+/// An authentication challenge returned by a registry in the
+/// `WWW-Authenticate` header.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Challenge {
+    scheme: String,
+    params: HashMap<String, String>,
+}
+
+impl Challenge {
+    /// Extracts the challenge from a registry response, if there is one.
+    pub fn from_response(response: &reqwest::Response) -> Option<Self> {
+        let header = response.headers().get(WWW_AUTHENTICATE)?.to_str().ok()?;
+        Self::parse(header)
+    }
+
+    /// Parses a challenge of the form `Bearer realm="...",service="..."`.
+    pub fn parse(header: &str) -> Option<Self> {
+        let (scheme, rest) = match header.trim().split_once(' ') {
+            Some((scheme, rest)) => (scheme, rest),
+            None => (header.trim(), ""),
+        };
+        if scheme.is_empty() {
+            return None;
+        }
+
+        let mut params = HashMap::new();
+        let mut rest = rest.trim();
+        while !rest.is_empty() {
+            let (key, value) = rest.split_once('=')?;
+            let value = value.trim_start();
+            let (value, remainder) = match value.strip_prefix('"') {
+                Some(quoted) => {
+                    let end = quoted.find('"')?;
+                    (&quoted[..end], &quoted[end + 1..])
+                }
+                None => match value.find(',') {
+                    Some(end) => (&value[..end], &value[end..]),
+                    None => (value, ""),
+                },
+            };
+            params.insert(key.trim().to_ascii_lowercase(), value.to_owned());
+            rest = remainder.trim_start().trim_start_matches(',').trim_start();
+        }
+
+        Some(Self {
+            scheme: scheme.to_owned(),
+            params,
+        })
+    }
+
+    /// Answers the challenge, returning the authorization to send with
+    /// subsequent requests. Bearer challenges are answered by requesting a
+    /// token for `scope` from the challenge realm, or a token for no
+    /// particular scope if it is empty. An identity token is exchanged for
+    /// an access token with the OAuth2 refresh token grant. An access token
+    /// is sent as is.
+    pub async fn authorize(
+        &self,
+        http: &reqwest::Client,
+        scope: &str,
+        auth: &RegistryAuth,
+    ) -> PublishResult<Authorization> {
+        if let RegistryAuth::Token(token) = auth {
+            return Ok(Authorization::Bearer(token.clone()));
+        }
+        if self.scheme.eq_ignore_ascii_case("basic") {
+            return match auth {
+                RegistryAuth::Basic(username, password) => {
+                    Ok(Authorization::Basic(username.clone(), password.clone()))
+                }
+                RegistryAuth::IdentityToken(token) => Ok(Authorization::Basic(
+                    IDENTITY_TOKEN_USERNAME.to_owned(),
+                    token.clone(),
+                )),
+                RegistryAuth::Anonymous | RegistryAuth::Token(_) => {
+                    Err(PublishError::RegistryUnauthorized(
+                        "the registry requires credentials".to_owned(),
+                    ))
+                }
+            };
+        }
+
+        let realm = self.params.get("realm").ok_or_else(|| {
+            PublishError::RegistryUnauthorized("malformed authentication challenge".to_owned())
+        })?;
+        let mut query = vec![];
+        if !scope.is_empty() {
+            query.push(("scope", scope));
+        }
+        if let Some(service) = self.params.get("service") {
+            query.push(("service", service.as_str()));
+        }
+
+        let request = match auth {
+            RegistryAuth::Anonymous | RegistryAuth::Token(_) => http.get(realm).query(&query),
+            RegistryAuth::Basic(username, password) => http
+                .get(realm)
+                .query(&query)
+                .basic_auth(username, Some(password.expose())),
+            RegistryAuth::IdentityToken(token) => {
+                let mut form = vec![
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", token.expose().as_str()),
+                    ("client_id", OAUTH_CLIENT_ID),
+                ];
+                form.extend(query);
+                http.post(realm).form(&form)
+            }
+        };
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let message = response.text().await.unwrap_or_default();
+            return Err(PublishError::RegistryUnauthorized(message));
+        }
+
+        let token: TokenResponse = serde_json::from_slice(&response.bytes().await?)
+            .map_err(|e| PublishError::Other(anyhow::anyhow!("Invalid registry token: {}", e)))?;
+        let token = token.token.or(token.access_token).ok_or_else(|| {
+            PublishError::RegistryUnauthorized("the registry did not issue a token".to_owned())
+        })?;
+        Ok(Authorization::Bearer(token))
+    }
+}
+
+/// Credentials to attach to registry requests once a challenge has been
+/// answered.
+#[derive(Clone, Debug)]
+pub(crate) enum Authorization {
+    Basic(String, Secret<String>),
+    Bearer(Secret<String>),
+}
+
+impl Authorization {
+    pub fn apply(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self {
+            Self::Basic(username, password) => {
+                request.basic_auth(username, Some(password.expose()))
+            }
+            Self::Bearer(token) => request.bearer_auth(token.expose()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: Option<Secret<String>>,
+    access_token: Option<Secret<String>>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_bearer_challenges() {
+        let challenge = Challenge::parse(
+            r#"Bearer realm="https://auth.example.com/token",service="registry.example.com",scope="registry:catalog:*""#,
+        )
+        .expect("should have parsed challenge");
+        assert_eq!("Bearer", challenge.scheme);
+        assert_eq!("https://auth.example.com/token", challenge.params["realm"]);
+        assert_eq!("registry.example.com", challenge.params["service"]);
+        assert_eq!("registry:catalog:*", challenge.params["scope"]);
+    }
+
+    #[test]
+    fn parses_basic_challenges() {
+        let challenge =
+            Challenge::parse(r#"Basic realm="Registry""#).expect("should have parsed challenge");
+        assert_eq!("Basic", challenge.scheme);
+        assert_eq!("Registry", challenge.params["realm"]);
+    }
+
+    #[test]
+    fn rejects_empty_challenges() {
+        assert_eq!(None, Challenge::parse(""));
+    }
+
+    #[test]
+    fn reads_credentials_from_the_environment() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        let basic = env_auth(
+            "ghcr.io",
+            env(&[
+                (REGISTRY_HOST_ENV, "ghcr.io"),
+                (REGISTRY_USERNAME_ENV, "ci"),
+                (REGISTRY_PASSWORD_ENV, "hunter2"),
+            ]),
+        );
+        assert!(matches!(basic, Some(RegistryAuth::Basic(username, _)) if username == "ci"));
+
+        let token = env_auth(
+            "ghcr.io",
+            env(&[
+                (REGISTRY_HOST_ENV, "https://ghcr.io/"),
+                (REGISTRY_USERNAME_ENV, "ci"),
+                (REGISTRY_PASSWORD_ENV, "hunter2"),
+                (REGISTRY_TOKEN_ENV, "t0ken"),
+            ]),
+        );
+        assert!(matches!(token, Some(RegistryAuth::Token(token)) if token.expose() == "t0ken"));
+
+        assert!(env_auth(
+            "ghcr.io",
+            env(&[
+                (REGISTRY_HOST_ENV, "ghcr.io"),
+                (REGISTRY_USERNAME_ENV, "ci")
+            ])
+        )
+        .is_none());
+        assert!(env_auth(
+            "ghcr.io",
+            env(&[(REGISTRY_HOST_ENV, "ghcr.io"), (REGISTRY_TOKEN_ENV, "")])
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn environment_credentials_are_only_sent_to_their_registry() {
+        let env = |name: &str| match name {
+            REGISTRY_HOST_ENV => Some("registry.example.com".to_owned()),
+            REGISTRY_USERNAME_ENV => Some("ci".to_owned()),
+            REGISTRY_PASSWORD_ENV => Some("hunter2".to_owned()),
+            REGISTRY_TOKEN_ENV => Some("t0ken".to_owned()),
+            _ => None,
+        };
+        assert!(env_auth("registry.example.com", env).is_some());
+        for other in ["ghcr.io", "index.docker.io", "registry.example.com:5000"] {
+            assert!(env_auth(other, env).is_none(), "{}", other);
+        }
+
+        // Without a host, the credentials are not sent anywhere.
+        let unscoped = |name: &str| match name {
+            REGISTRY_TOKEN_ENV => Some("t0ken".to_owned()),
+            _ => None,
+        };
+        assert!(env_auth("ghcr.io", unscoped).is_none());
+    }
+
+    #[test]
+    fn uses_github_tokens_for_ghcr() {
+        let env = |name: &str| match name {
+            GH_TOKEN_ENV => Some("t0ken".to_owned()),
+            _ => None,
+        };
+        let auth = github_token_auth("ghcr.io", env);
+        assert!(matches!(
+            auth,
+            Some(RegistryAuth::Basic(username, token))
+                if username == GITHUB_TOKEN_USERNAME && token.expose() == "t0ken"
+        ));
+        assert!(github_token_auth("quay.io", env).is_none());
+    }
+}
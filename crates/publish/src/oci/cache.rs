@@ -0,0 +1,404 @@
+//! Local cache of registry content.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use futures::{stream, StreamExt};
+
+use super::{archive::unpack_archive, digest::blob_path_segments, Client, Compression};
+use crate::{PublishError, PublishResult};
+
+const ASSETS_DIR: &str = "assets";
+const BLOBS_DIR: &str = "blobs";
+const LOCKED_DIR: &str = "locked";
+const MANIFESTS_DIR: &str = "manifests";
+const MISSING_DIR: &str = "missing";
+
+/// How many references to pull at once when warming the cache, if the
+/// caller does not say.
+pub const DEFAULT_WARM_CONCURRENCY: usize = 4;
+
+/// A local cache of content pulled from OCI registries. Blobs are stored by
+/// digest, so content shared between applications is only stored once.
+#[derive(Clone, Debug)]
+pub struct Cache {
+    root: PathBuf,
+}
+
+impl Cache {
+    /// Opens the cache at the given root directory, or at the default
+    /// location if no root is given, creating it if necessary.
+    pub async fn new(root: Option<PathBuf>) -> PublishResult<Self> {
+        let root = match root {
+            Some(root) => root,
+            None => Self::default_root()?,
+        };
+        for dir in [root.join(BLOBS_DIR), root.join(MANIFESTS_DIR)] {
+            tokio::fs::create_dir_all(&dir)
+                .await
+                .map_err(|e| PublishError::Io {
+                    source: e,
+                    description: format!("Failed to create cache directory {}", dir.display()),
+                })?;
+        }
+        Ok(Self { root })
+    }
+
+    /// The root directory of the cache.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The root directory of the cache if none is specified.
+    pub fn default_root() -> PublishResult<PathBuf> {
+        let cache_dir = dirs::cache_dir()
+            .ok_or_else(|| PublishError::Other(anyhow::anyhow!("Cannot find cache directory")))?;
+        Ok(cache_dir.join("spin").join("registry"))
+    }
+
+    /// The path at which the blob with the given digest is cached, under a
+    /// directory for the digest's algorithm, so that blobs addressed by
+    /// different algorithms never collide.
+    pub fn blob_path(&self, digest: &str) -> PathBuf {
+        let (algorithm, hex) = blob_path_segments(digest);
+        self.root.join(BLOBS_DIR).join(algorithm).join(hex)
+    }
+
+    /// The path at which the manifest for the given reference is cached.
+    /// The reference may be either a tag or a digest.
+    pub fn manifest_path(&self, registry: &str, repository: &str, reference: &str) -> PathBuf {
+        self.reference_path(MANIFESTS_DIR, registry, repository, reference)
+    }
+
+    fn reference_path(
+        &self,
+        dir: &str,
+        registry: &str,
+        repository: &str,
+        reference: &str,
+    ) -> PathBuf {
+        let mut path = self.root.join(dir).join(path_safe(registry));
+        for segment in repository.split('/') {
+            path.push(path_safe(segment));
+        }
+        path.join(format!("{}.json", path_safe(reference)))
+    }
+
+    /// The directory in which the static asset files of a component of a
+    /// pulled application are assembled. The application is identified by
+    /// the digest of its config.
+    pub fn assets_dir(&self, config_digest: &str, component_id: &str) -> PathBuf {
+        self.root
+            .join(ASSETS_DIR)
+            .join(path_safe(config_digest))
+            .join(path_safe(component_id))
+    }
+
+    /// The path at which the assembled locked application of the manifest
+    /// or index with the given digest is cached, so that running it again
+    /// need not assemble it again.
+    pub fn locked_app_path(&self, digest: &str) -> PathBuf {
+        self.root
+            .join(LOCKED_DIR)
+            .join(format!("{}.json", path_safe(digest)))
+    }
+
+    /// Copies a cached blob to the given path, decompressing it if it is
+    /// compressed, unless the path already exists.
+    pub async fn copy_blob(
+        &self,
+        digest: &str,
+        dest: &Path,
+        compression: Compression,
+    ) -> PublishResult<()> {
+        if dest.exists() {
+            return Ok(());
+        }
+        if let Some(dir) = dest.parent() {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|e| PublishError::Io {
+                    source: e,
+                    description: format!("Failed to create cache directory {}", dir.display()),
+                })?;
+        }
+        if compression != Compression::None {
+            let data = self.read_blob(digest).await?.ok_or_else(|| {
+                PublishError::Other(anyhow::anyhow!("Blob {} is not cached", digest))
+            })?;
+            return write_file(dest, &compression.decompress(&data)?).await;
+        }
+        // Copied via a temporary file, as in `write_file`.
+        let temp = partial_path(dest);
+        let copy_error = |e| PublishError::Io {
+            source: e,
+            description: format!(
+                "Failed to copy cached blob {} to {}",
+                digest,
+                dest.display()
+            ),
+        };
+        tokio::fs::copy(self.blob_path(digest), &temp)
+            .await
+            .map_err(copy_error)?;
+        tokio::fs::rename(&temp, dest).await.map_err(copy_error)
+    }
+
+    /// Unpacks a cached archive blob into the given directory.
+    pub async fn unpack_blob(&self, digest: &str, dest: &Path) -> PublishResult<()> {
+        let data = self
+            .read_blob(digest)
+            .await?
+            .ok_or_else(|| PublishError::Other(anyhow::anyhow!("Blob {} is not cached", digest)))?;
+        let dest = dest.to_owned();
+        tokio::task::spawn_blocking(move || unpack_archive(&data, &dest))
+            .await
+            .map_err(|e| PublishError::Other(e.into()))?
+    }
+
+    /// Reads a cached blob, if present.
+    pub async fn read_blob(&self, digest: &str) -> PublishResult<Option<Vec<u8>>> {
+        read_if_exists(&self.blob_path(digest)).await
+    }
+
+    /// Whether a blob is cached, without reading it.
+    pub async fn has_blob(&self, digest: &str) -> PublishResult<bool> {
+        let path = self.blob_path(digest);
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) => Ok(metadata.is_file()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(PublishError::Io {
+                source: e,
+                description: format!("Failed to read cached file {}", path.display()),
+            }),
+        }
+    }
+
+    /// Writes a blob into the cache.
+    pub async fn write_blob(&self, digest: &str, data: &[u8]) -> PublishResult<()> {
+        write_file(&self.blob_path(digest), data).await
+    }
+
+    /// Reads the cached assembled locked application of a manifest or
+    /// index, if present.
+    pub async fn read_locked_app(&self, digest: &str) -> PublishResult<Option<Vec<u8>>> {
+        read_if_exists(&self.locked_app_path(digest)).await
+    }
+
+    /// Writes the assembled locked application of a manifest or index into
+    /// the cache.
+    pub async fn write_locked_app(&self, digest: &str, data: &[u8]) -> PublishResult<()> {
+        write_file(&self.locked_app_path(digest), data).await
+    }
+
+    /// Reads a cached manifest, if present.
+    pub async fn read_manifest(
+        &self,
+        registry: &str,
+        repository: &str,
+        reference: &str,
+    ) -> PublishResult<Option<Vec<u8>>> {
+        read_if_exists(&self.manifest_path(registry, repository, reference)).await
+    }
+
+    /// Writes a manifest into the cache under the given reference.
+    pub async fn write_manifest(
+        &self,
+        registry: &str,
+        repository: &str,
+        reference: &str,
+        data: &[u8],
+    ) -> PublishResult<()> {
+        write_file(&self.manifest_path(registry, repository, reference), data).await
+    }
+
+    /// Records that a registry has no manifest for the reference, so that
+    /// it need not be asked again for a while.
+    pub async fn record_missing(
+        &self,
+        registry: &str,
+        repository: &str,
+        reference: &str,
+    ) -> PublishResult<()> {
+        let path = self.reference_path(MISSING_DIR, registry, repository, reference);
+        let now = crate::seams::now().timestamp();
+        write_file(&path, now.to_string().as_bytes()).await
+    }
+
+    /// Whether a registry was recorded as having no manifest for the
+    /// reference less than `ttl` ago. A zero `ttl` bypasses the record.
+    pub async fn is_missing(
+        &self,
+        registry: &str,
+        repository: &str,
+        reference: &str,
+        ttl: Duration,
+    ) -> PublishResult<bool> {
+        let path = self.reference_path(MISSING_DIR, registry, repository, reference);
+        let recorded = match read_if_exists(&path).await? {
+            Some(recorded) => recorded,
+            None => return Ok(false),
+        };
+        let recorded: i64 = String::from_utf8_lossy(&recorded)
+            .trim()
+            .parse()
+            .unwrap_or(0);
+        Ok(crate::seams::now().timestamp() - recorded < ttl.as_secs() as i64)
+    }
+
+    /// Forgets that a registry had no manifest for the reference.
+    pub async fn clear_missing(
+        &self,
+        registry: &str,
+        repository: &str,
+        reference: &str,
+    ) -> PublishResult<()> {
+        let path = self.reference_path(MISSING_DIR, registry, repository, reference);
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(PublishError::Io {
+                source: e,
+                description: format!("Failed to remove cached file {}", path.display()),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Pulls a list of applications into the cache, several at a time, so
+    /// that a node can serve them without waiting on the registry. Returns
+    /// the outcome for each reference, in the order given: the manifest
+    /// digest if the pull succeeded. A failure to pull one reference does
+    /// not stop the others.
+    pub async fn warm(
+        &self,
+        client: &Client,
+        references: impl IntoIterator<Item = String>,
+        concurrency: usize,
+    ) -> Vec<(String, PublishResult<String>)> {
+        stream::iter(references)
+            .map(|reference| async move {
+                let result = client.pull_into_cache(&reference, self).await;
+                (reference, result)
+            })
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
+}
+
+// Registry hosts may contain ports and references may be digests, neither
+// of which is safe to use as a file name on all platforms. Names from
+// pulled configs, such as component IDs, may be anything, so separators are
+// replaced too, and names which are only dots are prefixed, so that no name
+// leaves its directory.
+fn path_safe(text: &str) -> String {
+    let safe = text.replace([':', '/', '\\'], "_");
+    if safe.is_empty() || safe.chars().all(|c| c == '.') {
+        format!("_{}", safe)
+    } else {
+        safe
+    }
+}
+
+async fn read_if_exists(path: &Path) -> PublishResult<Option<Vec<u8>>> {
+    match tokio::fs::read(path).await {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(PublishError::Io {
+            source: e,
+            description: format!("Failed to read cached file {}", path.display()),
+        }),
+    }
+}
+
+/// The temporary path at which a file is written before being moved into
+/// place.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(".{}.partial", std::process::id()));
+    path.with_file_name(name)
+}
+
+async fn write_file(path: &Path, data: &[u8]) -> PublishResult<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(|e| PublishError::Io {
+                source: e,
+                description: format!("Failed to create cache directory {}", dir.display()),
+            })?;
+    }
+    // Written via a temporary file, so that a cancelled or failed write
+    // never leaves a partial file which would be taken for cached content.
+    let temp = partial_path(path);
+    let write_error = |e| PublishError::Io {
+        source: e,
+        description: format!("Failed to write cached file {}", path.display()),
+    };
+    if let Err(e) = tokio::fs::write(&temp, data).await {
+        let _ = tokio::fs::remove_file(&temp).await;
+        return Err(write_error(e));
+    }
+    tokio::fs::rename(&temp, path).await.map_err(write_error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cache_paths_are_path_safe() {
+        let cache = Cache {
+            root: PathBuf::from("/cache"),
+        };
+        assert_eq!(
+            PathBuf::from("/cache/blobs/sha256/abc"),
+            cache.blob_path("sha256:abc")
+        );
+        assert_eq!(
+            PathBuf::from("/cache/manifests/localhost_5000/org/app/sha256_abc.json"),
+            cache.manifest_path("localhost:5000", "org/app", "sha256:abc")
+        );
+        assert_eq!(
+            PathBuf::from("/cache/locked/sha256_abc.json"),
+            cache.locked_app_path("sha256:abc")
+        );
+        assert_eq!(
+            PathBuf::from("/cache/assets/sha256_abc/.._.._.."),
+            cache.assets_dir("sha256:abc", "../../..")
+        );
+        assert_eq!(
+            PathBuf::from("/cache/assets/sha256_abc/_.."),
+            cache.assets_dir("sha256:abc", "..")
+        );
+    }
+
+    #[tokio::test]
+    async fn remembers_missing_references() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache = Cache::new(Some(temp.path().to_owned())).await.unwrap();
+        let ttl = Duration::from_secs(60);
+        assert!(!cache.is_missing("r", "org/app", "v1", ttl).await.unwrap());
+
+        cache.record_missing("r", "org/app", "v1").await.unwrap();
+        assert!(cache.is_missing("r", "org/app", "v1", ttl).await.unwrap());
+        assert!(!cache
+            .is_missing("r", "org/app", "v1", Duration::ZERO)
+            .await
+            .unwrap());
+
+        cache.clear_missing("r", "org/app", "v1").await.unwrap();
+        assert!(!cache.is_missing("r", "org/app", "v1", ttl).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn checks_for_blobs_without_reading_them() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache = Cache::new(Some(temp.path().to_owned())).await.unwrap();
+        assert!(!cache.has_blob("sha256:abc").await.unwrap());
+
+        cache.write_blob("sha256:abc", b"layer").await.unwrap();
+        assert!(cache.has_blob("sha256:abc").await.unwrap());
+    }
+}
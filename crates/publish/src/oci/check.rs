@@ -0,0 +1,251 @@
+//! Checking that the credentials for a registry work, and what they allow,
+//! before anything is pushed.
+
+use reqwest::{
+    header::{CONTENT_LENGTH, LOCATION},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    auth::{registry_auth, Authorization, Challenge, RegistryAuth},
+    registry_response_error, split_location, Client,
+};
+use crate::{PublishError, PublishResult};
+
+/// What checking a registry found out about the credentials for it.
+#[derive(Clone, Debug, Serialize)]
+pub struct RegistryCheck {
+    /// The registry host
+    pub registry: String,
+    /// The repository whose push permission was checked, if one was given
+    pub repository: Option<String>,
+    /// The kind of credentials used: `anonymous`, `basic`,
+    /// `identity-token` or `access-token`
+    pub credentials: String,
+    /// Who the credentials identify, as far as can be told: the subject of
+    /// the token the registry issued or, failing that, the username
+    pub identity: Option<String>,
+    /// The access the registry granted, as `<type>:<name>:<actions>`
+    /// scopes, if it issued a token which records it
+    pub scopes: Vec<String>,
+    /// Whether the registry allowed an upload to the repository to be
+    /// started, if a repository was given
+    pub can_push: Option<bool>,
+    /// Why the registry refused the upload, if it did
+    pub push_error: Option<String>,
+}
+
+impl Client {
+    /// Checks the credentials for a registry by answering the challenge to
+    /// its API root and, if the location names a repository
+    /// (`<registry>/<repository>`), by starting an upload to the repository
+    /// and cancelling it, so that push permission is known before an
+    /// application is assembled. Nothing is written to the registry.
+    pub async fn check_registry(&self, location: &str) -> PublishResult<RegistryCheck> {
+        let (registry, repository) = split_location(location);
+        let auth = registry_auth(registry);
+        let scope = match repository {
+            Some(repository) => format!("repository:{}:pull,push", repository),
+            None => String::new(),
+        };
+
+        let url = format!("{}://{}/v2/", self.scheme(registry), registry);
+        let http = self.http_for(&url);
+        let response =
+            http.get(&url)
+                .send()
+                .await
+                .map_err(|e| PublishError::RegistryUnreachable {
+                    registry: registry.to_owned(),
+                    source: e,
+                })?;
+        let mut authorization = match response.status() {
+            StatusCode::UNAUTHORIZED => {
+                let challenge = Challenge::from_response(&response).ok_or_else(|| {
+                    PublishError::RegistryUnauthorized(format!(
+                        "{} did not say how to authenticate",
+                        registry
+                    ))
+                })?;
+                let authorization = challenge.authorize(http, &scope, &auth).await?;
+                let response = authorization.apply(http.get(&url)).send().await?;
+                match response.status() {
+                    StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                        return Err(PublishError::RegistryUnauthorized(format!(
+                            "{} rejected the credentials",
+                            registry
+                        )))
+                    }
+                    s if !s.is_success() => {
+                        return Err(registry_response_error(&url, response).await)
+                    }
+                    _ => Some(authorization),
+                }
+            }
+            s if s.is_success() => None,
+            _ => return Err(registry_response_error(&url, response).await),
+        };
+
+        let (can_push, push_error) = match repository {
+            Some(repository) => {
+                let check = self
+                    .check_upload(registry, repository, &auth, &scope, &mut authorization)
+                    .await;
+                match check {
+                    Ok(()) => (Some(true), None),
+                    Err(e) => (Some(false), Some(e.to_string())),
+                }
+            }
+            None => (None, None),
+        };
+
+        let claims = authorization.as_ref().and_then(token_claims);
+        let identity = claims
+            .as_ref()
+            .and_then(|claims| claims.sub.clone())
+            .filter(|sub| !sub.is_empty())
+            .or_else(|| match &auth {
+                RegistryAuth::Basic(username, _) => Some(username.clone()),
+                _ => None,
+            });
+        let scopes = claims
+            .map(|claims| claims.access.iter().map(TokenAccess::scope).collect())
+            .unwrap_or_default();
+
+        Ok(RegistryCheck {
+            registry: registry.to_owned(),
+            repository: repository.map(str::to_owned),
+            credentials: auth_kind(&auth).to_owned(),
+            identity,
+            scopes,
+            can_push,
+            push_error,
+        })
+    }
+
+    /// Starts an upload to a repository and cancels it, to find out whether
+    /// the registry allows pushing to it. If the registry allowed anonymous
+    /// access to its API root, but asks for credentials to push, its
+    /// challenge is answered and the authorization kept.
+    async fn check_upload(
+        &self,
+        registry: &str,
+        repository: &str,
+        auth: &RegistryAuth,
+        scope: &str,
+        authorization: &mut Option<Authorization>,
+    ) -> PublishResult<()> {
+        let url = format!(
+            "{}://{}/v2/{}/blobs/uploads/",
+            self.scheme(registry),
+            registry,
+            repository
+        );
+        let http = self.http_for(&url);
+        let start = |authorization: Option<&Authorization>| {
+            let request = http.post(&url).header(CONTENT_LENGTH, 0);
+            match authorization {
+                Some(authorization) => authorization.apply(request),
+                None => request,
+            }
+        };
+        let mut response = start(authorization.as_ref()).send().await?;
+        if response.status() == StatusCode::UNAUTHORIZED && authorization.is_none() {
+            if let Some(challenge) = Challenge::from_response(&response) {
+                let answer = challenge.authorize(http, scope, auth).await?;
+                response = start(Some(&answer)).send().await?;
+                *authorization = Some(answer);
+            }
+        }
+        let authorization = authorization.as_ref();
+        if response.status() != StatusCode::ACCEPTED {
+            return Err(registry_response_error(&url, response).await);
+        }
+
+        // The upload is abandoned if it cannot be cancelled, which
+        // registries clean up after a while, so a failure is not reported.
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| reqwest::Url::parse(&url).ok()?.join(location).ok());
+        if let Some(location) = location {
+            let request = http.delete(location);
+            let request = match authorization {
+                Some(authorization) => authorization.apply(request),
+                None => request,
+            };
+            if let Err(e) = request.send().await {
+                tracing::debug!("Failed to cancel check upload: {}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn auth_kind(auth: &RegistryAuth) -> &'static str {
+    match auth {
+        RegistryAuth::Anonymous => "anonymous",
+        RegistryAuth::Basic(..) => "basic",
+        RegistryAuth::IdentityToken(_) => "identity-token",
+        RegistryAuth::Token(_) => "access-token",
+    }
+}
+
+/// The claims of a registry token which say who it was issued to and what
+/// it allows, as defined by the Docker token authentication specification.
+#[derive(Debug, Deserialize)]
+struct TokenClaims {
+    sub: Option<String>,
+    #[serde(default)]
+    access: Vec<TokenAccess>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenAccess {
+    #[serde(rename = "type")]
+    kind: String,
+    name: String,
+    #[serde(default)]
+    actions: Vec<String>,
+}
+
+impl TokenAccess {
+    fn scope(&self) -> String {
+        format!("{}:{}:{}", self.kind, self.name, self.actions.join(","))
+    }
+}
+
+/// Reads the claims of a bearer token, if it is a JWT. The signature is not
+/// checked, as the claims are only reported.
+fn token_claims(authorization: &Authorization) -> Option<TokenClaims> {
+    let token = match authorization {
+        Authorization::Bearer(token) => token.expose(),
+        Authorization::Basic(..) => return None,
+    };
+    let payload = token.split('.').nth(1)?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    serde_json::from_slice(&payload).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Secret;
+
+    #[test]
+    fn reads_token_claims() {
+        let claims = br#"{"sub":"ci-bot","access":[{"type":"repository","name":"org/app","actions":["pull","push"]}]}"#;
+        let token = format!(
+            "e30.{}.c2ln",
+            base64::encode_config(claims, base64::URL_SAFE_NO_PAD)
+        );
+        let claims = token_claims(&Authorization::Bearer(Secret::new(token))).unwrap();
+        assert_eq!(Some("ci-bot".to_owned()), claims.sub);
+        assert_eq!("repository:org/app:pull,push", claims.access[0].scope());
+
+        let opaque = Authorization::Bearer(Secret::new("opaque".to_owned()));
+        assert!(token_claims(&opaque).is_none());
+    }
+}
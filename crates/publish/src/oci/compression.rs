@@ -0,0 +1,143 @@
+//! Compression of the data layers holding static asset files.
+
+use std::{fs::File, io::Write, path::Path};
+
+use super::DATA_LAYER_MEDIA_TYPE;
+use crate::{PublishError, PublishResult};
+
+/// The media type of data layers compressed with gzip.
+pub const DATA_LAYER_GZIP_MEDIA_TYPE: &str = "application/vnd.wasm.content.layer.v1+data+gzip";
+/// The media type of data layers compressed with zstd.
+pub const DATA_LAYER_ZSTD_MEDIA_TYPE: &str = "application/vnd.wasm.content.layer.v1+data+zstd";
+
+/// How the data layers of an application are compressed. Wasm layers are
+/// never compressed, so that they can be used straight from the cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Data layers hold the asset files as they are.
+    #[default]
+    None,
+    /// Data layers are compressed with gzip.
+    Gzip,
+    /// Data layers are compressed with zstd, which is usually both smaller
+    /// and faster than gzip.
+    Zstd,
+}
+
+impl Compression {
+    /// The compression of a layer with the given Spin media type.
+    pub fn from_media_type(media_type: &str) -> Self {
+        match media_type {
+            DATA_LAYER_GZIP_MEDIA_TYPE => Self::Gzip,
+            DATA_LAYER_ZSTD_MEDIA_TYPE => Self::Zstd,
+            _ => Self::None,
+        }
+    }
+
+    /// The media type of data layers compressed this way.
+    pub fn data_layer_media_type(&self) -> &'static str {
+        match self {
+            Self::None => DATA_LAYER_MEDIA_TYPE,
+            Self::Gzip => DATA_LAYER_GZIP_MEDIA_TYPE,
+            Self::Zstd => DATA_LAYER_ZSTD_MEDIA_TYPE,
+        }
+    }
+
+    /// Compresses layer content. The output depends only on the input, so
+    /// that a file compresses to the same digest on every push.
+    pub fn compress(&self, data: &[u8]) -> PublishResult<Vec<u8>> {
+        let compressed = match self {
+            Self::None => Ok(data.to_vec()),
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(data).and_then(|_| encoder.finish())
+            }
+            Self::Zstd => zstd::stream::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+        };
+        compressed.map_err(|e| PublishError::Io {
+            source: e,
+            description: "Failed to compress layer".to_owned(),
+        })
+    }
+
+    /// Compresses the file at `source` into `out` a block at a time, so
+    /// that the file is never held in memory. The output is the same as
+    /// that of [`compress`](Self::compress).
+    pub fn compress_file(&self, source: &Path, mut out: impl Write) -> PublishResult<()> {
+        let mut file = File::open(source).map_err(|e| PublishError::Io {
+            source: e,
+            description: format!("Failed to read {}", source.display()),
+        })?;
+        let compressed = match self {
+            Self::None => std::io::copy(&mut file, &mut out).map(drop),
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(out, flate2::Compression::default());
+                std::io::copy(&mut file, &mut encoder).and_then(|_| encoder.finish().map(drop))
+            }
+            Self::Zstd => zstd::stream::copy_encode(file, out, zstd::DEFAULT_COMPRESSION_LEVEL),
+        };
+        compressed.map_err(|e| PublishError::Io {
+            source: e,
+            description: format!("Failed to compress {}", source.display()),
+        })
+    }
+
+    /// Decompresses layer content.
+    pub fn decompress(&self, data: &[u8]) -> PublishResult<Vec<u8>> {
+        let decompressed = match self {
+            Self::None => Ok(data.to_vec()),
+            Self::Gzip => {
+                let mut decoder = flate2::write::GzDecoder::new(vec![]);
+                decoder.write_all(data).and_then(|_| decoder.finish())
+            }
+            Self::Zstd => zstd::stream::decode_all(data),
+        };
+        decompressed.map_err(|e| PublishError::Io {
+            source: e,
+            description: "Failed to decompress layer".to_owned(),
+        })
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(format!(
+                "unknown compression '{}': expected none, gzip or zstd",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compression_round_trips() {
+        let data = b"<html><body>hello hello hello hello</body></html>".repeat(10);
+        let dir = tempfile::tempdir().unwrap();
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let compressed = compression.compress(&data).unwrap();
+            assert_eq!(compressed, compression.compress(&data).unwrap());
+            let file = dir.path().join("data");
+            std::fs::write(&file, &data).unwrap();
+            let mut streamed = vec![];
+            compression.compress_file(&file, &mut streamed).unwrap();
+            assert_eq!(compressed, streamed);
+            assert_eq!(data, compression.decompress(&compressed).unwrap());
+            assert_eq!(
+                compression,
+                Compression::from_media_type(compression.data_layer_media_type())
+            );
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Copying Spin applications between references, which may be in different
+//! registries, by way of the cache.
+
+use oci_distribution::manifest::{OciImageIndex, OciImageManifest, OCI_IMAGE_MEDIA_TYPE};
+use serde::Deserialize;
+
+use super::{
+    artifact::{is_artifact_manifest, ARTIFACT_MANIFEST_MEDIA_TYPE},
+    index::{is_index, select_spin_manifest},
+    parse_reference,
+    pull::parse_image,
+    push::PushSession,
+    sha256_digest, Cache, Client, FetchedManifest,
+};
+use crate::{PublishError, PublishResult};
+
+impl Client {
+    /// Copies the Spin application at the source reference to the
+    /// destination reference, returning the digest of the manifest pushed
+    /// there. The application is pulled into the cache, and its manifest,
+    /// config and layers are pushed from there exactly as they were
+    /// fetched, so that it keeps its digest. Blobs which the destination
+    /// repository already has are not uploaded. If the source is an image
+    /// index, only the Spin application in it is copied, so the
+    /// destination has the digest of the application rather than of the
+    /// index.
+    pub async fn copy(
+        &self,
+        source: &str,
+        destination: &str,
+        cache: &Cache,
+    ) -> PublishResult<String> {
+        let (manifest, image) = self.cache_spin_manifest(source, cache).await?;
+        self.push_from_cache(destination, manifest, &image, cache)
+            .await
+    }
+
+    /// Pushes the Spin application cached under the reference, as by
+    /// [`load`](Self::load), to the reference's registry, returning the
+    /// digest of the pushed manifest.
+    pub async fn push_cached(&self, reference: &str, cache: &Cache) -> PublishResult<String> {
+        let parsed = parse_reference(reference)?;
+        let target = parsed.digest().or_else(|| parsed.tag()).unwrap_or("latest");
+        let (manifest, image) = read_cached_spin_manifest(reference, cache, target).await?;
+        self.push_from_cache(reference, manifest, &image, cache)
+            .await
+    }
+
+    /// Pulls the Spin application at the reference into the cache,
+    /// returning its manifest exactly as it was fetched and the image
+    /// manifest it parses as. If the reference is an image index, the
+    /// manifest is that of the Spin application in it.
+    pub(super) async fn cache_spin_manifest(
+        &self,
+        reference: &str,
+        cache: &Cache,
+    ) -> PublishResult<(FetchedManifest, OciImageManifest)> {
+        let (top_digest, _) = self.fetch_into_cache(reference, cache, None).await?;
+        read_cached_spin_manifest(reference, cache, &top_digest).await
+    }
+
+    /// Pushes a manifest and the config and layers it lists from the cache
+    /// to the destination reference.
+    async fn push_from_cache(
+        &self,
+        destination: &str,
+        manifest: FetchedManifest,
+        image: &OciImageManifest,
+        cache: &Cache,
+    ) -> PublishResult<String> {
+        let parsed = parse_reference(destination)?;
+        let registry = parsed.resolve_registry();
+        let repository = parsed.repository();
+        let target = parsed.digest().or_else(|| parsed.tag()).unwrap_or("latest");
+
+        let mut session = PushSession::new(self, registry, repository);
+        let mut digests = vec![&image.config.digest];
+        for layer in &image.layers {
+            if !digests.contains(&&layer.digest) {
+                digests.push(&layer.digest);
+            }
+        }
+        for digest in digests {
+            session.push_cached_blob(cache, digest).await?;
+        }
+        session
+            .put_manifest(target, &manifest.media_type, manifest.data)
+            .await
+    }
+}
+
+/// Reads the manifest cached under the given tag or digest in the
+/// reference's repository, returning it with the image manifest it parses
+/// as. If it is an image index, the manifest of the Spin application in it
+/// is read instead.
+async fn read_cached_spin_manifest(
+    reference: &str,
+    cache: &Cache,
+    target: &str,
+) -> PublishResult<(FetchedManifest, OciImageManifest)> {
+    let parsed = parse_reference(reference)?;
+    let registry = parsed.resolve_registry();
+    let repository = parsed.repository();
+    let cached_manifest = |target: String| async move {
+        cache
+            .read_manifest(registry, repository, &target)
+            .await?
+            .ok_or_else(|| {
+                PublishError::Other(anyhow::anyhow!(
+                    "Manifest {} of {} is not cached",
+                    target,
+                    reference
+                ))
+            })
+    };
+    let data = cached_manifest(target.to_owned()).await?;
+    let mut manifest = FetchedManifest {
+        digest: sha256_digest(&data),
+        media_type: String::new(),
+        data,
+    };
+    if is_index(&manifest) {
+        let index: OciImageIndex = serde_json::from_slice(&manifest.data).map_err(|e| {
+            PublishError::Other(anyhow::anyhow!(
+                "{} is not a valid image index: {}",
+                reference,
+                e
+            ))
+        })?;
+        let entry = select_spin_manifest(reference, &index)?;
+        manifest.data = cached_manifest(entry.digest.clone()).await?;
+        manifest.digest = entry.digest.clone();
+    }
+    manifest.media_type = manifest_media_type(&manifest.data);
+    let image = parse_image(reference, &manifest)?;
+    Ok((manifest, image))
+}
+
+/// The media type of manifest content, as it declares it. Image manifests
+/// which do not declare one are taken to be OCI image manifests.
+pub(super) fn manifest_media_type(data: &[u8]) -> String {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct MediaType {
+        media_type: Option<String>,
+    }
+
+    if is_artifact_manifest("", data) {
+        return ARTIFACT_MANIFEST_MEDIA_TYPE.to_owned();
+    }
+    serde_json::from_slice::<MediaType>(data)
+        .ok()
+        .and_then(|m| m.media_type)
+        .unwrap_or_else(|| OCI_IMAGE_MEDIA_TYPE.to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use oci_distribution::manifest::IMAGE_MANIFEST_MEDIA_TYPE;
+
+    #[test]
+    fn copied_manifests_keep_their_media_type() {
+        let docker = format!(r#"{{"schemaVersion":2,"mediaType":"{IMAGE_MANIFEST_MEDIA_TYPE}"}}"#);
+        assert_eq!(
+            IMAGE_MANIFEST_MEDIA_TYPE,
+            manifest_media_type(docker.as_bytes())
+        );
+        assert_eq!(
+            OCI_IMAGE_MEDIA_TYPE,
+            manifest_media_type(br#"{"schemaVersion":2}"#)
+        );
+        let artifact = format!(r#"{{"mediaType":"{ARTIFACT_MANIFEST_MEDIA_TYPE}"}}"#);
+        assert_eq!(
+            ARTIFACT_MANIFEST_MEDIA_TYPE,
+            manifest_media_type(artifact.as_bytes())
+        );
+    }
+}
@@ -0,0 +1,408 @@
+//! Logging in to registries, and the stores in which registry credentials
+//! are kept.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use reqwest::StatusCode;
+use serde_json::{Map, Value};
+
+use super::{
+    auth::{Challenge, RegistryAuth},
+    Client,
+};
+use crate::{PublishError, PublishResult, Secret};
+
+const SPIN_CREDENTIALS_FILE: &str = "registry-auth.json";
+const DOCKER_CONFIG_FILE: &str = "config.json";
+/// The key under which Docker records credentials for Docker Hub.
+const DOCKER_HUB_KEY: &str = "https://index.docker.io/v1/";
+const DOCKER_HUB_REGISTRY: &str = "index.docker.io";
+/// The key under which podman records credentials for Docker Hub.
+const PODMAN_HUB_KEY: &str = "docker.io";
+const PODMAN_AUTH_FILE: &str = "auth.json";
+
+/// A file of registry credentials in the Docker configuration format, in
+/// which each registry's username and password are recorded, base64
+/// encoded, under `auths`. Other settings in the file are left as they are.
+#[derive(Clone, Debug)]
+pub struct CredentialStore {
+    path: PathBuf,
+    kind: StoreKind,
+}
+
+/// Which tool's conventions a credential store follows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StoreKind {
+    Spin,
+    Docker,
+    Podman,
+}
+
+impl CredentialStore {
+    /// Spin's own credential store, which is consulted before the Docker
+    /// configuration.
+    pub fn spin() -> PublishResult<Self> {
+        let config_dir = dirs::config_dir().ok_or_else(|| {
+            PublishError::Other(anyhow::anyhow!("Cannot find configuration directory"))
+        })?;
+        Ok(Self {
+            path: config_dir.join("spin").join(SPIN_CREDENTIALS_FILE),
+            kind: StoreKind::Spin,
+        })
+    }
+
+    /// The Docker configuration file, in `$DOCKER_CONFIG` if it is set and
+    /// in `~/.docker` otherwise, so that credentials are shared with Docker
+    /// and other tools which read its configuration.
+    pub fn docker() -> PublishResult<Self> {
+        let dir = match std::env::var_os("DOCKER_CONFIG") {
+            Some(dir) => PathBuf::from(dir),
+            None => dirs::home_dir()
+                .ok_or_else(|| PublishError::Other(anyhow::anyhow!("Cannot find home directory")))?
+                .join(".docker"),
+        };
+        Ok(Self {
+            path: dir.join(DOCKER_CONFIG_FILE),
+            kind: StoreKind::Docker,
+        })
+    }
+
+    /// The podman (containers-auth.json) credential files, in the order
+    /// podman consults them, so that credentials from `podman login` are
+    /// used on machines without Docker. These are `$REGISTRY_AUTH_FILE` if
+    /// it is set and otherwise `$XDG_RUNTIME_DIR/containers/auth.json`,
+    /// where `podman login` writes, followed by
+    /// `$XDG_CONFIG_HOME/containers/auth.json` (by default in `~/.config`).
+    pub fn podman() -> Vec<Self> {
+        podman_auth_files(|name| std::env::var_os(name), dirs::home_dir())
+            .into_iter()
+            .map(|path| Self {
+                path,
+                kind: StoreKind::Podman,
+            })
+            .collect()
+    }
+
+    /// The path of the store's file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Looks up the username and password stored for a registry.
+    pub fn get(&self, registry: &str) -> PublishResult<Option<(String, Secret<String>)>> {
+        let config = self.read()?;
+        let auth = config
+            .get("auths")
+            .and_then(|auths| auths.get(self.key(registry)))
+            .and_then(|entry| entry.get("auth"))
+            .and_then(Value::as_str);
+        let auth = match auth {
+            Some(auth) => auth,
+            None => return Ok(None),
+        };
+        let decoded = base64::decode(auth)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        match decoded.as_deref().and_then(|d| d.split_once(':')) {
+            Some((username, password)) => Ok(Some((
+                username.to_owned(),
+                Secret::new(password.to_owned()),
+            ))),
+            None => Err(PublishError::Other(anyhow::anyhow!(
+                "The credentials for {} in {} are malformed",
+                registry,
+                self.path.display()
+            ))),
+        }
+    }
+
+    /// Stores the username and password for a registry, replacing any
+    /// already stored for it.
+    pub fn store(
+        &self,
+        registry: &str,
+        username: &str,
+        password: &Secret<String>,
+    ) -> PublishResult<()> {
+        let mut config = self.read()?;
+        if self.kind == StoreKind::Docker && config.contains_key("credsStore") {
+            tracing::warn!(
+                "{} names a credential helper, which Docker uses instead of the stored credentials",
+                self.path.display()
+            );
+        }
+        let auth = base64::encode(format!("{}:{}", username, password.expose()));
+        let auths = config
+            .entry("auths")
+            .or_insert_with(|| Value::Object(Map::new()));
+        match auths.as_object_mut() {
+            Some(auths) => {
+                auths.insert(
+                    self.key(registry).to_owned(),
+                    serde_json::json!({ "auth": auth }),
+                );
+            }
+            None => {
+                return Err(PublishError::Other(anyhow::anyhow!(
+                    "{} has malformed auths",
+                    self.path.display()
+                )))
+            }
+        }
+        self.write(&config)
+    }
+
+    /// Removes the credentials stored for a registry. Returns whether there
+    /// were any.
+    pub fn remove(&self, registry: &str) -> PublishResult<bool> {
+        let mut config = self.read()?;
+        let removed = config
+            .get_mut("auths")
+            .and_then(Value::as_object_mut)
+            .and_then(|auths| auths.remove(self.key(registry)))
+            .is_some();
+        if removed {
+            self.write(&config)?;
+        }
+        Ok(removed)
+    }
+
+    /// The key under which a registry's credentials are stored.
+    fn key<'a>(&self, registry: &'a str) -> &'a str {
+        match self.kind {
+            StoreKind::Docker => docker_key(registry),
+            StoreKind::Podman if registry == DOCKER_HUB_REGISTRY => PODMAN_HUB_KEY,
+            _ => registry,
+        }
+    }
+
+    fn read(&self) -> PublishResult<Map<String, Value>> {
+        match std::fs::read(&self.path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| {
+                PublishError::Other(anyhow::anyhow!(
+                    "{} is not a valid credential store: {}",
+                    self.path.display(),
+                    e
+                ))
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Map::new()),
+            Err(e) => Err(PublishError::Io {
+                source: e,
+                description: format!("Failed to read {}", self.path.display()),
+            }),
+        }
+    }
+
+    fn write(&self, config: &Map<String, Value>) -> PublishResult<()> {
+        let io_error = |source| PublishError::Io {
+            source,
+            description: format!("Failed to write {}", self.path.display()),
+        };
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).map_err(io_error)?;
+        }
+        let data = serde_json::to_vec_pretty(config).map_err(|e| PublishError::Other(e.into()))?;
+        write_private(&self.path, &data).map_err(io_error)
+    }
+}
+
+fn podman_auth_files(
+    var: impl Fn(&str) -> Option<std::ffi::OsString>,
+    home_dir: Option<PathBuf>,
+) -> Vec<PathBuf> {
+    if let Some(path) = var("REGISTRY_AUTH_FILE") {
+        return vec![PathBuf::from(path)];
+    }
+    let runtime_dir = var("XDG_RUNTIME_DIR").map(PathBuf::from);
+    let config_dir = var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home_dir.map(|home| home.join(".config")));
+    runtime_dir
+        .into_iter()
+        .chain(config_dir)
+        .map(|dir| dir.join("containers").join(PODMAN_AUTH_FILE))
+        .collect()
+}
+
+/// Writes a file only its owner can read, as it holds credentials.
+#[cfg(unix)]
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(data)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, data)
+}
+
+/// The key under which Docker's configuration and credential helpers know
+/// a registry, which differs from the registry's host for Docker Hub.
+pub(super) fn docker_key(registry: &str) -> &str {
+    if registry == DOCKER_HUB_REGISTRY {
+        DOCKER_HUB_KEY
+    } else {
+        registry
+    }
+}
+
+/// The name under which a registry given on the command line, perhaps as
+/// a URL, is accessed and its credentials stored.
+pub fn normalize_registry(registry: &str) -> String {
+    let registry = registry
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_end_matches('/');
+    let registry = registry.strip_suffix("/v1").unwrap_or(registry);
+    match registry {
+        "docker.io" | "registry-1.docker.io" | DOCKER_HUB_REGISTRY => {
+            DOCKER_HUB_REGISTRY.to_owned()
+        }
+        other => other.to_owned(),
+    }
+}
+
+impl Client {
+    /// Checks that a registry accepts the given username and password, by
+    /// answering the authentication challenge to its API root, without
+    /// storing them.
+    pub async fn login(
+        &self,
+        registry: &str,
+        username: &str,
+        password: &Secret<String>,
+    ) -> PublishResult<()> {
+        let url = format!("{}://{}/v2/", self.scheme(registry), registry);
+        let auth = RegistryAuth::Basic(username.to_owned(), password.clone());
+        let http = self.http_for(&url);
+        let response =
+            http.get(&url)
+                .send()
+                .await
+                .map_err(|e| PublishError::RegistryUnreachable {
+                    registry: registry.to_owned(),
+                    source: e,
+                })?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            tracing::info!("{} does not require credentials", registry);
+            return Ok(());
+        }
+
+        let challenge = Challenge::from_response(&response).ok_or_else(|| {
+            PublishError::RegistryUnauthorized(format!(
+                "{} did not say how to authenticate",
+                registry
+            ))
+        })?;
+        let authorization = challenge.authorize(http, "", &auth).await?;
+        let response = authorization.apply(http.get(&url)).send().await?;
+        match response.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                Err(PublishError::RegistryUnauthorized(format!(
+                    "{} rejected the credentials",
+                    registry
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stores_credentials_alongside_other_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = CredentialStore {
+            path: dir.path().join(DOCKER_CONFIG_FILE),
+            kind: StoreKind::Docker,
+        };
+        std::fs::write(store.path(), r#"{"psFormat": "table"}"#).unwrap();
+
+        let password = Secret::new("hunter2".to_owned());
+        store.store(DOCKER_HUB_REGISTRY, "me", &password).unwrap();
+        let (username, stored) = store.get(DOCKER_HUB_REGISTRY).unwrap().unwrap();
+        assert_eq!(
+            ("me", "hunter2"),
+            (username.as_str(), stored.expose().as_str())
+        );
+
+        let config: Value = serde_json::from_slice(&std::fs::read(store.path()).unwrap()).unwrap();
+        assert_eq!("table", config["psFormat"]);
+        assert!(config["auths"][DOCKER_HUB_KEY].is_object());
+
+        assert!(store.remove(DOCKER_HUB_REGISTRY).unwrap());
+        assert!(store.get(DOCKER_HUB_REGISTRY).unwrap().is_none());
+        assert!(!store.remove(DOCKER_HUB_REGISTRY).unwrap());
+    }
+
+    #[test]
+    fn reads_podman_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = CredentialStore {
+            path: dir.path().join(PODMAN_AUTH_FILE),
+            kind: StoreKind::Podman,
+        };
+        let auth = base64::encode("me:hunter2");
+        std::fs::write(
+            store.path(),
+            format!(r#"{{"auths": {{"docker.io": {{"auth": "{}"}}}}}}"#, auth),
+        )
+        .unwrap();
+        let (username, _) = store.get(DOCKER_HUB_REGISTRY).unwrap().unwrap();
+        assert_eq!("me", username);
+    }
+
+    #[test]
+    fn finds_podman_auth_files() {
+        let home = Some(PathBuf::from("/home/me"));
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| std::ffi::OsString::from(*value))
+            }
+        };
+        assert_eq!(
+            vec![
+                PathBuf::from("/run/user/1000/containers/auth.json"),
+                PathBuf::from("/home/me/.config/containers/auth.json"),
+            ],
+            podman_auth_files(env(&[("XDG_RUNTIME_DIR", "/run/user/1000")]), home.clone())
+        );
+        assert_eq!(
+            vec![PathBuf::from("/ci/auth.json")],
+            podman_auth_files(
+                env(&[
+                    ("REGISTRY_AUTH_FILE", "/ci/auth.json"),
+                    ("XDG_RUNTIME_DIR", "/run/user/1000")
+                ]),
+                home
+            )
+        );
+    }
+
+    #[test]
+    fn normalizes_registry_names() {
+        assert_eq!("index.docker.io", normalize_registry("docker.io"));
+        assert_eq!(
+            "index.docker.io",
+            normalize_registry("https://index.docker.io/v1/")
+        );
+        assert_eq!("ghcr.io", normalize_registry("https://ghcr.io/"));
+        assert_eq!("localhost:5000", normalize_registry("localhost:5000"));
+        assert_eq!(DOCKER_HUB_KEY, docker_key("index.docker.io"));
+        assert_eq!("ghcr.io", docker_key("ghcr.io"));
+    }
+}
@@ -0,0 +1,78 @@
+//! Bounding how long registry operations may take.
+//!
+//! All registry operations may also be cancelled by dropping their futures:
+//! requests in flight are abandoned, and content is only ever added to the
+//! cache whole, so a cancelled pull leaves nothing partial behind.
+
+use std::{future::Future, time::Duration};
+
+use spin_app::locked::LockedApp;
+
+use super::{Cache, Client, PushResult};
+use crate::{PublishError, PublishResult};
+
+impl Client {
+    /// Pushes an application as [`push`](Self::push) does, giving up if the
+    /// push has not finished within the deadline. Blobs uploaded before the
+    /// deadline stay in the registry, so that a retry need not upload them
+    /// again, but the reference is only updated if the push finishes.
+    pub async fn push_with_deadline(
+        &self,
+        app: &LockedApp,
+        reference: &str,
+        deadline: Duration,
+    ) -> PublishResult<PushResult> {
+        with_deadline(
+            format!("Push to {}", reference),
+            deadline,
+            self.push(app, reference),
+        )
+        .await
+    }
+
+    /// Pulls an application as [`pull`](Self::pull) does, giving up if the
+    /// pull has not finished within the deadline. Layers cached before the
+    /// deadline are kept for the next pull.
+    pub async fn pull_with_deadline(
+        &self,
+        reference: &str,
+        cache: &Cache,
+        deadline: Duration,
+    ) -> PublishResult<LockedApp> {
+        with_deadline(
+            format!("Pull of {}", reference),
+            deadline,
+            self.pull(reference, cache),
+        )
+        .await
+    }
+}
+
+async fn with_deadline<T>(
+    operation: String,
+    deadline: Duration,
+    future: impl Future<Output = PublishResult<T>>,
+) -> PublishResult<T> {
+    match tokio::time::timeout(deadline, future).await {
+        Ok(result) => result,
+        Err(_) => Err(PublishError::DeadlineExceeded {
+            operation,
+            deadline,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn gives_up_at_deadline() {
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        };
+        let result = with_deadline("Test".to_owned(), Duration::from_millis(10), slow).await;
+        assert!(matches!(result, Err(PublishError::DeadlineExceeded { .. })));
+    }
+}
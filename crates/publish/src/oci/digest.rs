@@ -0,0 +1,138 @@
+//! Content digests. OCI allows content to be addressed by more than one
+//! algorithm, so digests are always handled with their algorithm, which is
+//! also part of the paths at which blobs are stored.
+
+use spin_loader::digest::{bytes_sha256_string, bytes_sha512_string};
+
+use crate::{PublishError, PublishResult};
+
+/// An algorithm by which content is addressed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum DigestAlgorithm {
+    /// SHA-256, which content is addressed by unless it says otherwise
+    #[default]
+    Sha256,
+    /// SHA-512
+    Sha512,
+}
+
+impl DigestAlgorithm {
+    /// The name of the algorithm in digests, such as `sha256`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+        }
+    }
+
+    /// The algorithm with the given name, if it is supported.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(Self::Sha256),
+            "sha512" => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    /// The digest of some content, as `<algorithm>:<hex>`.
+    pub fn digest(self, data: &[u8]) -> String {
+        let hex = match self {
+            Self::Sha256 => bytes_sha256_string(data),
+            Self::Sha512 => bytes_sha512_string(data),
+        };
+        format!("{}:{}", self.name(), hex)
+    }
+
+    /// The length of the hex encoding of the algorithm's digests.
+    fn hex_len(self) -> usize {
+        match self {
+            Self::Sha256 => 64,
+            Self::Sha512 => 128,
+        }
+    }
+}
+
+impl std::fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Splits a digest into its algorithm and hex encoding, checking that the
+/// algorithm is supported and the encoding is well formed for it.
+pub fn parse_digest(digest: &str) -> PublishResult<(DigestAlgorithm, &str)> {
+    let invalid = |reason: &str| PublishError::InvalidDigest {
+        digest: digest.to_owned(),
+        reason: reason.to_owned(),
+    };
+    let (name, hex) = digest
+        .split_once(':')
+        .ok_or_else(|| invalid("it has no algorithm"))?;
+    let algorithm = DigestAlgorithm::from_name(name)
+        .ok_or_else(|| invalid(&format!("the {} algorithm is not supported", name)))?;
+    let well_formed = hex.len() == algorithm.hex_len()
+        && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    if !well_formed {
+        return Err(invalid(&format!(
+            "it is not {} lowercase hex digits",
+            algorithm.hex_len()
+        )));
+    }
+    Ok((algorithm, hex))
+}
+
+/// The directory and file name under which a blob is stored in a
+/// content-addressed store, such as `sha512` and its hex digest. Digests
+/// without an algorithm are taken to be SHA-256. Anything which could escape
+/// the store is replaced, so that a malformed digest from a registry cannot
+/// name a path outside it.
+pub(super) fn blob_path_segments(digest: &str) -> (&str, String) {
+    let (algorithm, hex) = digest
+        .split_once(':')
+        .unwrap_or((DigestAlgorithm::Sha256.name(), digest));
+    let safe = |segment: &str| segment.replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+    match DigestAlgorithm::from_name(algorithm) {
+        Some(algorithm) => (algorithm.name(), safe(hex)),
+        None => ("unknown", safe(digest)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn digests_round_trip() {
+        for algorithm in [DigestAlgorithm::Sha256, DigestAlgorithm::Sha512] {
+            let digest = algorithm.digest(b"layer");
+            let (parsed, hex) = parse_digest(&digest).unwrap();
+            assert_eq!(algorithm, parsed);
+            assert_eq!(algorithm.hex_len(), hex.len());
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_digests() {
+        assert!(parse_digest("md5:d41d8cd98f00b204e9800998ecf8427e").is_err());
+        assert!(parse_digest("sha256:abc").is_err());
+        assert!(parse_digest(&format!("sha512:{}", "A".repeat(128))).is_err());
+        assert!(parse_digest("abc").is_err());
+    }
+
+    #[test]
+    fn blob_paths_stay_in_the_store() {
+        assert_eq!(
+            ("sha512", "abc".to_owned()),
+            blob_path_segments("sha512:abc")
+        );
+        assert_eq!(("sha256", "abc".to_owned()), blob_path_segments("abc"));
+        assert_eq!(
+            ("sha256", "______etc".to_owned()),
+            blob_path_segments("sha256:../../etc")
+        );
+        assert_eq!(
+            ("unknown", "x____y".to_owned()),
+            blob_path_segments("x:../y")
+        );
+    }
+}
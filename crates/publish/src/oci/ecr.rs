@@ -0,0 +1,171 @@
+//! Authentication to Amazon Elastic Container Registry, whose registry
+//! passwords are short-lived tokens issued to AWS credentials.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+
+use super::auth::RegistryAuth;
+use crate::{PublishError, PublishResult, Secret};
+
+/// How long before a token expires that a new one is requested in its place,
+/// so that a token does not expire during the operation it was issued for.
+const EXPIRY_MARGIN_MINUTES: i64 = 15;
+
+lazy_static::lazy_static! {
+    /// The tokens issued to this process, by registry. Tokens last twelve
+    /// hours, which a long-running process may outlive, so each is kept with
+    /// its expiry and replaced when it is about to expire.
+    static ref TOKENS: Mutex<HashMap<String, EcrToken>> = Mutex::new(HashMap::new());
+}
+
+/// A token issued by ECR, with the username with which it is sent.
+#[derive(Clone)]
+struct EcrToken {
+    username: String,
+    password: Secret<String>,
+    expires_at: DateTime<Utc>,
+}
+
+impl EcrToken {
+    /// Whether the token can still be used at the given time, with time to
+    /// spare for the operation it is used for.
+    fn is_fresh(&self, now: DateTime<Utc>) -> bool {
+        now + Duration::minutes(EXPIRY_MARGIN_MINUTES) < self.expires_at
+    }
+}
+
+/// The region of an ECR registry, of the form
+/// `<account>.dkr.ecr.<region>.amazonaws.com`, or `None` if the registry is
+/// not in ECR.
+pub(super) fn ecr_region(registry: &str) -> Option<&str> {
+    let host = registry.split(':').next()?;
+    let host = host
+        .strip_suffix(".amazonaws.com")
+        .or_else(|| host.strip_suffix(".amazonaws.com.cn"))?;
+    let (_, rest) = host
+        .split_once(".dkr.ecr.")
+        .or_else(|| host.split_once(".dkr.ecr-fips."))?;
+    (!rest.is_empty() && !rest.contains('.')).then_some(rest)
+}
+
+/// Obtains a token for an ECR registry with the AWS credential chain,
+/// reusing the one issued earlier in the process until it is about to
+/// expire. Returns `None` if the registry is not in ECR.
+pub(super) fn ecr_auth(registry: &str) -> PublishResult<Option<RegistryAuth>> {
+    let region = match ecr_region(registry) {
+        Some(region) => region,
+        None => return Ok(None),
+    };
+    let cached = TOKENS.lock().unwrap().get(registry).cloned();
+    if let Some(token) = cached.filter(|token| token.is_fresh(Utc::now())) {
+        return Ok(Some(basic(token)));
+    }
+
+    // The lock is not held while the token is requested, so that lookups
+    // for other registries are not held up by it. Concurrent lookups for
+    // the same registry may each request a token, and the last one is kept.
+    let token = request_token(region).map_err(|reason| {
+        PublishError::RegistryUnauthorized(format!(
+            "cannot get an ECR token for {}: {}",
+            registry, reason
+        ))
+    })?;
+    TOKENS
+        .lock()
+        .unwrap()
+        .insert(registry.to_owned(), token.clone());
+    Ok(Some(basic(token)))
+}
+
+/// Requests a token from ECR in a region. Credentials are looked up from
+/// synchronous code, which may be running on an async runtime, so the
+/// request is made on a runtime of its own on another thread.
+fn request_token(region: &str) -> Result<EcrToken, String> {
+    let region = region.to_owned();
+    std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| format!("cannot start the AWS client: {}", e))?
+            .block_on(get_authorization_token(region))
+    })
+    .join()
+    .map_err(|_| "the AWS client panicked".to_owned())?
+}
+
+async fn get_authorization_token(region: String) -> Result<EcrToken, String> {
+    let config = aws_config::from_env()
+        .region(aws_sdk_ecr::Region::new(region))
+        .load()
+        .await;
+    let output = aws_sdk_ecr::Client::new(&config)
+        .get_authorization_token()
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    let data = output
+        .authorization_data()
+        .and_then(|data| data.first())
+        .ok_or("ECR returned no authorization data")?;
+    let token = data
+        .authorization_token()
+        .ok_or("ECR returned no authorization token")?;
+    let expires_at = data
+        .expires_at()
+        .and_then(|expires_at| Utc.timestamp_opt(expires_at.secs(), 0).single())
+        .ok_or("ECR returned a token without an expiry")?;
+    parse_token(token, expires_at)
+}
+
+/// Decodes an ECR authorization token, which is the base64 encoding of
+/// `<username>:<password>`.
+fn parse_token(token: &str, expires_at: DateTime<Utc>) -> Result<EcrToken, String> {
+    let decoded = base64::decode(token)
+        .ok()
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .ok_or("ECR returned a malformed authorization token")?;
+    let (username, password) = decoded
+        .split_once(':')
+        .ok_or("ECR returned a malformed authorization token")?;
+    Ok(EcrToken {
+        username: username.to_owned(),
+        password: Secret::new(password.to_owned()),
+        expires_at,
+    })
+}
+
+fn basic(token: EcrToken) -> RegistryAuth {
+    RegistryAuth::Basic(token.username, token.password)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recognises_ecr_registries() {
+        assert_eq!(
+            Some("us-west-2"),
+            ecr_region("123456789012.dkr.ecr.us-west-2.amazonaws.com")
+        );
+        assert_eq!(
+            Some("cn-north-1"),
+            ecr_region("123456789012.dkr.ecr.cn-north-1.amazonaws.com.cn")
+        );
+        assert_eq!(None, ecr_region("public.ecr.aws"));
+        assert_eq!(None, ecr_region("ghcr.io"));
+    }
+
+    #[test]
+    fn tokens_are_replaced_before_they_expire() {
+        let now = Utc::now();
+        let token = parse_token(&base64::encode("AWS:secret"), now + Duration::hours(12)).unwrap();
+        assert_eq!("AWS", token.username);
+        assert_eq!("secret", token.password.expose());
+        assert!(token.is_fresh(now));
+        assert!(token.is_fresh(now + Duration::hours(11)));
+        assert!(!token.is_fresh(now + Duration::minutes(12 * 60 - 5)));
+        assert!(parse_token("not base64!", now).is_err());
+    }
+}
@@ -0,0 +1,315 @@
+//! Image indexes, which let a Spin application share a reference with
+//! platform-specific variants of it, such as modules precompiled for each
+//! host architecture.
+
+use oci_distribution::manifest::{
+    ImageIndexEntry, OciImageIndex, OciImageManifest, Platform, IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+    OCI_IMAGE_INDEX_MEDIA_TYPE,
+};
+use serde::Deserialize;
+
+use super::{
+    parse_reference, profile::DOCKER_CONFIG_MEDIA_TYPE, pull::parse_image, push::PushSession,
+    spin_media_type, Client, FetchedManifest, SPIN_CONFIG_MEDIA_TYPE,
+};
+use crate::{PublishError, PublishResult};
+
+/// The operating system of the platform by which a Spin application is
+/// identified in an image index.
+pub const SPIN_PLATFORM_OS: &str = "wasi";
+/// The architecture of the platform by which a Spin application is
+/// identified in an image index.
+pub const SPIN_PLATFORM_ARCHITECTURE: &str = "wasm32";
+
+pub(super) const OCI_IMAGE_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+
+/// The platform of an image, as recorded in its config.
+#[derive(Deserialize)]
+struct ImageConfigPlatform {
+    architecture: String,
+    os: String,
+    #[serde(rename = "os.version")]
+    os_version: Option<String>,
+    variant: Option<String>,
+}
+
+impl Client {
+    /// Pushes an image index listing the given manifests to the reference,
+    /// returning the digest of the index. The manifests must already be in
+    /// the repository.
+    pub async fn push_index(
+        &self,
+        reference: &str,
+        manifests: Vec<ImageIndexEntry>,
+    ) -> PublishResult<String> {
+        let parsed = parse_reference(reference)?;
+        let registry = parsed.resolve_registry();
+        let repository = parsed.repository();
+        let target = parsed.digest().or_else(|| parsed.tag()).unwrap_or("latest");
+
+        let index = OciImageIndex {
+            schema_version: 2,
+            media_type: Some(OCI_IMAGE_INDEX_MEDIA_TYPE.to_owned()),
+            manifests,
+            annotations: None,
+        };
+        let data = serde_json::to_vec(&index).map_err(|e| {
+            PublishError::Other(anyhow::anyhow!("Failed to serialize image index: {}", e))
+        })?;
+        PushSession::new(self, registry, repository)
+            .put_manifest(target, OCI_IMAGE_INDEX_MEDIA_TYPE, data)
+            .await
+    }
+
+    /// Describes the manifest at the reference as an entry of an image
+    /// index. A Spin application is given the Spin platform, and any other
+    /// image the platform recorded in its config, if it has one.
+    pub async fn index_entry(&self, reference: &str) -> PublishResult<ImageIndexEntry> {
+        let parsed = parse_reference(reference)?;
+        let registry = parsed.resolve_registry();
+        let repository = parsed.repository();
+        let target = parsed.digest().or_else(|| parsed.tag()).unwrap_or("latest");
+
+        let manifest = self.fetch_manifest(registry, repository, target).await?;
+        if is_index(&manifest) {
+            return Err(PublishError::Other(anyhow::anyhow!(
+                "{} is an image index, which cannot be listed in another index",
+                reference
+            )));
+        }
+        let image = parse_image(reference, &manifest)?;
+
+        let config_media_type =
+            spin_media_type(&image.config.media_type, image.config.annotations.as_ref());
+        let platform = if config_media_type == SPIN_CONFIG_MEDIA_TYPE {
+            Some(spin_platform())
+        } else {
+            let config = self
+                .fetch_blob(registry, repository, &image.config.digest)
+                .await?;
+            serde_json::from_slice::<ImageConfigPlatform>(&config)
+                .ok()
+                .map(|p| Platform {
+                    architecture: p.architecture,
+                    os: p.os,
+                    os_version: p.os_version,
+                    os_features: None,
+                    variant: p.variant,
+                    features: None,
+                })
+        };
+
+        Ok(ImageIndexEntry {
+            media_type: manifest.media_type,
+            size: manifest.data.len() as i64,
+            digest: manifest.digest,
+            platform,
+            annotations: None,
+        })
+    }
+}
+
+/// The platform by which a Spin application is identified in an image
+/// index.
+pub fn spin_platform() -> Platform {
+    Platform {
+        architecture: SPIN_PLATFORM_ARCHITECTURE.to_owned(),
+        os: SPIN_PLATFORM_OS.to_owned(),
+        os_version: None,
+        os_features: None,
+        variant: None,
+        features: None,
+    }
+}
+
+/// Whether a fetched manifest is an image index rather than an image
+/// manifest. Registries which do not report a media type are detected by
+/// the content.
+pub(super) fn is_index(manifest: &FetchedManifest) -> bool {
+    if manifest.media_type == OCI_IMAGE_INDEX_MEDIA_TYPE
+        || manifest.media_type == IMAGE_MANIFEST_LIST_MEDIA_TYPE
+    {
+        return true;
+    }
+    serde_json::from_slice::<serde_json::Value>(&manifest.data)
+        .map(|value| value.get("manifests").is_some() && value.get("layers").is_none())
+        .unwrap_or(false)
+}
+
+/// Finds the entry for the Spin application in an image index: the one
+/// with a Wasm platform or, failing that, the only one without a platform.
+/// An index of only operating system and architecture specific images is
+/// one of container images, which cannot be run as a Spin application.
+pub(super) fn select_spin_manifest<'a>(
+    reference: &str,
+    index: &'a OciImageIndex,
+) -> PublishResult<&'a ImageIndexEntry> {
+    if let Some(entry) = index
+        .manifests
+        .iter()
+        .find(|e| e.platform.as_ref().map_or(false, is_wasm_platform))
+    {
+        return Ok(entry);
+    }
+    let mut unplatformed = index.manifests.iter().filter(|e| e.platform.is_none());
+    match (unplatformed.next(), unplatformed.next()) {
+        (Some(entry), None) => Ok(entry),
+        (None, _) => Err(PublishError::NotSpinApplication {
+            reference: reference.to_owned(),
+            platforms: index
+                .manifests
+                .iter()
+                .filter_map(|e| e.platform.as_ref())
+                .map(platform_name)
+                .collect(),
+        }),
+        _ => Err(PublishError::Other(anyhow::anyhow!(
+            "{} is an image index with several manifests for no particular platform, so the Spin application cannot be told apart",
+            reference
+        ))),
+    }
+}
+
+/// Checks that an image is a Spin application, rather than a container
+/// image. An application pushed in Docker compatibility mode has a
+/// container image config media type, but its config is still the locked
+/// application.
+pub(super) fn check_spin_image(
+    reference: &str,
+    image: &OciImageManifest,
+    config: &[u8],
+) -> PublishResult<()> {
+    let config_media_type =
+        spin_media_type(&image.config.media_type, image.config.annotations.as_ref());
+    match config_media_type {
+        SPIN_CONFIG_MEDIA_TYPE => Ok(()),
+        OCI_IMAGE_CONFIG_MEDIA_TYPE | DOCKER_CONFIG_MEDIA_TYPE if is_locked_app(config) => Ok(()),
+        OCI_IMAGE_CONFIG_MEDIA_TYPE | DOCKER_CONFIG_MEDIA_TYPE => {
+            Err(PublishError::NotSpinApplication {
+                reference: reference.to_owned(),
+                platforms: vec![],
+            })
+        }
+        other => Err(PublishError::Other(anyhow::anyhow!(
+            "{} is not a Spin application: its config has media type {}",
+            reference,
+            other
+        ))),
+    }
+}
+
+fn is_locked_app(config: &[u8]) -> bool {
+    serde_json::from_slice::<serde_json::Value>(config)
+        .map(|config| config.get("spin_lock_version").is_some())
+        .unwrap_or(false)
+}
+
+/// Whether a platform is one on which Wasm runs, such as `wasi/wasm32`,
+/// rather than an operating system and processor architecture.
+fn is_wasm_platform(platform: &Platform) -> bool {
+    matches!(platform.architecture.as_str(), "wasm" | "wasm32") || platform.os.starts_with("wasi")
+}
+
+fn platform_name(platform: &Platform) -> String {
+    match &platform.variant {
+        Some(variant) => format!("{}/{}/{}", platform.os, platform.architecture, variant),
+        None => format!("{}/{}", platform.os, platform.architecture),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use oci_distribution::manifest::OCI_IMAGE_MEDIA_TYPE;
+
+    fn entry(digest: &str, platform: Option<Platform>) -> ImageIndexEntry {
+        ImageIndexEntry {
+            media_type: OCI_IMAGE_MEDIA_TYPE.to_owned(),
+            digest: digest.to_owned(),
+            size: 0,
+            platform,
+            annotations: None,
+        }
+    }
+
+    #[test]
+    fn selects_spin_variant_of_index() {
+        let amd64 = Platform {
+            architecture: "amd64".to_owned(),
+            os: "linux".to_owned(),
+            ..spin_platform()
+        };
+        let mut index = OciImageIndex {
+            schema_version: 2,
+            media_type: Some(OCI_IMAGE_INDEX_MEDIA_TYPE.to_owned()),
+            manifests: vec![
+                entry("sha256:cwasm", Some(amd64)),
+                entry("sha256:spin", Some(spin_platform())),
+            ],
+            annotations: None,
+        };
+        let select = |index| select_spin_manifest("app", index).map(|e| e.digest.clone());
+        assert_eq!("sha256:spin", select(&index).unwrap());
+
+        index.manifests[1].platform = None;
+        assert_eq!("sha256:spin", select(&index).unwrap());
+
+        index.manifests.push(entry("sha256:other", None));
+        assert!(select(&index).is_err());
+    }
+
+    #[test]
+    fn rejects_container_image_indexes() {
+        let platform = |os: &str, architecture: &str| Platform {
+            os: os.to_owned(),
+            architecture: architecture.to_owned(),
+            ..spin_platform()
+        };
+        let index = OciImageIndex {
+            schema_version: 2,
+            media_type: Some(OCI_IMAGE_INDEX_MEDIA_TYPE.to_owned()),
+            manifests: vec![
+                entry("sha256:amd64", Some(platform("linux", "amd64"))),
+                entry("sha256:arm64", Some(platform("linux", "arm64"))),
+            ],
+            annotations: None,
+        };
+        let error = select_spin_manifest("nginx", &index).unwrap_err();
+        assert_eq!(
+            "nginx is a container image index for linux/amd64, linux/arm64, not a Spin application",
+            error.to_string()
+        );
+
+        let wasi = OciImageIndex {
+            manifests: vec![
+                entry("sha256:amd64", Some(platform("linux", "amd64"))),
+                entry("sha256:wasm", Some(platform("wasip1", "wasm"))),
+            ],
+            ..index
+        };
+        assert_eq!(
+            "sha256:wasm",
+            select_spin_manifest("app", &wasi).unwrap().digest
+        );
+    }
+
+    #[test]
+    fn recognises_docker_compatible_applications() {
+        let image = |config_media_type: &str| OciImageManifest {
+            config: oci_distribution::manifest::OciDescriptor {
+                media_type: config_media_type.to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let locked_app = br#"{"spin_lock_version":0,"triggers":[],"components":[]}"#;
+        let container = br#"{"architecture":"amd64","os":"linux","rootfs":{}}"#;
+
+        assert!(check_spin_image("app", &image(DOCKER_CONFIG_MEDIA_TYPE), locked_app).is_ok());
+        assert!(matches!(
+            check_spin_image("nginx", &image(DOCKER_CONFIG_MEDIA_TYPE), container),
+            Err(PublishError::NotSpinApplication { .. })
+        ));
+        assert!(check_spin_image("app", &image(SPIN_CONFIG_MEDIA_TYPE), container).is_ok());
+    }
+}
@@ -0,0 +1,222 @@
+//! Inspection of a Spin application in a registry, from its manifest and
+//! config, without downloading its layers.
+
+use std::collections::{BTreeMap, HashMap};
+
+use oci_distribution::manifest::{OciImageIndex, OciImageManifest};
+use serde::Serialize;
+use spin_app::locked::LockedApp;
+
+use super::{
+    index::{check_spin_image, is_index, select_spin_manifest},
+    parse_reference,
+    pull::{parse_app, parse_image},
+    spin_media_type, Client, COMPONENT_ANNOTATION, GUEST_PATH_ANNOTATION,
+};
+use crate::{PublishError, PublishResult};
+
+/// A summary of a Spin application in a registry.
+#[derive(Clone, Debug, Serialize)]
+pub struct Inspection {
+    /// The reference which was inspected
+    pub reference: String,
+    /// The digest of the application's manifest
+    pub digest: String,
+    /// The media type of the application's manifest
+    pub media_type: String,
+    /// The digest of the image index the application was found in, if the
+    /// reference is an image index
+    pub index_digest: Option<String>,
+    /// The digest of the config object, the locked application
+    pub config_digest: String,
+    /// The types of the application's triggers, such as `http`
+    pub trigger_types: Vec<String>,
+    /// The application's components
+    pub components: Vec<InspectedComponent>,
+    /// The layers listed in the manifest
+    pub layers: Vec<InspectedLayer>,
+    /// The annotations of the manifest
+    pub annotations: BTreeMap<String, String>,
+    /// The size of the config and of each distinct layer, in bytes
+    pub total_bytes: u64,
+}
+
+/// A component of an inspected application.
+#[derive(Clone, Debug, Serialize)]
+pub struct InspectedComponent {
+    /// The ID of the component
+    pub id: String,
+    /// The digest of the component's Wasm module or component
+    pub source_digest: Option<String>,
+    /// How many asset files the component has. Assets pushed as an archive
+    /// count as one.
+    pub asset_count: usize,
+}
+
+/// A layer of an inspected application.
+#[derive(Clone, Debug, Serialize)]
+pub struct InspectedLayer {
+    /// The digest of the layer
+    pub digest: String,
+    /// The Spin media type of the layer
+    pub media_type: String,
+    /// The size of the layer in bytes
+    pub size: u64,
+    /// The component which uses the layer, if recorded
+    pub component: Option<String>,
+    /// The path of the asset file in the component's file system, if the
+    /// layer is an asset file
+    pub guest_path: Option<String>,
+}
+
+impl Client {
+    /// Describes the Spin application at the reference from its manifest
+    /// and config, without downloading its layers. If the reference is an
+    /// image index, the Spin application in it is described.
+    pub async fn inspect(&self, reference: &str) -> PublishResult<Inspection> {
+        let parsed = parse_reference(reference)?;
+        let registry = parsed.resolve_registry();
+        let repository = parsed.repository();
+        let target = parsed.digest().or_else(|| parsed.tag()).unwrap_or("latest");
+
+        let manifest = self.fetch_manifest(registry, repository, target).await?;
+        let (index_digest, manifest) = if is_index(&manifest) {
+            let index: OciImageIndex = serde_json::from_slice(&manifest.data).map_err(|e| {
+                PublishError::Other(anyhow::anyhow!(
+                    "{} is not a valid image index: {}",
+                    reference,
+                    e
+                ))
+            })?;
+            let entry = select_spin_manifest(reference, &index)?;
+            let image = self
+                .fetch_manifest(registry, repository, &entry.digest)
+                .await?;
+            (Some(manifest.digest), image)
+        } else {
+            (None, manifest)
+        };
+        let image = parse_image(reference, &manifest)?;
+        let config = self
+            .fetch_blob(registry, repository, &image.config.digest)
+            .await?;
+        check_spin_image(reference, &image, &config)?;
+        let app = parse_app(reference, &config)?;
+
+        Ok(Inspection {
+            reference: reference.to_owned(),
+            digest: manifest.digest,
+            media_type: manifest.media_type,
+            index_digest,
+            ..summarize(&image, &app)
+        })
+    }
+}
+
+/// Summarizes an application from its image manifest and config, leaving
+/// the reference and manifest details empty.
+fn summarize(image: &OciImageManifest, app: &LockedApp) -> Inspection {
+    let mut trigger_types: Vec<String> = vec![];
+    for trigger in &app.triggers {
+        if !trigger_types.contains(&trigger.trigger_type) {
+            trigger_types.push(trigger.trigger_type.clone());
+        }
+    }
+
+    let components = app
+        .components
+        .iter()
+        .map(|c| InspectedComponent {
+            id: c.id.clone(),
+            source_digest: c.source.content.digest.clone(),
+            asset_count: c.files.len(),
+        })
+        .collect();
+
+    let layers: Vec<_> = image
+        .layers
+        .iter()
+        .map(|layer| {
+            let annotation = |name: &str| layer.annotations.as_ref()?.get(name).cloned();
+            InspectedLayer {
+                digest: layer.digest.clone(),
+                media_type: spin_media_type(&layer.media_type, layer.annotations.as_ref())
+                    .to_owned(),
+                size: layer.size as u64,
+                component: annotation(COMPONENT_ANNOTATION),
+                guest_path: annotation(GUEST_PATH_ANNOTATION),
+            }
+        })
+        .collect();
+    let distinct: HashMap<_, _> = layers.iter().map(|l| (&l.digest, l.size)).collect();
+    let total_bytes = distinct.values().sum::<u64>() + image.config.size as u64;
+
+    Inspection {
+        reference: String::new(),
+        digest: String::new(),
+        media_type: String::new(),
+        index_digest: None,
+        config_digest: image.config.digest.clone(),
+        trigger_types,
+        components,
+        layers,
+        annotations: image
+            .annotations
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .collect(),
+        total_bytes,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::oci::{SPIN_CONFIG_MEDIA_TYPE, WASM_LAYER_MEDIA_TYPE};
+    use oci_distribution::manifest::OciDescriptor;
+
+    #[test]
+    fn summaries_count_shared_layers_once() {
+        let layer = |digest: &str, component: &str| OciDescriptor {
+            media_type: WASM_LAYER_MEDIA_TYPE.to_owned(),
+            digest: digest.to_owned(),
+            size: 100,
+            urls: None,
+            annotations: Some(HashMap::from([(
+                COMPONENT_ANNOTATION.to_owned(),
+                component.to_owned(),
+            )])),
+        };
+        let image = OciImageManifest {
+            config: OciDescriptor {
+                media_type: SPIN_CONFIG_MEDIA_TYPE.to_owned(),
+                digest: "sha256:config".to_owned(),
+                size: 10,
+                ..Default::default()
+            },
+            layers: vec![layer("sha256:wasm", "a"), layer("sha256:wasm", "b")],
+            ..Default::default()
+        };
+        let app = LockedApp::from_json(
+            br#"{
+                "spin_lock_version": 0,
+                "triggers": [
+                    {"id": "a", "trigger_type": "http", "trigger_config": {}},
+                    {"id": "b", "trigger_type": "http", "trigger_config": {}}
+                ],
+                "components": [
+                    {"id": "a", "source": {"content_type": "application/wasm", "digest": "sha256:wasm"}},
+                    {"id": "b", "source": {"content_type": "application/wasm", "digest": "sha256:wasm"}}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let summary = summarize(&image, &app);
+        assert_eq!(vec!["http"], summary.trigger_types);
+        assert_eq!(2, summary.components.len());
+        assert_eq!(Some("b"), summary.layers[1].component.as_deref());
+        assert_eq!(110, summary.total_bytes);
+    }
+}
@@ -0,0 +1,159 @@
+//! File-backed layers, which let layers of any size be assembled and
+//! uploaded without their content being held in memory.
+
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use spin_loader::digest::file_sha256_string;
+
+use crate::{PublishError, PublishResult, StagedFile};
+
+/// Distinguishes the partly written files of layers being built at once.
+static PARTIAL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// A layer whose content is a file: the digest and size of the content and
+/// the path it is read from when it is uploaded.
+#[derive(Clone, Debug)]
+pub(super) struct FileLayer {
+    pub(super) digest: String,
+    pub(super) path: PathBuf,
+    pub(super) size: u64,
+}
+
+impl FileLayer {
+    /// Describes an existing file, digesting it a block at a time.
+    pub(super) fn from_file(path: PathBuf) -> PublishResult<Self> {
+        let io_error = |source| PublishError::Io {
+            description: format!("Failed to calculate digest for {}", path.display()),
+            source,
+        };
+        let size = std::fs::metadata(&path).map_err(io_error)?.len();
+        let sha256 = file_sha256_string(&path).map_err(io_error)?;
+        Ok(Self {
+            digest: format!("sha256:{}", sha256),
+            path,
+            size,
+        })
+    }
+
+    /// Builds a layer by writing its content to a file in the given
+    /// directory, which is named by the digest of the content once it is
+    /// complete, so that building the same content twice yields one file.
+    pub(super) fn create(
+        dir: &Path,
+        write: impl FnOnce(&mut BufWriter<File>) -> PublishResult<()>,
+    ) -> PublishResult<Self> {
+        let io_error = |description: String| {
+            move |source: std::io::Error| PublishError::Io {
+                description,
+                source,
+            }
+        };
+        std::fs::create_dir_all(dir).map_err(io_error(format!(
+            "Failed to create layer directory {}",
+            dir.display()
+        )))?;
+
+        let partial = dir.join(format!(
+            "partial-{}",
+            PARTIAL_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::create(&partial)
+            .map_err(io_error(format!("Failed to create {}", partial.display())))?;
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .map_err(io_error(format!("Failed to write {}", partial.display())))?;
+
+        let Self { digest, size, .. } = Self::from_file(partial.clone())?;
+        let path = dir.join(digest.trim_start_matches("sha256:"));
+        std::fs::rename(&partial, &path).map_err(io_error(format!(
+            "Failed to move {} to {}",
+            partial.display(),
+            path.display()
+        )))?;
+        Ok(Self { digest, path, size })
+    }
+}
+
+impl From<StagedFile> for FileLayer {
+    fn from(staged: StagedFile) -> Self {
+        Self {
+            digest: staged.digest(),
+            path: staged.path,
+            size: staged.size,
+        }
+    }
+}
+
+/// A directory for the layers built during a push, such as asset archives
+/// and compressed files, which is removed when it is dropped.
+pub(super) struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    /// Chooses a new scratch directory in the system temporary directory.
+    /// It is only created once a layer is built in it.
+    pub(super) fn new() -> Self {
+        static SCRATCH_DIRS: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "spin-layers-{}-{}",
+            std::process::id(),
+            SCRATCH_DIRS.fetch_add(1, Ordering::Relaxed)
+        );
+        Self {
+            path: std::env::temp_dir().join(name),
+        }
+    }
+
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if self.path.exists() {
+            if let Err(e) = std::fs::remove_dir_all(&self.path) {
+                tracing::warn!("Failed to remove {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::oci::sha256_digest;
+    use std::io::Write;
+
+    #[test]
+    fn built_layers_are_named_by_digest() {
+        let scratch = ScratchDir::new();
+        let build = || {
+            FileLayer::create(scratch.path(), |out| {
+                out.write_all(b"hello").map_err(|source| PublishError::Io {
+                    description: "Failed to write".to_owned(),
+                    source,
+                })
+            })
+            .unwrap()
+        };
+        let layer = build();
+        assert_eq!(sha256_digest(b"hello"), layer.digest);
+        assert_eq!(5, layer.size);
+        assert_eq!(b"hello".to_vec(), std::fs::read(&layer.path).unwrap());
+        assert_eq!(layer.path, build().path);
+
+        let path = scratch.path().to_owned();
+        drop(scratch);
+        assert!(!path.exists());
+    }
+}
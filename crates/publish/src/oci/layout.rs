@@ -0,0 +1,371 @@
+//! OCI image layouts: directories holding applications in the standard
+//! format which other OCI tooling reads, so that they can be moved where
+//! there is no registry to pull them from.
+
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use flate2::read::GzDecoder;
+use oci_distribution::manifest::{ImageIndexEntry, OciImageIndex, OCI_IMAGE_INDEX_MEDIA_TYPE};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    digest::blob_path_segments,
+    index::{check_spin_image, is_index, select_spin_manifest, spin_platform},
+    parse_reference,
+    pull::parse_image,
+    push::blocking,
+    verify_digest, Cache, Client, FetchedManifest,
+};
+use crate::{PublishError, PublishResult};
+
+/// The file marking a directory as an OCI image layout.
+pub(super) const OCI_LAYOUT_FILE: &str = "oci-layout";
+/// The image index listing the manifests in an OCI image layout.
+pub(super) const OCI_LAYOUT_INDEX_FILE: &str = "index.json";
+/// The annotation giving the name of a manifest in an OCI image layout.
+pub(super) const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+const IMAGE_LAYOUT_VERSION: &str = "1.0.0";
+/// The directory of the cache into which layout tarballs are unpacked while
+/// they are loaded.
+const UNPACKED_LAYOUTS_DIR: &str = "layouts";
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImageLayout {
+    image_layout_version: String,
+}
+
+impl Client {
+    /// Saves the Spin application at the reference, by way of the cache,
+    /// into the OCI image layout at `dir`, creating the layout if it does
+    /// not exist. The application is named in the layout by `name` or,
+    /// failing that, by the tag of the reference, replacing any manifest
+    /// of the same name. Returns the digest of the saved manifest.
+    pub async fn save(
+        &self,
+        reference: &str,
+        cache: &Cache,
+        dir: &Path,
+        name: Option<&str>,
+    ) -> PublishResult<String> {
+        let (manifest, image) = self.cache_spin_manifest(reference, cache).await?;
+        let name = match name {
+            Some(name) => name.to_owned(),
+            None => parse_reference(reference)?
+                .tag()
+                .unwrap_or("latest")
+                .to_owned(),
+        };
+
+        let layout_file = dir.join(OCI_LAYOUT_FILE);
+        if !layout_file.exists() {
+            let layout = ImageLayout {
+                image_layout_version: IMAGE_LAYOUT_VERSION.to_owned(),
+            };
+            write_layout_file(&layout_file, &to_json(&layout)?).await?;
+        }
+
+        for descriptor in std::iter::once(&image.config).chain(&image.layers) {
+            let dest = layout_blob_path(dir, &descriptor.digest);
+            if dest.exists() {
+                continue;
+            }
+            let data = cache.read_blob(&descriptor.digest).await?.ok_or_else(|| {
+                PublishError::Other(anyhow::anyhow!("Blob {} is not cached", descriptor.digest))
+            })?;
+            write_layout_file(&dest, &data).await?;
+        }
+        write_layout_file(&layout_blob_path(dir, &manifest.digest), &manifest.data).await?;
+
+        let mut index = read_layout_index(dir).await?;
+        index
+            .manifests
+            .retain(|entry| ref_name(entry) != Some(&name));
+        index.manifests.push(ImageIndexEntry {
+            media_type: manifest.media_type,
+            size: manifest.data.len() as i64,
+            digest: manifest.digest.clone(),
+            platform: Some(spin_platform()),
+            annotations: Some(HashMap::from([(REF_NAME_ANNOTATION.to_owned(), name)])),
+        });
+        write_layout_file(&dir.join(OCI_LAYOUT_INDEX_FILE), &to_json(&index)?).await?;
+
+        Ok(manifest.digest)
+    }
+
+    /// Loads a Spin application from the OCI image layout at `path`, which
+    /// may be a directory or a tarball of one, optionally gzipped, into the
+    /// cache under the reference, from which it can be pushed with
+    /// [`push_cached`](Self::push_cached). The application is the manifest
+    /// named `name` in the layout or, if no name is given, the only one in
+    /// it. Content is checked against its digest as it is loaded. Returns
+    /// the digest of the loaded manifest.
+    pub async fn load(
+        &self,
+        path: &Path,
+        name: Option<&str>,
+        reference: &str,
+        cache: &Cache,
+    ) -> PublishResult<String> {
+        if path.is_dir() {
+            return load_layout(path, name, reference, cache).await;
+        }
+
+        let unpacked = cache
+            .root()
+            .join(UNPACKED_LAYOUTS_DIR)
+            .join(std::process::id().to_string());
+        let (archive, dest) = (path.to_owned(), unpacked.clone());
+        let result = match blocking(move || unpack_layout_archive(&archive, &dest)).await {
+            Ok(()) => load_layout(&unpacked, name, reference, cache).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = tokio::fs::remove_dir_all(&unpacked).await {
+            tracing::warn!("Failed to remove {}: {}", unpacked.display(), e);
+        }
+        result
+    }
+}
+
+/// Loads the named, or only, Spin application in an OCI image layout
+/// directory into the cache under the reference.
+async fn load_layout(
+    dir: &Path,
+    name: Option<&str>,
+    reference: &str,
+    cache: &Cache,
+) -> PublishResult<String> {
+    if !dir.join(OCI_LAYOUT_FILE).exists() {
+        return Err(PublishError::Other(anyhow::anyhow!(
+            "{} is not an OCI image layout",
+            dir.display()
+        )));
+    }
+    let index = read_layout_index(dir).await?;
+    let entry = select_layout_entry(dir, &index, name)?;
+    let mut manifest = FetchedManifest {
+        data: read_layout_blob(dir, &entry.digest).await?,
+        media_type: entry.media_type.clone(),
+        digest: entry.digest.clone(),
+    };
+    if is_index(&manifest) {
+        let index: OciImageIndex = serde_json::from_slice(&manifest.data).map_err(|e| {
+            PublishError::Other(anyhow::anyhow!(
+                "{} is not a valid image index: {}",
+                entry.digest,
+                e
+            ))
+        })?;
+        let entry = select_spin_manifest(reference, &index)?;
+        manifest = FetchedManifest {
+            data: read_layout_blob(dir, &entry.digest).await?,
+            media_type: entry.media_type.clone(),
+            digest: entry.digest.clone(),
+        };
+    }
+    let image = parse_image(reference, &manifest)?;
+    let config = read_layout_blob(dir, &image.config.digest).await?;
+    check_spin_image(reference, &image, &config)?;
+
+    cache.write_blob(&image.config.digest, &config).await?;
+    for layer in &image.layers {
+        if cache.blob_path(&layer.digest).exists() {
+            continue;
+        }
+        let data = read_layout_blob(dir, &layer.digest).await?;
+        cache.write_blob(&layer.digest, &data).await?;
+    }
+
+    // As when pulling, the manifest is cached last, so that a cached
+    // manifest means the content it refers to is cached too.
+    let parsed = parse_reference(reference)?;
+    let registry = parsed.resolve_registry();
+    let repository = parsed.repository();
+    let target = parsed.digest().or_else(|| parsed.tag()).unwrap_or("latest");
+    cache
+        .write_manifest(registry, repository, &manifest.digest, &manifest.data)
+        .await?;
+    if target != manifest.digest {
+        cache
+            .write_manifest(registry, repository, target, &manifest.data)
+            .await?;
+    }
+    Ok(manifest.digest)
+}
+
+/// Finds the manifest with the given name in an OCI image layout's index
+/// or, if no name is given, the only manifest in it.
+fn select_layout_entry<'a>(
+    dir: &Path,
+    index: &'a OciImageIndex,
+    name: Option<&str>,
+) -> PublishResult<&'a ImageIndexEntry> {
+    let names = || {
+        let names: Vec<_> = index
+            .manifests
+            .iter()
+            .map(|e| ref_name(e).map_or("(unnamed)", String::as_str))
+            .collect();
+        names.join(", ")
+    };
+    match name {
+        Some(name) => index
+            .manifests
+            .iter()
+            .find(|e| ref_name(e).map(String::as_str) == Some(name))
+            .ok_or_else(|| {
+                PublishError::Other(anyhow::anyhow!(
+                    "{} has no manifest named {}. It has: {}",
+                    dir.display(),
+                    name,
+                    names()
+                ))
+            }),
+        None => match index.manifests.as_slice() {
+            [entry] => Ok(entry),
+            [] => Err(PublishError::Other(anyhow::anyhow!(
+                "{} has no manifests",
+                dir.display()
+            ))),
+            _ => Err(PublishError::Other(anyhow::anyhow!(
+                "{} has several manifests ({}). Choose one by name",
+                dir.display(),
+                names()
+            ))),
+        },
+    }
+}
+
+/// Reads a blob from an OCI image layout, checking its digest.
+async fn read_layout_blob(dir: &Path, digest: &str) -> PublishResult<Vec<u8>> {
+    let path = layout_blob_path(dir, digest);
+    let data = tokio::fs::read(&path).await.map_err(|e| PublishError::Io {
+        source: e,
+        description: format!("Failed to read {}", path.display()),
+    })?;
+    verify_digest(&path.display().to_string(), digest, &data)?;
+    Ok(data)
+}
+
+/// Unpacks a tarball of an OCI image layout, which may be gzipped.
+fn unpack_layout_archive(archive: &Path, dest: &Path) -> PublishResult<()> {
+    let io_error = |e: std::io::Error| PublishError::Io {
+        source: e,
+        description: format!("Failed to unpack {}", archive.display()),
+    };
+    let mut file = std::fs::File::open(archive).map_err(io_error)?;
+    let mut magic = [0; 2];
+    let gzipped = file.read_exact(&mut magic).is_ok() && magic == GZIP_MAGIC;
+    file.seek(SeekFrom::Start(0)).map_err(io_error)?;
+    let unpacked = if gzipped {
+        tar::Archive::new(GzDecoder::new(file)).unpack(dest)
+    } else {
+        tar::Archive::new(file).unpack(dest)
+    };
+    unpacked.map_err(io_error)
+}
+
+/// The path of the blob with the given digest in an OCI image layout.
+pub(super) fn layout_blob_path(dir: &Path, digest: &str) -> PathBuf {
+    let (algorithm, hex) = blob_path_segments(digest);
+    dir.join("blobs").join(algorithm).join(hex)
+}
+
+/// The name of a manifest in an OCI image layout, if it has one.
+pub(super) fn ref_name(entry: &ImageIndexEntry) -> Option<&String> {
+    entry.annotations.as_ref()?.get(REF_NAME_ANNOTATION)
+}
+
+/// Reads the index of an OCI image layout, or an empty index if the layout
+/// has none yet.
+pub(super) async fn read_layout_index(dir: &Path) -> PublishResult<OciImageIndex> {
+    let path = dir.join(OCI_LAYOUT_INDEX_FILE);
+    match tokio::fs::read(&path).await {
+        Ok(data) => serde_json::from_slice(&data).map_err(|e| {
+            PublishError::Other(anyhow::anyhow!(
+                "{} is not a valid image index: {}",
+                path.display(),
+                e
+            ))
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(OciImageIndex {
+            schema_version: 2,
+            media_type: Some(OCI_IMAGE_INDEX_MEDIA_TYPE.to_owned()),
+            manifests: vec![],
+            annotations: None,
+        }),
+        Err(e) => Err(PublishError::Io {
+            source: e,
+            description: format!("Failed to read {}", path.display()),
+        }),
+    }
+}
+
+fn to_json(value: &impl Serialize) -> PublishResult<Vec<u8>> {
+    serde_json::to_vec(value).map_err(|e| PublishError::Other(e.into()))
+}
+
+async fn write_layout_file(path: &Path, data: &[u8]) -> PublishResult<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(|e| PublishError::Io {
+                source: e,
+                description: format!("Failed to create directory {}", dir.display()),
+            })?;
+    }
+    tokio::fs::write(path, data)
+        .await
+        .map_err(|e| PublishError::Io {
+            source: e,
+            description: format!("Failed to write {}", path.display()),
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn selects_layout_manifests_by_name() {
+        let entry = |digest: &str, name: &str| ImageIndexEntry {
+            media_type: OCI_IMAGE_INDEX_MEDIA_TYPE.to_owned(),
+            digest: digest.to_owned(),
+            size: 0,
+            platform: None,
+            annotations: Some(HashMap::from([(
+                REF_NAME_ANNOTATION.to_owned(),
+                name.to_owned(),
+            )])),
+        };
+        let mut index = OciImageIndex {
+            schema_version: 2,
+            media_type: None,
+            manifests: vec![entry("sha256:v1", "v1")],
+            annotations: None,
+        };
+        let select = |index: &OciImageIndex, name| {
+            select_layout_entry(Path::new("app"), index, name).map(|e| e.digest.clone())
+        };
+        assert_eq!("sha256:v1", select(&index, None).unwrap());
+
+        index.manifests.push(entry("sha256:v2", "v2"));
+        assert_eq!("sha256:v2", select(&index, Some("v2")).unwrap());
+        assert!(select(&index, None).is_err());
+        assert!(select(&index, Some("v3")).is_err());
+    }
+
+    #[test]
+    fn layout_blobs_are_stored_by_algorithm() {
+        assert_eq!(
+            Path::new("app").join("blobs").join("sha256").join("abc"),
+            layout_blob_path(Path::new("app"), "sha256:abc")
+        );
+    }
+}
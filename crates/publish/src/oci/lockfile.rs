@@ -0,0 +1,99 @@
+//! Lockfiles, which pin the references of applications run from registries
+//! to the digests they resolved to, so that later runs use the same content.
+
+use std::{collections::BTreeMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use super::parse_reference;
+use crate::{PublishError, PublishResult};
+
+/// The lockfile used if none is specified.
+pub const DEFAULT_LOCKFILE: &str = "spin-oci.lock";
+
+/// The digests to which references are pinned.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lockfile {
+    /// The pinned digest of each reference
+    pub pins: BTreeMap<String, String>,
+}
+
+impl Lockfile {
+    /// Loads a lockfile, which is empty if it does not exist yet.
+    pub fn load(path: &Path) -> PublishResult<Self> {
+        match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| {
+                PublishError::Other(anyhow::anyhow!(
+                    "{} is not a valid lockfile: {}",
+                    path.display(),
+                    e
+                ))
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(PublishError::Io {
+                source: e,
+                description: format!("Failed to read {}", path.display()),
+            }),
+        }
+    }
+
+    /// Saves the lockfile, replacing whatever was at the path.
+    pub fn save(&self, path: &Path) -> PublishResult<()> {
+        let mut data =
+            serde_json::to_vec_pretty(self).map_err(|e| PublishError::Other(e.into()))?;
+        data.push(b'\n');
+        std::fs::write(path, data).map_err(|source| PublishError::Io {
+            source,
+            description: format!("Failed to write {}", path.display()),
+        })
+    }
+
+    /// The digest a reference is pinned to, if it is pinned.
+    pub fn pinned(&self, reference: &str) -> Option<&str> {
+        self.pins.get(reference).map(String::as_str)
+    }
+
+    /// Pins a reference to a digest.
+    pub fn pin(&mut self, reference: &str, digest: &str) {
+        self.pins.insert(reference.to_owned(), digest.to_owned());
+    }
+}
+
+/// The reference by which exactly the content with the given digest is
+/// pulled from a reference's repository.
+pub fn pinned_reference(reference: &str, digest: &str) -> PublishResult<String> {
+    let parsed = parse_reference(reference)?;
+    Ok(format!(
+        "{}/{}@{}",
+        parsed.resolve_registry(),
+        parsed.repository(),
+        digest
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pins_survive_saving() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(DEFAULT_LOCKFILE);
+        let mut lockfile = Lockfile::load(&path).unwrap();
+        assert_eq!(None, lockfile.pinned("ghcr.io/org/app:v1"));
+
+        lockfile.pin("ghcr.io/org/app:v1", "sha256:abc");
+        lockfile.save(&path).unwrap();
+        let loaded = Lockfile::load(&path).unwrap();
+        assert_eq!(Some("sha256:abc"), loaded.pinned("ghcr.io/org/app:v1"));
+        assert_eq!(lockfile, loaded);
+    }
+
+    #[test]
+    fn pinned_references_use_the_digest() {
+        assert_eq!(
+            "ghcr.io/org/app@sha256:abc",
+            pinned_reference("ghcr.io/org/app:v1", "sha256:abc").unwrap()
+        );
+    }
+}
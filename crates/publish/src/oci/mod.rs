@@ -0,0 +1,1227 @@
+#![deny(missing_docs)]
+
+//! Functions for working with Spin applications in OCI registries.
+
+mod archive;
+mod artifact;
+mod auth;
+mod cache;
+mod check;
+mod compression;
+mod copy;
+mod credentials;
+mod deadline;
+mod digest;
+#[cfg(feature = "ecr")]
+mod ecr;
+mod index;
+mod inspect;
+mod layer;
+mod layout;
+mod lockfile;
+mod operations;
+mod policy;
+mod profile;
+mod proxy;
+mod pull;
+mod push;
+mod registries;
+mod resume;
+mod sbom;
+mod sign;
+mod tls;
+mod validate;
+
+use std::{collections::HashMap, sync::Arc};
+
+use oci_distribution::{
+    manifest::{
+        IMAGE_MANIFEST_LIST_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE,
+        OCI_IMAGE_MEDIA_TYPE,
+    },
+    Reference,
+};
+use reqwest::{
+    header::{ACCEPT, CONTENT_TYPE, LINK},
+    Method, StatusCode,
+};
+use serde::Deserialize;
+
+use crate::{throttle::Throttle, PublishError, PublishFilter, PublishResult, Staging};
+use auth::{registry_auth, Authorization, Challenge, RegistryAuth};
+
+pub use archive::ARCHIVE_LAYER_MEDIA_TYPE;
+pub use artifact::{ARTIFACT_MANIFEST_MEDIA_TYPE, SPIN_ARTIFACT_TYPE};
+pub use auth::{
+    REGISTRY_HOST_ENV, REGISTRY_PASSWORD_ENV, REGISTRY_TOKEN_ENV, REGISTRY_USERNAME_ENV,
+};
+pub use cache::{Cache, DEFAULT_WARM_CONCURRENCY};
+pub use check::RegistryCheck;
+pub use compression::{Compression, DATA_LAYER_GZIP_MEDIA_TYPE, DATA_LAYER_ZSTD_MEDIA_TYPE};
+pub use credentials::{normalize_registry, CredentialStore};
+pub use digest::{parse_digest, DigestAlgorithm};
+pub use index::{spin_platform, SPIN_PLATFORM_ARCHITECTURE, SPIN_PLATFORM_OS};
+pub use inspect::{InspectedComponent, InspectedLayer, Inspection};
+pub use lockfile::{pinned_reference, Lockfile, DEFAULT_LOCKFILE};
+pub use operations::{Operation, OperationKind, OPERATIONS_LOG_FILE};
+pub use policy::{PolicyViolation, TrustPolicy};
+pub use profile::{
+    is_media_type_rejection, spin_media_type, MediaTypeProfile, MEDIA_TYPE_ANNOTATION,
+    PROFILE_ANNOTATION,
+};
+pub use proxy::Proxy;
+pub use push::{read_locked_app, DryRunPush, ExistingTagPolicy, PushResult, PushedLayer};
+pub use registries::{RegistryConfig, RegistryHost, REGISTRY_CONFIG_FILE};
+pub use resume::{PartialUpload, ResumeToken};
+pub use sbom::{sbom_media_type, spdx_sbom, CYCLONEDX_MEDIA_TYPE, SPDX_MEDIA_TYPE};
+pub use sign::{SigningKey, VerificationKey, SIGNATURE_ANNOTATION, SIMPLE_SIGNING_MEDIA_TYPE};
+
+const CATALOG_PAGE_SIZE: usize = 100;
+const CATALOG_SCOPE: &str = "registry:catalog:*";
+
+/// How many layers are downloaded at once by default when pulling an
+/// application.
+pub const DEFAULT_MAX_CONCURRENT_DOWNLOADS: usize = 8;
+
+/// The user agent with which the client identifies itself to registries.
+const USER_AGENT: &str = concat!("spin/", env!("CARGO_PKG_VERSION"));
+
+/// How long to wait for a registry to answer a ping.
+const PING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+const DOCKER_CONTENT_DIGEST_HEADER: &str = "Docker-Content-Digest";
+
+/// The media type of the config object of a Spin application, which is
+/// the application's locked manifest.
+pub const SPIN_CONFIG_MEDIA_TYPE: &str = "application/vnd.fermyon.spin.application.v1+config";
+/// The media type of layers containing Wasm modules or components.
+pub const WASM_LAYER_MEDIA_TYPE: &str = "application/vnd.wasm.content.layer.v1+wasm";
+/// The media type of layers containing static asset files.
+pub const DATA_LAYER_MEDIA_TYPE: &str = "application/vnd.wasm.content.layer.v1+data";
+
+/// Layer annotation recording the ID of the component which uses a layer.
+pub const COMPONENT_ANNOTATION: &str = "dev.fermyon.spin.component";
+/// Layer annotation recording the path of a static asset file in the file
+/// system of the component which uses it.
+pub const GUEST_PATH_ANNOTATION: &str = "dev.fermyon.spin.guest-path";
+
+/// Manifest annotation recording when an artifact was created.
+pub const CREATED_ANNOTATION: &str = "org.opencontainers.image.created";
+/// Manifest annotation recording the source control revision an artifact
+/// was built from.
+pub const REVISION_ANNOTATION: &str = "org.opencontainers.image.revision";
+/// Manifest annotation recording the URL of the source an artifact was
+/// built from.
+pub const SOURCE_ANNOTATION: &str = "org.opencontainers.image.source";
+
+const COSIGN_SIGNATURE_TAG_SUFFIX: &str = ".sig";
+
+const MANIFEST_MEDIA_TYPES: &[&str] = &[
+    OCI_IMAGE_MEDIA_TYPE,
+    artifact::ARTIFACT_MANIFEST_MEDIA_TYPE,
+    OCI_IMAGE_INDEX_MEDIA_TYPE,
+    IMAGE_MANIFEST_MEDIA_TYPE,
+    IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+];
+
+const DOCKER_HUB_REGISTRIES: &[&str] = &["docker.io", "index.docker.io", "registry-1.docker.io"];
+const DOCKER_HUB_REPOSITORIES_URL: &str = "https://hub.docker.com/v2/repositories";
+
+/// Client for working with Spin applications in OCI registries.
+pub struct Client {
+    http: reqwest::Client,
+    insecure: bool,
+    download_throttle: Option<Arc<Throttle>>,
+    trust_policy: Option<Arc<TrustPolicy>>,
+    validate_wasm: bool,
+    staging: Option<Staging>,
+    filter: Option<PublishFilter>,
+    max_concurrent_downloads: usize,
+    compression: Compression,
+    archive_assets: bool,
+    artifact_manifest: bool,
+    docker_compatible: bool,
+    negative_cache: Option<(Cache, std::time::Duration)>,
+    annotations: HashMap<String, String>,
+    resume: Option<ResumeToken>,
+    operation_log: Option<Cache>,
+    existing_tag_policy: ExistingTagPolicy,
+    root_certificates: Vec<reqwest::Certificate>,
+    client_certificates: HashMap<String, tls::ClientCertificate>,
+    registry_http: HashMap<String, reqwest::Client>,
+    registry_config: RegistryConfig,
+}
+
+impl Client {
+    /// Creates a new client. If `insecure` is set, registries are accessed
+    /// over plain HTTP. To access only some registries over plain HTTP, use
+    /// [`with_registry_config`](Self::with_registry_config) instead.
+    pub fn new(insecure: bool) -> PublishResult<Self> {
+        let http = reqwest::Client::builder().user_agent(USER_AGENT).build()?;
+        Ok(Self {
+            http,
+            insecure,
+            download_throttle: None,
+            trust_policy: None,
+            validate_wasm: false,
+            staging: None,
+            filter: None,
+            max_concurrent_downloads: DEFAULT_MAX_CONCURRENT_DOWNLOADS,
+            compression: Compression::None,
+            archive_assets: false,
+            artifact_manifest: false,
+            docker_compatible: false,
+            negative_cache: None,
+            annotations: HashMap::new(),
+            resume: None,
+            operation_log: None,
+            existing_tag_policy: ExistingTagPolicy::Error,
+            root_certificates: vec![],
+            client_certificates: HashMap::new(),
+            registry_http: HashMap::new(),
+            registry_config: RegistryConfig::default(),
+        })
+    }
+
+    /// Makes all registry requests with the given HTTP client, so that
+    /// proxy, TLS and connection pool settings can be shared with other
+    /// clients. The client is used as is, so it should identify itself with
+    /// a user agent of its own.
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Checks that layers claiming to be Wasm are structurally valid
+    /// modules or components when they are fetched with
+    /// [`fetch_layer`](Self::fetch_layer).
+    pub fn with_wasm_validation(mut self, validate: bool) -> Self {
+        self.validate_wasm = validate;
+        self
+    }
+
+    /// Enforces a trust policy on all content fetched by this client.
+    /// Content which violates the policy is refused before it is returned
+    /// to the caller.
+    pub fn with_trust_policy(mut self, policy: TrustPolicy) -> Self {
+        self.trust_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Stages application files before pushing them, sharing the staged
+    /// content and digests with other publishes.
+    pub fn with_staging(mut self, staging: Staging) -> Self {
+        self.staging = Some(staging);
+        self
+    }
+
+    /// Pushes only the asset files admitted by the filter. Paths are matched
+    /// as they appear in the component's file system.
+    pub fn with_filter(mut self, filter: PublishFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Compresses the data layers holding static asset files when pushing.
+    /// Compressed layers are decompressed transparently when pulled.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Packs all the static asset files of each component into a single
+    /// archive layer when pushing, for registries which limit how many
+    /// layers a manifest may have. Archives are unpacked when pulled.
+    pub fn with_asset_archives(mut self, archive: bool) -> Self {
+        self.archive_assets = archive;
+        self
+    }
+
+    /// Pushes applications with an OCI 1.1 artifact manifest carrying the
+    /// Spin artifact type, so that registries and tools can classify them,
+    /// rather than with an image manifest.
+    pub fn with_artifact_manifest(mut self, artifact_manifest: bool) -> Self {
+        self.artifact_manifest = artifact_manifest;
+        self
+    }
+
+    /// Pushes applications with a Docker schema 2 manifest and container
+    /// image media types, for registries which reject anything else. Such
+    /// applications cannot use compression, asset archives or artifact
+    /// manifests. Pulls recognise them by their config either way.
+    pub fn with_docker_compatibility(mut self, docker_compatible: bool) -> Self {
+        self.docker_compatible = docker_compatible;
+        self
+    }
+
+    /// Sets what a push does when the registry refuses to replace an
+    /// existing tag, as registries which enforce immutable tags do. By
+    /// default the push fails with [`PublishError::ImmutableTag`].
+    pub fn with_existing_tag_policy(mut self, policy: ExistingTagPolicy) -> Self {
+        self.existing_tag_policy = policy;
+        self
+    }
+
+    /// Remembers in the cache which references a registry has no manifest
+    /// for, and answers requests for them from the cache for `ttl` rather
+    /// than asking the registry again. A zero `ttl` bypasses the remembered
+    /// results while still keeping them up to date.
+    pub fn with_negative_cache(mut self, cache: Cache, ttl: std::time::Duration) -> Self {
+        self.negative_cache = Some((cache, ttl));
+        self
+    }
+
+    /// Adds annotations to the manifests of pushed applications, such as
+    /// [`REVISION_ANNOTATION`] and [`SOURCE_ANNOTATION`]. They replace any
+    /// annotations Spin sets itself of the same name. Docker compatible
+    /// manifests cannot carry annotations, so they are left out of those.
+    pub fn with_annotations(mut self, annotations: HashMap<String, String>) -> Self {
+        self.annotations.extend(annotations);
+        self
+    }
+
+    /// Records pushes in the operations log of the given cache. Pulls are
+    /// always recorded in the log of the cache they pull into.
+    pub fn with_operation_log(mut self, cache: Cache) -> Self {
+        self.operation_log = Some(cache);
+        self
+    }
+
+    /// Resumes a push which failed part way: layers the token records as
+    /// pushed are not checked for or uploaded again, and an unfinished
+    /// chunked upload is continued from wherever the registry got to.
+    pub fn with_resume_token(mut self, token: ResumeToken) -> Self {
+        self.resume = Some(token);
+        self
+    }
+
+    /// Sets how many layers are downloaded at once when pulling an
+    /// application.
+    pub fn with_max_concurrent_downloads(mut self, max: usize) -> Self {
+        self.max_concurrent_downloads = max.max(1);
+        self
+    }
+
+    /// Limits blob downloads to the given number of bytes per second,
+    /// shared across all downloads made by this client.
+    pub fn with_download_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.download_throttle = Some(Arc::new(Throttle::new(bytes_per_sec)));
+        self
+    }
+
+    /// Checks that a registry is reachable and implements the distribution
+    /// API, using its `/v2/` version check endpoint. A registry which
+    /// requires authentication for the check still counts as reachable.
+    pub async fn ping(&self, registry: &str) -> PublishResult<()> {
+        let url = format!("{}://{}/v2/", self.scheme(registry), registry);
+        let response = self
+            .http_for(&url)
+            .get(&url)
+            .timeout(PING_TIMEOUT)
+            .send()
+            .await
+            .map_err(|source| PublishError::RegistryUnreachable {
+                registry: registry.to_owned(),
+                source,
+            })?;
+        match response.status() {
+            s if s.is_success() => Ok(()),
+            StatusCode::UNAUTHORIZED => Ok(()),
+            _ => Err(registry_response_error(&url, response).await),
+        }
+    }
+
+    /// Lists the repositories in a registry. The location is a registry
+    /// host, optionally followed by a namespace (`<registry>/<namespace>`)
+    /// to which the listing is restricted.
+    ///
+    /// This uses the registry's catalog API, except for Docker Hub, which
+    /// provides its own listing API and requires a namespace.
+    pub async fn list_repositories(&self, location: &str) -> PublishResult<Vec<String>> {
+        let (registry, namespace) = split_location(location);
+
+        let mut repositories = if DOCKER_HUB_REGISTRIES.contains(&registry) {
+            let namespace = namespace.ok_or_else(|| {
+                PublishError::RepositoryListingUnsupported(format!(
+                    "{} can only list repositories within a namespace",
+                    registry
+                ))
+            })?;
+            self.list_docker_hub_repositories(namespace).await?
+        } else {
+            self.list_catalog_repositories(registry).await?
+        };
+
+        if let Some(namespace) = namespace {
+            let prefix = format!("{}/", namespace);
+            repositories.retain(|r| r.starts_with(&prefix));
+        }
+        repositories.sort();
+        Ok(repositories)
+    }
+
+    async fn list_catalog_repositories(&self, registry: &str) -> PublishResult<Vec<String>> {
+        let base_url = format!("{}://{}", self.scheme(registry), registry);
+        let mut url = format!("{}/v2/_catalog?n={}", base_url, CATALOG_PAGE_SIZE);
+        let auth = registry_auth(registry);
+        let mut authorization: Option<Authorization> = None;
+        let mut repositories = vec![];
+
+        loop {
+            let response = self
+                .get_authorized(&url, &[], CATALOG_SCOPE, &auth, &mut authorization)
+                .await?;
+
+            match response.status() {
+                s if s.is_success() => {}
+                StatusCode::NOT_FOUND => {
+                    return Err(PublishError::RepositoryListingUnsupported(format!(
+                        "{} does not provide a catalog API",
+                        registry
+                    )))
+                }
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                    return Err(PublishError::RegistryUnauthorized(format!(
+                        "not permitted to list repositories in {}",
+                        registry
+                    )))
+                }
+                _ => return Err(registry_response_error(&url, response).await),
+            }
+
+            let next = next_page_link(&response).map(|link| match link.strip_prefix('/') {
+                Some(path) => format!("{}/{}", base_url, path),
+                None => link,
+            });
+            let catalog: Catalog = parse_json(&url, response).await?;
+            repositories.extend(catalog.repositories);
+
+            match next {
+                Some(next) => url = next,
+                None => break,
+            }
+        }
+
+        Ok(repositories)
+    }
+
+    async fn list_docker_hub_repositories(&self, namespace: &str) -> PublishResult<Vec<String>> {
+        let mut url = format!(
+            "{}/{}/?page_size={}",
+            DOCKER_HUB_REPOSITORIES_URL, namespace, CATALOG_PAGE_SIZE
+        );
+        let mut repositories = vec![];
+
+        loop {
+            let response = self.get(&url, &[], None).await?;
+            if !response.status().is_success() {
+                return Err(registry_response_error(&url, response).await);
+            }
+            let page: DockerHubRepositories = parse_json(&url, response).await?;
+            repositories.extend(
+                page.results
+                    .into_iter()
+                    .map(|r| format!("{}/{}", r.namespace, r.name)),
+            );
+
+            match page.next {
+                Some(next) => url = next,
+                None => break,
+            }
+        }
+
+        Ok(repositories)
+    }
+
+    /// Lists the tags in a repository, given as `<registry>/<repository>`.
+    /// Any tag or digest in the reference is ignored.
+    pub async fn list_tags(&self, repository: &str) -> PublishResult<Vec<String>> {
+        let parsed = parse_reference(repository)?;
+        let registry = parsed.resolve_registry();
+        let repository = parsed.repository();
+
+        let base_url = format!("{}://{}", self.scheme(registry), registry);
+        let mut url = format!(
+            "{}/v2/{}/tags/list?n={}",
+            base_url, repository, CATALOG_PAGE_SIZE
+        );
+        let auth = registry_auth(registry);
+        let mut authorization: Option<Authorization> = None;
+        let mut tags = vec![];
+
+        loop {
+            let response = self
+                .get_authorized(
+                    &url,
+                    &[],
+                    &pull_scope(repository),
+                    &auth,
+                    &mut authorization,
+                )
+                .await?;
+
+            match response.status() {
+                s if s.is_success() => {}
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                    return Err(PublishError::RegistryUnauthorized(format!(
+                        "not permitted to list tags in {}/{}",
+                        registry, repository
+                    )))
+                }
+                _ => return Err(registry_response_error(&url, response).await),
+            }
+
+            let next = next_page_link(&response).map(|link| match link.strip_prefix('/') {
+                Some(path) => format!("{}/{}", base_url, path),
+                None => link,
+            });
+            let list: TagList = parse_json(&url, response).await?;
+            tags.extend(list.tags.unwrap_or_default());
+
+            match next {
+                Some(next) => url = next,
+                None => break,
+            }
+        }
+
+        tags.sort();
+        Ok(tags)
+    }
+
+    /// Checks whether a registry has a manifest for the reference, without
+    /// fetching it.
+    pub async fn exists(&self, reference: &str) -> PublishResult<bool> {
+        let parsed = parse_reference(reference)?;
+        let registry = parsed.resolve_registry();
+        let repository = parsed.repository();
+        let target = parsed.digest().or_else(|| parsed.tag()).unwrap_or("latest");
+        if self.known_missing(registry, repository, target).await? {
+            return Ok(false);
+        }
+
+        let url = format!(
+            "{}://{}/v2/{}/manifests/{}",
+            self.scheme(registry),
+            registry,
+            repository,
+            target
+        );
+        let response = self
+            .request_authorized(
+                Method::HEAD,
+                &url,
+                MANIFEST_MEDIA_TYPES,
+                &pull_scope(repository),
+                &registry_auth(registry),
+                &mut None,
+            )
+            .await?;
+        let exists = match response.status() {
+            s if s.is_success() => true,
+            StatusCode::NOT_FOUND => false,
+            _ => return Err(registry_response_error(&url, response).await),
+        };
+        self.remember_missing(registry, repository, target, !exists)
+            .await?;
+        Ok(exists)
+    }
+
+    /// Deletes the manifest at the reference from its registry, returning
+    /// the digest of the deleted manifest. A tag is resolved to the digest
+    /// it points to, so deleting a tag also removes every other tag of the
+    /// same manifest. Layers are left for the registry to garbage collect.
+    pub async fn delete(&self, reference: &str) -> PublishResult<String> {
+        let parsed = parse_reference(reference)?;
+        let registry = parsed.resolve_registry();
+        let repository = parsed.repository();
+        let target = parsed.digest().or_else(|| parsed.tag()).unwrap_or("latest");
+
+        // Registries accept deletion only by digest
+        let digest = self
+            .fetch_manifest_unchecked(registry, repository, target)
+            .await?
+            .digest;
+        let url = format!(
+            "{}://{}/v2/{}/manifests/{}",
+            self.scheme(registry),
+            registry,
+            repository,
+            digest
+        );
+        let response = self
+            .request_authorized(
+                Method::DELETE,
+                &url,
+                &[],
+                &format!("repository:{}:delete", repository),
+                &registry_auth(registry),
+                &mut None,
+            )
+            .await?;
+        match response.status() {
+            s if s.is_success() => {}
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                return Err(PublishError::RegistryUnauthorized(format!(
+                    "not permitted to delete {}",
+                    reference
+                )))
+            }
+            StatusCode::METHOD_NOT_ALLOWED => {
+                return Err(PublishError::Other(anyhow::anyhow!(
+                    "Registry {} does not allow deleting manifests",
+                    registry
+                )))
+            }
+            _ => return Err(registry_response_error(&url, response).await),
+        }
+
+        self.remember_missing(registry, repository, target, true)
+            .await?;
+        self.remember_missing(registry, repository, &digest, true)
+            .await?;
+        Ok(digest)
+    }
+
+    /// Whether the negative cache, if any, says that the registry has no
+    /// manifest for the reference.
+    async fn known_missing(
+        &self,
+        registry: &str,
+        repository: &str,
+        reference: &str,
+    ) -> PublishResult<bool> {
+        match &self.negative_cache {
+            Some((cache, ttl)) => {
+                let missing = cache
+                    .is_missing(registry, repository, reference, *ttl)
+                    .await?;
+                if missing {
+                    tracing::debug!(
+                        "{}/{}:{} is cached as missing",
+                        registry,
+                        repository,
+                        reference
+                    );
+                }
+                Ok(missing)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Records in the negative cache, if any, whether the registry has a
+    /// manifest for the reference.
+    async fn remember_missing(
+        &self,
+        registry: &str,
+        repository: &str,
+        reference: &str,
+        missing: bool,
+    ) -> PublishResult<()> {
+        match &self.negative_cache {
+            Some((cache, _)) if missing => {
+                cache.record_missing(registry, repository, reference).await
+            }
+            Some((cache, _)) => cache.clear_missing(registry, repository, reference).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Fetches a manifest from a registry, returning its content, media
+    /// type and digest.
+    pub async fn fetch_manifest(
+        &self,
+        registry: &str,
+        repository: &str,
+        reference: &str,
+    ) -> PublishResult<FetchedManifest> {
+        let policy = match &self.trust_policy {
+            Some(policy) => policy,
+            None => {
+                return self
+                    .fetch_manifest_unchecked(registry, repository, reference)
+                    .await
+            }
+        };
+        policy.check_registry(registry, repository)?;
+
+        let manifest = self
+            .fetch_manifest_unchecked(registry, repository, reference)
+            .await?;
+        let separator = if is_digest(reference) { '@' } else { ':' };
+        let full_reference = format!("{}/{}{}{}", registry, repository, separator, reference);
+        policy.check_manifest(&full_reference, &manifest.data, crate::seams::now())?;
+        if policy.requires_signature() {
+            let signatures = self
+                .fetch_signatures(registry, repository, &manifest.digest)
+                .await?;
+            policy.check_signatures(&full_reference, &signatures)?;
+        }
+        Ok(manifest)
+    }
+
+    async fn fetch_manifest_unchecked(
+        &self,
+        registry: &str,
+        repository: &str,
+        reference: &str,
+    ) -> PublishResult<FetchedManifest> {
+        let url = format!(
+            "{}://{}/v2/{}/manifests/{}",
+            self.scheme(registry),
+            registry,
+            repository,
+            reference
+        );
+        if self.known_missing(registry, repository, reference).await? {
+            return Err(PublishError::RegistryResponse {
+                url,
+                status: StatusCode::NOT_FOUND.as_u16(),
+                message: "manifest unknown (cached)".to_owned(),
+            });
+        }
+        let auth = registry_auth(registry);
+        let response = self
+            .get_authorized(
+                &url,
+                MANIFEST_MEDIA_TYPES,
+                &pull_scope(repository),
+                &auth,
+                &mut None,
+            )
+            .await?;
+        let missing = response.status() == StatusCode::NOT_FOUND;
+        if missing || response.status().is_success() {
+            self.remember_missing(registry, repository, reference, missing)
+                .await?;
+        }
+        if !response.status().is_success() {
+            return Err(registry_response_error(&url, response).await);
+        }
+
+        let media_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or(OCI_IMAGE_MEDIA_TYPE)
+            .to_owned();
+        let digest = response
+            .headers()
+            .get(DOCKER_CONTENT_DIGEST_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_owned());
+        let data = response.bytes().await?.to_vec();
+        // A manifest requested by digest must have that digest, and one
+        // requested by tag the digest the registry reports for it.
+        let digest = match digest {
+            _ if is_digest(reference) => reference.to_owned(),
+            Some(digest) => digest,
+            None => sha256_digest(&data),
+        };
+        verify_digest(&url, &digest, &data)?;
+
+        Ok(FetchedManifest {
+            data,
+            media_type,
+            digest,
+        })
+    }
+
+    /// Fetches a blob from a registry, checking that its content has the
+    /// requested digest.
+    pub async fn fetch_blob(
+        &self,
+        registry: &str,
+        repository: &str,
+        digest: &str,
+    ) -> PublishResult<Vec<u8>> {
+        if let Some(policy) = &self.trust_policy {
+            policy.check_registry(registry, repository)?;
+        }
+        self.fetch_blob_unchecked(registry, repository, digest)
+            .await
+    }
+
+    /// Fetches a layer from a registry. If Wasm validation is enabled and
+    /// the layer's media type says it is Wasm, the content is checked before
+    /// it is returned.
+    pub async fn fetch_layer(
+        &self,
+        registry: &str,
+        repository: &str,
+        digest: &str,
+        media_type: &str,
+    ) -> PublishResult<Vec<u8>> {
+        let data = self.fetch_blob(registry, repository, digest).await?;
+        if self.validate_wasm && media_type == WASM_LAYER_MEDIA_TYPE {
+            validate::validate_wasm(&data).map_err(|reason| PublishError::InvalidWasmLayer {
+                digest: digest.to_owned(),
+                reason,
+            })?;
+        }
+        Ok(data)
+    }
+
+    async fn fetch_blob_unchecked(
+        &self,
+        registry: &str,
+        repository: &str,
+        digest: &str,
+    ) -> PublishResult<Vec<u8>> {
+        let url = format!(
+            "{}://{}/v2/{}/blobs/{}",
+            self.scheme(registry),
+            registry,
+            repository,
+            digest
+        );
+        let auth = registry_auth(registry);
+        let mut response = self
+            .get_authorized(&url, &[], &pull_scope(repository), &auth, &mut None)
+            .await?;
+        if !response.status().is_success() {
+            return Err(registry_response_error(&url, response).await);
+        }
+
+        let data = match &self.download_throttle {
+            Some(throttle) => {
+                let mut data = Vec::with_capacity(response.content_length().unwrap_or(0) as usize);
+                while let Some(chunk) = response.chunk().await? {
+                    throttle.consume(chunk.len()).await;
+                    data.extend_from_slice(&chunk);
+                }
+                data
+            }
+            None => response.bytes().await?.to_vec(),
+        };
+        verify_digest(&url, digest, &data)?;
+        Ok(data)
+    }
+
+    /// Sends a GET request, answering the registry's authentication
+    /// challenge if it issues one. The resulting authorization is kept in
+    /// `authorization` so that it can be reused for subsequent requests.
+    async fn get_authorized(
+        &self,
+        url: &str,
+        accept: &[&str],
+        scope: &str,
+        auth: &RegistryAuth,
+        authorization: &mut Option<Authorization>,
+    ) -> PublishResult<reqwest::Response> {
+        self.request_authorized(Method::GET, url, accept, scope, auth, authorization)
+            .await
+    }
+
+    /// Sends a request, answering the registry's authentication challenge
+    /// as [`get_authorized`](Self::get_authorized) does.
+    async fn request_authorized(
+        &self,
+        method: Method,
+        url: &str,
+        accept: &[&str],
+        scope: &str,
+        auth: &RegistryAuth,
+        authorization: &mut Option<Authorization>,
+    ) -> PublishResult<reqwest::Response> {
+        let response = self
+            .request(method.clone(), url, accept, authorization.as_ref())
+            .await?;
+        if response.status() != StatusCode::UNAUTHORIZED || authorization.is_some() {
+            return Ok(response);
+        }
+
+        match Challenge::from_response(&response) {
+            Some(challenge) => {
+                let answer = challenge.authorize(self.http_for(url), scope, auth).await?;
+                let response = self.request(method, url, accept, Some(&answer)).await?;
+                *authorization = Some(answer);
+                Ok(response)
+            }
+            None => Ok(response),
+        }
+    }
+
+    async fn get(
+        &self,
+        url: &str,
+        accept: &[&str],
+        authorization: Option<&Authorization>,
+    ) -> PublishResult<reqwest::Response> {
+        self.request(Method::GET, url, accept, authorization).await
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        url: &str,
+        accept: &[&str],
+        authorization: Option<&Authorization>,
+    ) -> PublishResult<reqwest::Response> {
+        let mut request = self.http_for(url).request(method, url);
+        if !accept.is_empty() {
+            request = request.header(ACCEPT, accept.join(", "));
+        }
+        if let Some(authorization) = authorization {
+            request = authorization.apply(request);
+        }
+        Ok(request.send().await?)
+    }
+
+    fn scheme(&self, registry: &str) -> &'static str {
+        if self.insecure || self.registry_config.plain_http(registry) {
+            "http"
+        } else {
+            "https"
+        }
+    }
+}
+
+/// A manifest fetched from a registry.
+pub struct FetchedManifest {
+    /// The raw manifest content
+    pub data: Vec<u8>,
+    /// The media type reported by the registry
+    pub media_type: String,
+    /// The digest of the manifest content
+    pub digest: String,
+}
+
+fn parse_reference(reference: &str) -> PublishResult<Reference> {
+    reference.parse().map_err(
+        |e: oci_distribution::ParseError| PublishError::InvalidReference {
+            reference: reference.to_owned(),
+            reason: e.to_string(),
+        },
+    )
+}
+
+fn pull_scope(repository: &str) -> String {
+    format!("repository:{}:pull", repository)
+}
+
+fn sha256_digest(data: &[u8]) -> String {
+    DigestAlgorithm::Sha256.digest(data)
+}
+
+/// Whether a manifest reference is a digest rather than a tag.
+pub(super) fn is_digest(reference: &str) -> bool {
+    reference.contains(':')
+}
+
+/// Checks that content downloaded from `location` has the expected digest.
+fn verify_digest(location: &str, expected: &str, data: &[u8]) -> PublishResult<()> {
+    let (algorithm, _) = parse_digest(expected)?;
+    let actual = algorithm.digest(data);
+    if actual != expected {
+        return Err(PublishError::DigestMismatch {
+            location: location.to_owned(),
+            expected: expected.to_owned(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+/// Splits a `<registry>/<namespace>` location into its parts.
+fn split_location(location: &str) -> (&str, Option<&str>) {
+    let location = location.trim_end_matches('/');
+    match location.split_once('/') {
+        Some((registry, namespace)) => (registry, Some(namespace)),
+        None => (location, None),
+    }
+}
+
+/// Extracts the target of a `Link: <...>; rel="next"` header, as used by the
+/// distribution spec for pagination.
+fn next_page_link(response: &reqwest::Response) -> Option<String> {
+    let link = response.headers().get(LINK)?.to_str().ok()?;
+    let (target, params) = link.split_once(';')?;
+    if !params.contains("rel=\"next\"") {
+        return None;
+    }
+    let target = target.trim().strip_prefix('<')?.strip_suffix('>')?;
+    Some(target.to_owned())
+}
+
+async fn parse_json<T: serde::de::DeserializeOwned>(
+    url: &str,
+    response: reqwest::Response,
+) -> PublishResult<T> {
+    let body = response.bytes().await?;
+    serde_json::from_slice(&body).map_err(|e| PublishError::RegistryResponse {
+        url: url.to_owned(),
+        status: StatusCode::OK.as_u16(),
+        message: format!("invalid response body: {}", e),
+    })
+}
+
+async fn registry_response_error(url: &str, response: reqwest::Response) -> PublishError {
+    let status = response.status().as_u16();
+    let message = response.text().await.unwrap_or_default();
+    PublishError::RegistryResponse {
+        url: url.to_owned(),
+        status,
+        message,
+    }
+}
+
+#[derive(Deserialize)]
+struct Catalog {
+    #[serde(default)]
+    repositories: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct TagList {
+    // Registries report a repository without tags as `null`
+    tags: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct DockerHubRepositories {
+    next: Option<String>,
+    results: Vec<DockerHubRepository>,
+}
+
+#[derive(Deserialize)]
+struct DockerHubRepository {
+    name: String,
+    namespace: String,
+}
+
+#[cfg(test)]
+mod test {
+    use std::{convert::Infallible, sync::Mutex};
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Response, Server,
+    };
+
+    use super::*;
+
+    /// Serves the distribution API on a local port, answering each request
+    /// with `handler`, and returns the registry host and a record of the
+    /// requests made as `<method> <path>`.
+    fn fake_registry(
+        handler: fn(&Method, &str) -> Response<Body>,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let recorded = requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let recorded = recorded.clone();
+            async move {
+                let service = service_fn(move |req: hyper::Request<Body>| {
+                    let path = req.uri().to_string();
+                    recorded
+                        .lock()
+                        .unwrap()
+                        .push(format!("{} {}", req.method(), path));
+                    let response = handler(req.method(), &path);
+                    async move { Ok::<_, Infallible>(response) }
+                });
+                Ok::<_, Infallible>(service)
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let host = server.local_addr().to_string();
+        tokio::spawn(server);
+        (host, requests)
+    }
+
+    fn respond(status: StatusCode) -> Response<Body> {
+        Response::builder()
+            .status(status)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn checks_manifests_exist_without_fetching_them() {
+        let (host, requests) = fake_registry(|_, path| match path {
+            "/v2/app/manifests/v1" => respond(StatusCode::OK),
+            "/v2/app/manifests/latest" => respond(StatusCode::NOT_FOUND),
+            _ => respond(StatusCode::INTERNAL_SERVER_ERROR),
+        });
+        let client = Client::new(true).unwrap();
+
+        assert!(client.exists(&format!("{}/app:v1", host)).await.unwrap());
+        assert!(!client.exists(&format!("{}/app", host)).await.unwrap());
+        assert!(client
+            .exists(&format!("{}/app:broken", host))
+            .await
+            .is_err());
+        assert!(requests
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|r| r.starts_with("HEAD ")));
+    }
+
+    #[tokio::test]
+    async fn lists_tags_across_pages() {
+        let (host, _) = fake_registry(|_, path| match path {
+            "/v2/app/tags/list?n=100" => Response::builder()
+                .header(LINK, r#"</v2/app/tags/list?n=100&last=v2>; rel="next""#)
+                .body(Body::from(r#"{"name":"app","tags":["v2","latest"]}"#))
+                .unwrap(),
+            "/v2/app/tags/list?n=100&last=v2" => {
+                Response::new(Body::from(r#"{"name":"app","tags":["v1"]}"#))
+            }
+            "/v2/empty/tags/list?n=100" => {
+                Response::new(Body::from(r#"{"name":"empty","tags":null}"#))
+            }
+            _ => respond(StatusCode::NOT_FOUND),
+        });
+        let client = Client::new(true).unwrap();
+
+        assert_eq!(
+            vec!["latest", "v1", "v2"],
+            client.list_tags(&format!("{}/app", host)).await.unwrap()
+        );
+        assert!(client
+            .list_tags(&format!("{}/empty", host))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn deletes_tags_by_the_digest_they_point_to() {
+        let (host, requests) = fake_registry(|method, path| {
+            let by_digest = path.ends_with(&sha256_digest(b"{}"));
+            match (method, path) {
+                (&Method::GET, "/v2/app/manifests/v1" | "/v2/locked/manifests/v1") => {
+                    Response::new(Body::from("{}"))
+                }
+                (&Method::DELETE, p) if by_digest && p.starts_with("/v2/app/") => {
+                    respond(StatusCode::ACCEPTED)
+                }
+                (&Method::DELETE, _) => respond(StatusCode::METHOD_NOT_ALLOWED),
+                _ => respond(StatusCode::NOT_FOUND),
+            }
+        });
+        let client = Client::new(true).unwrap();
+
+        let digest = client.delete(&format!("{}/app:v1", host)).await.unwrap();
+        assert_eq!(sha256_digest(b"{}"), digest);
+        assert_eq!(
+            vec![
+                "GET /v2/app/manifests/v1".to_owned(),
+                format!("DELETE /v2/app/manifests/{}", digest),
+            ],
+            *requests.lock().unwrap()
+        );
+
+        let err = client
+            .delete(&format!("{}/locked:v1", host))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("does not allow deleting"));
+    }
+
+    /// A client whose requests cannot reach any registry, standing in for
+    /// one without the TLS settings a registry requires.
+    fn unusable_http() -> reqwest::Client {
+        reqwest::Client::builder()
+            .proxy(reqwest::Proxy::all("http://127.0.0.1:1").unwrap())
+            .build()
+            .unwrap()
+    }
+
+    fn accept_manifests(method: &Method, path: &str) -> Response<Body> {
+        match (method, path) {
+            (&Method::PUT, "/v2/app/manifests/v1") => respond(StatusCode::CREATED),
+            _ => respond(StatusCode::NOT_FOUND),
+        }
+    }
+
+    #[tokio::test]
+    async fn pushes_present_the_registry_client_certificate() {
+        let (host, requests) = fake_registry(accept_manifests);
+        let mut client = Client::new(true).unwrap();
+        client.http = unusable_http();
+        // As `with_client_certificate` would for the registry
+        client
+            .registry_http
+            .insert(host.clone(), reqwest::Client::new());
+
+        let mut session = push::PushSession::new(&client, &host, "app");
+        session
+            .put_manifest("v1", OCI_IMAGE_MEDIA_TYPE, b"{}".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(vec!["PUT /v2/app/manifests/v1"], *requests.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn pushes_skip_verifying_registries_configured_to() {
+        let (host, requests) = fake_registry(accept_manifests);
+        let config = RegistryConfig {
+            registries: vec![RegistryHost {
+                host: host.clone(),
+                plain_http: true,
+                skip_verify: true,
+            }],
+        };
+        let mut client = Client::new(false)
+            .unwrap()
+            .with_registry_config(config)
+            .unwrap();
+        client.http = unusable_http();
+
+        let mut session = push::PushSession::new(&client, &host, "app");
+        session
+            .put_manifest("v1", OCI_IMAGE_MEDIA_TYPE, b"{}".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(vec!["PUT /v2/app/manifests/v1"], *requests.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn policy_violations_name_manifests_pulled_by_digest() {
+        let (host, _) = fake_registry(|_, path| match path {
+            p if p.ends_with(&sha256_digest(b"{}")) => Response::new(Body::from("{}")),
+            _ => respond(StatusCode::NOT_FOUND),
+        });
+        let client = Client::new(true).unwrap().with_trust_policy(TrustPolicy {
+            max_artifact_age_days: Some(30),
+            ..Default::default()
+        });
+
+        let digest = sha256_digest(b"{}");
+        let err = client
+            .fetch_manifest(&host, "app", &digest)
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&format!("{}/app@{}", host, digest)));
+    }
+
+    #[test]
+    fn splits_locations() {
+        assert_eq!(("localhost:5000", None), split_location("localhost:5000"));
+        assert_eq!(("localhost:5000", None), split_location("localhost:5000/"));
+        assert_eq!(
+            ("ghcr.io", Some("fermyon/apps")),
+            split_location("ghcr.io/fermyon/apps")
+        );
+    }
+
+    #[test]
+    fn verifies_content_digests() {
+        let digest = sha256_digest(b"layer");
+        assert!(verify_digest("test", &digest, b"layer").is_ok());
+        assert!(matches!(
+            verify_digest("test", &digest, b"tampered"),
+            Err(PublishError::DigestMismatch { .. })
+        ));
+        assert!(verify_digest("test", "md5:abc", b"layer").is_err());
+
+        let digest = DigestAlgorithm::Sha512.digest(b"layer");
+        assert!(verify_digest("test", &digest, b"layer").is_ok());
+        assert!(verify_digest("test", &digest, b"tampered").is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! The log of registry operations kept in the cache, which records each
+//! push and pull so that local state can be matched up with what happened
+//! in the registry.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use super::Cache;
+use crate::{PublishError, PublishResult};
+
+/// The append-only log of operations, in the cache root.
+pub const OPERATIONS_LOG_FILE: &str = "operations.log";
+
+/// A push or pull recorded in the operations log.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Operation {
+    /// When the operation finished, as an RFC 3339 timestamp
+    pub time: String,
+    /// Whether the operation was a push or a pull
+    pub kind: OperationKind,
+    /// The reference pushed or pulled
+    pub reference: String,
+    /// The digest of the manifest pushed or pulled, if the operation got
+    /// as far as knowing it
+    pub digest: Option<String>,
+    /// The total size in bytes of the application's config and layers, if
+    /// the operation got as far as knowing it
+    pub size: Option<u64>,
+    /// How many bytes were uploaded or downloaded, rather than already
+    /// being in the registry or the cache
+    pub transferred: u64,
+    /// How long the operation took, in milliseconds
+    pub duration_ms: u64,
+    /// Why the operation failed, if it did
+    pub error: Option<String>,
+}
+
+/// The kind of a logged operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OperationKind {
+    /// An application was pushed to a registry.
+    Push,
+    /// An application was pulled into the cache.
+    Pull,
+}
+
+impl Operation {
+    /// Describes an operation which has just finished.
+    pub(super) fn finished(kind: OperationKind, reference: &str, duration: Duration) -> Self {
+        Self {
+            time: crate::seams::now().to_rfc3339(),
+            kind,
+            reference: reference.to_owned(),
+            digest: None,
+            size: None,
+            transferred: 0,
+            duration_ms: duration.as_millis() as u64,
+            error: None,
+        }
+    }
+}
+
+impl Cache {
+    /// Appends an operation to the operations log.
+    pub async fn record_operation(&self, operation: &Operation) -> PublishResult<()> {
+        let path = self.root().join(OPERATIONS_LOG_FILE);
+        let io_error = |source| PublishError::Io {
+            source,
+            description: format!("Failed to write {}", path.display()),
+        };
+        let mut line = serde_json::to_vec(operation).map_err(|e| PublishError::Other(e.into()))?;
+        line.push(b'\n');
+        // Each record is appended in a single write, so that processes
+        // logging at once do not interleave their records.
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .map_err(io_error)?;
+        file.write_all(&line).await.map_err(io_error)
+    }
+
+    /// Reads the operations log, oldest first. Records which cannot be
+    /// read, such as one cut short by a crash, are skipped.
+    pub async fn operations(&self) -> PublishResult<Vec<Operation>> {
+        let path = self.root().join(OPERATIONS_LOG_FILE);
+        let text = match tokio::fs::read_to_string(&path).await {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => {
+                return Err(PublishError::Io {
+                    source: e,
+                    description: format!("Failed to read {}", path.display()),
+                })
+            }
+        };
+        Ok(text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(operation) => Some(operation),
+                Err(e) => {
+                    tracing::debug!("Skipping unreadable operations log record: {}", e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Appends an operation to the log, warning rather than failing if it
+    /// cannot be, as the operation itself is done.
+    pub(super) async fn log_operation(&self, operation: Operation) {
+        if let Err(e) = self.record_operation(&operation).await {
+            tracing::warn!(
+                "Failed to log {:?} of {}: {}",
+                operation.kind,
+                operation.reference,
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn operations_are_appended_in_order() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache = Cache::new(Some(temp.path().to_owned())).await.unwrap();
+        assert!(cache.operations().await.unwrap().is_empty());
+
+        let pull = Operation::finished(OperationKind::Pull, "r/app:v1", Duration::from_millis(5));
+        let push = Operation {
+            error: Some("unauthorized".to_owned()),
+            ..Operation::finished(OperationKind::Push, "r/app:v2", Duration::ZERO)
+        };
+        cache.record_operation(&pull).await.unwrap();
+        cache.record_operation(&push).await.unwrap();
+
+        let log = temp.path().join(OPERATIONS_LOG_FILE);
+        let mut text = std::fs::read_to_string(&log).unwrap();
+        text.push_str("{\"time\": \"trunc");
+        std::fs::write(&log, text).unwrap();
+        assert_eq!(vec![pull, push], cache.operations().await.unwrap());
+    }
+}
@@ -0,0 +1,262 @@
+//! Content trust policy for pulled content.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+
+use super::{
+    sign::{CosignSignature, VerificationKey},
+    CREATED_ANNOTATION,
+};
+use crate::{PublishError, PublishResult};
+
+/// Restrictions on which registry content may be pulled, cached and run.
+///
+/// A policy is read from a TOML file such as:
+///
+/// ```toml
+/// allowed_registries = ["ghcr.io/my-org", "*.internal.example.com"]
+/// required_signature_identities = ["release@example.com"]
+/// trusted_keys = ["cosign.pub"]
+/// max_artifact_age_days = 90
+/// ```
+///
+/// Signature identities are matched against the `identity` claim of
+/// cosign signatures attached to a manifest (as set by
+/// `cosign sign -a identity=...`). On their own, identities are checked as
+/// claims only. If the policy also has trusted keys, only the claims of
+/// signatures made with those keys count.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrustPolicy {
+    /// Registries, or registry namespaces, from which content may be pulled.
+    /// An entry may begin with `*.` to allow all subdomains of a host. If
+    /// empty, all registries are allowed.
+    #[serde(default)]
+    pub allowed_registries: Vec<String>,
+    /// Identities of which at least one must have signed a manifest. If
+    /// empty, signatures are not required.
+    #[serde(default)]
+    pub required_signature_identities: Vec<String>,
+    /// Keys of which at least one must have signed a manifest. If empty,
+    /// signatures are not verified.
+    #[serde(skip)]
+    pub trusted_keys: Vec<VerificationKey>,
+    /// The PEM encoded public key files given as `trusted_keys` in a policy
+    /// file, relative to the file. [`load`](Self::load) reads them into
+    /// `trusted_keys`.
+    #[serde(default, rename = "trusted_keys")]
+    pub trusted_key_files: Vec<PathBuf>,
+    /// The maximum age of an artifact, according to its creation annotation.
+    pub max_artifact_age_days: Option<u32>,
+}
+
+/// A way in which content failed to satisfy a trust policy.
+#[derive(Debug, thiserror::Error)]
+pub enum PolicyViolation {
+    /// The content is not signed with any of the trusted keys
+    #[error("{0} is not signed with any of the policy's trusted keys")]
+    UntrustedSignature(String),
+    /// The content is from a registry the policy does not allow
+    #[error("{0} is not in the policy's allowed registries")]
+    RegistryNotAllowed(String),
+    /// The content is not signed by any of the required identities
+    #[error("{reference} is not signed by any of the required identities ({identities})")]
+    MissingSignature {
+        /// The reference that was pulled
+        reference: String,
+        /// The identities the policy requires, comma separated
+        identities: String,
+    },
+    /// The content does not record its creation time
+    #[error("{0} has no valid creation time annotation, so its age cannot be checked")]
+    UnknownAge(String),
+    /// The content is older than the policy allows
+    #[error("{reference} was created {age_days} days ago, but the policy allows at most {max_days} days")]
+    TooOld {
+        /// The reference that was pulled
+        reference: String,
+        /// The age of the content in days
+        age_days: i64,
+        /// The maximum age the policy allows
+        max_days: u32,
+    },
+}
+
+impl TrustPolicy {
+    /// Loads a policy from a TOML file.
+    pub fn load(path: impl AsRef<Path>) -> PublishResult<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| PublishError::Io {
+            source: e,
+            description: format!("Failed to read trust policy {}", path.display()),
+        })?;
+        let mut policy: Self = toml::from_str(&text).map_err(|e| {
+            PublishError::Other(anyhow::anyhow!(
+                "Invalid trust policy {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        for key_file in &policy.trusted_key_files {
+            policy
+                .trusted_keys
+                .push(VerificationKey::from_file(&dir.join(key_file))?);
+        }
+        Ok(policy)
+    }
+
+    /// Checks that content may be pulled from the given repository.
+    pub fn check_registry(&self, registry: &str, repository: &str) -> Result<(), PolicyViolation> {
+        let location = format!("{}/{}", registry, repository);
+        if self.allowed_registries.is_empty()
+            || self
+                .allowed_registries
+                .iter()
+                .any(|allowed| location_matches(allowed, registry, &location))
+        {
+            Ok(())
+        } else {
+            Err(PolicyViolation::RegistryNotAllowed(location))
+        }
+    }
+
+    /// Whether manifests must be signed to satisfy the policy.
+    pub fn requires_signature(&self) -> bool {
+        !self.required_signature_identities.is_empty() || !self.trusted_keys.is_empty()
+    }
+
+    /// Checks the signatures found for a manifest.
+    pub(crate) fn check_signatures(
+        &self,
+        reference: &str,
+        signatures: &[CosignSignature],
+    ) -> Result<(), PolicyViolation> {
+        let mut trusted: Vec<_> = signatures.iter().collect();
+        if !self.trusted_keys.is_empty() {
+            trusted.retain(|s| {
+                s.signature.as_ref().map_or(false, |signature| {
+                    self.trusted_keys
+                        .iter()
+                        .any(|key| key.verifies(&s.payload, signature))
+                })
+            });
+            if trusted.is_empty() {
+                return Err(PolicyViolation::UntrustedSignature(reference.to_owned()));
+            }
+        }
+
+        if self.required_signature_identities.is_empty()
+            || trusted
+                .iter()
+                .filter_map(|s| s.identity.as_deref())
+                .any(|id| self.required_signature_identities.iter().any(|r| r == id))
+        {
+            Ok(())
+        } else {
+            Err(PolicyViolation::MissingSignature {
+                reference: reference.to_owned(),
+                identities: self.required_signature_identities.join(", "),
+            })
+        }
+    }
+
+    /// Checks the annotations of a manifest against the policy.
+    pub(crate) fn check_manifest(
+        &self,
+        reference: &str,
+        manifest: &[u8],
+        now: DateTime<Utc>,
+    ) -> Result<(), PolicyViolation> {
+        let max_days = match self.max_artifact_age_days {
+            Some(max_days) => max_days,
+            None => return Ok(()),
+        };
+
+        #[derive(Deserialize)]
+        struct Annotated {
+            #[serde(default)]
+            annotations: HashMap<String, String>,
+        }
+        let created = serde_json::from_slice::<Annotated>(manifest)
+            .ok()
+            .and_then(|m| m.annotations.get(CREATED_ANNOTATION).cloned())
+            .and_then(|created| DateTime::parse_from_rfc3339(&created).ok())
+            .ok_or_else(|| PolicyViolation::UnknownAge(reference.to_owned()))?;
+
+        let age = now.signed_duration_since(created);
+        if age > Duration::days(max_days.into()) {
+            return Err(PolicyViolation::TooOld {
+                reference: reference.to_owned(),
+                age_days: age.num_days(),
+                max_days,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn location_matches(allowed: &str, registry: &str, location: &str) -> bool {
+    let allowed = allowed.trim_end_matches('/');
+    if let Some(domain) = allowed.strip_prefix("*.") {
+        return registry
+            .strip_suffix(domain)
+            .map_or(false, |sub| sub.ends_with('.'));
+    }
+    allowed == registry
+        || location
+            .strip_prefix(allowed)
+            .map_or(false, |rest| rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn allowed_registries_match_hosts_namespaces_and_subdomains() {
+        let policy = TrustPolicy {
+            allowed_registries: vec![
+                "ghcr.io/my-org".to_owned(),
+                "*.example.com".to_owned(),
+                "localhost:5000".to_owned(),
+            ],
+            ..Default::default()
+        };
+        assert!(policy.check_registry("ghcr.io", "my-org/app").is_ok());
+        assert!(policy.check_registry("ghcr.io", "my-org-evil/app").is_err());
+        assert!(policy.check_registry("ghcr.io", "other/app").is_err());
+        assert!(policy.check_registry("cr.example.com", "app").is_ok());
+        assert!(policy.check_registry("example.com", "app").is_err());
+        assert!(policy.check_registry("localhost:5000", "app").is_ok());
+    }
+
+    #[test]
+    fn max_age_requires_recent_creation_annotation() {
+        let policy = TrustPolicy {
+            max_artifact_age_days: Some(30),
+            ..Default::default()
+        };
+        let manifest =
+            br#"{"annotations":{"org.opencontainers.image.created":"2022-10-01T00:00:00Z"}}"#;
+        let soon = DateTime::parse_from_rfc3339("2022-10-15T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let later = soon + Duration::days(60);
+
+        assert!(policy.check_manifest("app", manifest, soon).is_ok());
+        assert!(matches!(
+            policy.check_manifest("app", manifest, later),
+            Err(PolicyViolation::TooOld { .. })
+        ));
+        assert!(matches!(
+            policy.check_manifest("app", b"{}", soon),
+            Err(PolicyViolation::UnknownAge(_))
+        ));
+    }
+}
@@ -0,0 +1,156 @@
+//! Media type profiles for registries which reject Spin's media types.
+
+use std::collections::HashMap;
+
+use oci_distribution::manifest::{IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE};
+
+use super::SPIN_CONFIG_MEDIA_TYPE;
+
+/// Manifest annotation recording the media type profile used for a push.
+pub const PROFILE_ANNOTATION: &str = "dev.fermyon.spin.media-type-profile";
+/// Descriptor annotation recording the Spin media type of content pushed
+/// with a generic media type.
+pub const MEDIA_TYPE_ANNOTATION: &str = "dev.fermyon.spin.media-type";
+
+const COMPATIBLE_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+const COMPATIBLE_LAYER_MEDIA_TYPE: &str = "application/octet-stream";
+
+/// The config media type of Docker schema 2 images.
+pub(super) const DOCKER_CONFIG_MEDIA_TYPE: &str = "application/vnd.docker.container.image.v1+json";
+const DOCKER_LAYER_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+
+/// The media types used to describe Spin content in a manifest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaTypeProfile {
+    /// Spin's own config and layer media types. This is preferred, because
+    /// it lets registries and tools recognise Spin applications.
+    Native,
+    /// Generic media types which registries accept, with the Spin media
+    /// type recorded in an annotation on each descriptor.
+    Compatible,
+    /// A Docker schema 2 manifest with the media types of a container
+    /// image, for registries which accept nothing else. Schema 2 has no
+    /// annotations, so layers cannot record their Spin media types or
+    /// where they belong; the config is still the locked application, by
+    /// which pulls recognise the application.
+    Docker,
+}
+
+impl MediaTypeProfile {
+    /// The name recorded in the manifest's profile annotation.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Native => "native",
+            Self::Compatible => "compatible",
+            Self::Docker => "docker",
+        }
+    }
+
+    /// The media type of manifests pushed with this profile.
+    pub fn manifest_media_type(&self) -> &'static str {
+        match self {
+            Self::Native | Self::Compatible => OCI_IMAGE_MEDIA_TYPE,
+            Self::Docker => IMAGE_MANIFEST_MEDIA_TYPE,
+        }
+    }
+
+    /// Whether manifests pushed with this profile may carry annotations.
+    pub fn supports_annotations(&self) -> bool {
+        !matches!(self, Self::Docker)
+    }
+
+    /// The media type and descriptor annotations to use for content with
+    /// the given Spin media type.
+    pub fn describe(&self, spin_media_type: &str) -> (String, Option<HashMap<String, String>>) {
+        match self {
+            Self::Native => (spin_media_type.to_owned(), None),
+            Self::Compatible => {
+                let media_type = if spin_media_type == SPIN_CONFIG_MEDIA_TYPE {
+                    COMPATIBLE_CONFIG_MEDIA_TYPE
+                } else {
+                    COMPATIBLE_LAYER_MEDIA_TYPE
+                };
+                let annotations =
+                    HashMap::from([(MEDIA_TYPE_ANNOTATION.to_owned(), spin_media_type.to_owned())]);
+                (media_type.to_owned(), Some(annotations))
+            }
+            Self::Docker => {
+                let media_type = if spin_media_type == SPIN_CONFIG_MEDIA_TYPE {
+                    DOCKER_CONFIG_MEDIA_TYPE
+                } else {
+                    DOCKER_LAYER_MEDIA_TYPE
+                };
+                (media_type.to_owned(), None)
+            }
+        }
+    }
+
+    /// The manifest annotations recording this profile, which are empty if
+    /// the profile does not support annotations.
+    pub fn manifest_annotations(&self) -> HashMap<String, String> {
+        if !self.supports_annotations() {
+            return HashMap::new();
+        }
+        HashMap::from([(PROFILE_ANNOTATION.to_owned(), self.name().to_owned())])
+    }
+}
+
+/// The Spin media type of a descriptor, whichever profile it was pushed
+/// with.
+pub fn spin_media_type<'a>(
+    media_type: &'a str,
+    annotations: Option<&'a HashMap<String, String>>,
+) -> &'a str {
+    annotations
+        .and_then(|a| a.get(MEDIA_TYPE_ANNOTATION))
+        .map(|m| m.as_str())
+        .unwrap_or(media_type)
+}
+
+/// Whether a registry's response to a manifest push means that it rejected
+/// the media types in the manifest, so that the push may succeed with the
+/// compatible profile.
+pub fn is_media_type_rejection(status: u16, body: &str) -> bool {
+    if !matches!(status, 400 | 415 | 422) {
+        return false;
+    }
+    let body = body.to_ascii_lowercase();
+    body.contains("manifest_invalid")
+        || body.contains("media type")
+        || body.contains("mediatype")
+        || body.contains("unsupported")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::oci::WASM_LAYER_MEDIA_TYPE;
+
+    #[test]
+    fn compatible_profile_round_trips_media_types() {
+        let (media_type, annotations) =
+            MediaTypeProfile::Compatible.describe(WASM_LAYER_MEDIA_TYPE);
+        assert_eq!(COMPATIBLE_LAYER_MEDIA_TYPE, media_type);
+        assert_eq!(
+            WASM_LAYER_MEDIA_TYPE,
+            spin_media_type(&media_type, annotations.as_ref())
+        );
+
+        let (media_type, annotations) = MediaTypeProfile::Native.describe(WASM_LAYER_MEDIA_TYPE);
+        assert_eq!(
+            WASM_LAYER_MEDIA_TYPE,
+            spin_media_type(&media_type, annotations.as_ref())
+        );
+    }
+
+    #[test]
+    fn detects_media_type_rejections() {
+        assert!(is_media_type_rejection(
+            400,
+            r#"{"errors":[{"code":"MANIFEST_INVALID","message":"manifest invalid"}]}"#
+        ));
+        assert!(is_media_type_rejection(415, "Unsupported media type"));
+        assert!(!is_media_type_rejection(401, "unsupported"));
+        assert!(!is_media_type_rejection(400, "blob unknown"));
+    }
+}
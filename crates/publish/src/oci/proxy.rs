@@ -0,0 +1,326 @@
+//! A pull-through registry proxy backed by the local cache.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use oci_distribution::manifest::OCI_IMAGE_MEDIA_TYPE;
+use serde::Deserialize;
+
+use super::{is_digest, spin_media_type, Cache, Client, DOCKER_CONTENT_DIGEST_HEADER};
+use crate::{PublishError, PublishResult};
+
+/// Serves registry content over the OCI distribution API from the local
+/// cache, fetching anything missing from an upstream registry. This lets
+/// several local Spin instances or development clusters share one warm
+/// cache.
+///
+/// Only pulls are supported.
+pub struct Proxy {
+    client: Client,
+    cache: Cache,
+    upstream: String,
+    // Media types of the layers of manifests served so far, by digest, so
+    // that blobs can be validated according to what they claim to be
+    layer_media_types: Mutex<HashMap<String, String>>,
+}
+
+impl Proxy {
+    /// Creates a proxy for the given upstream registry host.
+    pub fn new(client: Client, cache: Cache, upstream: impl Into<String>) -> Self {
+        Self {
+            client,
+            cache,
+            upstream: upstream.into(),
+            layer_media_types: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Serves the distribution API on the given address until the server
+    /// fails.
+    pub async fn serve(self, listen_addr: SocketAddr) -> PublishResult<()> {
+        let self_ = Arc::new(self);
+        let make_service = make_service_fn(|_| {
+            let self_ = self_.clone();
+            async move {
+                let service = service_fn(move |req| {
+                    let self_ = self_.clone();
+                    async move { Ok::<_, Infallible>(self_.handle(req).await) }
+                });
+                Ok::<_, Infallible>(service)
+            }
+        });
+
+        Server::try_bind(&listen_addr)
+            .map_err(|e| anyhow::anyhow!("Unable to listen on {}: {}", listen_addr, e))?
+            .serve(make_service)
+            .await
+            .map_err(|e| anyhow::anyhow!("Registry proxy failed: {}", e))?;
+        Ok(())
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let head_only = match *req.method() {
+            Method::GET => false,
+            Method::HEAD => true,
+            _ => return status_response(StatusCode::METHOD_NOT_ALLOWED),
+        };
+
+        let path = req.uri().path();
+        let result = match ProxyRoute::parse(path) {
+            Some(ProxyRoute::Base) => Ok(Content {
+                data: b"{}".to_vec(),
+                media_type: "application/json".to_owned(),
+                digest: None,
+            }),
+            Some(ProxyRoute::Manifest {
+                repository,
+                reference,
+            }) => self.manifest(repository, reference).await,
+            Some(ProxyRoute::Blob { repository, digest }) => self.blob(repository, digest).await,
+            None => return status_response(StatusCode::NOT_FOUND),
+        };
+
+        match result {
+            Ok(content) => content.into_response(head_only),
+            Err(e) => {
+                tracing::warn!("Registry proxy failed to serve {}: {}", path, e);
+                status_response(error_status(&e))
+            }
+        }
+    }
+
+    async fn manifest(&self, repository: &str, reference: &str) -> PublishResult<Content> {
+        let upstream = &self.upstream;
+
+        // Manifests addressed by digest never change, but tags can move, so
+        // only fall back to the cache for tags when upstream is unavailable.
+        if is_digest(reference) {
+            if let Some(data) = self
+                .cache
+                .read_manifest(upstream, repository, reference)
+                .await?
+            {
+                self.record_layers(&data);
+                return Ok(Content::manifest(data, Some(reference.to_owned())));
+            }
+        }
+
+        match self
+            .client
+            .fetch_manifest(upstream, repository, reference)
+            .await
+        {
+            Ok(manifest) => {
+                self.cache
+                    .write_manifest(upstream, repository, reference, &manifest.data)
+                    .await?;
+                self.cache
+                    .write_manifest(upstream, repository, &manifest.digest, &manifest.data)
+                    .await?;
+                self.record_layers(&manifest.data);
+                Ok(Content {
+                    data: manifest.data,
+                    media_type: manifest.media_type,
+                    digest: Some(manifest.digest),
+                })
+            }
+            Err(e) => match self
+                .cache
+                .read_manifest(upstream, repository, reference)
+                .await?
+            {
+                Some(data) => {
+                    tracing::warn!(
+                        "Serving cached manifest for {}:{}: {}",
+                        repository,
+                        reference,
+                        e
+                    );
+                    self.record_layers(&data);
+                    Ok(Content::manifest(data, None))
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    async fn blob(&self, repository: &str, digest: &str) -> PublishResult<Content> {
+        let data = match self.cache.read_blob(digest).await? {
+            Some(data) => data,
+            None => {
+                let media_type = self.layer_media_types.lock().unwrap().get(digest).cloned();
+                let data = match media_type {
+                    Some(media_type) => {
+                        self.client
+                            .fetch_layer(&self.upstream, repository, digest, &media_type)
+                            .await?
+                    }
+                    None => {
+                        self.client
+                            .fetch_blob(&self.upstream, repository, digest)
+                            .await?
+                    }
+                };
+                self.cache.write_blob(digest, &data).await?;
+                data
+            }
+        };
+        Ok(Content {
+            data,
+            media_type: "application/octet-stream".to_owned(),
+            digest: Some(digest.to_owned()),
+        })
+    }
+
+    fn record_layers(&self, manifest: &[u8]) {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Layers {
+            #[serde(default)]
+            layers: Vec<Layer>,
+        }
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Layer {
+            media_type: String,
+            digest: String,
+            annotations: Option<HashMap<String, String>>,
+        }
+
+        if let Ok(manifest) = serde_json::from_slice::<Layers>(manifest) {
+            let mut media_types = self.layer_media_types.lock().unwrap();
+            for layer in manifest.layers {
+                // Layers pushed with the compatible profile record their
+                // Spin media type in an annotation
+                let media_type = spin_media_type(&layer.media_type, layer.annotations.as_ref());
+                media_types.insert(layer.digest.clone(), media_type.to_owned());
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ProxyRoute<'a> {
+    Base,
+    Manifest {
+        repository: &'a str,
+        reference: &'a str,
+    },
+    Blob {
+        repository: &'a str,
+        digest: &'a str,
+    },
+}
+
+impl<'a> ProxyRoute<'a> {
+    fn parse(path: &'a str) -> Option<Self> {
+        let path = path.strip_prefix("/v2")?;
+        if path.is_empty() || path == "/" {
+            return Some(Self::Base);
+        }
+        let path = path.strip_prefix('/')?;
+        if let Some((repository, reference)) = path.rsplit_once("/manifests/") {
+            return Some(Self::Manifest {
+                repository,
+                reference,
+            });
+        }
+        if let Some((repository, digest)) = path.rsplit_once("/blobs/") {
+            return Some(Self::Blob { repository, digest });
+        }
+        None
+    }
+}
+
+struct Content {
+    data: Vec<u8>,
+    media_type: String,
+    digest: Option<String>,
+}
+
+impl Content {
+    fn manifest(data: Vec<u8>, digest: Option<String>) -> Self {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct MediaType {
+            media_type: Option<String>,
+        }
+        let media_type = serde_json::from_slice::<MediaType>(&data)
+            .ok()
+            .and_then(|m| m.media_type)
+            .unwrap_or_else(|| OCI_IMAGE_MEDIA_TYPE.to_owned());
+        Self {
+            data,
+            media_type,
+            digest,
+        }
+    }
+
+    fn into_response(self, head_only: bool) -> Response<Body> {
+        let mut builder = Response::builder()
+            .header(CONTENT_TYPE, self.media_type)
+            .header(hyper::header::CONTENT_LENGTH, self.data.len());
+        if let Some(digest) = self.digest {
+            builder = builder.header(DOCKER_CONTENT_DIGEST_HEADER, digest);
+        }
+        let body = if head_only {
+            Body::empty()
+        } else {
+            Body::from(self.data)
+        };
+        builder
+            .body(body)
+            .unwrap_or_else(|_| status_response(StatusCode::INTERNAL_SERVER_ERROR))
+    }
+}
+
+fn error_status(error: &PublishError) -> StatusCode {
+    match error {
+        PublishError::RegistryResponse { status, .. } => {
+            StatusCode::from_u16(*status).unwrap_or(StatusCode::BAD_GATEWAY)
+        }
+        PublishError::RegistryUnauthorized(_) => StatusCode::UNAUTHORIZED,
+        PublishError::PolicyViolation(_) => StatusCode::FORBIDDEN,
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_distribution_routes() {
+        assert_eq!(Some(ProxyRoute::Base), ProxyRoute::parse("/v2/"));
+        assert_eq!(
+            Some(ProxyRoute::Manifest {
+                repository: "org/app",
+                reference: "v1"
+            }),
+            ProxyRoute::parse("/v2/org/app/manifests/v1")
+        );
+        assert_eq!(
+            Some(ProxyRoute::Blob {
+                repository: "app",
+                digest: "sha256:abc"
+            }),
+            ProxyRoute::parse("/v2/app/blobs/sha256:abc")
+        );
+        assert_eq!(None, ProxyRoute::parse("/v1/app"));
+    }
+}
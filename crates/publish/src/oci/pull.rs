@@ -0,0 +1,579 @@
+//! Pulling Spin applications from OCI registries.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use futures::{stream, StreamExt, TryStreamExt};
+use oci_distribution::manifest::{OciDescriptor, OciImageIndex, OciImageManifest};
+use spin_app::locked::{ContentPath, ContentRef, LockedApp};
+
+use super::{
+    artifact::{is_artifact_manifest, ArtifactManifest},
+    index::{check_spin_image, is_index, select_spin_manifest},
+    lockfile::pinned_reference,
+    operations::{Operation, OperationKind},
+    parse_reference, spin_media_type, Cache, Client, Compression, FetchedManifest, TrustPolicy,
+    VerificationKey, ARCHIVE_LAYER_MEDIA_TYPE, COMPONENT_ANNOTATION,
+};
+use crate::{PublishError, PublishResult};
+
+impl Client {
+    /// Pulls an application into the cache and returns it with its
+    /// component sources pointing at the cached Wasm layers. The static
+    /// asset files of each component are assembled from the cached data
+    /// layers into a directory in the cache, which is mounted at the root
+    /// of the component's file system. Compressed data layers are
+    /// decompressed, and archive layers unpacked, as they are assembled.
+    pub async fn pull(&self, reference: &str, cache: &Cache) -> PublishResult<LockedApp> {
+        let (_, app) = self.pull_resolved(reference, cache).await?;
+        Ok(app)
+    }
+
+    /// Pulls an application as [`Client::pull`] does, also returning the
+    /// digest the reference resolved to, by which it can be pinned. The
+    /// assembled application is cached under the digest, so pulling the
+    /// same content again skips parsing its config and assembling it.
+    pub async fn pull_resolved(
+        &self,
+        reference: &str,
+        cache: &Cache,
+    ) -> PublishResult<(String, LockedApp)> {
+        let (digest, image) = self.fetch_into_cache(reference, cache, None).await?;
+        if let Some(app) = assembled_app(&digest, cache).await {
+            tracing::debug!("Using the assembled application cached for {}", digest);
+            return Ok((digest, app));
+        }
+
+        let app = cached_app(reference, &image, cache).await?;
+        let app = assemble(app, &image, cache).await?;
+        let written = match app.to_json() {
+            Ok(json) => cache.write_locked_app(&digest, &json).await,
+            Err(e) => Err(PublishError::Other(e.into())),
+        };
+        if let Err(e) = written {
+            tracing::warn!("Failed to cache the assembled application: {}", e);
+        }
+        Ok((digest, app))
+    }
+
+    /// Pulls the application a reference was pinned to, by its digest.
+    /// If the reference has a tag, the tag must still resolve to the
+    /// digest, so that a tag which has been moved is noticed rather than
+    /// silently ignored.
+    pub async fn pull_pinned(
+        &self,
+        reference: &str,
+        digest: &str,
+        cache: &Cache,
+    ) -> PublishResult<LockedApp> {
+        let parsed = parse_reference(reference)?;
+        if let Some(tag) = parsed.tag() {
+            let manifest = self
+                .fetch_manifest(parsed.resolve_registry(), parsed.repository(), tag)
+                .await?;
+            if manifest.digest != digest {
+                return Err(PublishError::PinnedDigestMismatch {
+                    reference: reference.to_owned(),
+                    expected: digest.to_owned(),
+                    actual: manifest.digest,
+                });
+            }
+        }
+        let (_, app) = self
+            .pull_resolved(&pinned_reference(reference, digest)?, cache)
+            .await?;
+        Ok(app)
+    }
+
+    /// Pulls an application as [`pull`](Self::pull) does, but only if its
+    /// manifest has a cosign signature made with one of the given keys, in
+    /// addition to anything the client's trust policy requires. The
+    /// application is pulled by the digest whose signature was verified,
+    /// so that the reference cannot be moved to other content in between.
+    pub async fn pull_verified(
+        &self,
+        reference: &str,
+        cache: &Cache,
+        keys: &[VerificationKey],
+    ) -> PublishResult<LockedApp> {
+        if keys.is_empty() {
+            return Err(PublishError::Other(anyhow::anyhow!(
+                "No keys were given to verify the signature of {}",
+                reference
+            )));
+        }
+        let parsed = parse_reference(reference)?;
+        let registry = parsed.resolve_registry();
+        let repository = parsed.repository();
+        let target = parsed.digest().or_else(|| parsed.tag()).unwrap_or("latest");
+
+        let manifest = self.fetch_manifest(registry, repository, target).await?;
+        let signatures = self
+            .fetch_signatures(registry, repository, &manifest.digest)
+            .await?;
+        let policy = TrustPolicy {
+            trusted_keys: keys.to_vec(),
+            ..Default::default()
+        };
+        policy.check_signatures(reference, &signatures)?;
+
+        let verified = format!("{}/{}@{}", registry, repository, manifest.digest);
+        self.pull(&verified, cache).await
+    }
+
+    /// Pulls a single component of an application, downloading only its
+    /// Wasm layer and asset files, and returns the application reduced to
+    /// that component and its triggers, as [`pull`](Self::pull) would.
+    /// Layers are matched to the component by their component annotation,
+    /// or, for applications pushed without annotations, by the digests the
+    /// component refers to. The manifest is not cached, as the content it
+    /// refers to is only partially cached.
+    pub async fn pull_component(
+        &self,
+        reference: &str,
+        component_id: &str,
+        cache: &Cache,
+    ) -> PublishResult<LockedApp> {
+        let (_, image) = self
+            .fetch_into_cache(reference, cache, Some(component_id))
+            .await?;
+        let mut app = cached_app(reference, &image, cache).await?;
+        app.components.retain(|c| c.id == component_id);
+        app.triggers.retain(|t| {
+            t.trigger_config.get("component").and_then(|c| c.as_str()) == Some(component_id)
+        });
+        assemble(app, &image, cache).await
+    }
+
+    /// Pulls an application into the cache: its manifest, config and every
+    /// layer which is not already cached, downloading up to the client's
+    /// maximum number of layers at once. If the reference is an image
+    /// index, the application is its Spin variant. Returns the digest of
+    /// the manifest or index, under which it is cached as well as under its
+    /// tag. Every
+    /// download is checked against the digest by which it was requested,
+    /// so pulling by digest pins the whole application.
+    pub async fn pull_into_cache(&self, reference: &str, cache: &Cache) -> PublishResult<String> {
+        let (digest, _) = self.fetch_into_cache(reference, cache, None).await?;
+        Ok(digest)
+    }
+
+    /// Fetches an application's manifest, config and layers into the
+    /// cache, or only the layers of the given component, recording the
+    /// pull in the cache's operations log.
+    pub(super) async fn fetch_into_cache(
+        &self,
+        reference: &str,
+        cache: &Cache,
+        component_id: Option<&str>,
+    ) -> PublishResult<(String, OciImageManifest)> {
+        let started = Instant::now();
+        let downloaded = AtomicU64::new(0);
+        let result = self
+            .download_into_cache(reference, cache, component_id, &downloaded)
+            .await;
+
+        let mut operation = Operation::finished(OperationKind::Pull, reference, started.elapsed());
+        operation.transferred = downloaded.into_inner();
+        match &result {
+            Ok((digest, image)) => {
+                operation.digest = Some(digest.clone());
+                operation.size = Some(image_size(image));
+            }
+            Err(e) => operation.error = Some(e.to_string()),
+        }
+        cache.log_operation(operation).await;
+        result
+    }
+
+    /// Downloads whatever of an application is not already cached into the
+    /// cache, counting the bytes downloaded.
+    async fn download_into_cache(
+        &self,
+        reference: &str,
+        cache: &Cache,
+        component_id: Option<&str>,
+        downloaded: &AtomicU64,
+    ) -> PublishResult<(String, OciImageManifest)> {
+        let parsed = parse_reference(reference)?;
+        let registry = parsed.resolve_registry();
+        let repository = parsed.repository();
+        let target = parsed.digest().or_else(|| parsed.tag()).unwrap_or("latest");
+
+        let manifest = self.fetch_manifest(registry, repository, target).await?;
+        let (index, manifest) = if is_index(&manifest) {
+            let parsed: OciImageIndex = serde_json::from_slice(&manifest.data).map_err(|e| {
+                PublishError::Other(anyhow::anyhow!(
+                    "{} is not a valid image index: {}",
+                    reference,
+                    e
+                ))
+            })?;
+            let entry = select_spin_manifest(reference, &parsed)?;
+            let image = self
+                .fetch_manifest(registry, repository, &entry.digest)
+                .await?;
+            (Some(manifest), image)
+        } else {
+            (None, manifest)
+        };
+        let image = parse_image(reference, &manifest)?;
+
+        let config = match cache.read_blob(&image.config.digest).await? {
+            Some(config) => config,
+            None => {
+                let config = self
+                    .fetch_blob(registry, repository, &image.config.digest)
+                    .await?;
+                downloaded.fetch_add(config.len() as u64, Ordering::Relaxed);
+                cache.write_blob(&image.config.digest, &config).await?;
+                config
+            }
+        };
+        check_spin_image(reference, &image, &config)?;
+        let layers = match component_id {
+            Some(component_id) => {
+                let app = parse_app(reference, &config)?;
+                component_layers(reference, &image, &app, component_id)?
+            }
+            None => image.layers.iter().collect(),
+        };
+
+        stream::iter(layers)
+            .map(|layer| async move {
+                if cache.has_blob(&layer.digest).await? {
+                    return Ok(());
+                }
+                let media_type = spin_media_type(&layer.media_type, layer.annotations.as_ref());
+                let data = self
+                    .fetch_layer(registry, repository, &layer.digest, media_type)
+                    .await?;
+                downloaded.fetch_add(data.len() as u64, Ordering::Relaxed);
+                cache.write_blob(&layer.digest, &data).await
+            })
+            .buffer_unordered(self.max_concurrent_downloads)
+            .try_collect::<()>()
+            .await?;
+        let top = index.as_ref().unwrap_or(&manifest);
+        if component_id.is_some() {
+            return Ok((top.digest.clone(), image));
+        }
+
+        // The manifests are written last, so that a cached manifest means
+        // the content it refers to is cached too. An index is cached under
+        // the reference, as the registry serves it, and the application's
+        // manifest under its digest.
+        cache
+            .write_manifest(registry, repository, &manifest.digest, &manifest.data)
+            .await?;
+        if let Some(index) = &index {
+            cache
+                .write_manifest(registry, repository, &index.digest, &index.data)
+                .await?;
+        }
+        if target != top.digest {
+            cache
+                .write_manifest(registry, repository, target, &top.data)
+                .await?;
+        }
+        Ok((top.digest.clone(), image))
+    }
+}
+
+/// The total size in bytes of an image's config and layers.
+fn image_size(image: &OciImageManifest) -> u64 {
+    let layers: i64 = image.layers.iter().map(|l| l.size).sum();
+    (image.config.size + layers).max(0) as u64
+}
+
+/// Parses an image manifest or, for an application pushed with an artifact
+/// manifest, its image equivalent.
+pub(super) fn parse_image(
+    reference: &str,
+    manifest: &FetchedManifest,
+) -> PublishResult<OciImageManifest> {
+    let invalid = |e: serde_json::Error| {
+        PublishError::Other(anyhow::anyhow!(
+            "{} is not a Spin application manifest: {}",
+            reference,
+            e
+        ))
+    };
+    if !is_artifact_manifest(&manifest.media_type, &manifest.data) {
+        return serde_json::from_slice(&manifest.data).map_err(invalid);
+    }
+    let artifact: ArtifactManifest = serde_json::from_slice(&manifest.data).map_err(invalid)?;
+    let artifact_type = artifact.artifact_type.clone();
+    artifact.into_image().ok_or_else(|| {
+        PublishError::Other(anyhow::anyhow!(
+            "{} is an artifact of type {}, not a Spin application",
+            reference,
+            artifact_type
+        ))
+    })
+}
+
+/// Reads the application from the cached config of an image.
+async fn cached_app(
+    reference: &str,
+    image: &OciImageManifest,
+    cache: &Cache,
+) -> PublishResult<LockedApp> {
+    let config_digest = &image.config.digest;
+    let config = cache
+        .read_blob(config_digest)
+        .await?
+        .ok_or_else(|| missing_content(reference, config_digest))?;
+    parse_app(reference, &config)
+}
+
+pub(super) fn parse_app(reference: &str, config: &[u8]) -> PublishResult<LockedApp> {
+    LockedApp::from_json(config).map_err(|e| {
+        PublishError::Other(anyhow::anyhow!(
+            "{} does not contain a valid Spin application: {}",
+            reference,
+            e
+        ))
+    })
+}
+
+/// The assembled application cached for a manifest or index digest, if it
+/// is cached and the content it points at has not been removed from the
+/// cache since.
+async fn assembled_app(digest: &str, cache: &Cache) -> Option<LockedApp> {
+    let data = cache.read_locked_app(digest).await.ok()??;
+    let app = LockedApp::from_json(&data).ok()?;
+    let sources = app.components.iter().flat_map(|component| {
+        let files = component.files.iter().map(|file| &file.content);
+        std::iter::once(&component.source.content).chain(files)
+    });
+    for source in sources {
+        let path = source
+            .source
+            .as_deref()
+            .and_then(|source| url::Url::parse(source).ok())
+            .and_then(|url| url.to_file_path().ok())?;
+        if !path.exists() {
+            return None;
+        }
+    }
+    Some(app)
+}
+
+/// Points the components of a pulled application at their cached content,
+/// assembling each component's asset files into a directory in the cache.
+async fn assemble(
+    mut app: LockedApp,
+    image: &OciImageManifest,
+    cache: &Cache,
+) -> PublishResult<LockedApp> {
+    let config_digest = &image.config.digest;
+    let media_types: HashMap<_, _> = image
+        .layers
+        .iter()
+        .map(|layer| {
+            let media_type = spin_media_type(&layer.media_type, layer.annotations.as_ref());
+            (layer.digest.as_str(), media_type)
+        })
+        .collect();
+
+    for component in &mut app.components {
+        let digest = content_digest(&component.source.content, &component.id)?;
+        component.source.content = ContentRef {
+            source: Some(file_url(&cache.blob_path(digest))?),
+            digest: Some(digest.to_owned()),
+        };
+
+        if component.files.is_empty() {
+            continue;
+        }
+        check_component_id(&component.id)?;
+        let assets_dir = cache.assets_dir(config_digest, &component.id);
+        for file in &component.files {
+            let digest = content_digest(&file.content, &component.id)?;
+            let dest = assets_dir.join(relative_guest_path(&file.path, &component.id)?);
+            match media_types.get(digest).copied() {
+                Some(ARCHIVE_LAYER_MEDIA_TYPE) => cache.unpack_blob(digest, &dest).await?,
+                media_type => {
+                    let compression =
+                        media_type.map_or(Compression::None, Compression::from_media_type);
+                    cache.copy_blob(digest, &dest, compression).await?
+                }
+            }
+        }
+        component.files = vec![ContentPath {
+            content: ContentRef {
+                source: Some(file_url(&assets_dir)?),
+                digest: None,
+            },
+            path: PathBuf::from("/"),
+        }];
+    }
+
+    Ok(app)
+}
+
+/// The layers holding the content of a component: those annotated as
+/// belonging to it, and those without a component annotation which it
+/// refers to.
+fn component_layers<'a>(
+    reference: &str,
+    image: &'a OciImageManifest,
+    app: &LockedApp,
+    component_id: &str,
+) -> PublishResult<Vec<&'a OciDescriptor>> {
+    let component = app
+        .components
+        .iter()
+        .find(|c| c.id == component_id)
+        .ok_or_else(|| {
+            PublishError::Other(anyhow::anyhow!(
+                "{} has no component {}",
+                reference,
+                component_id
+            ))
+        })?;
+    let referenced: HashSet<_> = std::iter::once(&component.source.content)
+        .chain(component.files.iter().map(|f| &f.content))
+        .filter_map(|content| content.digest.as_deref())
+        .collect();
+    let layers = image
+        .layers
+        .iter()
+        .filter(|layer| {
+            match layer
+                .annotations
+                .as_ref()
+                .and_then(|a| a.get(COMPONENT_ANNOTATION))
+            {
+                Some(owner) => owner == component_id,
+                None => referenced.contains(layer.digest.as_str()),
+            }
+        })
+        .collect();
+    Ok(layers)
+}
+
+fn content_digest<'a>(content: &'a ContentRef, component_id: &str) -> PublishResult<&'a str> {
+    content.digest.as_deref().ok_or_else(|| {
+        PublishError::Other(anyhow::anyhow!(
+            "Component {} refers to content without a digest",
+            component_id
+        ))
+    })
+}
+
+fn missing_content(reference: &str, digest: &str) -> PublishError {
+    PublishError::Other(anyhow::anyhow!(
+        "Content {} of {} is missing from the cache",
+        digest,
+        reference
+    ))
+}
+
+/// Checks that a component ID from a pulled config can name its asset
+/// directory, as IDs which are not a single normal path segment could
+/// place the directory outside the cache.
+fn check_component_id(component_id: &str) -> PublishResult<()> {
+    let mut components = Path::new(component_id).components();
+    let is_segment = matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+        && !component_id.contains(['/', '\\']);
+    if !is_segment {
+        return Err(PublishError::Other(anyhow::anyhow!(
+            "Component ID {:?} cannot be used as a directory name",
+            component_id
+        )));
+    }
+    Ok(())
+}
+
+/// The path of an asset file within the assembled asset directory. Paths
+/// which would escape the directory are refused.
+fn relative_guest_path(guest_path: &Path, component_id: &str) -> PublishResult<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in guest_path.components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(segment) => relative.push(segment),
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(PublishError::Other(anyhow::anyhow!(
+                    "Component {} mounts a file at {}, which is outside its file system",
+                    component_id,
+                    guest_path.display()
+                )))
+            }
+        }
+    }
+    Ok(relative)
+}
+
+fn file_url(path: &Path) -> PublishResult<String> {
+    url::Url::from_file_path(path)
+        .map(|url| url.to_string())
+        .map_err(|_| {
+            PublishError::Other(anyhow::anyhow!(
+                "Cannot construct file URL for {}",
+                path.display()
+            ))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn guest_paths_stay_within_assets_dir() {
+        assert_eq!(
+            PathBuf::from("static/index.html"),
+            relative_guest_path(Path::new("/static/index.html"), "web").unwrap()
+        );
+        assert!(relative_guest_path(Path::new("/static/../../etc/passwd"), "web").is_err());
+    }
+
+    #[tokio::test]
+    async fn component_ids_cannot_escape_the_cache() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache = Cache::new(Some(temp.path().join("cache"))).await.unwrap();
+        let image: OciImageManifest = serde_json::from_value(serde_json::json!({
+            "schemaVersion": 2,
+            "config": {
+                "mediaType": "application/vnd.fermyon.spin.application.v1+config",
+                "digest": "sha256:config",
+                "size": 1
+            },
+            "layers": []
+        }))
+        .unwrap();
+
+        for id in ["../../..", "..", ".", "a/b", "a\\b", ""] {
+            let app = serde_json::json!({
+                "spin_lock_version": 0,
+                "triggers": [],
+                "components": [{
+                    "id": id,
+                    "source": {"content_type": "application/wasm", "digest": "sha256:wasm"},
+                    "files": [{"digest": "sha256:asset", "path": "/evil.txt"}]
+                }]
+            });
+            let app = LockedApp::from_json(app.to_string().as_bytes()).unwrap();
+            let error = assemble(app, &image, &cache).await.unwrap_err();
+            assert!(
+                error
+                    .to_string()
+                    .contains("cannot be used as a directory name"),
+                "{:?}: {}",
+                id,
+                error
+            );
+        }
+        let entries: Vec<_> = std::fs::read_dir(temp.path()).unwrap().collect();
+        assert_eq!(1, entries.len(), "nothing is written beside the cache");
+        assert!(check_component_id("web-ui").is_ok());
+    }
+}
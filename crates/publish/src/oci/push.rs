@@ -0,0 +1,1596 @@
+//! Pushing Spin applications to OCI registries.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use futures::{channel::mpsc, stream, SinkExt, StreamExt};
+use oci_distribution::manifest::{OciDescriptor, OciImageManifest};
+use reqwest::{
+    header::{CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LOCATION, RANGE},
+    StatusCode,
+};
+use serde::Serialize;
+use spin_app::locked::{ContentPath, ContentRef, LockedApp};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::{
+    archive::{build_archive, ARCHIVE_LAYER_MEDIA_TYPE},
+    artifact::{ArtifactManifest, ARTIFACT_MANIFEST_MEDIA_TYPE},
+    auth::{registry_auth_with_warnings, Authorization, Challenge, RegistryAuth},
+    is_digest, is_media_type_rejection,
+    layer::{FileLayer, ScratchDir},
+    operations::{Operation, OperationKind},
+    parse_reference, registry_response_error,
+    resume::{PartialUpload, ResumeToken},
+    sha256_digest, Cache, Client, Compression, MediaTypeProfile, COMPONENT_ANNOTATION,
+    DATA_LAYER_MEDIA_TYPE, DOCKER_CONTENT_DIGEST_HEADER, GUEST_PATH_ANNOTATION,
+    SPIN_CONFIG_MEDIA_TYPE, WASM_LAYER_MEDIA_TYPE,
+};
+use crate::{guest_path::guest_path, PublishError, PublishFilter, PublishResult, PublishWarning};
+
+const BLOB_MEDIA_TYPE: &str = "application/octet-stream";
+
+/// How many layers are prepared ahead of the one being uploaded.
+const PIPELINE_DEPTH: usize = 4;
+/// Files larger than this are uploaded in chunks of this size.
+const UPLOAD_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+/// How many times a failed chunk upload is resumed before giving up.
+const MAX_CHUNK_RETRIES: usize = 3;
+/// How many bumped tags are tried when the registry refuses to replace a tag.
+const MAX_TAG_BUMPS: usize = 32;
+
+impl Client {
+    /// Pushes a locked application to a registry, returning the digest of
+    /// the pushed manifest and the layers which make it up.
+    ///
+    /// The application's Wasm modules are pushed as Wasm layers and its
+    /// static asset files as data layers, one layer per distinct file
+    /// admitted by the client's filter, if it has one. If the client packs
+    /// assets into archives, each component's files are instead pushed as
+    /// a single archive layer. Data layers are compressed if the client has
+    /// a compression set.
+    ///
+    /// The config object is the locked application, with each local file
+    /// reference replaced by the digest of the layer holding its content.
+    /// If the registry rejects Spin's media types, the manifest is pushed
+    /// again using the compatible media type profile. If the client pushes
+    /// artifact manifests, an OCI artifact manifest with the Spin artifact
+    /// type is pushed instead, falling back to an image manifest if the
+    /// registry does not support artifact manifests. If the client is in
+    /// Docker compatibility mode, a Docker schema 2 manifest is pushed. If
+    /// the registry refuses to replace an existing tag, the client's
+    /// [`ExistingTagPolicy`] decides what happens.
+    ///
+    /// If the push fails after some layers have been pushed, the error is a
+    /// [`PublishError::PushIncomplete`] with a [`ResumeToken`] recording
+    /// them, with which the push can be resumed.
+    ///
+    /// Layers are read, digested and compressed a few at a time ahead of
+    /// the upload, so that preparing later layers overlaps uploading
+    /// earlier ones. Archives and compressed layers are built in files,
+    /// and large files are uploaded in chunks, so that no layer is held in
+    /// memory whole unless it is small enough to upload in one request.
+    pub async fn push(&self, app: &LockedApp, reference: &str) -> PublishResult<PushResult> {
+        let parsed = parse_reference(reference)?;
+        let registry = parsed.resolve_registry();
+        let repository = parsed.repository();
+        let target = parsed.digest().or_else(|| parsed.tag()).unwrap_or("latest");
+
+        let started = Instant::now();
+        let mut session = PushSession::new(self, registry, repository);
+        if let Some(token) = &self.resume {
+            session.resume(token);
+        }
+        let result = match self.push_with(&mut session, app, target).await {
+            Ok((result, _)) => Ok(result),
+            Err(e) => Err(session.incomplete(reference, e)),
+        };
+
+        if let Some(cache) = &self.operation_log {
+            let mut operation =
+                Operation::finished(OperationKind::Push, reference, started.elapsed());
+            match &result {
+                Ok(pushed) => {
+                    operation.digest = Some(pushed.manifest_digest.clone());
+                    operation.size = Some(pushed.total_bytes);
+                    operation.transferred = pushed.uploaded_bytes();
+                }
+                Err(e) => operation.error = Some(e.to_string()),
+            }
+            cache.log_operation(operation).await;
+        }
+        result
+    }
+
+    /// Assembles a push as [`push`](Self::push) would, without contacting
+    /// the registry: layers are digested, and compressed or archived as
+    /// they would be, but nothing is uploaded. Returns the manifest and
+    /// config which would be pushed. As the registry is not asked, an
+    /// artifact manifest is never replaced by an image manifest, and image
+    /// manifests use Spin's media types unless the client is in Docker
+    /// compatibility mode.
+    pub async fn dry_run_push(
+        &self,
+        app: &LockedApp,
+        reference: &str,
+    ) -> PublishResult<DryRunPush> {
+        let parsed = parse_reference(reference)?;
+        let registry = parsed.resolve_registry();
+        let repository = parsed.repository();
+        let target = parsed.digest().or_else(|| parsed.tag()).unwrap_or("latest");
+
+        let mut session = PushSession::new(self, registry, repository);
+        session.dry_run = true;
+        let (result, config) = self.push_with(&mut session, app, target).await?;
+        let (manifest_media_type, manifest) = session.manifest.take().ok_or_else(|| {
+            PublishError::Other(anyhow::anyhow!("Dry run did not produce a manifest"))
+        })?;
+        Ok(DryRunPush {
+            manifest,
+            manifest_media_type,
+            config,
+            result,
+        })
+    }
+
+    /// Pushes a locked application in the given session, returning the
+    /// outcome and the config object.
+    async fn push_with(
+        &self,
+        session: &mut PushSession<'_>,
+        app: &LockedApp,
+        target: &str,
+    ) -> PublishResult<(PushResult, Vec<u8>)> {
+        if self.docker_compatible
+            && (self.compression != Compression::None
+                || self.archive_assets
+                || self.artifact_manifest)
+        {
+            return Err(PublishError::Other(anyhow::anyhow!(
+                "Compression, asset archives and artifact manifests cannot be used in Docker compatibility mode, as Docker manifests cannot record them"
+            )));
+        }
+
+        // Layers are prepared a few at a time, in manifest order, while
+        // those already prepared are uploaded. The bounded channel between
+        // the stages limits how far preparation runs ahead of the upload.
+        let mut app = app.clone();
+        let jobs = self.layer_jobs(&app, &mut session.warnings)?;
+        let mut files = vec![vec![]; app.components.len()];
+        let scratch = ScratchDir::new();
+        let scratch_path = scratch.path();
+        let (mut sender, mut receiver) = mpsc::channel(PIPELINE_DEPTH);
+        let prepare = async move {
+            let mut prepared = stream::iter(jobs)
+                .map(|job| self.prepare_layer(job, scratch_path))
+                .buffered(PIPELINE_DEPTH);
+            while let Some(layer) = prepared.next().await {
+                let failed = layer.is_err();
+                // Sending fails only if the upload stage has given up
+                if sender.send(layer).await.is_err() || failed {
+                    break;
+                }
+            }
+            Ok::<_, PublishError>(())
+        };
+        let upload = async {
+            while let Some(layer) = receiver.next().await {
+                let PreparedLayer {
+                    layer,
+                    media_type,
+                    annotations,
+                    slot,
+                } = layer?;
+                session.push_layer(&layer, media_type, annotations).await?;
+                let digest = layer.digest;
+                match slot {
+                    Slot::Source(index) => {
+                        app.components[index].source.content = digest_ref(digest)
+                    }
+                    Slot::File(index, path) => files[index].push(ContentPath {
+                        content: digest_ref(digest),
+                        path,
+                    }),
+                }
+            }
+            Ok::<_, PublishError>(())
+        };
+        futures::try_join!(prepare, upload)?;
+        for (component, files) in app.components.iter_mut().zip(files) {
+            component.files = files;
+        }
+
+        let config = app.to_json().map_err(|e| {
+            PublishError::Other(anyhow::anyhow!("Failed to serialize application: {}", e))
+        })?;
+        let config_digest = sha256_digest(&config);
+        let config_data = config.clone();
+        let config = session
+            .push_blob(config, &config_digest, SPIN_CONFIG_MEDIA_TYPE)
+            .await?;
+
+        let (target, manifest_digest) = match self.push_app_manifest(session, target, &config).await
+        {
+            Err(PublishError::RegistryResponse {
+                status, message, ..
+            }) if !is_digest(target) && is_immutable_tag_rejection(status, &message) => {
+                self.push_to_immutable_tag(session, target, &config, message)
+                    .await?
+            }
+            result => (target.to_owned(), result?),
+        };
+
+        let layers: Vec<_> = session
+            .blobs
+            .iter()
+            .map(|blob| PushedLayer {
+                digest: blob.digest.clone(),
+                size: blob.size as u64,
+                media_type: blob.media_type.to_owned(),
+                uploaded: blob.uploaded,
+                annotations: blob.annotations.clone(),
+            })
+            .collect();
+        let distinct: HashMap<_, _> = layers.iter().map(|l| (&l.digest, l.size)).collect();
+        let total_bytes = distinct.values().sum::<u64>() + config.size as u64;
+        let result = PushResult {
+            manifest_digest,
+            target,
+            layers,
+            total_bytes,
+            warnings: std::mem::take(&mut session.warnings),
+        };
+        Ok((result, config_data))
+    }
+
+    /// Pushes the manifest of an application whose config and layers have
+    /// been pushed, returning its digest.
+    async fn push_app_manifest(
+        &self,
+        session: &mut PushSession<'_>,
+        target: &str,
+        config: &PushedBlob,
+    ) -> PublishResult<String> {
+        if self.docker_compatible {
+            session
+                .push_manifest(target, config, MediaTypeProfile::Docker)
+                .await
+        } else if self.artifact_manifest {
+            match session.push_artifact_manifest(target, config).await {
+                Err(PublishError::RegistryResponse {
+                    status, message, ..
+                }) if is_media_type_rejection(status, &message) => {
+                    tracing::info!(
+                        "{} rejected the artifact manifest; pushing an image manifest",
+                        session.registry
+                    );
+                    session.warnings.push(PublishWarning::FallbackMediaType {
+                        registry: session.registry.to_owned(),
+                        rejected: "artifact manifest".to_owned(),
+                        pushed: "image manifest".to_owned(),
+                    });
+                    session.push_image_manifest(target, config).await
+                }
+                result => result,
+            }
+        } else {
+            session.push_image_manifest(target, config).await
+        }
+    }
+
+    /// Pushes the manifest of an application whose tag the registry refused
+    /// to replace, as the client's [`ExistingTagPolicy`] says, returning
+    /// the tag or digest it was pushed to and its digest.
+    async fn push_to_immutable_tag(
+        &self,
+        session: &mut PushSession<'_>,
+        tag: &str,
+        config: &PushedBlob,
+        message: String,
+    ) -> PublishResult<(String, String)> {
+        let (registry, repository) = (session.registry, session.repository);
+        let immutable = |tag: &str, message: String| PublishError::ImmutableTag {
+            reference: format!("{}/{}:{}", registry, repository, tag),
+            message,
+        };
+        match self.existing_tag_policy {
+            ExistingTagPolicy::Error => Err(immutable(tag, message)),
+            ExistingTagPolicy::DigestOnly => {
+                // The rejected manifest is pushed as it was, so that its
+                // digest is the one the tag would have had.
+                let (media_type, data) = session
+                    .manifest
+                    .take()
+                    .ok_or_else(|| immutable(tag, message))?;
+                tracing::info!("{} already exists; pushing by digest only", tag);
+                let digest = session
+                    .put_manifest(&sha256_digest(&data), &media_type, data)
+                    .await?;
+                Ok((digest.clone(), digest))
+            }
+            ExistingTagPolicy::Bump => {
+                let mut message = message;
+                for bumped in bumped_tags(tag) {
+                    match self.push_app_manifest(session, &bumped, config).await {
+                        Err(PublishError::RegistryResponse {
+                            status,
+                            message: rejection,
+                            ..
+                        }) if is_immutable_tag_rejection(status, &rejection) => {
+                            message = rejection;
+                        }
+                        result => {
+                            let digest = result?;
+                            tracing::info!("{} already exists; pushed to {}", tag, bumped);
+                            return Ok((bumped, digest));
+                        }
+                    }
+                }
+                Err(immutable(&format!("{}-{}", tag, MAX_TAG_BUMPS), message))
+            }
+        }
+    }
+}
+
+impl Client {
+    /// Lists the layers making up an application, in manifest order: each
+    /// component's Wasm module followed by its asset files admitted by the
+    /// filter or, if the client packs assets into archives, an archive of
+    /// them. Files the filter excludes are recorded in `warnings`.
+    fn layer_jobs(
+        &self,
+        app: &LockedApp,
+        warnings: &mut Vec<PublishWarning>,
+    ) -> PublishResult<Vec<LayerJob>> {
+        let follow_symlinks = self
+            .filter
+            .as_ref()
+            .map_or(true, PublishFilter::follows_symlinks);
+        let mut jobs = vec![];
+        for (index, component) in app.components.iter().enumerate() {
+            jobs.push(LayerJob {
+                source: LayerSource::File(
+                    local_path(&component.source.content, &component.id)?,
+                    WASM_LAYER_MEDIA_TYPE,
+                ),
+                annotations: layer_annotations(&component.id, None),
+                slot: Slot::Source(index),
+            });
+
+            let mut assets = vec![];
+            for file in &component.files {
+                let host_path = local_path(&file.content, &component.id)?;
+                for (relative_path, host_file) in asset_files(&host_path, follow_symlinks)? {
+                    let path = if relative_path.as_os_str().is_empty() {
+                        file.path.clone()
+                    } else {
+                        file.path.join(relative_path)
+                    };
+                    // Host separators must not leak into the pushed config
+                    // and annotations, which are read on other platforms.
+                    let path = PathBuf::from(guest_path(&path));
+                    if let Some(filter) = &self.filter {
+                        if filter.excludes(&path) {
+                            tracing::debug!("Not pushing {}: excluded by filter", path.display());
+                            warnings.push(PublishWarning::SkippedFile { path });
+                            continue;
+                        }
+                    }
+                    assets.push((path, host_file));
+                }
+            }
+
+            if self.archive_assets && !assets.is_empty() {
+                jobs.push(LayerJob {
+                    source: LayerSource::Archive(assets),
+                    annotations: layer_annotations(&component.id, None),
+                    slot: Slot::File(index, PathBuf::from("/")),
+                });
+            } else {
+                for (path, host_file) in assets {
+                    jobs.push(LayerJob {
+                        source: LayerSource::File(host_file, DATA_LAYER_MEDIA_TYPE),
+                        annotations: layer_annotations(&component.id, Some(&path)),
+                        slot: Slot::File(index, path),
+                    });
+                }
+            }
+        }
+        Ok(jobs)
+    }
+
+    /// Digests a layer's content, compressing or archiving it first if the
+    /// client is set up to. Archives and compressed files are written to
+    /// files in the scratch directory. If the client has a staging area,
+    /// files are staged, so that their digests are only computed if they
+    /// have changed since they were last staged.
+    async fn prepare_layer(&self, job: LayerJob, scratch: &Path) -> PublishResult<PreparedLayer> {
+        let scratch = scratch.to_owned();
+        let (media_type, layer) = match job.source {
+            LayerSource::Archive(assets) => {
+                let layer = blocking(move || {
+                    FileLayer::create(&scratch, |out| build_archive(&assets, out).map(drop))
+                })
+                .await?;
+                (ARCHIVE_LAYER_MEDIA_TYPE, layer)
+            }
+            LayerSource::File(path, media_type)
+                if media_type == DATA_LAYER_MEDIA_TYPE && self.compression != Compression::None =>
+            {
+                let compression = self.compression;
+                let layer = blocking(move || {
+                    FileLayer::create(&scratch, |out| compression.compress_file(&path, out))
+                })
+                .await?;
+                (compression.data_layer_media_type(), layer)
+            }
+            LayerSource::File(path, media_type) => match &self.staging {
+                Some(staging) => (media_type, staging.stage(&path).await?.into()),
+                None => (
+                    media_type,
+                    blocking(move || FileLayer::from_file(path)).await?,
+                ),
+            },
+        };
+        Ok(PreparedLayer {
+            layer,
+            media_type,
+            annotations: job.annotations,
+            slot: job.slot,
+        })
+    }
+}
+
+/// Reads a locked application from a JSON file, such as one written by an
+/// earlier build stage, so that it can be pushed without its manifest. As
+/// its content is read when it is pushed, every component's Wasm module and
+/// asset files must be local files which still exist.
+pub fn read_locked_app(path: &Path) -> PublishResult<LockedApp> {
+    let data = std::fs::read(path).map_err(|source| PublishError::Io {
+        description: format!("Failed to read {}", path.display()),
+        source,
+    })?;
+    let app = LockedApp::from_json(&data).map_err(|e| {
+        PublishError::Other(anyhow::anyhow!(
+            "{} is not a locked application: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    for component in &app.components {
+        let contents = std::iter::once(&component.source.content)
+            .chain(component.files.iter().map(|f| &f.content));
+        for content in contents {
+            let local = local_path(content, &component.id)?;
+            if !local.exists() {
+                return Err(PublishError::Other(anyhow::anyhow!(
+                    "Component {} refers to {}, which does not exist",
+                    component.id,
+                    local.display()
+                )));
+            }
+        }
+    }
+    Ok(app)
+}
+
+/// A layer of an application to be prepared for upload.
+struct LayerJob {
+    source: LayerSource,
+    annotations: HashMap<String, String>,
+    slot: Slot,
+}
+
+/// The local content of a layer.
+enum LayerSource {
+    /// A file, pushed as a layer of the given media type
+    File(PathBuf, &'static str),
+    /// A component's asset files, as pairs of guest path and host file,
+    /// pushed as a single archive layer
+    Archive(Vec<(PathBuf, PathBuf)>),
+}
+
+/// Where the digest of a layer belongs in the config.
+enum Slot {
+    /// The source of the component with the given index
+    Source(usize),
+    /// A file of the component with the given index, at the given path in
+    /// its file system
+    File(usize, PathBuf),
+}
+
+/// A layer which is ready to upload.
+struct PreparedLayer {
+    layer: FileLayer,
+    media_type: &'static str,
+    annotations: HashMap<String, String>,
+    slot: Slot,
+}
+
+/// What a push does when the registry refuses to replace an existing tag,
+/// as registries which enforce immutable tags do.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExistingTagPolicy {
+    /// Fail the push.
+    #[default]
+    Error,
+    /// Push to the first of `<tag>-1`, `<tag>-2` and so on which the
+    /// registry accepts.
+    Bump,
+    /// Push the manifest by its digest, without a tag.
+    DigestOnly,
+}
+
+impl std::str::FromStr for ExistingTagPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::Error),
+            "bump" => Ok(Self::Bump),
+            "digest-only" => Ok(Self::DigestOnly),
+            _ => Err(format!(
+                "unknown existing tag policy '{}': expected error, bump or digest-only",
+                s
+            )),
+        }
+    }
+}
+
+/// Whether a registry's response to a manifest push means that it refused
+/// to replace an existing tag because its tags are immutable.
+pub(super) fn is_immutable_tag_rejection(status: u16, body: &str) -> bool {
+    if !matches!(status, 400 | 403 | 405 | 409 | 412) {
+        return false;
+    }
+    let body = body.to_ascii_lowercase();
+    body.contains("immutable")
+        || body.contains("already exists")
+        || body.contains("cannot be overwritten")
+}
+
+/// The outcome of pushing an application.
+#[derive(Clone, Debug)]
+pub struct PushResult {
+    /// The digest of the pushed manifest, by which the application can be
+    /// referred to independently of its tag
+    pub manifest_digest: String,
+    /// The tag or digest the manifest was pushed to, which differs from the
+    /// one requested if the registry refused to replace an existing tag
+    pub target: String,
+    /// The layers of the pushed application
+    pub layers: Vec<PushedLayer>,
+    /// The total size in bytes of the distinct layers and the config
+    pub total_bytes: u64,
+    /// Conditions which did not stop the push, such as files excluded by
+    /// the filter or media types the registry rejected
+    pub warnings: Vec<PublishWarning>,
+}
+
+impl PushResult {
+    /// The reference by which the pushed application can be pulled: the
+    /// pushed reference, with the tag or digest it was actually pushed to.
+    pub fn pushed_reference(&self, reference: &str) -> PublishResult<String> {
+        let parsed = parse_reference(reference)?;
+        let separator = if is_digest(&self.target) { '@' } else { ':' };
+        Ok(format!(
+            "{}/{}{}{}",
+            parsed.registry(),
+            parsed.repository(),
+            separator,
+            self.target
+        ))
+    }
+
+    /// The total size in bytes of the distinct layers which were uploaded,
+    /// rather than already being in the repository.
+    pub fn uploaded_bytes(&self) -> u64 {
+        let uploaded: HashMap<_, _> = self
+            .layers
+            .iter()
+            .filter(|l| l.uploaded)
+            .map(|l| (&l.digest, l.size))
+            .collect();
+        uploaded.values().sum()
+    }
+}
+
+/// What pushing an application would upload, as assembled by
+/// [`Client::dry_run_push`].
+#[derive(Clone, Debug)]
+pub struct DryRunPush {
+    /// The manifest which would be pushed
+    pub manifest: Vec<u8>,
+    /// The media type of the manifest
+    pub manifest_media_type: String,
+    /// The config object: the locked application, with each local file
+    /// reference replaced by the digest of the layer holding its content
+    pub config: Vec<u8>,
+    /// The digest the manifest would have and the layers it would list, of
+    /// which none are marked as uploaded
+    pub result: PushResult,
+}
+
+/// A layer of a pushed application. A file used in more than one place is
+/// uploaded once, but has a layer for each place, annotated with where it
+/// belongs.
+#[derive(Clone, Debug, Serialize)]
+pub struct PushedLayer {
+    /// The digest of the layer content
+    pub digest: String,
+    /// The size of the layer in bytes
+    pub size: u64,
+    /// The Spin media type of the layer, whichever profile the manifest
+    /// was pushed with
+    pub media_type: String,
+    /// Whether the layer was uploaded, rather than already being in the
+    /// repository
+    pub uploaded: bool,
+    /// The annotations recording which component the layer belongs to and,
+    /// for an asset file, where it is in the component's file system
+    pub annotations: HashMap<String, String>,
+}
+
+/// A blob which has been pushed, with the Spin media type of its content
+/// and the annotations of the layer it makes up.
+#[derive(Clone)]
+pub(super) struct PushedBlob {
+    pub(super) digest: String,
+    pub(super) size: i64,
+    media_type: &'static str,
+    uploaded: bool,
+    annotations: HashMap<String, String>,
+}
+
+/// The state of a single push: the registry authorization, which is
+/// obtained on the first request and reused, and the layers pushed so far.
+/// In a dry run, nothing is sent to the registry, and the manifest is kept
+/// instead of being uploaded.
+pub(super) struct PushSession<'a> {
+    client: &'a Client,
+    registry: &'a str,
+    repository: &'a str,
+    auth: RegistryAuth,
+    authorization: Option<Authorization>,
+    blobs: Vec<PushedBlob>,
+    dry_run: bool,
+    manifest: Option<(String, Vec<u8>)>,
+    /// Layers which are in the repository, to record in a resume token
+    pushed: Vec<String>,
+    /// The layer being uploaded, if any
+    uploading: Option<String>,
+    /// The chunked upload under way, if any
+    partial: Option<PartialUpload>,
+    /// Layers which a resumed push need not upload
+    resumed: HashSet<String>,
+    /// An unfinished chunked upload of a resumed push
+    resumed_upload: Option<PartialUpload>,
+    /// Conditions which did not stop the push, to report with its result
+    warnings: Vec<PublishWarning>,
+}
+
+impl<'a> PushSession<'a> {
+    pub(super) fn new(client: &'a Client, registry: &'a str, repository: &'a str) -> Self {
+        let (auth, warnings) = registry_auth_with_warnings(registry);
+        Self {
+            client,
+            registry,
+            repository,
+            auth,
+            authorization: None,
+            blobs: vec![],
+            dry_run: false,
+            manifest: None,
+            pushed: vec![],
+            uploading: None,
+            partial: None,
+            resumed: HashSet::new(),
+            resumed_upload: None,
+            warnings,
+        }
+    }
+
+    /// Resumes the push recorded by a resume token.
+    fn resume(&mut self, token: &ResumeToken) {
+        self.resumed.extend(token.pushed.iter().cloned());
+        self.resumed_upload = token.partial.clone();
+    }
+
+    /// Records how far a failed push got, unless it got nowhere, in which
+    /// case the error is returned as it is.
+    fn incomplete(&mut self, reference: &str, error: PublishError) -> PublishError {
+        let partial = self.partial.take().or_else(|| self.resumed_upload.take());
+        if self.pushed.is_empty() && partial.is_none() {
+            return error;
+        }
+        let token = ResumeToken {
+            reference: reference.to_owned(),
+            pushed: std::mem::take(&mut self.pushed),
+            failed: self.uploading.take(),
+            partial,
+        };
+        PublishError::PushIncomplete {
+            token: Box::new(token),
+            source: Box::new(error),
+        }
+    }
+
+    /// Pushes a prepared layer with the given annotations, uploading its
+    /// content unless the same content has already been pushed.
+    async fn push_layer(
+        &mut self,
+        layer: &FileLayer,
+        media_type: &'static str,
+        annotations: HashMap<String, String>,
+    ) -> PublishResult<()> {
+        if self.reuse_layer(&layer.digest, &annotations) {
+            return Ok(());
+        }
+        let pushed = self.push_file(layer, media_type).await?;
+        self.add_layer(PushedBlob {
+            annotations,
+            ..pushed
+        });
+        Ok(())
+    }
+
+    /// Adds a layer with the given annotations for content which has
+    /// already been pushed, if it has. Returns whether it had been.
+    fn reuse_layer(&mut self, digest: &str, annotations: &HashMap<String, String>) -> bool {
+        let layer = match self.blobs.iter().find(|b| b.digest == digest) {
+            Some(pushed) => PushedBlob {
+                uploaded: false,
+                annotations: annotations.clone(),
+                ..pushed.clone()
+            },
+            None => return false,
+        };
+        self.add_layer(layer);
+        true
+    }
+
+    /// Adds a layer to the manifest, unless it would duplicate one already
+    /// there.
+    fn add_layer(&mut self, layer: PushedBlob) {
+        let duplicate = self
+            .blobs
+            .iter()
+            .any(|b| b.digest == layer.digest && b.annotations == layer.annotations);
+        if !duplicate {
+            self.blobs.push(layer);
+        }
+    }
+
+    /// Uploads a blob in a single request.
+    pub(super) async fn push_blob(
+        &mut self,
+        data: Vec<u8>,
+        digest: &str,
+        media_type: &'static str,
+    ) -> PublishResult<PushedBlob> {
+        let size = data.len() as i64;
+        if self.dry_run {
+            return Ok(PushedBlob {
+                digest: digest.to_owned(),
+                size,
+                media_type,
+                uploaded: false,
+                annotations: HashMap::new(),
+            });
+        }
+        if let Some(existing) = self.existing_blob(digest, size, media_type).await? {
+            return Ok(existing);
+        }
+
+        let location = self.start_upload().await?;
+        let upload_url = with_digest(&location, digest);
+        let response = self
+            .send(|http| {
+                http.put(&upload_url)
+                    .header(CONTENT_TYPE, BLOB_MEDIA_TYPE)
+                    .body(data.clone())
+            })
+            .await?;
+        if !response.status().is_success() {
+            return Err(registry_response_error(&upload_url, response).await);
+        }
+
+        Ok(PushedBlob {
+            digest: digest.to_owned(),
+            size,
+            media_type,
+            uploaded: true,
+            annotations: HashMap::new(),
+        })
+    }
+
+    /// Uploads a blob from the cache, unless the repository already has it.
+    /// Blobs larger than a single upload chunk are uploaded in chunks.
+    pub(super) async fn push_cached_blob(
+        &mut self,
+        cache: &Cache,
+        digest: &str,
+    ) -> PublishResult<PushedBlob> {
+        let path = cache.blob_path(digest);
+        let size = tokio::fs::metadata(&path)
+            .await
+            .map_err(|source| PublishError::Io {
+                source,
+                description: format!("Failed to read cached blob {}", digest),
+            })?
+            .len();
+        let layer = FileLayer {
+            digest: digest.to_owned(),
+            path,
+            size,
+        };
+        self.push_file(&layer, BLOB_MEDIA_TYPE).await
+    }
+
+    /// Uploads a file-backed layer, in a single request if it is small
+    /// enough and in chunks otherwise.
+    async fn push_file(
+        &mut self,
+        layer: &FileLayer,
+        media_type: &'static str,
+    ) -> PublishResult<PushedBlob> {
+        if self.resumed.contains(&layer.digest) {
+            self.pushed.push(layer.digest.clone());
+            return Ok(PushedBlob {
+                digest: layer.digest.clone(),
+                size: layer.size as i64,
+                media_type,
+                uploaded: false,
+                annotations: HashMap::new(),
+            });
+        }
+
+        self.uploading = Some(layer.digest.clone());
+        let pushed = if layer.size > UPLOAD_CHUNK_SIZE {
+            self.push_file_chunked(&layer.path, &layer.digest, layer.size, media_type)
+                .await?
+        } else {
+            let data = read_file(&layer.path).await?;
+            self.push_blob(data, &layer.digest, media_type).await?
+        };
+        self.uploading = None;
+        if !self.dry_run {
+            self.pushed.push(layer.digest.clone());
+        }
+        Ok(pushed)
+    }
+
+    /// Uploads a file in chunks, so that only one chunk is held in memory
+    /// at a time. If a chunk fails to upload, the upload resumes from
+    /// wherever the registry reports that it got to.
+    async fn push_file_chunked(
+        &mut self,
+        path: &Path,
+        digest: &str,
+        size: u64,
+        media_type: &'static str,
+    ) -> PublishResult<PushedBlob> {
+        if self.dry_run {
+            return Ok(PushedBlob {
+                digest: digest.to_owned(),
+                size: size as i64,
+                media_type,
+                uploaded: false,
+                annotations: HashMap::new(),
+            });
+        }
+        if let Some(existing) = self.existing_blob(digest, size as i64, media_type).await? {
+            return Ok(existing);
+        }
+        let io_error = |source: std::io::Error| PublishError::Io {
+            description: format!("Failed to read {}", path.display()),
+            source,
+        };
+
+        let mut file = tokio::fs::File::open(path).await.map_err(io_error)?;
+        let (mut location, mut offset) = match self.resumed_upload.take() {
+            Some(upload) if upload.digest == digest => {
+                match self.upload_offset(&upload.location).await {
+                    Ok(offset) => {
+                        tracing::info!("Resuming upload of {} from byte {}", digest, offset);
+                        (upload.location, offset)
+                    }
+                    Err(e) => {
+                        tracing::info!("Cannot resume upload of {} ({}); restarting it", digest, e);
+                        (self.start_upload().await?, 0)
+                    }
+                }
+            }
+            other => {
+                self.resumed_upload = other;
+                (self.start_upload().await?, 0)
+            }
+        };
+        let mut retries = 0;
+
+        while offset < size {
+            let len = UPLOAD_CHUNK_SIZE.min(size - offset);
+            let mut chunk = vec![0; len as usize];
+            file.seek(SeekFrom::Start(offset)).await.map_err(io_error)?;
+            file.read_exact(&mut chunk).await.map_err(io_error)?;
+            let range = format!("{}-{}", offset, offset + len - 1);
+
+            let result = self
+                .send(|http| {
+                    http.patch(&location)
+                        .header(CONTENT_TYPE, BLOB_MEDIA_TYPE)
+                        .header(CONTENT_RANGE, &range)
+                        .header(CONTENT_LENGTH, len)
+                        .body(chunk.clone())
+                })
+                .await;
+            let failure = match result {
+                Ok(response) if response.status() == StatusCode::ACCEPTED => {
+                    location = upload_location(&location, &response, self.base_url())?;
+                    offset += len;
+                    retries = 0;
+                    self.partial = Some(PartialUpload {
+                        digest: digest.to_owned(),
+                        location: location.clone(),
+                    });
+                    continue;
+                }
+                Ok(response) if is_retryable(response.status()) => {
+                    registry_response_error(&location, response).await
+                }
+                Ok(response) => return Err(registry_response_error(&location, response).await),
+                Err(e) => e,
+            };
+
+            if retries == MAX_CHUNK_RETRIES {
+                return Err(failure);
+            }
+            retries += 1;
+            offset = self.upload_offset(&location).await?;
+            tracing::info!(
+                "Chunk upload of {} failed ({}); resuming from byte {}",
+                path.display(),
+                failure,
+                offset
+            );
+        }
+
+        let upload_url = with_digest(&location, digest);
+        let response = self
+            .send(|http| http.put(&upload_url).header(CONTENT_LENGTH, 0))
+            .await?;
+        if !response.status().is_success() {
+            return Err(registry_response_error(&upload_url, response).await);
+        }
+        self.partial = None;
+
+        Ok(PushedBlob {
+            digest: digest.to_owned(),
+            size: size as i64,
+            media_type,
+            uploaded: true,
+            annotations: HashMap::new(),
+        })
+    }
+
+    /// Describes the blob with the given digest if the repository already
+    /// has it, so that it need not be uploaded again.
+    async fn existing_blob(
+        &mut self,
+        digest: &str,
+        size: i64,
+        media_type: &'static str,
+    ) -> PublishResult<Option<PushedBlob>> {
+        let url = format!(
+            "{}/v2/{}/blobs/{}",
+            self.base_url(),
+            self.repository,
+            digest
+        );
+        let response = self.send(|http| http.head(&url)).await?;
+        match response.status() {
+            s if s.is_success() => {
+                tracing::debug!("Skipping upload of {}: already in the repository", digest);
+                Ok(Some(PushedBlob {
+                    digest: digest.to_owned(),
+                    size,
+                    media_type,
+                    uploaded: false,
+                    annotations: HashMap::new(),
+                }))
+            }
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Err(registry_response_error(&url, response).await),
+        }
+    }
+
+    /// Starts a blob upload, returning the URL to upload the content to.
+    async fn start_upload(&mut self) -> PublishResult<String> {
+        let url = format!("{}/v2/{}/blobs/uploads/", self.base_url(), self.repository);
+        let response = self
+            .send(|http| http.post(&url).header(CONTENT_LENGTH, 0))
+            .await?;
+        if response.status() != StatusCode::ACCEPTED {
+            return Err(registry_response_error(&url, response).await);
+        }
+        upload_location(&url, &response, self.base_url())
+    }
+
+    /// How much of an upload the registry has received.
+    async fn upload_offset(&mut self, location: &str) -> PublishResult<u64> {
+        let response = self.send(|http| http.get(location)).await?;
+        if !response.status().is_success() {
+            return Err(registry_response_error(location, response).await);
+        }
+        let range = response.headers().get(RANGE).and_then(|r| r.to_str().ok());
+        Ok(received_bytes(range))
+    }
+
+    fn base_url(&self) -> String {
+        format!("{}://{}", self.client.scheme(self.registry), self.registry)
+    }
+
+    /// Pushes an image manifest for the pushed layers and config, using
+    /// Spin's media types unless the registry rejects them.
+    async fn push_image_manifest(
+        &mut self,
+        target: &str,
+        config: &PushedBlob,
+    ) -> PublishResult<String> {
+        match self
+            .push_manifest(target, config, MediaTypeProfile::Native)
+            .await
+        {
+            Err(PublishError::RegistryResponse {
+                status, message, ..
+            }) if is_media_type_rejection(status, &message) => {
+                tracing::info!(
+                    "{} rejected Spin media types; pushing with the compatible profile",
+                    self.registry
+                );
+                self.warnings.push(PublishWarning::FallbackMediaType {
+                    registry: self.registry.to_owned(),
+                    rejected: "Spin media types".to_owned(),
+                    pushed: "compatible media types".to_owned(),
+                });
+                self.push_manifest(target, config, MediaTypeProfile::Compatible)
+                    .await
+            }
+            result => result,
+        }
+    }
+
+    /// Pushes an OCI artifact manifest with the Spin artifact type for the
+    /// pushed layers and config.
+    async fn push_artifact_manifest(
+        &mut self,
+        target: &str,
+        config: &PushedBlob,
+    ) -> PublishResult<String> {
+        let profile = MediaTypeProfile::Native;
+        let manifest = ArtifactManifest::spin(
+            describe(config, profile),
+            self.blobs.iter().map(|b| describe(b, profile)).collect(),
+            non_empty(self.manifest_annotations(profile)),
+        );
+        let data = serde_json::to_vec(&manifest).map_err(|e| {
+            PublishError::Other(anyhow::anyhow!("Failed to serialize manifest: {}", e))
+        })?;
+        self.put_manifest(target, ARTIFACT_MANIFEST_MEDIA_TYPE, data)
+            .await
+    }
+
+    /// Pushes the manifest for the pushed layers and config, describing
+    /// them using the given profile.
+    async fn push_manifest(
+        &mut self,
+        target: &str,
+        config: &PushedBlob,
+        profile: MediaTypeProfile,
+    ) -> PublishResult<String> {
+        let manifest = OciImageManifest {
+            schema_version: 2,
+            media_type: Some(profile.manifest_media_type().to_owned()),
+            config: describe(config, profile),
+            layers: self.blobs.iter().map(|b| describe(b, profile)).collect(),
+            annotations: non_empty(self.manifest_annotations(profile)),
+        };
+        let data = serde_json::to_vec(&manifest).map_err(|e| {
+            PublishError::Other(anyhow::anyhow!("Failed to serialize manifest: {}", e))
+        })?;
+        self.put_manifest(target, profile.manifest_media_type(), data)
+            .await
+    }
+
+    /// The annotations of a manifest pushed with the given profile: those
+    /// of the profile and any given to the client.
+    fn manifest_annotations(&self, profile: MediaTypeProfile) -> HashMap<String, String> {
+        let mut annotations = profile.manifest_annotations();
+        if profile.supports_annotations() {
+            annotations.extend(self.client.annotations.clone());
+        }
+        annotations
+    }
+
+    /// Uploads a manifest of the given media type to the target tag or
+    /// digest, returning the digest of the manifest.
+    pub(super) async fn put_manifest(
+        &mut self,
+        target: &str,
+        media_type: &str,
+        data: Vec<u8>,
+    ) -> PublishResult<String> {
+        let fallback_digest = sha256_digest(&data);
+        self.manifest = Some((media_type.to_owned(), data.clone()));
+        if self.dry_run {
+            return Ok(fallback_digest);
+        }
+        let response = self.put_manifest_response(target, media_type, data).await?;
+        let digest = response
+            .headers()
+            .get(DOCKER_CONTENT_DIGEST_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_owned())
+            .unwrap_or(fallback_digest);
+        Ok(digest)
+    }
+
+    /// Uploads a manifest as [`put_manifest`](Self::put_manifest) does,
+    /// returning the registry's successful response.
+    pub(super) async fn put_manifest_response(
+        &mut self,
+        target: &str,
+        media_type: &str,
+        data: Vec<u8>,
+    ) -> PublishResult<reqwest::Response> {
+        let url = format!(
+            "{}://{}/v2/{}/manifests/{}",
+            self.client.scheme(self.registry),
+            self.registry,
+            self.repository,
+            target
+        );
+        let response = self
+            .send(|http| {
+                http.put(&url)
+                    .header(CONTENT_TYPE, media_type)
+                    .body(data.clone())
+            })
+            .await?;
+        if !response.status().is_success() {
+            return Err(registry_response_error(&url, response).await);
+        }
+        Ok(response)
+    }
+
+    /// Sends a request, answering the registry's authentication challenge
+    /// if it issues one. The request is built afresh if it has to be
+    /// resent with credentials.
+    async fn send(
+        &mut self,
+        request: impl Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    ) -> PublishResult<reqwest::Response> {
+        // Every request of the session goes to its registry, so they all use
+        // the registry's client, with its client certificate and TLS settings
+        let http = self.client.http_for(&self.base_url());
+        let build = |authorization: Option<&Authorization>| match authorization {
+            Some(authorization) => authorization.apply(request(http)),
+            None => request(http),
+        };
+
+        let response = build(self.authorization.as_ref()).send().await?;
+        if response.status() != StatusCode::UNAUTHORIZED || self.authorization.is_some() {
+            return Ok(response);
+        }
+        match Challenge::from_response(&response) {
+            Some(challenge) => {
+                let scope = format!("repository:{}:pull,push", self.repository);
+                let answer = challenge.authorize(http, &scope, &self.auth).await?;
+                let response = build(Some(&answer)).send().await?;
+                self.authorization = Some(answer);
+                Ok(response)
+            }
+            None => Ok(response),
+        }
+    }
+}
+
+/// The upload URL given by the location header of an upload response,
+/// which may be relative to the registry.
+fn upload_location(
+    url: &str,
+    response: &reqwest::Response,
+    base_url: String,
+) -> PublishResult<String> {
+    let location = response
+        .headers()
+        .get(LOCATION)
+        .and_then(|l| l.to_str().ok())
+        .ok_or_else(|| PublishError::RegistryResponse {
+            url: url.to_owned(),
+            status: response.status().as_u16(),
+            message: "upload response has no location".to_owned(),
+        })?;
+    Ok(match location.strip_prefix('/') {
+        Some(path) => format!("{}/{}", base_url, path),
+        None => location.to_owned(),
+    })
+}
+
+/// The tags tried in turn when the registry refuses to replace `tag`:
+/// `<tag>-1`, `<tag>-2` and so on, up to [`MAX_TAG_BUMPS`].
+fn bumped_tags(tag: &str) -> impl Iterator<Item = String> + '_ {
+    (1..=MAX_TAG_BUMPS).map(move |n| format!("{}-{}", tag, n))
+}
+
+/// How many bytes of an upload the registry has received, given the range
+/// it reports. The range is inclusive, as in `0-1023`.
+fn received_bytes(range: Option<&str>) -> u64 {
+    range
+        .and_then(|r| r.split_once('-'))
+        .and_then(|(_, end)| end.parse::<u64>().ok())
+        .map_or(0, |end| end + 1)
+}
+
+/// The URL which completes an upload of content with the given digest.
+fn with_digest(location: &str, digest: &str) -> String {
+    let separator = if location.contains('?') { '&' } else { '?' };
+    format!("{}{}digest={}", location, separator, digest)
+}
+
+/// Whether a chunk upload which failed with the given status is worth
+/// resuming.
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::RANGE_NOT_SATISFIABLE
+}
+
+/// Describes a pushed blob using the given profile, with the annotations
+/// of its layer.
+fn describe(blob: &PushedBlob, profile: MediaTypeProfile) -> OciDescriptor {
+    let (media_type, profile_annotations) = profile.describe(blob.media_type);
+    let mut annotations = blob.annotations.clone();
+    annotations.extend(profile_annotations.unwrap_or_default());
+    let annotations = non_empty(annotations).filter(|_| profile.supports_annotations());
+    OciDescriptor {
+        media_type,
+        digest: blob.digest.clone(),
+        size: blob.size,
+        urls: None,
+        annotations,
+    }
+}
+
+fn non_empty(annotations: HashMap<String, String>) -> Option<HashMap<String, String>> {
+    (!annotations.is_empty()).then_some(annotations)
+}
+
+/// The annotations recording where a layer belongs: the component which
+/// uses it and, for a static asset file, its path in the component's file
+/// system.
+fn layer_annotations(component_id: &str, guest_path: Option<&Path>) -> HashMap<String, String> {
+    let mut annotations =
+        HashMap::from([(COMPONENT_ANNOTATION.to_owned(), component_id.to_owned())]);
+    if let Some(guest_path) = guest_path {
+        annotations.insert(
+            GUEST_PATH_ANNOTATION.to_owned(),
+            guest_path.to_string_lossy().into_owned(),
+        );
+    }
+    annotations
+}
+
+async fn read_file(path: &Path) -> PublishResult<Vec<u8>> {
+    tokio::fs::read(path)
+        .await
+        .map_err(|source| PublishError::Io {
+            description: format!("Failed to read {}", path.display()),
+            source,
+        })
+}
+
+/// Runs blocking work, such as hashing or compression, off the async
+/// executor, so that it does not hold up uploads.
+pub(super) async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> PublishResult<T> + Send + 'static,
+) -> PublishResult<T> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|e| PublishError::Other(e.into()))?
+}
+
+fn digest_ref(digest: String) -> ContentRef {
+    ContentRef {
+        source: None,
+        digest: Some(digest),
+    }
+}
+
+/// The local path of content referred to by a `file:` URL.
+fn local_path(content: &ContentRef, component_id: &str) -> PublishResult<PathBuf> {
+    let source = content.source.as_deref().ok_or_else(|| {
+        PublishError::Other(anyhow::anyhow!(
+            "Component {} refers to content which is not a local file",
+            component_id
+        ))
+    })?;
+    url::Url::parse(source)
+        .ok()
+        .filter(|url| url.scheme() == "file")
+        .and_then(|url| url.to_file_path().ok())
+        .ok_or_else(|| {
+            PublishError::Other(anyhow::anyhow!(
+                "Component {} refers to {}, which is not a local file",
+                component_id,
+                source
+            ))
+        })
+}
+
+/// The files making up a static asset mount, with their paths relative to
+/// the mount. A file mount is a single file with an empty relative path.
+/// Symbolic links within the mount are skipped unless they are followed.
+fn asset_files(host_path: &Path, follow_symlinks: bool) -> PublishResult<Vec<(PathBuf, PathBuf)>> {
+    let io_error = |path: &Path| {
+        let description = format!("Failed to read assets in {}", path.display());
+        move |source| PublishError::Io {
+            description,
+            source,
+        }
+    };
+
+    if !host_path.is_dir() {
+        return Ok(vec![(PathBuf::new(), host_path.to_owned())]);
+    }
+
+    let mut files = vec![];
+    let mut dirs = vec![host_path.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir).map_err(io_error(&dir))? {
+            let entry = entry.map_err(io_error(&dir))?;
+            let path = entry.path();
+            let is_symlink = entry.file_type().map_err(io_error(&path))?.is_symlink();
+            if is_symlink && !follow_symlinks {
+                continue;
+            }
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                let relative_path = path
+                    .strip_prefix(host_path)
+                    .expect("walked path should be within the mount")
+                    .to_owned();
+                files.push((relative_path, path));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detects_immutable_tag_rejections() {
+        assert!(is_immutable_tag_rejection(
+            400,
+            r#"{"errors":[{"code":"TAG_INVALID","message":"The image tag 'v1' already exists in the 'app' repository and cannot be overwritten because the repository is immutable."}]}"#
+        ));
+        assert!(is_immutable_tag_rejection(
+            412,
+            "the tag v1 is immutable, cannot be overwritten"
+        ));
+        assert!(!is_immutable_tag_rejection(400, "manifest invalid"));
+        assert!(!is_immutable_tag_rejection(401, "already exists"));
+    }
+
+    #[test]
+    fn bumped_tags_are_numbered_up_to_the_limit() {
+        let tags: Vec<_> = bumped_tags("v1").collect();
+        assert_eq!(MAX_TAG_BUMPS, tags.len());
+        assert_eq!("v1-1", tags[0]);
+        assert_eq!(format!("v1-{}", MAX_TAG_BUMPS), tags[MAX_TAG_BUMPS - 1]);
+    }
+
+    #[test]
+    fn chunked_uploads_resume_after_the_received_range() {
+        assert_eq!(1024, received_bytes(Some("0-1023")));
+        assert_eq!(0, received_bytes(None));
+        assert_eq!(0, received_bytes(Some("garbage")));
+        assert_eq!(
+            "https://r.io/up/1?state=x&digest=sha256:abc",
+            with_digest("https://r.io/up/1?state=x", "sha256:abc")
+        );
+        assert_eq!(
+            "https://r.io/up/1?digest=sha256:abc",
+            with_digest("https://r.io/up/1", "sha256:abc")
+        );
+        assert!(is_retryable(StatusCode::BAD_GATEWAY));
+        assert!(is_retryable(StatusCode::RANGE_NOT_SATISFIABLE));
+        assert!(!is_retryable(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn shared_content_gets_a_layer_for_each_place_it_belongs() {
+        let client = Client::new(false).unwrap();
+        let mut session = PushSession::new(&client, "registry.example.com", "app");
+        let a = layer_annotations("a", Some(Path::new("/static/logo.png")));
+        let b = layer_annotations("b", Some(Path::new("/logo.png")));
+        session.add_layer(PushedBlob {
+            digest: "sha256:logo".to_owned(),
+            size: 4,
+            media_type: DATA_LAYER_MEDIA_TYPE,
+            uploaded: true,
+            annotations: a.clone(),
+        });
+
+        assert!(session.reuse_layer("sha256:logo", &b));
+        assert!(session.reuse_layer("sha256:logo", &a));
+        assert!(!session.reuse_layer("sha256:other", &a));
+        assert_eq!(2, session.blobs.len());
+        assert!(!session.blobs[1].uploaded);
+        assert_eq!(
+            Some("/logo.png"),
+            session.blobs[1]
+                .annotations
+                .get(GUEST_PATH_ANNOTATION)
+                .map(String::as_str)
+        );
+
+        let native = describe(&session.blobs[1], MediaTypeProfile::Native);
+        assert_eq!(Some(b), native.annotations);
+        let docker = describe(&session.blobs[1], MediaTypeProfile::Docker);
+        assert_eq!(None, docker.annotations);
+    }
+
+    #[test]
+    fn pushed_references_use_the_pushed_target() {
+        let pushed = |target: &str| PushResult {
+            manifest_digest: "sha256:abc".to_owned(),
+            target: target.to_owned(),
+            layers: vec![],
+            total_bytes: 0,
+            warnings: vec![],
+        };
+        assert_eq!(
+            "ghcr.io/org/app:v1-1",
+            pushed("v1-1")
+                .pushed_reference("ghcr.io/org/app:v1")
+                .unwrap()
+        );
+        assert_eq!(
+            "ghcr.io/org/app@sha256:abc",
+            pushed("sha256:abc")
+                .pushed_reference("ghcr.io/org/app:v1")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn lists_asset_files_relative_to_mount() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("a.txt"), "a").unwrap();
+        std::fs::write(dir.path().join("sub").join("b.txt"), "b").unwrap();
+
+        let files = asset_files(dir.path(), true).unwrap();
+        let relative_paths: Vec<_> = files.iter().map(|(relative, _)| relative).collect();
+        assert_eq!(
+            vec![&PathBuf::from("a.txt"), &Path::new("sub").join("b.txt")],
+            relative_paths
+        );
+
+        let file = dir.path().join("a.txt");
+        assert_eq!(
+            vec![(PathBuf::new(), file.clone())],
+            asset_files(&file, true).unwrap()
+        );
+    }
+
+    #[test]
+    fn locked_app_files_must_refer_to_existing_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let wasm = dir.path().join("app.wasm");
+        let lock = dir.path().join("spin.lock");
+        let app = serde_json::json!({
+            "spin_lock_version": 0,
+            "triggers": [],
+            "components": [{
+                "id": "app",
+                "source": {
+                    "content_type": "application/wasm",
+                    "source": url::Url::from_file_path(&wasm).unwrap().to_string()
+                }
+            }]
+        });
+        std::fs::write(&lock, app.to_string()).unwrap();
+        assert!(read_locked_app(&lock).is_err());
+
+        std::fs::write(&wasm, b"\0asm").unwrap();
+        let app = read_locked_app(&lock).unwrap();
+        assert_eq!("app", app.components[0].id);
+    }
+
+    #[tokio::test]
+    async fn pipelined_layers_keep_manifest_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let wasm = dir.path().join("app.wasm");
+        let assets = dir.path().join("assets");
+        std::fs::write(&wasm, b"\0asm").unwrap();
+        std::fs::create_dir(&assets).unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            std::fs::write(assets.join(name), name).unwrap();
+        }
+        let url = |path: &Path| url::Url::from_file_path(path).unwrap().to_string();
+        let app = serde_json::json!({
+            "spin_lock_version": 0,
+            "triggers": [],
+            "components": [{
+                "id": "app",
+                "source": {"content_type": "application/wasm", "source": url(&wasm)},
+                "files": [{"source": url(&assets), "path": "/"}]
+            }]
+        });
+        let app = LockedApp::from_json(app.to_string().as_bytes()).unwrap();
+
+        let client = Client::new(false).unwrap();
+        let push = client
+            .dry_run_push(&app, "localhost:5000/app:v1")
+            .await
+            .unwrap();
+        let config = LockedApp::from_json(&push.config).unwrap();
+        let component = &config.components[0];
+        assert_eq!(
+            Some(sha256_digest(b"\0asm")),
+            component.source.content.digest
+        );
+        let files: Vec<_> = component
+            .files
+            .iter()
+            .map(|f| (f.path.clone(), f.content.digest.clone().unwrap()))
+            .collect();
+        let expected: Vec<_> = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|name| (Path::new("/").join(name), sha256_digest(name.as_bytes())))
+            .collect();
+        assert_eq!(expected, files);
+        assert_eq!(4, push.result.layers.len());
+    }
+
+    #[tokio::test]
+    async fn dry_runs_assemble_the_manifest_without_uploading() {
+        let dir = tempfile::tempdir().unwrap();
+        let wasm = dir.path().join("app.wasm");
+        std::fs::write(&wasm, b"\0asm").unwrap();
+        let app = serde_json::json!({
+            "spin_lock_version": 0,
+            "triggers": [],
+            "components": [{
+                "id": "app",
+                "source": {
+                    "content_type": "application/wasm",
+                    "source": url::Url::from_file_path(&wasm).unwrap().to_string()
+                }
+            }]
+        });
+        let app = LockedApp::from_json(app.to_string().as_bytes()).unwrap();
+
+        // Nothing listens on the registry, so any request would fail
+        let client = Client::new(false).unwrap();
+        let push = client
+            .dry_run_push(&app, "registry.invalid/app:v1")
+            .await
+            .unwrap();
+        assert_eq!(sha256_digest(&push.manifest), push.result.manifest_digest);
+        assert!(push.result.layers.iter().all(|l| !l.uploaded));
+
+        let manifest: OciImageManifest = serde_json::from_slice(&push.manifest).unwrap();
+        assert_eq!(sha256_digest(&push.config), manifest.config.digest);
+        assert_eq!(sha256_digest(b"\0asm"), manifest.layers[0].digest);
+    }
+}
@@ -0,0 +1,150 @@
+//! Connection settings for particular registries, so that a local
+//! development registry can be used over plain HTTP, or with a self-signed
+//! certificate, without every registry being accessed that way.
+
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use super::Client;
+use crate::{PublishError, PublishResult};
+
+/// The name of the registry configuration file in Spin's configuration
+/// directory, which is read if no other file is given.
+pub const REGISTRY_CONFIG_FILE: &str = "registries.toml";
+
+/// Connection settings for particular registries, as containerd's registry
+/// host configuration has.
+///
+/// A configuration is read from a TOML file such as:
+///
+/// ```toml
+/// [[registry]]
+/// host = "localhost:5000"
+/// plain_http = true
+///
+/// [[registry]]
+/// host = "registry.dev.internal"
+/// skip_verify = true
+/// ```
+///
+/// Registries not in the configuration are accessed over HTTPS with
+/// certificate verification, unless the client is insecure.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegistryConfig {
+    /// The registries with settings of their own
+    #[serde(default, rename = "registry")]
+    pub registries: Vec<RegistryHost>,
+}
+
+/// The connection settings for a registry.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegistryHost {
+    /// The registry host, with the port if it is not the default, as in
+    /// references
+    pub host: String,
+    /// Access the registry over plain HTTP
+    #[serde(default)]
+    pub plain_http: bool,
+    /// Accept the registry's certificate without verifying it, as for a
+    /// self-signed certificate
+    #[serde(default)]
+    pub skip_verify: bool,
+}
+
+impl RegistryConfig {
+    /// Reads a registry configuration file.
+    pub fn load(path: impl AsRef<Path>) -> PublishResult<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| PublishError::Io {
+            source: e,
+            description: format!("Failed to read registry configuration {}", path.display()),
+        })?;
+        toml::from_str(&text).map_err(|e| {
+            PublishError::Other(anyhow::anyhow!(
+                "Invalid registry configuration {}: {}",
+                path.display(),
+                e
+            ))
+        })
+    }
+
+    /// Reads the registry configuration file in Spin's configuration
+    /// directory, or returns an empty configuration if there is none.
+    pub fn load_default() -> PublishResult<Self> {
+        match Self::default_path() {
+            Some(path) if path.exists() => Self::load(path),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    /// The registry configuration file in Spin's configuration directory.
+    pub fn default_path() -> Option<PathBuf> {
+        Some(dirs::config_dir()?.join("spin").join(REGISTRY_CONFIG_FILE))
+    }
+
+    /// Whether a registry is accessed over plain HTTP.
+    pub fn plain_http(&self, registry: &str) -> bool {
+        self.host(registry).map_or(false, |host| host.plain_http)
+    }
+
+    /// Whether a registry's certificate is accepted without verification.
+    pub fn skip_verify(&self, registry: &str) -> bool {
+        self.host(registry).map_or(false, |host| host.skip_verify)
+    }
+
+    /// The hosts whose certificates are accepted without verification.
+    pub(super) fn skip_verify_hosts(&self) -> impl Iterator<Item = &str> {
+        self.registries
+            .iter()
+            .filter(|host| host.skip_verify)
+            .map(|host| host.host.as_str())
+    }
+
+    fn host(&self, registry: &str) -> Option<&RegistryHost> {
+        self.registries.iter().find(|host| host.host == registry)
+    }
+}
+
+impl Client {
+    /// Applies connection settings for particular registries, such as
+    /// accessing a local registry over plain HTTP, in addition to the
+    /// client's settings for all registries.
+    pub fn with_registry_config(mut self, config: RegistryConfig) -> PublishResult<Self> {
+        self.registry_config = config;
+        self.rebuild_http()?;
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn settings_apply_to_their_registry_only() {
+        let config: RegistryConfig = toml::from_str(
+            r#"
+            [[registry]]
+            host = "localhost:5000"
+            plain_http = true
+
+            [[registry]]
+            host = "registry.dev.internal"
+            skip_verify = true
+            "#,
+        )
+        .unwrap();
+        assert!(config.plain_http("localhost:5000"));
+        assert!(!config.plain_http("localhost:5001"));
+        assert!(!config.plain_http("registry.dev.internal"));
+        assert!(config.skip_verify("registry.dev.internal"));
+        assert!(!config.skip_verify("ghcr.io"));
+        assert_eq!(
+            vec!["registry.dev.internal"],
+            config.skip_verify_hosts().collect::<Vec<_>>()
+        );
+    }
+}
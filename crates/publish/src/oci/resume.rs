@@ -0,0 +1,128 @@
+//! Resume tokens, which record how far a failed push got, so that pushing
+//! again uploads only what is left.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use spin_loader::digest::bytes_sha256_string;
+
+use crate::{PublishError, PublishResult};
+
+/// How far a failed push got: the blobs which are in the repository, and so
+/// need not be uploaded again, and the upload which failed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeToken {
+    /// The reference being pushed
+    pub reference: String,
+    /// The digests of the layers which are in the repository, whether the
+    /// push uploaded them or they were already there
+    pub pushed: Vec<String>,
+    /// The digest of the layer whose upload failed, if the push failed
+    /// while uploading a layer
+    pub failed: Option<String>,
+    /// A chunked upload which was under way when the push failed, which is
+    /// continued from wherever the registry got to
+    pub partial: Option<PartialUpload>,
+}
+
+/// A chunked upload which did not finish.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartialUpload {
+    /// The digest of the content being uploaded
+    pub digest: String,
+    /// The URL of the upload session
+    pub location: String,
+}
+
+impl ResumeToken {
+    /// The directory in which resume tokens are kept if none is specified.
+    pub fn default_dir() -> PublishResult<PathBuf> {
+        let cache_dir = dirs::cache_dir()
+            .ok_or_else(|| PublishError::Other(anyhow::anyhow!("Cannot find cache directory")))?;
+        Ok(cache_dir.join("spin").join("push-resume"))
+    }
+
+    /// Loads the token saved in the directory for a push to the reference,
+    /// if there is one.
+    pub async fn load(dir: &Path, reference: &str) -> PublishResult<Option<Self>> {
+        let path = token_path(dir, reference);
+        match tokio::fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data).map(Some).map_err(|e| {
+                PublishError::Other(anyhow::anyhow!(
+                    "{} is not a valid resume token: {}",
+                    path.display(),
+                    e
+                ))
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(PublishError::Io {
+                source: e,
+                description: format!("Failed to read {}", path.display()),
+            }),
+        }
+    }
+
+    /// Saves the token in the directory, replacing any earlier token for
+    /// the same reference. Returns the path of the saved token.
+    pub async fn save(&self, dir: &Path) -> PublishResult<PathBuf> {
+        let path = token_path(dir, &self.reference);
+        let io_error = |source| PublishError::Io {
+            source,
+            description: format!("Failed to write {}", path.display()),
+        };
+        let data = serde_json::to_vec_pretty(self).map_err(|e| PublishError::Other(e.into()))?;
+        tokio::fs::create_dir_all(dir).await.map_err(io_error)?;
+        tokio::fs::write(&path, data).await.map_err(io_error)?;
+        Ok(path)
+    }
+
+    /// Removes the token saved in the directory for a push to the
+    /// reference, once the push has finished.
+    pub async fn remove(dir: &Path, reference: &str) -> PublishResult<()> {
+        let path = token_path(dir, reference);
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(PublishError::Io {
+                source: e,
+                description: format!("Failed to remove {}", path.display()),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Tokens are named by the digest of the reference, which may contain
+/// characters not allowed in file names.
+fn token_path(dir: &Path, reference: &str) -> PathBuf {
+    dir.join(format!(
+        "{}.json",
+        bytes_sha256_string(reference.as_bytes())
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn tokens_are_saved_per_reference() {
+        let dir = tempfile::tempdir().unwrap();
+        let token = ResumeToken {
+            reference: "localhost:5000/app:v1".to_owned(),
+            pushed: vec!["sha256:abc".to_owned()],
+            failed: Some("sha256:def".to_owned()),
+            partial: None,
+        };
+        token.save(dir.path()).await.unwrap();
+
+        let loaded = ResumeToken::load(dir.path(), &token.reference).await;
+        assert_eq!(Some(token.clone()), loaded.unwrap());
+        let other = ResumeToken::load(dir.path(), "localhost:5000/app:v2").await;
+        assert_eq!(None, other.unwrap());
+
+        ResumeToken::remove(dir.path(), &token.reference)
+            .await
+            .unwrap();
+        let removed = ResumeToken::load(dir.path(), &token.reference).await;
+        assert_eq!(None, removed.unwrap());
+    }
+}
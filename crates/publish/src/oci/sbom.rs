@@ -0,0 +1,381 @@
+//! Software bills of materials for pushed applications, attached to their
+//! manifests through the OCI referrers API.
+
+use std::collections::BTreeMap;
+
+use oci_distribution::manifest::{OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{
+    parse_reference, pull_scope, push::PushSession, registry_auth, registry_response_error,
+    sha256_digest, Client, PushedLayer, COMPONENT_ANNOTATION, GUEST_PATH_ANNOTATION,
+    WASM_LAYER_MEDIA_TYPE,
+};
+use crate::{PublishError, PublishResult};
+
+/// The media type of SPDX SBOMs in JSON format.
+pub const SPDX_MEDIA_TYPE: &str = "application/spdx+json";
+/// The media type of CycloneDX SBOMs in JSON format.
+pub const CYCLONEDX_MEDIA_TYPE: &str = "application/vnd.cyclonedx+json";
+
+const EMPTY_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+const EMPTY_CONFIG: &[u8] = b"{}";
+const OCI_SUBJECT_HEADER: &str = "OCI-Subject";
+
+/// An entry of the referrers of a manifest.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Referrer {
+    digest: String,
+    artifact_type: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Referrers {
+    #[serde(default)]
+    manifests: Vec<Referrer>,
+}
+
+#[derive(Deserialize)]
+struct ArtifactLayers {
+    layers: Vec<ArtifactLayer>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ArtifactLayer {
+    media_type: String,
+    digest: String,
+}
+
+impl Client {
+    /// Attaches an SBOM of the given media type to the manifest at the
+    /// reference, as an artifact referring to it. Registries without the
+    /// referrers API are given the fallback referrers tag instead. Returns
+    /// the digest of the SBOM artifact's manifest.
+    pub async fn attach_sbom(
+        &self,
+        reference: &str,
+        sbom: Vec<u8>,
+        media_type: &'static str,
+    ) -> PublishResult<String> {
+        let parsed = parse_reference(reference)?;
+        let registry = parsed.resolve_registry();
+        let repository = parsed.repository();
+        let target = parsed.digest().or_else(|| parsed.tag()).unwrap_or("latest");
+        let subject = self.fetch_manifest(registry, repository, target).await?;
+
+        let mut session = PushSession::new(self, registry, repository);
+        let sbom_digest = sha256_digest(&sbom);
+        let sbom = session.push_blob(sbom, &sbom_digest, media_type).await?;
+        let config = session
+            .push_blob(
+                EMPTY_CONFIG.to_vec(),
+                &sha256_digest(EMPTY_CONFIG),
+                EMPTY_CONFIG_MEDIA_TYPE,
+            )
+            .await?;
+
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_IMAGE_MEDIA_TYPE,
+            "artifactType": media_type,
+            "config": {
+                "mediaType": EMPTY_CONFIG_MEDIA_TYPE,
+                "digest": config.digest,
+                "size": config.size,
+            },
+            "layers": [{
+                "mediaType": media_type,
+                "digest": sbom.digest,
+                "size": sbom.size,
+            }],
+            "subject": {
+                "mediaType": subject.media_type,
+                "digest": subject.digest,
+                "size": subject.data.len(),
+            },
+        });
+        let data = serde_json::to_vec(&manifest).map_err(|e| {
+            PublishError::Other(anyhow::anyhow!("Failed to serialize manifest: {}", e))
+        })?;
+        let size = data.len();
+        let digest = sha256_digest(&data);
+        let response = session
+            .put_manifest_response(&digest, OCI_IMAGE_MEDIA_TYPE, data)
+            .await?;
+        if response.headers().contains_key(OCI_SUBJECT_HEADER) {
+            return Ok(digest);
+        }
+
+        // Without the referrers API, referrers are listed in an index under
+        // a tag derived from the subject's digest.
+        let tag = referrers_tag(&subject.digest);
+        let mut index = match self
+            .fetch_manifest_unchecked(registry, repository, &tag)
+            .await
+        {
+            Ok(existing) => serde_json::from_slice(&existing.data).map_err(|e| {
+                PublishError::Other(anyhow::anyhow!(
+                    "Invalid referrers index for {}: {}",
+                    subject.digest,
+                    e
+                ))
+            })?,
+            Err(PublishError::RegistryResponse { status: 404, .. }) => json!({
+                "schemaVersion": 2,
+                "mediaType": OCI_IMAGE_INDEX_MEDIA_TYPE,
+                "manifests": [],
+            }),
+            Err(e) => return Err(e),
+        };
+        if let Some(manifests) = index["manifests"].as_array_mut() {
+            manifests.push(json!({
+                "mediaType": OCI_IMAGE_MEDIA_TYPE,
+                "artifactType": media_type,
+                "digest": digest,
+                "size": size,
+            }));
+        }
+        let index = serde_json::to_vec(&index).map_err(|e| {
+            PublishError::Other(anyhow::anyhow!("Failed to serialize index: {}", e))
+        })?;
+        session
+            .put_manifest(&tag, OCI_IMAGE_INDEX_MEDIA_TYPE, index)
+            .await?;
+        self.remember_missing(registry, repository, &tag, false)
+            .await?;
+        Ok(digest)
+    }
+
+    /// Fetches the SBOM attached to the manifest at the reference, if it has
+    /// one, returning its media type and content. SPDX and CycloneDX SBOMs
+    /// are recognised.
+    pub async fn fetch_sbom(&self, reference: &str) -> PublishResult<Option<(String, Vec<u8>)>> {
+        let parsed = parse_reference(reference)?;
+        let registry = parsed.resolve_registry();
+        let repository = parsed.repository();
+        let target = parsed.digest().or_else(|| parsed.tag()).unwrap_or("latest");
+        let subject = self.fetch_manifest(registry, repository, target).await?;
+
+        let referrers = match self
+            .fetch_referrers(registry, repository, &subject.digest)
+            .await?
+        {
+            Some(referrers) => referrers,
+            None => return Ok(None),
+        };
+        let sbom = referrers.manifests.into_iter().find(|r| {
+            matches!(
+                r.artifact_type.as_deref(),
+                Some(SPDX_MEDIA_TYPE | CYCLONEDX_MEDIA_TYPE)
+            )
+        });
+        let sbom = match sbom {
+            Some(sbom) => sbom,
+            None => return Ok(None),
+        };
+
+        let manifest = self
+            .fetch_manifest(registry, repository, &sbom.digest)
+            .await?;
+        let artifact: ArtifactLayers = serde_json::from_slice(&manifest.data).map_err(|e| {
+            PublishError::Other(anyhow::anyhow!(
+                "Invalid SBOM manifest {}: {}",
+                sbom.digest,
+                e
+            ))
+        })?;
+        let layer = artifact.layers.into_iter().next().ok_or_else(|| {
+            PublishError::Other(anyhow::anyhow!("SBOM manifest {} is empty", sbom.digest))
+        })?;
+        let data = self.fetch_blob(registry, repository, &layer.digest).await?;
+        Ok(Some((layer.media_type, data)))
+    }
+
+    /// Lists the referrers of a manifest, using the referrers API or, if the
+    /// registry does not have it, the fallback referrers tag.
+    async fn fetch_referrers(
+        &self,
+        registry: &str,
+        repository: &str,
+        digest: &str,
+    ) -> PublishResult<Option<Referrers>> {
+        let url = format!(
+            "{}://{}/v2/{}/referrers/{}",
+            self.scheme(registry),
+            registry,
+            repository,
+            digest
+        );
+        let response = self
+            .get_authorized(
+                &url,
+                &[OCI_IMAGE_INDEX_MEDIA_TYPE],
+                &pull_scope(repository),
+                &registry_auth(registry),
+                &mut None,
+            )
+            .await?;
+        let data = match response.status() {
+            s if s.is_success() => response.bytes().await?.to_vec(),
+            StatusCode::NOT_FOUND => {
+                match self
+                    .fetch_manifest_unchecked(registry, repository, &referrers_tag(digest))
+                    .await
+                {
+                    Ok(index) => index.data,
+                    Err(PublishError::RegistryResponse { status: 404, .. }) => return Ok(None),
+                    Err(e) => return Err(e),
+                }
+            }
+            _ => return Err(registry_response_error(&url, response).await),
+        };
+        serde_json::from_slice(&data).map(Some).map_err(|e| {
+            PublishError::Other(anyhow::anyhow!("Invalid referrers of {}: {}", digest, e))
+        })
+    }
+}
+
+/// Generates an SPDX SBOM of a pushed application, with a package for each
+/// component containing its Wasm layer and asset files.
+pub fn spdx_sbom(name: &str, manifest_digest: &str, layers: &[PushedLayer]) -> Vec<u8> {
+    let mut components: BTreeMap<&str, Vec<&PushedLayer>> = BTreeMap::new();
+    for layer in layers {
+        let component = layer
+            .annotations
+            .get(COMPONENT_ANNOTATION)
+            .map_or("app", |c| c.as_str());
+        components.entry(component).or_default().push(layer);
+    }
+
+    let mut packages = vec![];
+    let mut files = vec![];
+    let mut relationships = vec![];
+    for (component, layers) in components {
+        let package_id = format!("SPDXRef-Package-{}", spdx_id(component));
+        packages.push(json!({
+            "SPDXID": package_id,
+            "name": component,
+            "downloadLocation": "NOASSERTION",
+            "filesAnalyzed": true,
+        }));
+        relationships.push(json!({
+            "spdxElementId": "SPDXRef-DOCUMENT",
+            "relationshipType": "DESCRIBES",
+            "relatedSpdxElement": package_id,
+        }));
+        for layer in layers {
+            let file_id = format!("SPDXRef-File-{}", files.len());
+            let file_name = match layer.annotations.get(GUEST_PATH_ANNOTATION) {
+                Some(path) => path.clone(),
+                None if layer.media_type == WASM_LAYER_MEDIA_TYPE => format!("{}.wasm", component),
+                None => format!("{}.assets", component),
+            };
+            let (_, hex) = layer.digest.split_once(':').unwrap_or(("", &layer.digest));
+            files.push(json!({
+                "SPDXID": file_id,
+                "fileName": file_name,
+                "checksums": [{ "algorithm": "SHA256", "checksumValue": hex }],
+            }));
+            relationships.push(json!({
+                "spdxElementId": package_id,
+                "relationshipType": "CONTAINS",
+                "relatedSpdxElement": file_id,
+            }));
+        }
+    }
+
+    let document = json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": name,
+        "documentNamespace": format!("https://spdx.org/spdxdocs/spin/{}", manifest_digest),
+        "creationInfo": {
+            "created": crate::seams::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            "creators": [concat!("Tool: spin-", env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": packages,
+        "files": files,
+        "relationships": relationships,
+    });
+    serde_json::to_vec_pretty(&document).expect("SBOM serialization cannot fail")
+}
+
+/// The media type of an SBOM file, recognised by its content.
+pub fn sbom_media_type(sbom: &[u8]) -> Option<&'static str> {
+    let document: serde_json::Value = serde_json::from_slice(sbom).ok()?;
+    if document.get("spdxVersion").is_some() {
+        Some(SPDX_MEDIA_TYPE)
+    } else if document.get("bomFormat").and_then(|f| f.as_str()) == Some("CycloneDX") {
+        Some(CYCLONEDX_MEDIA_TYPE)
+    } else {
+        None
+    }
+}
+
+/// The tag under which registries without the referrers API list the
+/// referrers of a manifest.
+pub(super) fn referrers_tag(digest: &str) -> String {
+    digest.replace(':', "-")
+}
+
+/// Replaces characters which SPDX identifiers may not contain.
+fn spdx_id(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::oci::DATA_LAYER_MEDIA_TYPE;
+    use std::collections::HashMap;
+
+    #[test]
+    fn generates_spdx_for_components_and_assets() {
+        let layer = |digest: &str, media_type: &str, annotations: &[(&str, &str)]| PushedLayer {
+            digest: digest.to_owned(),
+            size: 1,
+            media_type: media_type.to_owned(),
+            uploaded: true,
+            annotations: annotations
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>(),
+        };
+        let layers = vec![
+            layer(
+                "sha256:aaa",
+                WASM_LAYER_MEDIA_TYPE,
+                &[(COMPONENT_ANNOTATION, "web_ui")],
+            ),
+            layer(
+                "sha256:bbb",
+                DATA_LAYER_MEDIA_TYPE,
+                &[
+                    (COMPONENT_ANNOTATION, "web_ui"),
+                    (GUEST_PATH_ANNOTATION, "/index.html"),
+                ],
+            ),
+        ];
+
+        let sbom = spdx_sbom("app", "sha256:manifest", &layers);
+        assert_eq!(Some(SPDX_MEDIA_TYPE), sbom_media_type(&sbom));
+        let document: serde_json::Value = serde_json::from_slice(&sbom).unwrap();
+        assert_eq!("SPDXRef-Package-web-ui", document["packages"][0]["SPDXID"]);
+        assert_eq!("web_ui.wasm", document["files"][0]["fileName"]);
+        assert_eq!("/index.html", document["files"][1]["fileName"]);
+        assert_eq!("bbb", document["files"][1]["checksums"][0]["checksumValue"]);
+    }
+}
@@ -0,0 +1,373 @@
+//! Signatures of pushed applications in the format used by cosign, so that
+//! cluster admission policies can verify Spin applications the same way as
+//! container images.
+
+use std::{collections::HashMap, path::Path};
+
+use oci_distribution::manifest::{OciDescriptor, OciImageManifest, OCI_IMAGE_MEDIA_TYPE};
+use p256::{
+    ecdsa::{
+        signature::{Signer, Verifier},
+        Signature,
+    },
+    pkcs8::{DecodePrivateKey, DecodePublicKey},
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{
+    index::OCI_IMAGE_CONFIG_MEDIA_TYPE, parse_reference, push::PushSession, sbom::referrers_tag,
+    sha256_digest, Client, COSIGN_SIGNATURE_TAG_SUFFIX,
+};
+use crate::{PublishError, PublishResult};
+
+/// The media type of the signed payloads of cosign signatures.
+pub const SIMPLE_SIGNING_MEDIA_TYPE: &str = "application/vnd.dev.cosign.simplesigning.v1+json";
+/// The annotation of a signed payload layer holding its signature.
+pub const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+
+/// A key with which pushed applications are signed: an unencrypted PKCS#8
+/// ECDSA P-256 private key, such as one generated by
+/// `openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256`.
+/// Signatures can be verified by cosign with the matching public key.
+pub struct SigningKey(p256::ecdsa::SigningKey);
+
+impl SigningKey {
+    /// Loads a key from its PEM encoding.
+    pub fn from_pem(pem: &str) -> PublishResult<Self> {
+        if pem.contains("ENCRYPTED") {
+            return Err(PublishError::InvalidSigningKey(
+                "encrypted keys, including those generated by cosign, are not supported. Export the key unencrypted in PKCS#8 format".to_owned(),
+            ));
+        }
+        p256::ecdsa::SigningKey::from_pkcs8_pem(pem)
+            .map(Self)
+            .map_err(|e| {
+                PublishError::InvalidSigningKey(format!(
+                    "expected a PKCS#8 ECDSA P-256 private key: {}",
+                    e
+                ))
+            })
+    }
+
+    /// Loads a key from a PEM file.
+    pub fn from_file(path: &Path) -> PublishResult<Self> {
+        let pem = std::fs::read_to_string(path).map_err(|e| PublishError::Io {
+            source: e,
+            description: format!("Failed to read signing key {}", path.display()),
+        })?;
+        Self::from_pem(&pem)
+    }
+
+    /// The public key matching this key, with which its signatures are
+    /// verified.
+    pub fn verification_key(&self) -> VerificationKey {
+        VerificationKey(self.0.verifying_key())
+    }
+
+    /// Signs a payload, returning the base64 encoded DER signature.
+    fn sign(&self, payload: &[u8]) -> String {
+        let signature: Signature = self.0.sign(payload);
+        base64::encode(signature.to_der().as_bytes())
+    }
+}
+
+/// A key with which the signatures of pulled applications are verified: a
+/// PEM encoded ECDSA P-256 public key, such as the `cosign.pub` written by
+/// `cosign generate-key-pair`.
+#[derive(Clone, Debug)]
+pub struct VerificationKey(p256::ecdsa::VerifyingKey);
+
+impl VerificationKey {
+    /// Loads a key from its PEM encoding.
+    pub fn from_pem(pem: &str) -> PublishResult<Self> {
+        p256::ecdsa::VerifyingKey::from_public_key_pem(pem)
+            .map(Self)
+            .map_err(|e| {
+                PublishError::InvalidSigningKey(format!(
+                    "expected a PEM encoded ECDSA P-256 public key: {}",
+                    e
+                ))
+            })
+    }
+
+    /// Loads a key from a PEM file.
+    pub fn from_file(path: &Path) -> PublishResult<Self> {
+        let pem = std::fs::read_to_string(path).map_err(|e| PublishError::Io {
+            source: e,
+            description: format!("Failed to read verification key {}", path.display()),
+        })?;
+        Self::from_pem(&pem)
+    }
+
+    /// Whether a base64 encoded DER signature of the payload was made with
+    /// the matching private key.
+    pub(crate) fn verifies(&self, payload: &[u8], signature: &str) -> bool {
+        base64::decode(signature)
+            .ok()
+            .and_then(|der| Signature::from_der(&der).ok())
+            .map_or(false, |signature| {
+                self.0.verify(payload, &signature).is_ok()
+            })
+    }
+}
+
+/// A cosign signature of a manifest.
+#[derive(Clone, Debug)]
+pub(crate) struct CosignSignature {
+    /// The signed payload.
+    pub payload: Vec<u8>,
+    /// The base64 encoded signature of the payload, if it has one.
+    pub signature: Option<String>,
+    /// The identity claimed in the payload's optional annotations, as set
+    /// by `cosign sign -a identity=...`.
+    pub identity: Option<String>,
+}
+
+/// The payload of a cosign signature.
+#[derive(Deserialize)]
+struct SignaturePayload {
+    critical: SignatureCritical,
+    #[serde(default)]
+    optional: Option<HashMap<String, serde_json::Value>>,
+}
+
+#[derive(Deserialize)]
+struct SignatureCritical {
+    image: SignatureImage,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct SignatureImage {
+    docker_manifest_digest: String,
+}
+
+impl Client {
+    /// Finds the cosign signatures of a manifest whose payloads are for
+    /// that manifest.
+    pub(super) async fn fetch_signatures(
+        &self,
+        registry: &str,
+        repository: &str,
+        digest: &str,
+    ) -> PublishResult<Vec<CosignSignature>> {
+        let signatures = match self
+            .fetch_manifest_unchecked(registry, repository, &signature_tag(digest))
+            .await
+        {
+            Ok(signatures) => signatures,
+            Err(PublishError::RegistryResponse { status: 404, .. }) => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let manifest: OciImageManifest = serde_json::from_slice(&signatures.data)
+            .map_err(|e| anyhow::anyhow!("Invalid signature manifest for {}: {}", digest, e))?;
+
+        let mut found = vec![];
+        for layer in manifest.layers {
+            let payload = self
+                .fetch_blob_unchecked(registry, repository, &layer.digest)
+                .await?;
+            let parsed = match serde_json::from_slice::<SignaturePayload>(&payload) {
+                Ok(parsed) if parsed.critical.image.docker_manifest_digest == digest => parsed,
+                _ => continue,
+            };
+            found.push(CosignSignature {
+                identity: parsed
+                    .optional
+                    .as_ref()
+                    .and_then(|o| o.get("identity"))
+                    .and_then(|i| i.as_str())
+                    .map(|i| i.to_owned()),
+                signature: layer
+                    .annotations
+                    .and_then(|mut a| a.remove(SIGNATURE_ANNOTATION)),
+                payload,
+            });
+        }
+        Ok(found)
+    }
+
+    /// Signs the manifest at the reference and attaches the signature under
+    /// the tag where cosign looks for it, alongside any signatures already
+    /// there. Returns the digest of the signed manifest.
+    pub async fn sign(&self, reference: &str, key: &SigningKey) -> PublishResult<String> {
+        let parsed = parse_reference(reference)?;
+        let registry = parsed.resolve_registry();
+        let repository = parsed.repository();
+        let target = parsed.digest().or_else(|| parsed.tag()).unwrap_or("latest");
+        let subject = self.fetch_manifest(registry, repository, target).await?;
+
+        let payload =
+            simple_signing_payload(&format!("{}/{}", registry, repository), &subject.digest);
+        let signature = key.sign(&payload);
+
+        let tag = signature_tag(&subject.digest);
+        let mut layers = match self
+            .fetch_manifest_unchecked(registry, repository, &tag)
+            .await
+        {
+            Ok(existing) => {
+                serde_json::from_slice::<OciImageManifest>(&existing.data)
+                    .map_err(|e| {
+                        PublishError::Other(anyhow::anyhow!(
+                            "Invalid signatures manifest for {}: {}",
+                            subject.digest,
+                            e
+                        ))
+                    })?
+                    .layers
+            }
+            Err(PublishError::RegistryResponse { status: 404, .. }) => vec![],
+            Err(e) => return Err(e),
+        };
+
+        let mut session = PushSession::new(self, registry, repository);
+        let payload_digest = sha256_digest(&payload);
+        let payload = session
+            .push_blob(payload, &payload_digest, SIMPLE_SIGNING_MEDIA_TYPE)
+            .await?;
+        // Signing is deterministic, so signing again with the same key only
+        // replaces the identical signature.
+        layers.retain(|layer| {
+            layer.digest != payload.digest
+                || layer
+                    .annotations
+                    .as_ref()
+                    .and_then(|a| a.get(SIGNATURE_ANNOTATION))
+                    != Some(&signature)
+        });
+        layers.push(OciDescriptor {
+            media_type: SIMPLE_SIGNING_MEDIA_TYPE.to_owned(),
+            digest: payload.digest,
+            size: payload.size,
+            urls: None,
+            annotations: Some([(SIGNATURE_ANNOTATION.to_owned(), signature)].into()),
+        });
+
+        let config = json!({
+            "architecture": "",
+            "os": "",
+            "config": {},
+            "rootfs": {
+                "type": "layers",
+                "diff_ids": layers.iter().map(|l| &l.digest).collect::<Vec<_>>(),
+            },
+        })
+        .to_string()
+        .into_bytes();
+        let config_digest = sha256_digest(&config);
+        let config = session
+            .push_blob(config, &config_digest, OCI_IMAGE_CONFIG_MEDIA_TYPE)
+            .await?;
+
+        let manifest = OciImageManifest {
+            schema_version: 2,
+            media_type: Some(OCI_IMAGE_MEDIA_TYPE.to_owned()),
+            config: OciDescriptor {
+                media_type: OCI_IMAGE_CONFIG_MEDIA_TYPE.to_owned(),
+                digest: config.digest,
+                size: config.size,
+                urls: None,
+                annotations: None,
+            },
+            layers,
+            annotations: None,
+        };
+        let data = serde_json::to_vec(&manifest).map_err(|e| {
+            PublishError::Other(anyhow::anyhow!("Failed to serialize manifest: {}", e))
+        })?;
+        session
+            .put_manifest(&tag, OCI_IMAGE_MEDIA_TYPE, data)
+            .await?;
+        self.remember_missing(registry, repository, &tag, false)
+            .await?;
+        Ok(subject.digest)
+    }
+}
+
+/// The tag under which cosign stores the signatures of a manifest.
+pub(super) fn signature_tag(digest: &str) -> String {
+    format!("{}{}", referrers_tag(digest), COSIGN_SIGNATURE_TAG_SUFFIX)
+}
+
+/// The payload cosign signs: a simple signing document identifying the
+/// repository and the digest of the signed manifest.
+pub(super) fn simple_signing_payload(repository: &str, digest: &str) -> Vec<u8> {
+    json!({
+        "critical": {
+            "identity": {
+                "docker-reference": repository,
+            },
+            "image": {
+                "docker-manifest-digest": digest,
+            },
+            "type": "cosign container image signature",
+        },
+        "optional": null,
+    })
+    .to_string()
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn signatures_verify_with_public_key() {
+        let key = SigningKey(p256::ecdsa::SigningKey::from_bytes(&[7; 32]).unwrap());
+        let payload = simple_signing_payload("ghcr.io/fermyon/app", "sha256:abc");
+        let signature = key.sign(&payload);
+        assert_eq!(signature, key.sign(&payload));
+
+        let verification_key = key.verification_key();
+        assert!(verification_key.verifies(&payload, &signature));
+        assert!(!verification_key.verifies(b"tampered", &signature));
+
+        let document: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(
+            "sha256:abc",
+            document["critical"]["image"]["docker-manifest-digest"]
+        );
+        assert_eq!("sha256-abc.sig", signature_tag("sha256:abc"));
+    }
+
+    #[test]
+    fn trust_policy_requires_trusted_signatures() {
+        use crate::oci::{PolicyViolation, TrustPolicy};
+
+        let key = SigningKey(p256::ecdsa::SigningKey::from_bytes(&[7; 32]).unwrap());
+        let other = SigningKey(p256::ecdsa::SigningKey::from_bytes(&[8; 32]).unwrap());
+        let payload = json!({
+            "critical": {"image": {"docker-manifest-digest": "sha256:abc"}},
+            "optional": {"identity": "release@example.com"},
+        })
+        .to_string()
+        .into_bytes();
+        let signature = |key: &SigningKey| CosignSignature {
+            signature: Some(key.sign(&payload)),
+            identity: Some("release@example.com".to_owned()),
+            payload: payload.clone(),
+        };
+
+        let policy = TrustPolicy {
+            required_signature_identities: vec!["release@example.com".to_owned()],
+            trusted_keys: vec![key.verification_key()],
+            ..Default::default()
+        };
+        assert!(policy.check_signatures("app", &[signature(&key)]).is_ok());
+        assert!(matches!(
+            policy.check_signatures("app", &[signature(&other)]),
+            Err(PolicyViolation::UntrustedSignature(_))
+        ));
+
+        let claims_only = TrustPolicy {
+            trusted_keys: vec![],
+            ..policy
+        };
+        assert!(claims_only
+            .check_signatures("app", &[signature(&other)])
+            .is_ok());
+    }
+}
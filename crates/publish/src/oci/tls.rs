@@ -0,0 +1,213 @@
+//! TLS settings for registries which are not trusted through the system's
+//! certificate store, such as internal registries with private CAs, and for
+//! registries which require clients to present certificates.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+};
+
+use super::{Client, USER_AGENT};
+use crate::{PublishError, PublishResult, Secret};
+
+const PEM_CERTIFICATE_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+
+/// A certificate, and its private key, presented to a registry which
+/// requires mutual TLS.
+pub(super) struct ClientCertificate {
+    certificate: Vec<u8>,
+    key: Secret<Vec<u8>>,
+}
+
+impl ClientCertificate {
+    fn identity(&self, registry: &str) -> PublishResult<reqwest::Identity> {
+        reqwest::Identity::from_pkcs8_pem(&self.certificate, self.key.expose()).map_err(|e| {
+            PublishError::Other(anyhow::anyhow!(
+                "Invalid client certificate for {}: {}",
+                registry,
+                e
+            ))
+        })
+    }
+}
+
+impl Client {
+    /// Trusts the CA certificates in a PEM file, which may hold a bundle of
+    /// several, in addition to the system's, so that registries with
+    /// certificates issued by a private CA can be used without resorting to
+    /// plain HTTP. This replaces any HTTP client given with
+    /// [`with_http_client`](Self::with_http_client).
+    pub fn with_ca_certificates(mut self, path: &Path) -> PublishResult<Self> {
+        let pem = read_pem(path, "CA certificates")?;
+        let pem = String::from_utf8_lossy(&pem);
+        let blocks = pem_certificates(&pem);
+        if blocks.is_empty() {
+            return Err(PublishError::Other(anyhow::anyhow!(
+                "{} does not contain any PEM certificates",
+                path.display()
+            )));
+        }
+        for block in blocks {
+            let certificate = reqwest::Certificate::from_pem(block.as_bytes()).map_err(|e| {
+                PublishError::Other(anyhow::anyhow!(
+                    "{} contains an invalid certificate: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+            self.root_certificates.push(certificate);
+        }
+        self.rebuild_http()?;
+        Ok(self)
+    }
+
+    /// Presents a client certificate to a registry which requires mutual
+    /// TLS. The registry is its host, with the port if it is not the
+    /// default, as in references. The certificate and its private key are
+    /// read from PEM files, and the key must be in PKCS #8 form. Other
+    /// registries are not sent the certificate.
+    pub fn with_client_certificate(
+        mut self,
+        registry: &str,
+        certificate: &Path,
+        key: &Path,
+    ) -> PublishResult<Self> {
+        let client_certificate = ClientCertificate {
+            certificate: read_pem(certificate, "client certificate")?,
+            key: Secret::new(read_pem(key, "client key")?),
+        };
+        // Checked here so that a mismatched pair is reported before any
+        // request is made.
+        client_certificate.identity(registry)?;
+        self.client_certificates
+            .insert(registry.to_owned(), client_certificate);
+        self.rebuild_http()?;
+        Ok(self)
+    }
+
+    /// The HTTP client for requests to a URL, which presents the client
+    /// certificate for the URL's registry if there is one, and skips
+    /// verifying its certificate if the registry configuration says to.
+    pub(super) fn http_for(&self, url: &str) -> &reqwest::Client {
+        if self.registry_http.is_empty() {
+            return &self.http;
+        }
+        reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| {
+                let host = url.host_str()?;
+                let registry = match url.port() {
+                    Some(port) => format!("{}:{}", host, port),
+                    None => host.to_owned(),
+                };
+                self.registry_http.get(&registry)
+            })
+            .unwrap_or(&self.http)
+    }
+
+    /// Builds the HTTP clients for registry requests from the client's TLS
+    /// settings: one for most registries, and one for each registry with a
+    /// client certificate or whose certificate is not verified.
+    pub(super) fn rebuild_http(&mut self) -> PublishResult<()> {
+        self.http = self.build_http(None, false)?;
+        let registries: BTreeSet<&str> = self
+            .client_certificates
+            .keys()
+            .map(String::as_str)
+            .chain(self.registry_config.skip_verify_hosts())
+            .collect();
+        let mut registry_http = HashMap::new();
+        for registry in registries {
+            let identity = match self.client_certificates.get(registry) {
+                Some(certificate) => Some(certificate.identity(registry)?),
+                None => None,
+            };
+            let skip_verify = self.registry_config.skip_verify(registry);
+            registry_http.insert(registry.to_owned(), self.build_http(identity, skip_verify)?);
+        }
+        self.registry_http = registry_http;
+        Ok(())
+    }
+
+    fn build_http(
+        &self,
+        identity: Option<reqwest::Identity>,
+        skip_verify: bool,
+    ) -> PublishResult<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .danger_accept_invalid_certs(skip_verify);
+        for certificate in &self.root_certificates {
+            builder = builder.add_root_certificate(certificate.clone());
+        }
+        if let Some(identity) = identity {
+            builder = builder.identity(identity);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+fn read_pem(path: &Path, what: &str) -> PublishResult<Vec<u8>> {
+    std::fs::read(path).map_err(|source| PublishError::Io {
+        source,
+        description: format!("Failed to read {} {}", what, path.display()),
+    })
+}
+
+/// Splits a PEM bundle into its certificates, skipping anything else in it
+/// such as comments.
+fn pem_certificates(pem: &str) -> Vec<&str> {
+    let mut certificates = vec![];
+    let mut rest = pem;
+    while let Some(start) = rest.find(PEM_CERTIFICATE_BEGIN) {
+        let end = match rest[start..].find(PEM_CERTIFICATE_END) {
+            Some(end) => start + end + PEM_CERTIFICATE_END.len(),
+            None => break,
+        };
+        certificates.push(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    certificates
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn splits_pem_bundles() {
+        let bundle = "# Root CA\n-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n\
+                      # Intermediate\n-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n";
+        assert_eq!(
+            vec![
+                "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----",
+                "-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----",
+            ],
+            pem_certificates(bundle)
+        );
+        assert!(pem_certificates("-----BEGIN CERTIFICATE-----\nAAAA").is_empty());
+    }
+
+    #[test]
+    fn client_certificates_are_only_sent_to_their_registry() {
+        let mut client = Client::new(false).unwrap();
+        assert!(std::ptr::eq(
+            &client.http,
+            client.http_for("https://registry.local:5443/v2/")
+        ));
+
+        let registry = "registry.local:5443";
+        client
+            .registry_http
+            .insert(registry.to_owned(), reqwest::Client::new());
+        assert!(std::ptr::eq(
+            &client.registry_http[registry],
+            client.http_for("https://registry.local:5443/v2/app/blobs/uploads/")
+        ));
+        assert!(std::ptr::eq(
+            &client.http,
+            client.http_for("https://registry.local/v2/")
+        ));
+    }
+}
@@ -0,0 +1,32 @@
+//! Structural checks of pulled Wasm layers.
+
+use wasmparser::Parser;
+
+const WASM_MAGIC: &[u8] = b"\0asm";
+
+/// Checks that the data is a structurally valid Wasm module or component:
+/// that it starts with the Wasm preamble and all of its sections can be
+/// parsed. This does not type-check the module.
+pub(crate) fn validate_wasm(data: &[u8]) -> Result<(), String> {
+    if !data.starts_with(WASM_MAGIC) {
+        return Err("missing Wasm magic number".to_owned());
+    }
+    for payload in Parser::new(0).parse_all(data) {
+        payload.map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_truncated_or_mislabeled_modules() {
+        let empty_module = b"\0asm\x01\0\0\0";
+        assert!(validate_wasm(empty_module).is_ok());
+        assert!(validate_wasm(b"<html>not found</html>").is_err());
+        // A type section which claims more content than is present
+        assert!(validate_wasm(b"\0asm\x01\0\0\0\x01\x05\x01").is_err());
+    }
+}
@@ -0,0 +1,198 @@
+//! Environment-specific changes to locked applications.
+
+use std::path::{Path, PathBuf};
+
+use spin_app::locked::{ContentPath, ContentRef, LockedApp, LockedComponent};
+
+use crate::{PublishError, PublishResult};
+
+const ALLOWED_HTTP_HOSTS_KEY: &str = "allowed_http_hosts";
+
+/// Applies environment-specific changes to a [`LockedApp`], such as one
+/// pulled from a registry, before it is run or registered with a platform.
+/// This allows the same artifact to be used in several environments
+/// without rebuilding it.
+///
+/// Each change applies either to a single component, identified by ID, or
+/// to every component in the application.
+#[derive(Clone, Debug, Default)]
+pub struct LockedAppPatcher {
+    patches: Vec<(Option<String>, Patch)>,
+}
+
+#[derive(Clone, Debug)]
+enum Patch {
+    Env { name: String, value: String },
+    File { host: PathBuf, guest: PathBuf },
+    AllowedHosts(Vec<String>),
+}
+
+impl LockedAppPatcher {
+    /// Creates a patcher with no changes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets an environment variable, replacing any existing value.
+    pub fn env(
+        self,
+        component: Option<&str>,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Self {
+        self.push(
+            component,
+            Patch::Env {
+                name: name.into(),
+                value: value.into(),
+            },
+        )
+    }
+
+    /// Mounts a host file or directory at a guest path, replacing any
+    /// existing mount at that path.
+    pub fn file(
+        self,
+        component: Option<&str>,
+        host: impl Into<PathBuf>,
+        guest: impl Into<PathBuf>,
+    ) -> Self {
+        self.push(
+            component,
+            Patch::File {
+                host: host.into(),
+                guest: guest.into(),
+            },
+        )
+    }
+
+    /// Replaces the hosts to which outbound HTTP requests are allowed.
+    pub fn allowed_http_hosts<T: Into<String>>(
+        self,
+        component: Option<&str>,
+        hosts: impl IntoIterator<Item = T>,
+    ) -> Self {
+        let hosts = hosts.into_iter().map(|h| h.into()).collect();
+        self.push(component, Patch::AllowedHosts(hosts))
+    }
+
+    /// Applies the changes to an application. Fails without changing the
+    /// application if a change refers to a component the application does
+    /// not contain.
+    pub fn apply(&self, app: &mut LockedApp) -> PublishResult<()> {
+        for (component, _) in &self.patches {
+            if let Some(id) = component {
+                if !app.components.iter().any(|c| &c.id == id) {
+                    return Err(PublishError::UnknownComponent(id.clone()));
+                }
+            }
+        }
+
+        for (target, patch) in &self.patches {
+            for component in app
+                .components
+                .iter_mut()
+                .filter(|c| target.as_ref().map_or(true, |id| id == &c.id))
+            {
+                patch.apply(component)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn push(mut self, component: Option<&str>, patch: Patch) -> Self {
+        self.patches.push((component.map(|c| c.to_owned()), patch));
+        self
+    }
+}
+
+impl Patch {
+    fn apply(&self, component: &mut LockedComponent) -> PublishResult<()> {
+        match self {
+            Self::Env { name, value } => {
+                component.env.insert(name.clone(), value.clone());
+            }
+            Self::File { host, guest } => {
+                component.files.retain(|f| &f.path != guest);
+                component.files.push(ContentPath {
+                    content: ContentRef {
+                        source: Some(file_uri(host)?),
+                        ..Default::default()
+                    },
+                    path: guest.clone(),
+                });
+            }
+            Self::AllowedHosts(hosts) => {
+                component
+                    .metadata
+                    .insert(ALLOWED_HTTP_HOSTS_KEY.to_owned(), hosts.clone().into());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn file_uri(path: &Path) -> PublishResult<String> {
+    let path = path.canonicalize().map_err(|e| PublishError::Io {
+        source: e,
+        description: format!("Failed to resolve mount {}", path.display()),
+    })?;
+    let url = if path.is_dir() {
+        url::Url::from_directory_path(&path)
+    } else {
+        url::Url::from_file_path(&path)
+    }
+    .map_err(|_| anyhow::anyhow!("Could not construct file URL for {}", path.display()))?;
+    Ok(url.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_app() -> LockedApp {
+        LockedApp::from_json(
+            br#"{
+                "spin_lock_version": 0,
+                "triggers": [],
+                "components": [
+                    {"id": "one", "source": {"content_type": "application/wasm"}, "env": {"A": "1"}},
+                    {"id": "two", "source": {"content_type": "application/wasm"}}
+                ]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn patches_selected_or_all_components() {
+        let mut app = test_app();
+        LockedAppPatcher::new()
+            .env(None, "A", "2")
+            .allowed_http_hosts(Some("two"), ["example.com"])
+            .apply(&mut app)
+            .unwrap();
+
+        assert_eq!("2", app.components[0].env["A"]);
+        assert_eq!("2", app.components[1].env["A"]);
+        assert!(!app.components[0]
+            .metadata
+            .contains_key(ALLOWED_HTTP_HOSTS_KEY));
+        assert_eq!(
+            serde_json::json!(["example.com"]),
+            app.components[1].metadata[ALLOWED_HTTP_HOSTS_KEY]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_components_without_changes() {
+        let mut app = test_app();
+        let result = LockedAppPatcher::new()
+            .env(None, "A", "2")
+            .env(Some("three"), "B", "3")
+            .apply(&mut app);
+
+        assert!(matches!(result, Err(PublishError::UnknownComponent(_))));
+        assert_eq!("1", app.components[0].env["A"]);
+    }
+}
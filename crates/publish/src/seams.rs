@@ -0,0 +1,100 @@
+//! The sources of the values in generated artifacts which differ from one
+//! run to the next: the current time and the git commit of the application.
+//! With the `testing` feature, tests can fix them through
+//! [`testing`](crate::testing), so that generated manifests, invoices, SBOMs
+//! and cache files can be compared with golden files.
+
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+
+/// The current time, unless a test has fixed it.
+pub(crate) fn now() -> DateTime<Utc> {
+    #[cfg(feature = "testing")]
+    if let Some(time) = testing::FIXED.lock().unwrap().time {
+        return time;
+    }
+    Utc::now()
+}
+
+/// The abbreviated hash of the git commit checked out in a directory, if it
+/// is in a git repository, unless a test has fixed it.
+pub(crate) fn git_sha(dir: &Path) -> Option<String> {
+    #[cfg(feature = "testing")]
+    if let Some(sha) = &testing::FIXED.lock().unwrap().git_sha {
+        return Some(sha.clone());
+    }
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "--short=7", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let sha = String::from_utf8(output.stdout).ok()?;
+    Some(sha.trim().to_owned())
+}
+
+/// Seams through which tests fix the values in generated artifacts which
+/// otherwise differ from one run to the next. The values are fixed for the
+/// whole process, so tests which fix them should fix them to the same
+/// values.
+#[cfg(feature = "testing")]
+pub mod testing {
+    use std::sync::Mutex;
+
+    use chrono::{DateTime, Utc};
+
+    #[derive(Default)]
+    pub(super) struct Fixed {
+        pub(super) time: Option<DateTime<Utc>>,
+        pub(super) git_sha: Option<String>,
+    }
+
+    lazy_static::lazy_static! {
+        pub(super) static ref FIXED: Mutex<Fixed> = Mutex::new(Fixed::default());
+    }
+
+    /// Fixes the time recorded in generated artifacts and the cache, such
+    /// as SBOM creation times, `{date}` placeholders and operations log
+    /// entries, or unfixes it if `None`.
+    pub fn fix_time(time: Option<DateTime<Utc>>) {
+        FIXED.lock().unwrap().time = time;
+    }
+
+    /// Fixes the git commit hash given for `{git_sha}` placeholders, or
+    /// unfixes it if `None`.
+    pub fn fix_git_sha(sha: Option<&str>) {
+        FIXED.lock().unwrap().git_sha = sha.map(str::to_owned);
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod test {
+    use std::path::Path;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::testing::{fix_git_sha, fix_time};
+    use crate::{oci::spdx_sbom, TemplateContext};
+
+    #[test]
+    fn fixed_values_appear_in_generated_artifacts() {
+        fix_time(Some(Utc.ymd(2023, 1, 2).and_hms(3, 4, 5)));
+        fix_git_sha(Some("abc1234"));
+
+        let context = TemplateContext::new("1.0.0", Path::new("."));
+        assert_eq!(
+            "1.0.0-20230102-abc1234",
+            context.expand("{version}-{date}-{git_sha}").unwrap()
+        );
+        let sbom: serde_json::Value =
+            serde_json::from_slice(&spdx_sbom("app", "sha256:manifest", &[])).unwrap();
+        assert_eq!(
+            "2023-01-02T03:04:05Z",
+            sbom["creationInfo"]["created"].as_str().unwrap()
+        );
+    }
+}
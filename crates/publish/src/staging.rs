@@ -0,0 +1,210 @@
+#![deny(missing_docs)]
+
+//! Content-addressable staging of application files, shared between the
+//! Bindle and OCI publishers.
+
+use std::{
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+use spin_loader::digest::{bytes_sha256_string, file_sha256_string};
+
+use crate::{PublishError, PublishResult};
+
+const CONTENT_DIR: &str = "sha256";
+const INDEX_DIR: &str = "index";
+
+/// A content-addressable store of the files that make up applications being
+/// published. Each file is hashed once, and its content stored under its
+/// digest. An index of source files lets later publishes, whether to Bindle
+/// or to an OCI registry, reuse the digest and staged copy of a file which
+/// has not changed since it was staged instead of hashing and copying it
+/// again.
+#[derive(Clone, Debug)]
+pub struct Staging {
+    root: PathBuf,
+}
+
+/// A file which has been staged.
+#[derive(Clone, Debug)]
+pub struct StagedFile {
+    /// The SHA-256 digest of the file content, as a hex string
+    pub sha256: String,
+    /// The size of the file in bytes
+    pub size: u64,
+    /// The path of the staged copy of the file
+    pub path: PathBuf,
+}
+
+impl StagedFile {
+    /// The digest of the file content, in OCI `sha256:<hex>` form.
+    pub fn digest(&self) -> String {
+        format!("sha256:{}", self.sha256)
+    }
+}
+
+/// What the index records about a source file.
+#[derive(Serialize, Deserialize, PartialEq, Eq)]
+struct IndexEntry {
+    size: u64,
+    modified_nanos: u128,
+    sha256: String,
+}
+
+impl Staging {
+    /// Opens the staging area at the given root directory, or at the
+    /// default location if no root is given, creating it if necessary.
+    pub async fn new(root: Option<PathBuf>) -> PublishResult<Self> {
+        let root = match root {
+            Some(root) => root,
+            None => Self::default_root()?,
+        };
+        for dir in [root.join(CONTENT_DIR), root.join(INDEX_DIR)] {
+            tokio::fs::create_dir_all(&dir)
+                .await
+                .map_err(|e| PublishError::Io {
+                    source: e,
+                    description: format!("Failed to create staging directory {}", dir.display()),
+                })?;
+        }
+        Ok(Self { root })
+    }
+
+    /// The root directory of the staging area if none is specified.
+    pub fn default_root() -> PublishResult<PathBuf> {
+        let cache_dir = dirs::cache_dir()
+            .ok_or_else(|| PublishError::Other(anyhow::anyhow!("Cannot find cache directory")))?;
+        Ok(cache_dir.join("spin").join("staging"))
+    }
+
+    /// The path at which content with the given SHA-256 hex digest is
+    /// staged.
+    pub fn content_path(&self, sha256: &str) -> PathBuf {
+        self.root.join(CONTENT_DIR).join(sha256)
+    }
+
+    /// Stages a file, returning its digest and the path of the staged copy.
+    /// The file is only hashed and copied if it has changed since it was
+    /// last staged.
+    pub async fn stage(&self, source: &Path) -> PublishResult<StagedFile> {
+        let io_error = |description: String| {
+            move |source: std::io::Error| PublishError::Io {
+                source,
+                description,
+            }
+        };
+
+        let source = dunce::canonicalize(source)
+            .map_err(io_error(format!("Failed to find '{}'", source.display())))?;
+        let metadata = tokio::fs::metadata(&source)
+            .await
+            .map_err(io_error(format!(
+                "Failed to get file metadata: '{}'",
+                source.display()
+            )))?;
+        let modified_nanos = metadata
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+
+        let index_path = self.index_path(&source);
+        let indexed = tokio::fs::read(&index_path)
+            .await
+            .ok()
+            .and_then(|data| serde_json::from_slice::<IndexEntry>(&data).ok())
+            .filter(|entry| entry.size == metadata.len() && entry.modified_nanos == modified_nanos)
+            .filter(|entry| self.content_path(&entry.sha256).exists());
+        if let Some(entry) = indexed {
+            return Ok(StagedFile {
+                path: self.content_path(&entry.sha256),
+                sha256: entry.sha256,
+                size: entry.size,
+            });
+        }
+
+        let sha256 = file_sha256_string(&source).map_err(io_error(format!(
+            "Failed to calculate digest for '{}'",
+            source.display()
+        )))?;
+        let staged_path = self.content_path(&sha256);
+        if !staged_path.exists() {
+            // Copied rather than linked, because the source may later be
+            // modified in place, and staged content must never change.
+            copy_atomically(&source, &staged_path).await?;
+        }
+
+        let entry = IndexEntry {
+            size: metadata.len(),
+            modified_nanos,
+            sha256,
+        };
+        // The index is only an optimisation, so failing to update it is not
+        // an error.
+        if let Ok(data) = serde_json::to_vec(&entry) {
+            if let Err(e) = tokio::fs::write(&index_path, data).await {
+                tracing::debug!("Failed to update staging index for {:?}: {}", source, e);
+            }
+        }
+
+        Ok(StagedFile {
+            path: staged_path,
+            sha256: entry.sha256,
+            size: entry.size,
+        })
+    }
+
+    fn index_path(&self, source: &Path) -> PathBuf {
+        let key = bytes_sha256_string(source.to_string_lossy().as_bytes());
+        self.root.join(INDEX_DIR).join(key)
+    }
+}
+
+/// Copies `source` to `dest` via a temporary file, so that a partially
+/// written file is never taken for staged content.
+async fn copy_atomically(source: &Path, dest: &Path) -> PublishResult<()> {
+    let temp = dest.with_extension(format!("{}.tmp", std::process::id()));
+    tokio::fs::copy(source, &temp)
+        .await
+        .map_err(|e| PublishError::Io {
+            source: e,
+            description: format!(
+                "Failed to stage '{}' at '{}'",
+                source.display(),
+                temp.display()
+            ),
+        })?;
+    tokio::fs::rename(&temp, dest)
+        .await
+        .map_err(|e| PublishError::Io {
+            source: e,
+            description: format!("Failed to stage '{}'", dest.display()),
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn staging_reuses_unchanged_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let staging = Staging::new(Some(temp.path().join("staging")))
+            .await
+            .unwrap();
+        let source = temp.path().join("asset.txt");
+        std::fs::write(&source, "hello").unwrap();
+
+        let first = staging.stage(&source).await.unwrap();
+        assert_eq!(bytes_sha256_string(b"hello"), first.sha256);
+        assert_eq!(5, first.size);
+        assert_eq!(b"hello".to_vec(), std::fs::read(&first.path).unwrap());
+
+        let second = staging.stage(&source).await.unwrap();
+        assert_eq!(first.sha256, second.sha256);
+        assert_eq!(first.path, second.path);
+    }
+}
@@ -0,0 +1,111 @@
+#![deny(missing_docs)]
+
+//! Placeholder expansion for artifact references and versions.
+
+use std::{collections::HashMap, path::Path};
+
+use crate::{seams, PublishError, PublishResult};
+
+/// Values for the placeholders in reference and version templates:
+///
+/// * `{version}`: the application version from the manifest
+/// * `{git_sha}`: the abbreviated commit hash of the application directory's
+///   git checkout
+/// * `{date}`: the current UTC date, as `YYYYMMDD`
+///
+/// Further placeholders may be given values with
+/// [`with_value`](Self::with_value).
+#[derive(Clone, Debug)]
+pub struct TemplateContext {
+    version: String,
+    git_sha: Option<String>,
+    date: String,
+    values: HashMap<String, String>,
+}
+
+impl TemplateContext {
+    /// Gathers placeholder values for an application.
+    pub fn new(version: impl Into<String>, app_dir: &Path) -> Self {
+        Self {
+            version: version.into(),
+            git_sha: seams::git_sha(app_dir),
+            date: seams::now().format("%Y%m%d").to_string(),
+            values: HashMap::new(),
+        }
+    }
+
+    /// Gives a value to the placeholder with the given name, replacing any
+    /// value it already has.
+    pub fn with_value(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.values.insert(name.into(), value.into());
+        self
+    }
+
+    /// Replaces the placeholders in a template with their values.
+    pub fn expand(&self, template: &str) -> PublishResult<String> {
+        let invalid = |reason: String| PublishError::InvalidTemplate {
+            template: template.to_owned(),
+            reason,
+        };
+
+        let mut expanded = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            expanded.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| invalid("unclosed '{'".to_owned()))?;
+            let name = &rest[start + 1..start + end];
+            let value = match name {
+                _ if self.values.contains_key(name) => &self.values[name],
+                "version" => &self.version,
+                "date" => &self.date,
+                "git_sha" => self.git_sha.as_ref().ok_or_else(|| {
+                    invalid(
+                        "{git_sha} requires the application to be in a git repository".to_owned(),
+                    )
+                })?,
+                _ => return Err(invalid(format!("unknown placeholder {{{}}}", name))),
+            };
+            expanded.push_str(value);
+            rest = &rest[start + end + 1..];
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expands_known_placeholders() {
+        let context = TemplateContext {
+            version: "1.2.0".to_owned(),
+            git_sha: Some("abc1234".to_owned()),
+            date: "20221016".to_owned(),
+            values: HashMap::new(),
+        };
+        assert_eq!(
+            "ghcr.io/org/app:1.2.0-abc1234",
+            context
+                .expand("ghcr.io/org/app:{version}-{git_sha}")
+                .unwrap()
+        );
+        assert_eq!("d20221016", context.expand("d{date}").unwrap());
+        assert!(context.expand("{branch}").is_err());
+        assert!(context.expand("{version").is_err());
+
+        let context = TemplateContext {
+            git_sha: None,
+            ..context
+        };
+        assert!(context.expand("{git_sha}").is_err());
+
+        let context = context
+            .with_value("number", "42")
+            .with_value("date", "today");
+        assert_eq!("pr-42-today", context.expand("pr-{number}-{date}").unwrap());
+    }
+}
@@ -0,0 +1,111 @@
+#![deny(missing_docs)]
+
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::{ready, Future, Stream};
+use tokio::time::Sleep;
+
+/// Limits the average rate at which data is transferred. A throttle may be
+/// shared between several transfers, in which case the limit applies to
+/// their combined rate.
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_sec: u64,
+    state: Mutex<ThrottleState>,
+}
+
+#[derive(Debug)]
+struct ThrottleState {
+    start: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    /// Creates a throttle which limits transfers to the given number of
+    /// bytes per second.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            state: Mutex::new(ThrottleState {
+                start: Instant::now(),
+                bytes: 0,
+            }),
+        }
+    }
+
+    /// Waits until the given number of bytes may be transferred without
+    /// exceeding the limit.
+    pub async fn consume(&self, bytes: usize) {
+        let delay = self.delay_for(bytes);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Accounts for a transfer, returning how long to wait before the next.
+    fn delay_for(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        state.bytes += bytes as u64;
+        let due = Duration::from_secs_f64(state.bytes as f64 / self.bytes_per_sec as f64);
+        due.saturating_sub(state.start.elapsed())
+    }
+}
+
+/// A stream of data chunks which are yielded no faster than a throttle
+/// allows.
+pub(crate) struct Throttled<S> {
+    inner: S,
+    throttle: Arc<Throttle>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub(crate) fn new(inner: S, throttle: Arc<Throttle>) -> Self {
+        Self {
+            inner,
+            throttle,
+            delay: None,
+        }
+    }
+}
+
+impl<S, B, E> Stream for Throttled<S>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+{
+    type Item = Result<B, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(delay) = self.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+
+        let item = ready!(Pin::new(&mut self.inner).poll_next(cx));
+        if let Some(Ok(chunk)) = &item {
+            let delay = self.throttle.delay_for(chunk.as_ref().len());
+            if !delay.is_zero() {
+                self.delay = Some(Box::pin(tokio::time::sleep(delay)));
+            }
+        }
+        Poll::Ready(item)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn throttle_delays_transfers_beyond_the_limit() {
+        let throttle = Throttle::new(1000);
+        assert!(throttle.delay_for(10) <= Duration::from_millis(10));
+        assert!(throttle.delay_for(1990) > Duration::from_millis(1500));
+    }
+}
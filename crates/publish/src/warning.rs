@@ -0,0 +1,80 @@
+use std::{fmt, path::PathBuf};
+
+use serde::Serialize;
+
+/// Describes conditions which did not stop a publish operation, but which
+/// may mean its outcome is not quite what was asked for
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PublishWarning {
+    /// A file was not published because the publish filter excludes it
+    SkippedFile {
+        /// The path of the file in the application
+        path: PathBuf,
+    },
+    /// The registry rejected a manifest, so a more widely supported kind
+    /// was pushed instead
+    FallbackMediaType {
+        /// The registry
+        registry: String,
+        /// The kind of manifest the registry rejected
+        rejected: String,
+        /// The kind of manifest pushed instead
+        pushed: String,
+    },
+    /// Credentials for the registry could not be read, so others, or none,
+    /// were used
+    DegradedAuth {
+        /// The registry
+        registry: String,
+        /// Why the credentials could not be used
+        reason: String,
+    },
+}
+
+impl fmt::Display for PublishWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SkippedFile { path } => {
+                write!(f, "{} was excluded by the publish filter", path.display())
+            }
+            Self::FallbackMediaType {
+                registry,
+                rejected,
+                pushed,
+            } => write!(
+                f,
+                "{} rejected the {}; pushed the {} instead",
+                registry, rejected, pushed
+            ),
+            Self::DegradedAuth { registry, reason } => {
+                write!(f, "Cannot use credentials for {}: {}", registry, reason)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn warnings_serialize_with_their_kind() {
+        let warning = PublishWarning::DegradedAuth {
+            registry: "ghcr.io".to_owned(),
+            reason: "cannot read Spin credentials".to_owned(),
+        };
+        assert_eq!(
+            serde_json::json!({
+                "kind": "degraded_auth",
+                "registry": "ghcr.io",
+                "reason": "cannot read Spin credentials",
+            }),
+            serde_json::to_value(&warning).unwrap()
+        );
+        assert_eq!(
+            "Cannot use credentials for ghcr.io: cannot read Spin credentials",
+            warning.to_string()
+        );
+    }
+}
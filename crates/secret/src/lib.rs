@@ -0,0 +1,75 @@
+#![deny(missing_docs)]
+
+//! A wrapper for credentials which keeps them out of logs and error messages.
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+const REDACTED: &str = "[REDACTED]";
+
+/// A credential, such as a password or token. Its `Debug` and `Display`
+/// output is redacted, so that it cannot leak through debug prints of the
+/// types which contain it; use [`expose`](Self::expose) where the value is
+/// actually needed. It serializes as the bare value.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    /// Wraps a credential.
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    /// The credential itself.
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    /// Unwraps the credential.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl FromStr for Secret<String> {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_owned()))
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({})", REDACTED)
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redacts_debug_and_display_but_not_serialization() {
+        let secret = Secret::new("hunter2".to_owned());
+        assert!(!format!("{:?}", secret).contains("hunter2"));
+        assert!(!format!("{}", secret).contains("hunter2"));
+        assert!(!format!("{:?}", Some(&secret)).contains("hunter2"));
+        assert_eq!("\"hunter2\"", serde_json::to_string(&secret).unwrap());
+        assert_eq!("hunter2", secret.expose());
+    }
+}
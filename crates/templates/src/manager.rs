@@ -100,6 +100,11 @@ impl TemplateManager {
         Ok(Self { store })
     }
 
+    /// The directory in which templates are installed.
+    pub fn directory(&self) -> &Path {
+        self.store.root()
+    }
+
     /// Installs templates from the specified source.
     pub async fn install(
         &self,
@@ -28,6 +28,10 @@ impl TemplateStore {
         Ok(Self::new(templates_dir))
     }
 
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
     pub(crate) fn get_directory(&self, id: impl AsRef<str>) -> PathBuf {
         self.root.join(Self::relative_dir(id.as_ref()))
     }
@@ -3,13 +3,19 @@ use clap::{CommandFactory, Parser, Subcommand};
 use is_terminal::IsTerminal;
 use lazy_static::lazy_static;
 use spin_cli::commands::{
+    apps::AppsCommands,
     bindle::BindleCommands,
     build::BuildCommand,
+    cache::CacheCommands,
     deploy::DeployCommand,
     external::execute_external_subcommand,
     login::LoginCommand,
     new::{AddCommand, NewCommand},
+    oci::OciCommands,
+    paths::PathsCommand,
     plugins::PluginCommands,
+    registry::RegistryCommands,
+    telemetry::TelemetryCommands,
     templates::TemplateCommands,
     up::UpCommand,
 };
@@ -49,12 +55,23 @@ enum SpinApp {
     Add(AddCommand),
     Up(UpCommand),
     #[clap(subcommand)]
+    Apps(AppsCommands),
+    #[clap(subcommand)]
     Bindle(BindleCommands),
     Deploy(DeployCommand),
     Build(BuildCommand),
+    #[clap(subcommand)]
+    Cache(CacheCommands),
     Login(LoginCommand),
+    #[clap(subcommand)]
+    Oci(OciCommands),
+    Paths(PathsCommand),
     #[clap(subcommand, alias = "plugins")]
     Plugin(PluginCommands),
+    #[clap(subcommand)]
+    Registry(RegistryCommands),
+    #[clap(subcommand)]
+    Telemetry(TelemetryCommands),
     #[clap(subcommand, hide = true)]
     Trigger(TriggerCommands),
     #[clap(external_subcommand)]
@@ -75,13 +92,19 @@ impl SpinApp {
             Self::Up(cmd) => cmd.run().await,
             Self::New(cmd) => cmd.run().await,
             Self::Add(cmd) => cmd.run().await,
+            Self::Apps(cmd) => cmd.run().await,
             Self::Bindle(cmd) => cmd.run().await,
             Self::Deploy(cmd) => cmd.run().await,
             Self::Build(cmd) => cmd.run().await,
+            Self::Cache(cmd) => cmd.run().await,
             Self::Trigger(TriggerCommands::Http(cmd)) => cmd.run().await,
             Self::Trigger(TriggerCommands::Redis(cmd)) => cmd.run().await,
             Self::Login(cmd) => cmd.run().await,
+            Self::Oci(cmd) => cmd.run().await,
+            Self::Paths(cmd) => cmd.run().await,
             Self::Plugin(cmd) => cmd.run().await,
+            Self::Registry(cmd) => cmd.run().await,
+            Self::Telemetry(cmd) => cmd.run().await,
             Self::External(cmd) => execute_external_subcommand(cmd, SpinApp::command()).await,
         }
     }
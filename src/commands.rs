@@ -1,9 +1,13 @@
 //! Commands for the Spin CLI.
 
+/// Commands for managing deployed applications.
+pub mod apps;
 /// Command for creating bindles.
 pub mod bindle;
 /// Commands for building Spin applications.
 pub mod build;
+/// Commands for managing the local registry cache.
+pub mod cache;
 /// Command for deploying a Spin app to Hippo
 pub mod deploy;
 /// Commands for external subcommands (i.e. plugins)
@@ -12,8 +16,16 @@ pub mod external;
 pub mod login;
 /// Command for creating a new application.
 pub mod new;
+/// Commands for working with Spin applications in OCI registries.
+pub mod oci;
+/// Command for reporting the paths Spin uses.
+pub mod paths;
 /// Command for adding a plugin to Spin
 pub mod plugins;
+/// Commands for working with OCI registries.
+pub mod registry;
+/// Commands for the opt-in deployment statistics.
+pub mod telemetry;
 /// Commands for working with templates.
 pub mod templates;
 /// Commands for starting the runtime.
@@ -0,0 +1,336 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{anyhow, bail, Result};
+use clap::{Parser, Subcommand};
+use cloud::client::{Client as CloudClient, RevisionHistoryItem};
+use uuid::Uuid;
+
+use crate::opts::*;
+
+use super::{deploy::SPIN_DEPLOY_CHANNEL_NAME, login::LoginConnection, new::ParameterValue};
+
+/// Commands for managing applications deployed to Fermyon Cloud.
+#[derive(Subcommand, Debug)]
+pub enum AppsCommands {
+    /// View or update the environment variables of a deployed application.
+    Env(EnvCommand),
+    /// List the revisions of a deployed application and their provenance.
+    History(HistoryCommand),
+    /// Delete the channels with the given labels, and any application left
+    /// without channels.
+    Cleanup(CleanupCommand),
+}
+
+impl AppsCommands {
+    pub async fn run(self) -> Result<()> {
+        match self {
+            Self::Env(cmd) => cmd.run().await,
+            Self::History(cmd) => cmd.run().await,
+            Self::Cleanup(cmd) => cmd.run().await,
+        }
+    }
+}
+
+/// View or update the environment variables of a deployed application. The
+/// change takes effect without a redeploy.
+#[derive(Parser, Debug)]
+pub struct EnvCommand {
+    /// The name of the application.
+    pub app: String,
+
+    /// The channel whose variables to view or update. Defaults to the
+    /// channel created by `spin deploy`.
+    #[clap(long = "channel", default_value = SPIN_DEPLOY_CHANNEL_NAME)]
+    pub channel: String,
+
+    /// Set an environment variable, in the form `name=value`. May be
+    /// repeated.
+    #[clap(long = "set", multiple_occurrences = true)]
+    pub set: Vec<ParameterValue>,
+
+    /// Remove an environment variable. May be repeated.
+    #[clap(long = "unset", multiple_occurrences = true)]
+    pub unset: Vec<String>,
+
+    /// Print variable values. By default only names are printed, as values
+    /// are often secrets.
+    #[clap(long = "show-values")]
+    pub show_values: bool,
+
+    /// Use the Fermyon instance saved under the specified name.
+    #[clap(
+        name = "environment-name",
+        long = "environment-name",
+        env = DEPLOYMENT_ENV_NAME_ENV
+    )]
+    pub deployment_env_id: Option<String>,
+}
+
+impl EnvCommand {
+    pub async fn run(self) -> Result<()> {
+        let login = LoginConnection::load(self.deployment_env_id.as_deref()).await?;
+        let client = login.cloud_client()?;
+
+        let result = self.run_with(&client).await;
+        result.map_err(|e| login.explain_unauthorized(e))
+    }
+
+    async fn run_with(&self, client: &CloudClient) -> Result<()> {
+        let channel_id = self.channel_id(client).await?;
+        let mut variables: BTreeMap<_, _> = client
+            .get_environment_variables(channel_id)
+            .await?
+            .into_iter()
+            .map(|v| (v.key, v.value))
+            .collect();
+
+        if self.set.is_empty() && self.unset.is_empty() {
+            self.print(&variables);
+            return Ok(());
+        }
+
+        self.apply_changes(&mut variables)?;
+        client
+            .set_environment_variables(channel_id, variables.clone())
+            .await?;
+        println!(
+            "Updated environment variables of {} ({})",
+            self.app, self.channel
+        );
+        self.print(&variables);
+        Ok(())
+    }
+
+    /// Removes the variables given with `--unset`, then sets those given
+    /// with `--set`.
+    fn apply_changes(&self, variables: &mut BTreeMap<String, String>) -> Result<()> {
+        for name in &self.unset {
+            if variables.remove(name).is_none() {
+                bail!("Channel '{}' has no variable '{}'", self.channel, name);
+            }
+        }
+        for ParameterValue { name, value } in &self.set {
+            variables.insert(name.clone(), value.clone());
+        }
+        Ok(())
+    }
+
+    async fn channel_id(&self, client: &CloudClient) -> Result<Uuid> {
+        let app_id = client
+            .get_app_id(&self.app)
+            .await?
+            .ok_or_else(|| anyhow!("No application named '{}'", self.app))?;
+        client
+            .get_channel_id(app_id, &self.channel)
+            .await?
+            .ok_or_else(|| {
+                anyhow!(
+                    "Application '{}' has no channel '{}'",
+                    self.app,
+                    self.channel
+                )
+            })
+    }
+
+    fn print(&self, variables: &BTreeMap<String, String>) {
+        if variables.is_empty() {
+            println!("No environment variables are set");
+        }
+        for (name, value) in variables {
+            if self.show_values {
+                println!("{}={}", name, value);
+            } else {
+                println!("{}", name);
+            }
+        }
+    }
+}
+
+/// List the revisions of a deployed application, with the build metadata,
+/// deployer and registry digest the platform recorded for each.
+#[derive(Parser, Debug)]
+pub struct HistoryCommand {
+    /// The name of the application.
+    pub app: String,
+
+    /// The format in which to print the revisions: `text` or `json`.
+    #[clap(long = "output", arg_enum, default_value = "text")]
+    pub output: OutputFormat,
+
+    /// Use the Fermyon instance saved under the specified name.
+    #[clap(
+        name = "environment-name",
+        long = "environment-name",
+        env = DEPLOYMENT_ENV_NAME_ENV
+    )]
+    pub deployment_env_id: Option<String>,
+}
+
+/// How commands which describe something print their results.
+#[derive(clap::ArgEnum, Clone, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    #[clap(name = "text")]
+    Text,
+    #[clap(name = "json")]
+    Json,
+}
+
+impl HistoryCommand {
+    pub async fn run(self) -> Result<()> {
+        let login = LoginConnection::load(self.deployment_env_id.as_deref()).await?;
+        let client = login.cloud_client()?;
+
+        let result = self.run_with(&client).await;
+        result.map_err(|e| login.explain_unauthorized(e))
+    }
+
+    async fn run_with(&self, client: &CloudClient) -> Result<()> {
+        let app_id = client
+            .get_app_id(&self.app)
+            .await?
+            .ok_or_else(|| anyhow!("No application named '{}'", self.app))?;
+        let revisions = client.list_revision_history(app_id).await?;
+
+        match self.output {
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&revisions)?),
+            OutputFormat::Text => print_history(&revisions),
+        }
+        Ok(())
+    }
+}
+
+fn print_history(revisions: &[RevisionHistoryItem]) {
+    if revisions.is_empty() {
+        println!("No revisions have been deployed");
+    }
+    for revision in revisions {
+        println!(
+            "{}  {}  {}  {}",
+            revision.revision_number,
+            revision.created.as_deref().unwrap_or("-"),
+            revision.deployed_by.as_deref().unwrap_or("-"),
+            revision.registry_digest.as_deref().unwrap_or("-"),
+        );
+        for (key, value) in &revision.build_metadata {
+            println!("    {}={}", key, value);
+        }
+    }
+}
+
+/// Delete the channels deployed with the given labels (see `spin deploy
+/// --label`), and any application left without channels, such as the
+/// preview environments of a closed pull request.
+#[derive(Parser, Debug)]
+pub struct CleanupCommand {
+    /// Select channels with this label, in the form `name=value`. May be
+    /// repeated, in which case channels must have every label.
+    #[clap(long = "label", multiple_occurrences = true, required = true)]
+    pub labels: Vec<ParameterValue>,
+
+    /// Keep applications which are left without channels.
+    #[clap(long = "keep-apps")]
+    pub keep_apps: bool,
+
+    /// Print what would be deleted without deleting anything.
+    #[clap(long = "dry-run")]
+    pub dry_run: bool,
+
+    /// Use the Fermyon instance saved under the specified name.
+    #[clap(
+        name = "environment-name",
+        long = "environment-name",
+        env = DEPLOYMENT_ENV_NAME_ENV
+    )]
+    pub deployment_env_id: Option<String>,
+}
+
+impl CleanupCommand {
+    pub async fn run(self) -> Result<()> {
+        let login = LoginConnection::load(self.deployment_env_id.as_deref()).await?;
+        let client = login.cloud_client()?;
+
+        let result = self.run_with(&client).await;
+        result.map_err(|e| login.explain_unauthorized(e))
+    }
+
+    async fn run_with(&self, client: &CloudClient) -> Result<()> {
+        let labels: BTreeMap<_, _> = self
+            .labels
+            .iter()
+            .map(|l| (l.name.clone(), l.value.clone()))
+            .collect();
+        let channels = client.find_labelled_channels(&labels).await?;
+        if channels.is_empty() {
+            println!("No channels have the given labels");
+            return Ok(());
+        }
+
+        let verb = if self.dry_run {
+            "Would delete"
+        } else {
+            "Deleting"
+        };
+        for channel in &channels {
+            println!("{} channel {} of {}", verb, channel.name, channel.app_name);
+            if !self.dry_run {
+                client.delete_channel(channel.id).await?;
+            }
+        }
+
+        if self.keep_apps {
+            return Ok(());
+        }
+        let deleted: BTreeSet<_> = channels.iter().map(|c| c.id).collect();
+        let apps: BTreeMap<_, _> = channels.iter().map(|c| (c.app_id, &c.app_name)).collect();
+        for (app_id, app_name) in apps {
+            let remaining = client
+                .list_app_channels(app_id)
+                .await?
+                .into_iter()
+                .filter(|c| !deleted.contains(&c.id))
+                .count();
+            if remaining == 0 {
+                println!("{} application {}", verb, app_name);
+                if !self.dry_run {
+                    client.remove_app(app_id.to_string()).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn variables(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn env_changes_unset_then_set() {
+        let cmd = EnvCommand::try_parse_from([
+            "env", "myapp", "--unset", "old", "--unset", "kept", "--set", "kept=new", "--set",
+            "added=1",
+        ])
+        .unwrap();
+        let mut vars = variables(&[("old", "x"), ("kept", "y"), ("other", "z")]);
+        cmd.apply_changes(&mut vars).unwrap();
+        assert_eq!(
+            variables(&[("added", "1"), ("kept", "new"), ("other", "z")]),
+            vars
+        );
+    }
+
+    #[test]
+    fn env_refuses_to_unset_missing_variables() {
+        let cmd = EnvCommand::try_parse_from(["env", "myapp", "--unset", "missing"]).unwrap();
+        let mut vars = variables(&[("present", "x")]);
+        let err = cmd.apply_changes(&mut vars).unwrap_err();
+        assert!(err.to_string().contains("no variable 'missing'"));
+    }
+}
@@ -1,11 +1,16 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use semver::BuildMetadata;
 use spin_loader::bindle::BindleConnectionInfo;
+use spin_loader::local::parent_dir;
+use spin_publish::{PublishError, PublishFilter, PushOptions, PushOutcome, Secret, Staging};
 
-use crate::{opts::*, parse_buildinfo, sloth::warn_if_slow_response};
+use crate::{
+    opts::*, parse_buildinfo, parse_rate_limit, sloth::warn_if_slow_response,
+    staging_dirs::StagingDir,
+};
 
 /// Commands for publishing applications as bindles.
 #[derive(Subcommand, Debug)]
@@ -26,6 +31,29 @@ impl BindleCommands {
     }
 }
 
+/// Overrides for which of an application's files are published.
+#[derive(Parser, Debug)]
+pub struct PublishFilterOptions {
+    /// Publish hidden files and directories, whose names start with `.`
+    #[clap(long = "include-hidden", takes_value = false)]
+    pub include_hidden: bool,
+
+    /// Publish files reached through symbolic links
+    #[clap(long = "follow-symlinks", takes_value = false)]
+    pub follow_symlinks: bool,
+}
+
+impl PublishFilterOptions {
+    /// The filter for the application with the given manifest, from its
+    /// `.spinignore` file and these overrides.
+    pub fn filter(&self, app_file: &Path) -> Result<PublishFilter> {
+        let app_dir = parent_dir(app_file)?;
+        Ok(PublishFilter::load(&app_dir)?
+            .include_hidden(self.include_hidden)
+            .follow_symlinks(self.follow_symlinks))
+    }
+}
+
 /// Create a standalone bindle for subsequent publication.
 #[derive(Parser, Debug)]
 pub struct Prepare {
@@ -52,6 +80,9 @@ pub struct Prepare {
         short = 'd',
     )]
     pub staging_dir: PathBuf,
+
+    #[clap(flatten)]
+    pub filter: PublishFilterOptions,
 }
 
 /// Publish an application as a bindle.
@@ -106,7 +137,7 @@ pub struct Push {
         env = BINDLE_PASSWORD,
         requires = BINDLE_USERNAME
     )]
-    pub bindle_password: Option<String>,
+    pub bindle_password: Option<Secret<String>>,
 
     /// Ignore server certificate errors
     #[clap(
@@ -116,6 +147,24 @@ pub struct Push {
         takes_value = false,
     )]
     pub insecure: bool,
+
+    /// Limit the upload rate, in bytes per second. Accepts K, M and G
+    /// suffixes (e.g. `512K`).
+    #[clap(
+        long = "upload-limit",
+        env = UPLOAD_LIMIT_ENV,
+        parse(try_from_str = parse_rate_limit),
+    )]
+    pub upload_limit: Option<u64>,
+
+    /// Push only the parcels used by the specified component. May be
+    /// repeated. The parcels of other components must already be on the
+    /// server, for example from a previous push of the same version.
+    #[clap(long = "component", multiple_occurrences = true)]
+    pub components: Vec<String>,
+
+    #[clap(flatten)]
+    pub filter: PublishFilterOptions,
 }
 
 impl Prepare {
@@ -126,9 +175,12 @@ impl Prepare {
             .unwrap_or_else(|| DEFAULT_MANIFEST_FILE.as_ref());
 
         let dest_dir = &self.staging_dir;
-        let bindle_id = spin_publish::prepare_bindle(app_file, self.buildinfo, dest_dir)
-            .await
-            .map_err(crate::wrap_prepare_bindle_error)?;
+        let staging = Staging::new(None).await?;
+        let filter = self.filter.filter(app_file)?;
+        let bindle_id =
+            spin_publish::prepare_bindle(app_file, self.buildinfo, dest_dir, &staging, &filter)
+                .await
+                .map_err(crate::wrap_prepare_bindle_error)?;
 
         // We can't try to canonicalize it until the directory has been created
         let full_dest_dir =
@@ -151,31 +203,40 @@ impl Push {
             &self.bindle_server_url,
             self.insecure,
             self.bindle_username,
-            self.bindle_password,
+            self.bindle_password.map(Secret::into_inner),
         );
 
-        // TODO: only create this if not given a staging dir
-        let temp_dir = tempfile::tempdir()?;
-
-        let dest_dir = match &self.staging_dir {
-            None => temp_dir.path(),
-            Some(path) => path.as_path(),
-        };
+        let staging_dir = StagingDir::prepare(self.staging_dir.as_deref())?;
+        let dest_dir = staging_dir.path();
 
-        let bindle_id = spin_publish::prepare_bindle(app_file, self.buildinfo, dest_dir)
-            .await
-            .map_err(crate::wrap_prepare_bindle_error)?;
+        let staging = Staging::new(None).await?;
+        let filter = self.filter.filter(app_file)?;
+        let bindle_id =
+            spin_publish::prepare_bindle(app_file, self.buildinfo, dest_dir, &staging, &filter)
+                .await
+                .map_err(crate::wrap_prepare_bindle_error)?;
 
         let _sloth_warning = warn_if_slow_response(format!(
             "Uploading application to {}",
             self.bindle_server_url
         ));
 
-        spin_publish::push_all(&dest_dir, &bindle_id, bindle_connection_info.clone())
-            .await
-            .with_context(|| {
-                crate::push_all_failed_msg(dest_dir, bindle_connection_info.base_url())
-            })?;
+        let outcome = spin_publish::push_all(
+            &dest_dir,
+            &bindle_id,
+            bindle_connection_info.clone(),
+            &PushOptions {
+                upload_limit: self.upload_limit,
+                components: (!self.components.is_empty()).then(|| self.components.clone()),
+            },
+        )
+        .await
+        .with_context(|| crate::push_all_failed_msg(dest_dir, bindle_connection_info.base_url()))?;
+        if outcome == PushOutcome::AlreadyExists {
+            return Err(PublishError::BindleAlreadyExists(bindle_id.to_string())).with_context(
+                || crate::push_all_failed_msg(dest_dir, bindle_connection_info.base_url()),
+            );
+        }
 
         println!("pushed: {}", bindle_id);
         Ok(())
@@ -0,0 +1,209 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use spin_publish::oci::{Cache, Client, Operation, OperationKind, DEFAULT_WARM_CONCURRENCY};
+
+use crate::{
+    commands::{apps::OutputFormat, oci::TlsOptions},
+    opts::*,
+    staging_dirs::StagingDirs,
+};
+
+/// Commands for managing the local registry cache.
+#[derive(Subcommand, Debug)]
+pub enum CacheCommands {
+    /// Pull a list of applications into the cache ahead of time.
+    Warm(Warm),
+
+    /// Reclaim disk space used by Spin.
+    Prune(Prune),
+
+    /// List the pushes and pulls recorded in the cache's operations log.
+    History(History),
+}
+
+impl CacheCommands {
+    pub async fn run(self) -> Result<()> {
+        match self {
+            Self::Warm(cmd) => cmd.run().await,
+            Self::Prune(cmd) => cmd.run().await,
+            Self::History(cmd) => cmd.run().await,
+        }
+    }
+}
+
+/// Pull a list of applications into the cache ahead of time.
+#[derive(Parser, Debug)]
+pub struct Warm {
+    /// File listing the references to pull, one per line. Blank lines and
+    /// lines starting with `#` are ignored.
+    #[clap(short = 'f', long = "file")]
+    pub file: PathBuf,
+
+    /// Directory of the cache to warm. Defaults to the Spin registry cache.
+    #[clap(long = "cache-dir")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// How many references to pull at once.
+    #[clap(long = "concurrency", default_value_t = DEFAULT_WARM_CONCURRENCY)]
+    pub concurrency: usize,
+
+    /// Connect to registries over plain HTTP
+    #[clap(
+        name = INSECURE_OPT,
+        short = 'k',
+        long = "insecure",
+        takes_value = false,
+    )]
+    pub insecure: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsOptions,
+}
+
+impl Warm {
+    pub async fn run(self) -> Result<()> {
+        let contents = tokio::fs::read_to_string(&self.file)
+            .await
+            .with_context(|| format!("Failed to read {}", self.file.display()))?;
+        let references = parse_reference_list(&contents);
+
+        let client = self.tls.client(self.insecure)?;
+        let cache = Cache::new(self.cache_dir).await?;
+        let outcomes = cache.warm(&client, references, self.concurrency).await;
+
+        let mut failures = 0;
+        for (reference, outcome) in outcomes {
+            match outcome {
+                Ok(digest) => println!("{}: cached {}", reference, digest),
+                Err(e) => {
+                    failures += 1;
+                    eprintln!("{}: {:#}", reference, anyhow::Error::from(e));
+                }
+            }
+        }
+        if failures > 0 {
+            bail!("Failed to cache {} reference(s)", failures);
+        }
+        Ok(())
+    }
+}
+
+/// Reclaim disk space used by Spin.
+#[derive(Parser, Debug)]
+pub struct Prune {
+    /// Remove bindle staging directories which are no longer in use,
+    /// including those given with `--staging-dir`.
+    #[clap(long = "staging")]
+    pub staging: bool,
+}
+
+impl Prune {
+    pub async fn run(self) -> Result<()> {
+        if !self.staging {
+            bail!("Specify what to prune, e.g. `--staging`");
+        }
+        let removed = StagingDirs::open()?.remove_abandoned(true)?;
+        for path in &removed {
+            println!("Removed {}", path.display());
+        }
+        println!("Removed {} staging directories", removed.len());
+        Ok(())
+    }
+}
+
+/// List the pushes and pulls recorded in the cache's operations log, most
+/// recent last.
+#[derive(Parser, Debug)]
+pub struct History {
+    /// Directory of the cache whose log to read. Defaults to the Spin
+    /// registry cache.
+    #[clap(long = "cache-dir")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Only list operations on references containing this text (e.g. a
+    /// repository such as `ghcr.io/my-org/my-app`).
+    #[clap(long = "reference")]
+    pub reference: Option<String>,
+
+    /// Only list this many of the most recent operations.
+    #[clap(long = "limit")]
+    pub limit: Option<usize>,
+
+    /// The format in which to print the operations: `text` or `json`.
+    #[clap(long = "output", arg_enum, default_value = "text")]
+    pub output: OutputFormat,
+}
+
+impl History {
+    pub async fn run(self) -> Result<()> {
+        let cache = Cache::new(self.cache_dir).await?;
+        let mut operations = cache.operations().await?;
+        if let Some(reference) = &self.reference {
+            operations.retain(|o| o.reference.contains(reference.as_str()));
+        }
+        if let Some(limit) = self.limit {
+            let skip = operations.len().saturating_sub(limit);
+            operations.drain(..skip);
+        }
+
+        match self.output {
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&operations)?),
+            OutputFormat::Text => print_operations(&operations),
+        }
+        Ok(())
+    }
+}
+
+fn print_operations(operations: &[Operation]) {
+    if operations.is_empty() {
+        println!("No operations have been recorded");
+    }
+    for operation in operations {
+        let kind = match operation.kind {
+            OperationKind::Push => "push",
+            OperationKind::Pull => "pull",
+        };
+        let outcome = match &operation.error {
+            Some(error) => format!("failed: {}", error),
+            None => "ok".to_owned(),
+        };
+        println!(
+            "{}  {}  {}  {}  {} of {} bytes  {}ms  {}",
+            operation.time,
+            kind,
+            operation.reference,
+            operation.digest.as_deref().unwrap_or("-"),
+            operation.transferred,
+            operation
+                .size
+                .map_or_else(|| "?".to_owned(), |size| size.to_string()),
+            operation.duration_ms,
+            outcome
+        );
+    }
+}
+
+fn parse_reference_list(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reference_lists_skip_blanks_and_comments() {
+        let contents = "# fleet apps\nghcr.io/org/a:v1\n\n  ghcr.io/org/b:v2  \n";
+        assert_eq!(
+            vec!["ghcr.io/org/a:v1", "ghcr.io/org/b:v2"],
+            parse_reference_list(contents)
+        );
+    }
+}
@@ -3,11 +3,23 @@ use anyhow::{anyhow, bail, Context, Result};
 use bindle::Id;
 use chrono::{DateTime, Utc};
 use clap::Parser;
-use cloud::client::{Client as CloudClient, ConnectionConfig};
+use cloud::client::{
+    Client as CloudClient, ConnectionConfig, PatchChannelCommand, REGISTRY_TOKENS_PATH,
+};
 use cloud_openapi::models::ChannelRevisionSelectionStrategy as CloudChannelRevisionSelectionStrategy;
 use cloud_openapi::models::TokenInfo;
 use hippo::{Client, ConnectionInfo};
-use hippo_openapi::models::ChannelRevisionSelectionStrategy;
+use hippo_openapi::apis::{
+    channel_statuses_api::api_channel_statuses_get,
+    channels_api::api_channels_id_patch,
+    configuration::{ApiKey, Configuration as HippoConfiguration},
+};
+use hippo_openapi::models::{
+    ChannelRevisionSelectionStrategy, JobStatus, PatchChannelCommand as HippoPatchChannelCommand,
+    UpdateEnvironmentVariableDto as HippoEnvironmentVariable,
+    UpdateEnvironmentVariableDtoListField,
+};
+use is_terminal::IsTerminal;
 use rand::Rng;
 use semver::BuildMetadata;
 use sha2::{Digest, Sha256};
@@ -18,23 +30,45 @@ use spin_loader::bindle::BindleConnectionInfo;
 use spin_loader::local::config::{RawAppManifest, RawAppManifestAnyVersion};
 use spin_loader::local::{assets, config, parent_dir};
 use spin_manifest::ApplicationTrigger;
-use spin_manifest::{HttpTriggerConfiguration, TriggerConfig};
+use spin_manifest::{HttpTriggerConfiguration, RedisTriggerConfiguration, TriggerConfig};
+use spin_publish::{oci::TrustPolicy, PushOptions, PushOutcome, Secret, Staging, TemplateContext};
 use tokio::fs;
 use tracing::instrument;
 
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io;
 use std::io::{copy, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use url::Url;
 use uuid::Uuid;
 
-use crate::{opts::*, parse_buildinfo, sloth::warn_if_slow_response};
+use crate::{
+    commands::bindle::PublishFilterOptions,
+    deploy_lock::DeployLock,
+    deploy_summary::DeploySummary,
+    deployment_manifest::{
+        Change, CurrentChannel, CurrentDeployment, DeployedChannel, DeploymentManifest,
+    },
+    endpoints::EndpointRecorder,
+    git_source::GitSource,
+    local_check::verify_locally,
+    opts::*,
+    parse_buildinfo, parse_rate_limit,
+    paths::{config_root_dir, login_file},
+    provenance::GitProvenance,
+    sloth::warn_if_slow_response,
+    staging_dirs::StagingDir,
+    variables::{resolve_variables, VariableStore},
+    version_bump::{bump_manifest_version, commit_and_tag, BumpLevel},
+};
 
 use super::login::LoginCommand;
 use super::login::LoginConnection;
+use super::new::ParameterValue;
 
-const SPIN_DEPLOY_CHANNEL_NAME: &str = "spin-deploy";
+pub(crate) const SPIN_DEPLOY_CHANNEL_NAME: &str = "spin-deploy";
 
 const BINDLE_REGISTRY_URL_PATH: &str = "api/registry";
 
@@ -68,18 +102,32 @@ pub struct DeployCommand {
     )]
     pub no_buildinfo: bool,
 
-    /// Build metadata to append to the bindle version
+    /// Build metadata to append to the bindle version. May contain the
+    /// placeholders `{version}`, `{git_sha}` and `{date}`.
+    #[clap(name = BUILDINFO_OPT, long = "buildinfo")]
+    pub buildinfo: Option<String>,
+
+    /// Deploy existing bindle if it already exists on bindle server.
+    /// Equivalent to `--on-existing reuse`.
     #[clap(
-        name = BUILDINFO_OPT,
-        long = "buildinfo",
-        parse(try_from_str = parse_buildinfo),
+        short = 'e',
+        long = "deploy-existing-bindle",
+        conflicts_with = "on-existing"
     )]
-    pub buildinfo: Option<BuildMetadata>,
-
-    /// Deploy existing bindle if it already exists on bindle server
-    #[clap(short = 'e', long = "deploy-existing-bindle")]
     pub redeploy: bool,
 
+    /// What to do if the bindle already exists on the bindle server: fail
+    /// (`error`), deploy the existing bindle (`reuse`), or increment the
+    /// build metadata and push again (`bump-build`).
+    #[clap(name = "on-existing", long = "on-existing", arg_enum)]
+    pub on_existing: Option<OnExisting>,
+
+    /// If no build metadata is given and the bindle already exists, first
+    /// retry with a unique build suffix derived from the current time
+    /// (`timestamp`) or the application content (`digest`).
+    #[clap(long = "unique-build", arg_enum, env = "SPIN_DEPLOY_UNIQUE_BUILD")]
+    pub unique_build: Option<UniqueBuild>,
+
     /// How long in seconds to wait for a deployed HTTP application to become
     /// ready. The default is 60 seconds. Set it to 0 to skip waiting
     /// for readiness.
@@ -94,10 +142,169 @@ pub struct DeployCommand {
         env = DEPLOYMENT_ENV_NAME_ENV
     )]
     pub deployment_env_id: Option<String>,
+
+    /// Set a value for an application variable, in the form `name=value`.
+    /// Required variables which are not set are prompted for.
+    #[clap(long = "variable", multiple_occurrences = true)]
+    pub variables: Vec<ParameterValue>,
+
+    /// Fail if the application content no longer matches the digest recorded
+    /// in spin.deploy.lock, rather than updating the lockfile.
+    #[clap(long = "locked")]
+    pub locked: bool,
+
+    /// Write a report of every remote host and port contacted during the
+    /// deployment to the specified file.
+    #[clap(long = "record-endpoints")]
+    pub record_endpoints: Option<PathBuf>,
+
+    /// After a successful deployment, write a deployment manifest recording
+    /// the deployed revision, its channel and the application variables to
+    /// the specified file, in TOML. Secret variable values are not recorded.
+    #[clap(long = "export-manifest", value_name = "FILE")]
+    pub export_manifest: Option<PathBuf>,
+
+    /// Rather than deploying the application, bring the platform into line
+    /// with a deployment manifest written by `--export-manifest`, creating
+    /// the app and channel if need be and setting the recorded revision,
+    /// variables and labels. The changes are listed before they are made.
+    /// Values of secret variables may be given with `--variable`.
+    #[clap(
+        long = "apply",
+        value_name = "FILE",
+        conflicts_with_all = &["git", "bump", "build", "verify_locally", "export_manifest"]
+    )]
+    pub apply: Option<PathBuf>,
+
+    /// Limit the upload rate, in bytes per second. Accepts K, M and G
+    /// suffixes (e.g. `512K`).
+    #[clap(
+        long = "upload-limit",
+        env = UPLOAD_LIMIT_ENV,
+        parse(try_from_str = parse_rate_limit),
+    )]
+    pub upload_limit: Option<u64>,
+
+    /// Refuse to deploy to a registry which is not allowed by the content
+    /// trust policy in the specified file.
+    #[clap(long = "trust-policy", env = TRUST_POLICY_ENV)]
+    pub trust_policy: Option<PathBuf>,
+
+    /// Refuse to deploy unless the application is in a git repository with
+    /// no uncommitted changes, and the checked out commit is tagged.
+    #[clap(long = "strict-provenance", conflicts_with = "annotate-provenance")]
+    pub strict_provenance: bool,
+
+    /// Record the git commit, and whether it is dirty or untagged, in the
+    /// build metadata of the deployed version.
+    #[clap(name = "annotate-provenance", long = "annotate-provenance")]
+    pub annotate_provenance: bool,
+
+    /// Ask for confirmation, after showing what the deployment changes,
+    /// before making the new revision active.
+    #[clap(long = "confirm")]
+    pub confirm: bool,
+
+    /// Increment the version in spin.toml before deploying: `patch`,
+    /// `minor` or `major`.
+    #[clap(name = "bump", long = "bump", arg_enum, conflicts_with = "git")]
+    pub bump: Option<BumpLevel>,
+
+    /// Commit the bumped spin.toml and tag the commit `v<version>`.
+    #[clap(long = "bump-commit", requires = "bump")]
+    pub bump_commit: bool,
+
+    /// What to do if the application's HTTP routes overlap those of other
+    /// applications deployed to the same domain: `warn`, `error` or
+    /// `ignore`.
+    #[clap(
+        name = "route-conflicts",
+        long = "route-conflicts",
+        arg_enum,
+        default_value = "warn"
+    )]
+    pub route_conflicts: RouteConflicts,
+
+    /// Before uploading anything, check that each component compiles and
+    /// instantiates locally, catching missing imports or failing
+    /// initialization before the application is deployed.
+    #[clap(long = "verify-locally")]
+    pub verify_locally: bool,
+
+    /// When verifying locally, also run the HTTP trigger and check that
+    /// this route (e.g. `/health`) answers successfully.
+    #[clap(
+        long = "verify-route",
+        value_name = "ROUTE",
+        requires = "verify_locally"
+    )]
+    pub verify_route: Option<String>,
+
+    /// Deploy the application from this git repository rather than a local
+    /// directory. The repository is shallow cloned into a temporary
+    /// directory, and the path to spin.toml is relative to its root.
+    #[clap(long = "git", value_name = "URL")]
+    pub git: Option<String>,
+
+    /// The branch, tag or commit to deploy from the `--git` repository.
+    /// Defaults to the repository's default branch.
+    #[clap(long = "ref", value_name = "REF", requires = "git")]
+    pub git_ref: Option<String>,
+
+    /// The channel to deploy to, which is created if it does not exist.
+    /// May contain the placeholders `{app}` (the application name), `{env}`
+    /// (the environment name), `{version}`, `{git_sha}` and `{date}`, and
+    /// any given with `--template-value` (e.g. `pr-{number}`).
+    #[clap(long = "channel", default_value = SPIN_DEPLOY_CHANNEL_NAME)]
+    pub channel: String,
+
+    /// The domain of the channel, if it is created. May contain the same
+    /// placeholders as `--channel`. Defaults to a domain chosen by the
+    /// platform.
+    #[clap(long = "domain")]
+    pub domain: Option<String>,
+
+    /// Give a value to a placeholder in `--channel` or `--domain`, in the
+    /// form `name=value`. May be repeated.
+    #[clap(long = "template-value", multiple_occurrences = true)]
+    pub template_values: Vec<ParameterValue>,
+
+    /// Label the channel, in the form `name=value`, so that it can be
+    /// found later, for example by `spin apps cleanup`. May be repeated.
+    /// Placeholders are expanded in values as in `--channel`.
+    #[clap(long = "label", multiple_occurrences = true)]
+    pub labels: Vec<ParameterValue>,
+
+    /// Build the application before deploying it, as `spin build` does.
+    #[clap(long = "build")]
+    pub build: bool,
+
+    #[clap(flatten)]
+    pub filter: PublishFilterOptions,
+
+    #[clap(skip)]
+    endpoints: Arc<EndpointRecorder>,
 }
 
 impl DeployCommand {
     pub async fn run(self) -> Result<()> {
+        let endpoints = self.endpoints.clone();
+        let report_path = self.record_endpoints.clone();
+
+        let started = std::time::Instant::now();
+        let result = self.run_deploy().await;
+        crate::telemetry::record_deploy(started, &result);
+
+        // The report is most useful when the deployment was blocked, so
+        // write it whether or not the deployment succeeded
+        if let Some(path) = report_path {
+            endpoints.write_report(&path)?;
+            eprintln!("Endpoints contacted were recorded in {}", path.display());
+        }
+        result
+    }
+
+    async fn run_deploy(mut self) -> Result<()> {
         let path = self.config_file_path()?;
 
         // log in if config.json does not exist or cannot be read
@@ -153,37 +360,95 @@ impl DeployCommand {
 
         let sloth_warning =
             warn_if_slow_response(format!("Checking status ({})", login_connection.url));
+        self.endpoints.record(&login_connection.url, "platform API");
         check_healthz(&login_connection.url).await?;
         // Hippo has responded - we don't want to keep the sloth timer running.
         drop(sloth_warning);
 
+        if let Some(path) = self.apply.clone() {
+            if login_connection.bindle_url.is_some() {
+                bail!("--apply is only supported by Fermyon Cloud");
+            }
+            let explainer = login_connection.clone();
+            return self
+                .apply_cloud(login_connection, &path)
+                .await
+                .map_err(|e| explainer.explain_unauthorized(e));
+        }
+
+        // Kept until the deployment finishes, as the clone is removed when
+        // it is dropped
+        let _source = match &self.git {
+            Some(url) => {
+                println!("Fetching {}...", url);
+                self.endpoints.record_git_remote(url);
+                let source = GitSource::fetch(url, self.git_ref.as_deref())?;
+                self.app = source.path().join(&self.app);
+                Some(source)
+            }
+            None => None,
+        };
+
+        if self.build {
+            spin_build::build(&self.app).await?;
+        }
+
+        if let Some(level) = &self.bump {
+            let (old, new) = bump_manifest_version(&self.app, level)?;
+            println!("Bumped version from {} to {}", old, new);
+            if self.bump_commit {
+                commit_and_tag(&self.app, &new)?;
+                println!("Committed and tagged v{}", new);
+            }
+        }
+
+        if self.verify_locally {
+            println!("Verifying the application locally...");
+            verify_locally(&self.app, self.verify_route.as_deref())
+                .await
+                .context("Local verification failed")?;
+        }
+
         // TODO: we should have a smarter check in place here to determine the difference between Hippo and the Cloud APIs
         if login_connection.bindle_url.is_some() {
             self.deploy_hippo(login_connection).await
         } else {
             const DEVELOPER_CLOUD_FAQ: &str = "https://developer.fermyon.com/cloud/faq";
 
+            let explainer = login_connection.clone();
             self.deploy_cloud(login_connection)
                 .await
+                .map_err(|e| explainer.explain_unauthorized(e))
                 .map_err(|e| anyhow!("{:?}\n\nLearn more at {}", e, DEVELOPER_CLOUD_FAQ))
         }
     }
 
     // TODO: unify with login
     fn config_file_path(&self) -> Result<PathBuf> {
-        let root = dirs::config_dir()
-            .context("Cannot find configuration directory")?
-            .join("fermyon");
+        login_file(self.deployment_env_id.as_deref())
+    }
 
-        let file_stem = match &self.deployment_env_id {
+    fn environment_stem(&self) -> &str {
+        match &self.deployment_env_id {
             None => "config",
             Some(id) => id,
-        };
-        let file = format!("{}.json", file_stem);
+        }
+    }
 
-        let path = root.join(file);
+    fn on_existing(&self) -> OnExisting {
+        match &self.on_existing {
+            Some(on_existing) => on_existing.clone(),
+            None if self.redeploy => OnExisting::Reuse,
+            None => OnExisting::Error,
+        }
+    }
 
-        Ok(path)
+    fn variable_store(&self, app_name: &str) -> Result<VariableStore> {
+        Ok(VariableStore::new(
+            &config_root_dir()?,
+            self.environment_stem(),
+            app_name,
+        ))
     }
 
     async fn deploy_hippo(self, login_connection: LoginConnection) -> Result<()> {
@@ -191,31 +456,53 @@ impl DeployCommand {
         let RawAppManifestAnyVersion::V1(cfg) = cfg_any;
 
         ensure!(!cfg.components.is_empty(), "No components in spin.toml!");
+        ensure!(
+            self.labels.is_empty(),
+            "Hippo does not support channel labels, so --label cannot be used"
+        );
+
+        let digest = self.compute_digest(&cfg).await?;
+        self.check_lock(&cfg, &digest)?;
+        let channel_name = self.channel_name(&cfg)?;
+        let channel_domain = self.channel_domain(&cfg)?;
+
+        let variables =
+            resolve_variables(&cfg, &self.variables, &self.variable_store(&cfg.info.name)?)?;
 
         let buildinfo = if !self.no_buildinfo {
             match &self.buildinfo {
-                Some(i) => Some(i.clone()),
-                None => self.compute_buildinfo(&cfg).await.map(Option::Some)?,
+                Some(template) => Some(self.expand_buildinfo(template, &cfg)?),
+                None => Some(buildinfo_from_digest(&digest)?),
             }
         } else {
             None
         };
+        let buildinfo = self.apply_provenance(buildinfo)?;
 
+        self.endpoints.record_str(
+            login_connection.bindle_url.as_deref().unwrap(),
+            "Bindle server",
+        );
         let bindle_connection_info = BindleConnectionInfo::new(
             login_connection.bindle_url.unwrap(),
             login_connection.danger_accept_invalid_certs,
             login_connection.bindle_username,
-            login_connection.bindle_password,
+            login_connection.bindle_password.map(Secret::into_inner),
         );
 
         let bindle_id = self
-            .create_and_push_bindle(buildinfo, bindle_connection_info)
+            .create_and_push_bindle(buildinfo, &digest, bindle_connection_info)
             .await?;
 
+        let hippo_configuration = hippo_configuration(
+            &login_connection.url,
+            login_connection.danger_accept_invalid_certs,
+            login_connection.token.expose(),
+        )?;
         let hippo_client = Client::new(ConnectionInfo {
             url: login_connection.url.to_string(),
             danger_accept_invalid_certs: login_connection.danger_accept_invalid_certs,
-            api_key: Some(login_connection.token),
+            api_key: Some(login_connection.token.into_inner()),
         });
 
         let name = bindle_id.name().to_string();
@@ -231,13 +518,6 @@ impl DeployCommand {
                     bindle_id.version_string().clone(),
                 )
                 .await?;
-                let existing_channel_id = self
-                    .get_channel_id_hippo(
-                        &hippo_client,
-                        SPIN_DEPLOY_CHANNEL_NAME.to_string(),
-                        app_id,
-                    )
-                    .await?;
                 let active_revision_id = self
                     .get_revision_id_hippo(
                         &hippo_client,
@@ -245,21 +525,43 @@ impl DeployCommand {
                         app_id,
                     )
                     .await?;
-                Client::patch_channel(
-                    &hippo_client,
-                    existing_channel_id,
-                    None,
-                    None,
-                    Some(ChannelRevisionSelectionStrategy::UseSpecifiedRevision),
-                    None,
-                    Some(active_revision_id),
-                    None,
-                    None,
-                )
-                .await
-                .context("Problem patching a channel in Hippo")?;
-
-                existing_channel_id
+                match self
+                    .find_channel_id_hippo(&hippo_client, channel_name.clone(), app_id)
+                    .await?
+                {
+                    Some(existing_channel_id) => {
+                        Client::patch_channel(
+                            &hippo_client,
+                            existing_channel_id,
+                            None,
+                            None,
+                            Some(ChannelRevisionSelectionStrategy::UseSpecifiedRevision),
+                            None,
+                            Some(active_revision_id),
+                            None,
+                            None,
+                        )
+                        .await
+                        .context("Problem patching a channel in Hippo")?;
+
+                        existing_channel_id
+                    }
+                    None => {
+                        println!("Creating channel {}", channel_name);
+                        Client::add_channel(
+                            &hippo_client,
+                            app_id,
+                            channel_name.clone(),
+                            channel_domain.clone(),
+                            ChannelRevisionSelectionStrategy::UseSpecifiedRevision,
+                            None,
+                            Some(active_revision_id),
+                            None,
+                        )
+                        .await
+                        .context("Problem creating a channel in Hippo")?
+                    }
+                }
             }
             Err(_) => {
                 let range_rule = Some(bindle_id.version_string());
@@ -269,8 +571,8 @@ impl DeployCommand {
                 Client::add_channel(
                     &hippo_client,
                     app_id,
-                    String::from(SPIN_DEPLOY_CHANNEL_NAME),
-                    None,
+                    channel_name.clone(),
+                    channel_domain.clone(),
                     ChannelRevisionSelectionStrategy::UseRangeRule,
                     range_rule,
                     None,
@@ -281,15 +583,33 @@ impl DeployCommand {
             }
         };
 
+        if !variables.is_empty() {
+            let environment_variables = variables
+                .iter()
+                .map(|(key, value)| HippoEnvironmentVariable::new(key.clone(), value.clone()))
+                .collect();
+            let command = HippoPatchChannelCommand {
+                environment_variables: Some(Box::new(UpdateEnvironmentVariableDtoListField {
+                    value: Some(environment_variables),
+                })),
+                ..HippoPatchChannelCommand::new()
+            };
+            api_channels_id_patch(&hippo_configuration, &channel_id.to_string(), Some(command))
+                .await
+                .context("Problem setting application variables in Hippo")?;
+        }
+
         println!(
             "Deployed {} version {}",
             name.clone(),
             bindle_id.version_string()
         );
+        self.record_lock(&cfg, digest.clone(), &bindle_id)?;
         let channel = Client::get_channel_by_id(&hippo_client, &channel_id.to_string())
             .await
             .context("Problem getting channel by id")?;
         let app_base_url = build_app_base_url(&channel.domain, &login_connection.url)?;
+        self.endpoints.record(&app_base_url, "application");
         if let Ok(http_config) = HttpTriggerConfiguration::try_from(cfg.info.trigger.clone()) {
             wait_for_ready(
                 &app_base_url,
@@ -299,23 +619,51 @@ impl DeployCommand {
             .await;
             print_available_routes(&app_base_url, &http_config.base, &cfg);
         } else {
+            wait_for_running(
+                &hippo_configuration,
+                channel_id,
+                &cfg,
+                self.readiness_timeout_secs,
+            )
+            .await;
             println!("Application is running at {}", channel.domain);
         }
 
-        Ok(())
+        self.export_deployment_manifest(
+            DeploymentManifest {
+                name: cfg.info.name.clone(),
+                version: cfg.info.version.clone(),
+                bindle_id: bindle_id.to_string(),
+                digest,
+                secret_variables: vec![],
+                channel: DeployedChannel {
+                    name: channel_name,
+                    domain: Some(channel.domain),
+                    labels: BTreeMap::new(),
+                },
+                variables: BTreeMap::new(),
+            }
+            .with_variables(&cfg, &variables),
+        )
     }
 
     async fn deploy_cloud(self, login_connection: LoginConnection) -> Result<()> {
         let connection_config = ConnectionConfig {
             url: login_connection.url.to_string(),
             insecure: login_connection.danger_accept_invalid_certs,
-            token: TokenInfo {
-                token: Some(login_connection.token.clone()),
+            token: Secret::new(TokenInfo {
+                token: Some(login_connection.token.expose().clone()),
                 expiration: Some(login_connection.expiration.clone()),
-            },
+            }),
+            organization: login_connection.organization.clone(),
+            machine_id: login_connection.machine_id.clone(),
+            token_fingerprint: login_connection.token_fingerprint.clone(),
         };
 
-        let client = CloudClient::new(connection_config.clone());
+        let client = CloudClient::with_redirect_policy(
+            connection_config.clone(),
+            self.endpoints.redirect_policy("platform API"),
+        );
 
         let cfg_any = spin_loader::local::raw_manifest_from_file(&self.app).await?;
         let RawAppManifestAnyVersion::V1(cfg) = cfg_any;
@@ -327,9 +675,20 @@ impl DeployCommand {
             ApplicationTrigger::Redis(_) => bail!("Redis triggers are not supported"),
         }
 
+        let digest = self.compute_digest(&cfg).await?;
+        self.check_lock(&cfg, &digest)?;
+        let channel_name = self.channel_name(&cfg)?;
+        let labels = self.channel_labels(&cfg)?;
+        if self.domain.is_some() {
+            bail!("Fermyon Cloud chooses the domain of each channel, so --domain cannot be used");
+        }
+
+        let variables =
+            resolve_variables(&cfg, &self.variables, &self.variable_store(&cfg.info.name)?)?;
+
         let buildinfo = if !self.no_buildinfo {
             match &self.buildinfo {
-                Some(i) => Some(i.clone()),
+                Some(template) => Some(self.expand_buildinfo(template, &cfg)?),
                 // FIXME(lann): As a workaround for buggy partial bindle uploads,
                 // force a new bindle version on every upload.
                 None => Some(random_buildinfo()),
@@ -337,81 +696,148 @@ impl DeployCommand {
         } else {
             None
         };
+        let buildinfo = self.apply_provenance(buildinfo)?;
 
         let su = Url::parse(login_connection.url.as_str())?;
+        self.endpoints
+            .record(&su.join(REGISTRY_TOKENS_PATH)?, "registry token");
+        let registry_token = match client.create_registry_token().await {
+            Ok(registry_token) => registry_token.token.into_inner(),
+            Err(e) => {
+                // Platforms which predate registry tokens accept the login token
+                tracing::debug!("Using login token for registry: {:?}", e);
+                login_connection.token.into_inner()
+            }
+        };
+        self.endpoints
+            .record(&su.join(BINDLE_REGISTRY_URL_PATH)?, "registry");
         let bindle_connection_info = BindleConnectionInfo::from_token(
             su.join(BINDLE_REGISTRY_URL_PATH)?.to_string(),
             login_connection.danger_accept_invalid_certs,
-            login_connection.token,
+            registry_token,
         );
 
         let bindle_id = self
-            .create_and_push_bindle(buildinfo, bindle_connection_info)
+            .create_and_push_bindle(buildinfo, &digest, bindle_connection_info)
             .await?;
         let name = bindle_id.name().to_string();
 
         println!("Deploying...");
 
+        // Routes can only be checked on a domain, so those of an existing
+        // channel are checked before anything is changed. The changes are
+        // reviewed before anything is changed too, whether or not the
+        // channel exists.
+        let existing_app_id = self.get_app_id_cloud(&client, name.clone()).await.ok();
+        let existing_channel_id = match existing_app_id {
+            Some(app_id) => {
+                self.find_channel_id_cloud(&client, channel_name.clone(), app_id)
+                    .await?
+            }
+            None => None,
+        };
+        if let (Some(app_id), Some(channel_id)) = (existing_app_id, existing_channel_id) {
+            self.check_route_conflicts(&client, channel_id, app_id, &cfg)
+                .await?;
+        }
+        let approved = self
+            .review_changes(
+                &client,
+                existing_channel_id,
+                &bindle_id.version_string(),
+                &cfg,
+                &variables,
+            )
+            .await?;
+        if !approved {
+            match existing_channel_id {
+                Some(_) => println!("Deployment cancelled. The active revision is unchanged."),
+                None => println!(
+                    "Deployment cancelled. Channel {} was not created.",
+                    channel_name
+                ),
+            }
+            return Ok(());
+        }
+
         // Create or update app
         // TODO: this process involves many calls to Hippo. Should be able to update the channel
         // via only `add_revision` if bindle naming schema is updated so bindles can be deterministically ordered by Hippo.
-        let channel_id = match self.get_app_id_cloud(&client, name.clone()).await {
-            Ok(app_id) => {
+        let app_id = match existing_app_id {
+            Some(app_id) => {
                 CloudClient::add_revision(
                     &client,
                     name.clone(),
                     bindle_id.version_string().clone(),
                 )
                 .await?;
-                let existing_channel_id = self
-                    .get_channel_id_cloud(&client, SPIN_DEPLOY_CHANNEL_NAME.to_string(), app_id)
-                    .await?;
-                let active_revision_id = self
-                    .get_revision_id_cloud(&client, bindle_id.version_string().clone(), app_id)
-                    .await?;
-                CloudClient::patch_channel(
-                    &client,
-                    existing_channel_id,
-                    None,
-                    Some(CloudChannelRevisionSelectionStrategy::UseSpecifiedRevision),
-                    None,
-                    Some(active_revision_id),
-                    None,
-                )
-                .await
-                .context("Problem patching a channel")?;
-
-                existing_channel_id
+                app_id
             }
-            Err(_) => {
-                let app_id = CloudClient::add_app(&client, &name, &name)
-                    .await
-                    .context("Unable to create app")?;
-
+            None => {
                 // When creating the new app, InitialRevisionImport command is triggered
                 // which automatically imports all revisions from bindle into db
                 // therefore we do not need to call add_revision api explicitly here
-                let active_revision_id = self
-                    .get_revision_id_cloud(&client, bindle_id.version_string().clone(), app_id)
-                    .await?;
-
-                CloudClient::add_channel(
+                CloudClient::add_app(&client, &name, &name)
+                    .await
+                    .context("Unable to create app")?
+            }
+        };
+        let active_revision_id = self
+            .get_revision_id_cloud(&client, bindle_id.version_string().clone(), app_id)
+            .await?;
+        let channel_id = match existing_channel_id {
+            Some(channel_id) => channel_id,
+            None => {
+                // The platform chooses the domain of a new channel, so its
+                // routes are checked once it exists, before it serves the
+                // new revision.
+                println!("Creating channel {}", channel_name);
+                let channel_id = CloudClient::add_channel(
                     &client,
                     app_id,
-                    String::from(SPIN_DEPLOY_CHANNEL_NAME),
+                    channel_name.clone(),
                     CloudChannelRevisionSelectionStrategy::UseSpecifiedRevision,
                     None,
-                    Some(active_revision_id),
+                    None,
                 )
                 .await
-                .context("Problem creating a channel")?
+                .context("Problem creating a channel")?;
+                if let Err(e) = self
+                    .check_route_conflicts(&client, channel_id, app_id, &cfg)
+                    .await
+                {
+                    let created_app_id = existing_app_id.is_none().then_some(app_id);
+                    discard_deployment(&client, channel_id, created_app_id).await;
+                    return Err(e);
+                }
+                channel_id
             }
         };
+        client
+            .patch_channel(
+                channel_id,
+                PatchChannelCommand::new().with_active_revision(active_revision_id),
+            )
+            .await
+            .context("Problem patching a channel")?;
+
+        if !variables.is_empty() {
+            client
+                .merge_environment_variables(channel_id, variables.clone())
+                .await
+                .context("Problem setting application variables")?;
+        }
+        if !labels.is_empty() {
+            client.set_channel_labels(channel_id, &labels).await?;
+        }
+
+        self.record_lock(&cfg, digest.clone(), &bindle_id)?;
 
         let channel = CloudClient::get_channel_by_id(&client, &channel_id.to_string())
             .await
             .context("Problem getting channel by id")?;
         let app_base_url = build_app_base_url(&channel.domain, &login_connection.url)?;
+        self.endpoints.record(&app_base_url, "application");
         if let Ok(http_config) = HttpTriggerConfiguration::try_from(cfg.info.trigger.clone()) {
             wait_for_ready(
                 &app_base_url,
@@ -424,12 +850,283 @@ impl DeployCommand {
             println!("Application is running at {}", channel.domain);
         }
 
+        self.export_deployment_manifest(
+            DeploymentManifest {
+                name: cfg.info.name.clone(),
+                version: cfg.info.version.clone(),
+                bindle_id: bindle_id.to_string(),
+                digest,
+                secret_variables: vec![],
+                channel: DeployedChannel {
+                    name: channel_name,
+                    domain: Some(channel.domain),
+                    labels,
+                },
+                variables: BTreeMap::new(),
+            }
+            .with_variables(&cfg, &variables),
+        )
+    }
+
+    /// Brings the platform into line with the deployment manifest given by
+    /// `--apply`, making only the changes it needs.
+    async fn apply_cloud(self, login_connection: LoginConnection, path: &Path) -> Result<()> {
+        let manifest = DeploymentManifest::load(path)?;
+        let client = login_connection.cloud_client()?;
+        let revision = manifest.revision().to_owned();
+
+        let mut app_id = client.get_app_id(&manifest.name).await?;
+        let mut channel_id = None;
+        let mut current = CurrentDeployment {
+            app_exists: app_id.is_some(),
+            ..Default::default()
+        };
+        if let Some(app_id) = app_id {
+            current.revision_registered =
+                client.get_revision_id(app_id, &revision).await?.is_some();
+            channel_id = client
+                .get_channel_id(app_id, &manifest.channel.name)
+                .await?;
+        }
+        if let Some(id) = channel_id {
+            let channel = client
+                .get_channel_by_id(&id.to_string())
+                .await
+                .context("Problem getting channel by id")?;
+            current.channel = Some(CurrentChannel {
+                active_revision: channel.active_revision.map(|r| r.revision_number),
+                domain: channel.domain,
+                variables: channel
+                    .environment_variables
+                    .into_iter()
+                    .map(|v| (v.key, v.value))
+                    .collect(),
+                labels: client.get_channel_labels(id).await?,
+            });
+        }
+        if let (Some(channel), Some(domain)) = (&current.channel, &manifest.channel.domain) {
+            if &channel.domain != domain {
+                eprintln!(
+                    "Warning: channel {} serves {} rather than {}. Fermyon Cloud chooses the domain of each channel, so it is not changed.",
+                    manifest.channel.name, channel.domain, domain
+                );
+            }
+        }
+
+        let variables = manifest.desired_variables(&self.variables, current.channel.as_ref())?;
+        let changes = manifest.plan(&current, &variables);
+        if changes.is_empty() {
+            println!("{} is up to date with {}", manifest.name, path.display());
+            return Ok(());
+        }
+        println!("Changes to apply:");
+        for change in &changes {
+            println!("  {}", change);
+        }
+        if !self.confirm_changes()? {
+            println!("Nothing was changed.");
+            return Ok(());
+        }
+
+        let count = changes.len();
+        for change in changes {
+            match change {
+                Change::CreateApp(name) => {
+                    app_id = Some(
+                        client
+                            .add_app(&name, &name)
+                            .await
+                            .context("Unable to create app")?,
+                    );
+                }
+                Change::RegisterRevision(revision) => {
+                    client.add_revision(manifest.name.clone(), revision).await?;
+                }
+                Change::CreateChannel { name, revision } => {
+                    let app_id = app_id.context("The app does not exist")?;
+                    let revision_id = self
+                        .get_revision_id_cloud(&client, revision, app_id)
+                        .await?;
+                    channel_id = Some(
+                        client
+                            .add_channel(
+                                app_id,
+                                name,
+                                CloudChannelRevisionSelectionStrategy::UseSpecifiedRevision,
+                                None,
+                                Some(revision_id),
+                            )
+                            .await
+                            .context("Problem creating a channel")?,
+                    );
+                }
+                Change::SetActiveRevision { to, .. } => {
+                    let app_id = app_id.context("The app does not exist")?;
+                    let revision_id = self.get_revision_id_cloud(&client, to, app_id).await?;
+                    client
+                        .patch_channel(
+                            channel_id.context("The channel does not exist")?,
+                            PatchChannelCommand::new().with_active_revision(revision_id),
+                        )
+                        .await
+                        .context("Problem patching a channel")?;
+                }
+                Change::SetVariables(_) => {
+                    client
+                        .merge_environment_variables(
+                            channel_id.context("The channel does not exist")?,
+                            variables.clone(),
+                        )
+                        .await
+                        .context("Problem setting application variables")?;
+                }
+                Change::SetLabels(labels) => {
+                    client
+                        .set_channel_labels(
+                            channel_id.context("The channel does not exist")?,
+                            &labels,
+                        )
+                        .await?;
+                }
+            }
+        }
+        println!("Applied {} change(s) to {}", count, manifest.name);
         Ok(())
     }
 
-    async fn compute_buildinfo(&self, cfg: &RawAppManifest) -> Result<BuildMetadata> {
+    /// Checks whether the application's HTTP routes overlap those of other
+    /// applications on the channel's domain, which would shadow them or be
+    /// shadowed. Depending on `--route-conflicts`, overlaps are reported as
+    /// warnings or refuse the deployment.
+    async fn check_route_conflicts(
+        &self,
+        client: &CloudClient,
+        channel_id: Uuid,
+        app_id: Uuid,
+        cfg: &RawAppManifest,
+    ) -> Result<()> {
+        if self.route_conflicts == RouteConflicts::Ignore {
+            return Ok(());
+        }
+        let http_config = match HttpTriggerConfiguration::try_from(cfg.info.trigger.clone()) {
+            Ok(http_config) => http_config,
+            Err(_) => return Ok(()),
+        };
+        let channel = client
+            .get_channel_by_id(&channel_id.to_string())
+            .await
+            .context("Problem getting channel by id")?;
+        let deployed = match client.list_domain_routes(&channel.domain).await {
+            Ok(deployed) => deployed,
+            Err(e) => {
+                // Platforms which predate the routes API cannot be checked
+                tracing::debug!("Skipping route conflict check: {:?}", e);
+                return Ok(());
+            }
+        };
+
+        let mut conflicts = vec![];
+        for component in &cfg.components {
+            if let TriggerConfig::Http(http_cfg) = &component.trigger {
+                let route = RoutePattern::from(http_config.base.as_str(), http_cfg.route.as_str());
+                for other in deployed.iter().filter(|r| r.app_id != app_id) {
+                    if route.overlaps(&RoutePattern::from("/", other.route.as_str())) {
+                        conflicts.push(format!(
+                            "  {}: {} overlaps {} of application '{}'",
+                            component.id, route, other.route, other.app_name
+                        ));
+                    }
+                }
+            }
+        }
+        if conflicts.is_empty() {
+            return Ok(());
+        }
+
+        match self.route_conflicts {
+            RouteConflicts::Error => bail!(
+                "Routes overlap those of other applications on {}:\n{}\nUse --route-conflicts warn to deploy anyway",
+                channel.domain,
+                conflicts.join("\n")
+            ),
+            _ => {
+                eprintln!(
+                    "Warning: routes overlap those of other applications on {}:",
+                    channel.domain
+                );
+                for conflict in conflicts {
+                    eprintln!("{}", conflict);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Prints what the deployment changes relative to the channel's active
+    /// revision, or relative to nothing if the channel does not exist yet.
+    /// With `--confirm`, asks whether to go ahead, returning whether the
+    /// deployment was approved.
+    async fn review_changes(
+        &self,
+        client: &CloudClient,
+        channel_id: Option<Uuid>,
+        new_version: &str,
+        cfg: &RawAppManifest,
+        variables: &[(String, String)],
+    ) -> Result<bool> {
+        let channel = match channel_id {
+            Some(channel_id) => Some(
+                client
+                    .get_channel_by_id(&channel_id.to_string())
+                    .await
+                    .context("Problem getting the active revision")?,
+            ),
+            None => None,
+        };
+        let old_variables: BTreeMap<_, _> = channel
+            .iter()
+            .flat_map(|c| c.environment_variables.iter())
+            .map(|v| (v.key.clone(), v.value.clone()))
+            .collect();
+        // The given variables are set, and the channel's others are kept
+        let mut new_variables = old_variables.clone();
+        new_variables.extend(variables.iter().cloned());
+        let old_revision = channel.as_ref().and_then(|c| c.active_revision.as_deref());
+        let summary = DeploySummary::new(
+            old_revision.map(|r| r.revision_number.as_str()),
+            new_version,
+            old_revision
+                .into_iter()
+                .flat_map(|r| r.components.iter().map(|c| c.name.as_str())),
+            cfg.components.iter().map(|c| c.id.as_str()),
+            &old_variables,
+            &new_variables,
+        );
+        println!("Changes to the active revision:");
+        print!("{}", summary);
+        self.confirm_changes()
+    }
+
+    /// With `--confirm`, asks whether to make the changes which have been
+    /// listed, returning whether they were approved.
+    fn confirm_changes(&self) -> Result<bool> {
+        if !self.confirm {
+            return Ok(true);
+        }
+        if !std::io::stdin().is_terminal() {
+            bail!("--confirm requires an interactive terminal");
+        }
+        let approved = dialoguer::Confirm::new()
+            .with_prompt("Apply these changes?")
+            .default(false)
+            .interact()?;
+        Ok(approved)
+    }
+
+    async fn compute_digest(&self, cfg: &RawAppManifest) -> Result<String> {
         let mut sha256 = Sha256::new();
         let app_folder = parent_dir(&self.app)?;
+        let filter = self.filter.filter(&self.app)?;
 
         for x in cfg.components.iter() {
             match &x.source {
@@ -446,7 +1143,7 @@ impl DeployCommand {
             if let Some(files) = &x.wasm.files {
                 let exclude_files = x.wasm.exclude_files.clone().unwrap_or_default();
                 let fm = assets::collect(files, &exclude_files, &app_folder)?;
-                for f in fm.iter() {
+                for f in fm.iter().filter(|f| filter.admits(&app_folder, &f.src)) {
                     let mut r = File::open(&f.src)
                         .with_context(|| anyhow!("Cannot open file {}", &f.src.display()))?;
                     copy(&mut r, &mut sha256)?;
@@ -457,13 +1154,45 @@ impl DeployCommand {
         let mut r = File::open(&self.app)?;
         copy(&mut r, &mut sha256)?;
 
-        let mut final_digest = format!("q{:x}", sha256.finalize());
-        final_digest.truncate(8);
+        Ok(format!("sha256:{:x}", sha256.finalize()))
+    }
+
+    fn check_lock(&self, cfg: &RawAppManifest, digest: &str) -> Result<()> {
+        if !self.locked {
+            return Ok(());
+        }
+        let path = DeployLock::path_for(&self.app)?;
+        let lock = DeployLock::load(&path)?.with_context(|| {
+            format!(
+                "--locked was specified but {} does not exist",
+                path.display()
+            )
+        })?;
+        lock.verify(&cfg.info.name, &cfg.info.version, digest)
+    }
 
-        let buildinfo =
-            BuildMetadata::new(&final_digest).with_context(|| "Could not compute build info")?;
+    fn record_lock(&self, cfg: &RawAppManifest, digest: String, bindle_id: &Id) -> Result<()> {
+        // In locked mode the lockfile is an input, not an output
+        if self.locked {
+            return Ok(());
+        }
+        let lock = DeployLock {
+            name: cfg.info.name.clone(),
+            version: cfg.info.version.clone(),
+            digest,
+            bindle_id: bindle_id.to_string(),
+        };
+        lock.save(&DeployLock::path_for(&self.app)?)
+    }
 
-        Ok(buildinfo)
+    /// Writes the deployment manifest to the file given by
+    /// `--export-manifest`, if any.
+    fn export_deployment_manifest(&self, manifest: DeploymentManifest) -> Result<()> {
+        if let Some(path) = &self.export_manifest {
+            manifest.save(path)?;
+            println!("Deployment manifest written to {}", path.display());
+        }
+        Ok(())
     }
 
     async fn get_app_id_hippo(&self, hippo_client: &Client, name: String) -> Result<Uuid> {
@@ -476,10 +1205,8 @@ impl DeployCommand {
     }
 
     async fn get_app_id_cloud(&self, cloud_client: &CloudClient, name: String) -> Result<Uuid> {
-        let apps_vm = CloudClient::list_apps(cloud_client).await?;
-        let app = apps_vm.items.iter().find(|&x| x.name == name.clone());
-        match app {
-            Some(a) => Ok(a.id),
+        match cloud_client.get_app_id(&name).await? {
+            Some(app_id) => Ok(app_id),
             None => bail!("No app with name: {}", name),
         }
     }
@@ -512,125 +1239,335 @@ impl DeployCommand {
         bindle_version: String,
         app_id: Uuid,
     ) -> Result<Uuid> {
-        let mut revisions = cloud_client.list_revisions().await?;
-
-        loop {
-            if let Some(revision) = revisions
-                .items
-                .iter()
-                .find(|&x| x.revision_number == bindle_version && x.app_id == app_id)
-            {
-                return Ok(revision.id);
-            }
-
-            if revisions.is_last_page {
-                break;
-            }
-
-            revisions = cloud_client.list_revisions_next(&revisions).await?;
-        }
-
-        Err(anyhow!(
-            "No revision with version {} and app id {}",
-            bindle_version,
-            app_id
-        ))
+        cloud_client
+            .get_revision_id(app_id, &bindle_version)
+            .await?
+            .ok_or_else(|| {
+                anyhow!(
+                    "No revision with version {} and app id {}",
+                    bindle_version,
+                    app_id
+                )
+            })
     }
 
-    async fn get_channel_id_hippo(
+    async fn find_channel_id_hippo(
         &self,
         hippo_client: &Client,
         name: String,
         app_id: Uuid,
-    ) -> Result<Uuid> {
+    ) -> Result<Option<Uuid>> {
         let channels_vm = Client::list_channels(hippo_client).await?;
         let channel = channels_vm
             .items
             .iter()
             .find(|&x| x.app_id == app_id && x.name == name.clone());
-        match channel {
-            Some(c) => Ok(c.id),
-            None => bail!("No channel with app_id {} and name {}", app_id, name),
-        }
+        Ok(channel.map(|c| c.id))
     }
 
-    async fn get_channel_id_cloud(
+    async fn find_channel_id_cloud(
         &self,
         cloud_client: &CloudClient,
         name: String,
         app_id: Uuid,
-    ) -> Result<Uuid> {
-        let mut channels_vm = cloud_client.list_channels().await?;
+    ) -> Result<Option<Uuid>> {
+        cloud_client.get_channel_id(app_id, &name).await
+    }
 
-        loop {
-            if let Some(channel) = channels_vm
-                .items
-                .iter()
-                .find(|&x| x.app_id == app_id && x.name == name.clone())
-            {
-                return Ok(channel.id);
+    /// Values for the placeholders in channel names and domains: those of
+    /// build metadata, `{app}`, `{env}` and any given with
+    /// `--template-value`.
+    fn channel_template_context(&self, cfg: &RawAppManifest) -> Result<TemplateContext> {
+        let mut context = TemplateContext::new(&cfg.info.version, &parent_dir(&self.app)?)
+            .with_value("app", &cfg.info.name)
+            .with_value(
+                "env",
+                self.deployment_env_id.as_deref().unwrap_or("default"),
+            );
+        for value in &self.template_values {
+            context = context.with_value(&value.name, &value.value);
+        }
+        Ok(context)
+    }
+
+    fn channel_name(&self, cfg: &RawAppManifest) -> Result<String> {
+        Ok(self.channel_template_context(cfg)?.expand(&self.channel)?)
+    }
+
+    fn channel_labels(&self, cfg: &RawAppManifest) -> Result<BTreeMap<String, String>> {
+        let context = self.channel_template_context(cfg)?;
+        self.labels
+            .iter()
+            .map(|l| Ok((l.name.clone(), context.expand(&l.value)?)))
+            .collect()
+    }
+
+    fn channel_domain(&self, cfg: &RawAppManifest) -> Result<Option<String>> {
+        let context = self.channel_template_context(cfg)?;
+        Ok(self
+            .domain
+            .as_deref()
+            .map(|d| context.expand(d))
+            .transpose()?)
+    }
+
+    fn apply_provenance(&self, buildinfo: Option<BuildMetadata>) -> Result<Option<BuildMetadata>> {
+        if !self.strict_provenance && !self.annotate_provenance {
+            return Ok(buildinfo);
+        }
+
+        let app_dir = parent_dir(&self.app)?;
+        let provenance = GitProvenance::inspect(&app_dir)?;
+
+        if self.strict_provenance {
+            let problems = match &provenance {
+                Some(provenance) => provenance.problems(),
+                None => vec!["the application is not in a git repository"],
+            };
+            if !problems.is_empty() {
+                bail!(
+                    "Refusing to deploy with --strict-provenance because {}",
+                    problems.join(" and ")
+                );
             }
+            return Ok(buildinfo);
+        }
 
-            if channels_vm.is_last_page {
-                break;
+        let provenance = match provenance {
+            Some(provenance) => provenance,
+            None => {
+                eprintln!("The application is not in a git repository, so its provenance cannot be recorded");
+                return Ok(buildinfo);
             }
+        };
+        let mut identifiers: Vec<String> =
+            buildinfo.iter().map(|b| b.as_str().to_owned()).collect();
+        identifiers.extend(provenance.build_identifiers());
+        Ok(Some(parse_buildinfo(&identifiers.join("."))?))
+    }
 
-            channels_vm = cloud_client.list_channels_next(&channels_vm).await?;
-        }
+    fn expand_buildinfo(&self, template: &str, cfg: &RawAppManifest) -> Result<BuildMetadata> {
+        let context = TemplateContext::new(&cfg.info.version, &parent_dir(&self.app)?);
+        parse_buildinfo(&context.expand(template)?)
+    }
 
-        Err(anyhow!(
-            "No channel with app_id {} and name {}",
-            app_id,
-            name
-        ))
+    fn check_trust_policy(
+        &self,
+        bindle_connection_info: &BindleConnectionInfo,
+        bindle_name: &str,
+    ) -> Result<()> {
+        let policy = match &self.trust_policy {
+            Some(path) => TrustPolicy::load(path)?,
+            None => return Ok(()),
+        };
+        let url = Url::parse(bindle_connection_info.base_url())?;
+        let registry = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => bail!("Registry URL {} has no host", url),
+        };
+        policy
+            .check_registry(&registry, bindle_name)
+            .map_err(spin_publish::PublishError::from)?;
+        Ok(())
     }
 
     async fn create_and_push_bindle(
         &self,
         buildinfo: Option<BuildMetadata>,
+        digest: &str,
         bindle_connection_info: BindleConnectionInfo,
     ) -> Result<Id> {
-        let temp_dir = tempfile::tempdir()?;
-        let dest_dir = match &self.staging_dir {
-            None => temp_dir.path(),
-            Some(path) => path.as_path(),
+        let staging_dir = StagingDir::prepare(self.staging_dir.as_deref())?;
+        let dest_dir = staging_dir.path();
+
+        // Fail before preparing the bindle if it could not be uploaded anyway.
+        bindle_connection_info.ping().await?;
+
+        let mut buildinfo = buildinfo;
+        let mut bumps = 0;
+        let mut unique_build = match &self.buildinfo {
+            Some(_) => None,
+            None => self.unique_build.clone(),
         };
 
-        let bindle_id = spin_publish::prepare_bindle(&self.app, buildinfo, dest_dir)
+        let staging = Staging::new(None).await?;
+        let filter = self.filter.filter(&self.app)?;
+        loop {
+            let bindle_id = spin_publish::prepare_bindle(
+                &self.app,
+                buildinfo.clone(),
+                dest_dir,
+                &staging,
+                &filter,
+            )
             .await
             .map_err(crate::wrap_prepare_bindle_error)?;
 
-        println!(
-            "Uploading {} version {}...",
-            bindle_id.name(),
-            bindle_id.version()
-        );
+            self.check_trust_policy(&bindle_connection_info, bindle_id.name())?;
+
+            println!(
+                "Uploading {} version {}...",
+                bindle_id.name(),
+                bindle_id.version()
+            );
+
+            let outcome = spin_publish::push_all(
+                dest_dir,
+                &bindle_id,
+                bindle_connection_info.clone(),
+                &PushOptions {
+                    upload_limit: self.upload_limit,
+                    ..Default::default()
+                },
+            )
+            .await
+            .with_context(|| {
+                crate::push_all_failed_msg(dest_dir, bindle_connection_info.base_url())
+            })?;
+
+            if outcome == PushOutcome::AlreadyExists {
+                // Only try once, as the unique build is a policy for avoiding
+                // stale reuse rather than for resolving conflicts
+                if let Some(strategy) = unique_build.take() {
+                    if let Some(next) = unique_buildinfo(buildinfo.as_ref(), &strategy, digest)? {
+                        println!(
+                            "Bindle {} already exists, retrying with build {}",
+                            bindle_id, next
+                        );
+                        buildinfo = Some(next);
+                        continue;
+                    }
+                }
+            }
 
-        match spin_publish::push_all(dest_dir, &bindle_id, bindle_connection_info.clone()).await {
-            Err(spin_publish::PublishError::BindleAlreadyExists(err_msg)) => {
-                if self.redeploy {
-                    Ok(bindle_id.clone())
-                } else {
-                    Err(anyhow!(
-                        "Failed to push bindle to server.\n{}\nTry using the --deploy-existing-bindle flag",
-                        err_msg
-                    ))
+            match (outcome, self.on_existing()) {
+                (PushOutcome::Pushed, _) | (PushOutcome::AlreadyExists, OnExisting::Reuse) => {
+                    return Ok(bindle_id)
+                }
+                (PushOutcome::AlreadyExists, OnExisting::Error) => bail!(
+                    "Failed to push bindle to server.\nBindle {} already exists on the server\nTry using --on-existing reuse or --on-existing bump-build",
+                    bindle_id
+                ),
+                (PushOutcome::AlreadyExists, OnExisting::BumpBuild) => {
+                    bumps += 1;
+                    if bumps > MAX_BUILD_BUMPS {
+                        bail!(
+                            "Failed to push bindle to server: {} and {} previous builds already exist",
+                            bindle_id,
+                            MAX_BUILD_BUMPS
+                        );
+                    }
+                    let next = bump_buildinfo(buildinfo.as_ref())?;
+                    println!("Bindle {} already exists, retrying with build {}", bindle_id, next);
+                    buildinfo = Some(next);
                 }
             }
-            Err(err) => Err(err).with_context(|| {
-                crate::push_all_failed_msg(dest_dir, bindle_connection_info.base_url())
-            }),
-            Ok(()) => Ok(bindle_id.clone()),
         }
     }
 }
 
+/// How `spin deploy` handles a bindle that already exists on the server.
+#[derive(clap::ArgEnum, Clone, Debug, Eq, PartialEq)]
+pub enum OnExisting {
+    #[clap(name = "error")]
+    Error,
+    #[clap(name = "reuse")]
+    Reuse,
+    #[clap(name = "bump-build")]
+    BumpBuild,
+}
+
+/// How `spin deploy` handles HTTP routes which overlap those of other
+/// applications on the same domain.
+#[derive(clap::ArgEnum, Clone, Debug, Eq, PartialEq)]
+pub enum RouteConflicts {
+    #[clap(name = "warn")]
+    Warn,
+    #[clap(name = "error")]
+    Error,
+    #[clap(name = "ignore")]
+    Ignore,
+}
+
+const MAX_BUILD_BUMPS: usize = 20;
+
+/// How `spin deploy` derives a unique build suffix for a version which
+/// already exists.
+#[derive(clap::ArgEnum, Clone, Debug, Eq, PartialEq)]
+pub enum UniqueBuild {
+    #[clap(name = "timestamp")]
+    Timestamp,
+    #[clap(name = "digest")]
+    Digest,
+}
+
+/// Appends a unique suffix to the build metadata. Returns `None` if the
+/// build metadata already carries the suffix, in which case the existing
+/// bindle is the same build.
+fn unique_buildinfo(
+    buildinfo: Option<&BuildMetadata>,
+    strategy: &UniqueBuild,
+    digest: &str,
+) -> Result<Option<BuildMetadata>> {
+    let suffix = match strategy {
+        UniqueBuild::Timestamp => format!("t{}", Utc::now().format("%Y%m%d%H%M%S")),
+        UniqueBuild::Digest => buildinfo_from_digest(digest)?.to_string(),
+    };
+    let current = buildinfo.map(|b| b.as_str()).unwrap_or_default();
+    if current.split('.').any(|id| id == suffix) {
+        return Ok(None);
+    }
+    let next = if current.is_empty() {
+        suffix
+    } else {
+        format!("{}.{}", current, suffix)
+    };
+    let next =
+        BuildMetadata::new(&next).with_context(|| format!("Invalid build metadata {}", next))?;
+    Ok(Some(next))
+}
+
+/// Increments a trailing numeric build identifier, or appends one if there
+/// isn't one (so `q1234` becomes `q1234.1`, and `q1234.1` becomes `q1234.2`).
+fn bump_buildinfo(buildinfo: Option<&BuildMetadata>) -> Result<BuildMetadata> {
+    let current = buildinfo.map(|b| b.as_str()).unwrap_or_default();
+    let next = match current.rsplit_once('.') {
+        Some((base, counter)) if counter.parse::<u64>().is_ok() => {
+            format!("{}.{}", base, counter.parse::<u64>()? + 1)
+        }
+        _ if current.is_empty() => "1".to_owned(),
+        _ => format!("{}.1", current),
+    };
+    BuildMetadata::new(&next).with_context(|| format!("Invalid build metadata {}", next))
+}
+
+fn buildinfo_from_digest(digest: &str) -> Result<BuildMetadata> {
+    let hex = digest.trim_start_matches("sha256:");
+    let mut buildinfo = format!("q{}", hex);
+    buildinfo.truncate(8);
+    BuildMetadata::new(&buildinfo).with_context(|| "Could not compute build info")
+}
+
 fn random_buildinfo() -> BuildMetadata {
     let random_bytes: [u8; 4] = rand::thread_rng().gen();
     let random_hex: String = random_bytes.iter().map(|b| format!("{:x}", b)).collect();
     BuildMetadata::new(&format!("r{random_hex}")).unwrap()
 }
 
+/// Removes a channel created for a deployment which was refused, and the app
+/// if the deployment created it, so that nothing is left behind.
+async fn discard_deployment(client: &CloudClient, channel_id: Uuid, created_app_id: Option<Uuid>) {
+    if let Err(e) = client.delete_channel(channel_id).await {
+        tracing::warn!("Failed to remove channel {}: {:?}", channel_id, e);
+    }
+    if let Some(app_id) = created_app_id {
+        if let Err(e) = client.remove_app(app_id.to_string()).await {
+            tracing::warn!("Failed to remove app {}: {:?}", app_id, e);
+        }
+    }
+}
+
 fn build_app_base_url(app_domain: &str, hippo_url: &Url) -> Result<Url> {
     // HACK: We assume that the scheme (https vs http) of apps will match that of Hippo...
     let scheme = hippo_url.scheme();
@@ -640,12 +1577,12 @@ fn build_app_base_url(app_domain: &str, hippo_url: &Url) -> Result<Url> {
 }
 
 async fn check_healthz(base_url: &Url) -> Result<()> {
-    let healthz_url = base_url.join("healthz")?;
-    reqwest::get(healthz_url)
-        .await?
-        .error_for_status()
-        .with_context(|| format!("Server {} is unhealthy", base_url))?;
-    Ok(())
+    CloudClient::new(ConnectionConfig {
+        url: base_url.to_string(),
+        ..Default::default()
+    })
+    .ping()
+    .await
 }
 
 const READINESS_POLL_INTERVAL_SECS: u64 = 2;
@@ -693,6 +1630,111 @@ async fn wait_for_ready(app_base_url: &Url, bindle_version: &str, readiness_time
     }
 }
 
+/// Waits for an application whose trigger cannot be probed over HTTP, such
+/// as a Redis trigger, to be reported as running by Hippo's job status API.
+/// The status is that of the channel rather than of a revision, so a
+/// previous revision which is still running may be reported as ready.
+async fn wait_for_running(
+    configuration: &HippoConfiguration,
+    channel_id: Uuid,
+    cfg: &RawAppManifest,
+    readiness_timeout_secs: u16,
+) {
+    if readiness_timeout_secs == 0 {
+        return;
+    }
+
+    let start = std::time::Instant::now();
+    let readiness_timeout = std::time::Duration::from_secs(u64::from(readiness_timeout_secs));
+    let poll_interval = tokio::time::Duration::from_secs(READINESS_POLL_INTERVAL_SECS);
+
+    print!("Waiting for application to become ready");
+    let _ = std::io::stdout().flush();
+    loop {
+        let channel = channel_id.to_string();
+        let status = api_channel_statuses_get(configuration, None, None, Some(&channel))
+            .await
+            .map(|page| page.items.into_iter().next().map(|item| item.status));
+        match status {
+            Err(err) => {
+                println!("... readiness check failed: {err:?}");
+                return;
+            }
+            Ok(Some(JobStatus::Running)) => {
+                println!("... ready");
+                return;
+            }
+            Ok(Some(JobStatus::Dead)) => {
+                println!();
+                println!("Application deployed, but it stopped running");
+                print_trigger_diagnostics(cfg);
+                return;
+            }
+            Ok(status) => tracing::debug!("App not ready: {:?}", status),
+        }
+
+        print!(".");
+        let _ = std::io::stdout().flush();
+
+        if start.elapsed() >= readiness_timeout {
+            println!();
+            println!("Application deployed, but Spin could not establish readiness");
+            print_trigger_diagnostics(cfg);
+            return;
+        }
+        tokio::time::sleep(poll_interval).await;
+    }
+}
+
+/// Prints what a trigger depends on, to help explain why an application
+/// using it did not become ready.
+fn print_trigger_diagnostics(cfg: &RawAppManifest) {
+    if let Ok(redis) = RedisTriggerConfiguration::try_from(cfg.info.trigger.clone()) {
+        println!(
+            "The Redis trigger connects to {}. Check that the server is reachable from the platform and accepts the address's credentials.",
+            redacted_address(&redis.address)
+        );
+        for component in &cfg.components {
+            if let TriggerConfig::Redis(redis_cfg) = &component.trigger {
+                println!(
+                    "  {}: subscribes to channel '{}'",
+                    component.id, redis_cfg.channel
+                );
+            }
+        }
+    }
+}
+
+/// Removes any password from a server address, so that it is not printed.
+fn redacted_address(address: &str) -> String {
+    match Url::parse(address) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(Some("***"));
+            url.to_string()
+        }
+        _ => address.to_owned(),
+    }
+}
+
+/// A configuration for calling Hippo APIs which the Hippo client does not
+/// wrap, authenticated as the client is.
+fn hippo_configuration(url: &Url, insecure: bool, token: &str) -> Result<HippoConfiguration> {
+    Ok(HippoConfiguration {
+        base_path: url.as_str().trim_end_matches('/').to_owned(),
+        user_agent: Some(format!("spin/{}", env!("CARGO_PKG_VERSION"))),
+        client: reqwest::Client::builder()
+            .danger_accept_invalid_certs(insecure)
+            .build()?,
+        basic_auth: None,
+        oauth_access_token: None,
+        bearer_access_token: None,
+        api_key: Some(ApiKey {
+            prefix: Some("Bearer".to_owned()),
+            key: token.to_owned(),
+        }),
+    })
+}
+
 #[instrument(level = "debug")]
 async fn is_ready(app_info_url: &str, expected_version: &str) -> Result<bool> {
     // If the request fails, we assume the app isn't ready
@@ -744,3 +1786,33 @@ fn print_available_routes(
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bump_buildinfo_increments_trailing_counter() {
+        let bump = |b: Option<&str>| {
+            bump_buildinfo(b.map(|b| BuildMetadata::new(b).unwrap()).as_ref())
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!("1", bump(None));
+        assert_eq!("q1234.1", bump(Some("q1234")));
+        assert_eq!("q1234.2", bump(Some("q1234.1")));
+        assert_eq!("q1234.abc.1", bump(Some("q1234.abc")));
+    }
+
+    #[test]
+    fn digest_unique_build_is_not_reapplied() {
+        let digest = "sha256:0123456789abcdef";
+        let first = unique_buildinfo(None, &UniqueBuild::Digest, digest)
+            .unwrap()
+            .unwrap();
+        assert_eq!("q0123456", first.as_str());
+        assert!(unique_buildinfo(Some(&first), &UniqueBuild::Digest, digest)
+            .unwrap()
+            .is_none());
+    }
+}
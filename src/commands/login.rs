@@ -1,17 +1,20 @@
 use std::io::{stdin, Write};
-use std::path::PathBuf;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
 
 use anyhow::{bail, Context, Result};
 use clap::Parser;
-use cloud::client::{Client, ConnectionConfig};
+use cloud::auth::{AuthError, DeviceFlowAuthenticator, DeviceFlowEvent, TokenRefresher};
+use cloud::client::{Client, ConnectionConfig, Organization};
 use cloud_openapi::models::DeviceCodeItem;
 use cloud_openapi::models::TokenInfo;
+use futures::StreamExt;
 use hippo::Client as HippoClient;
 use hippo::ConnectionInfo;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json::json;
+use sha2::{Digest, Sha256};
+use spin_publish::Secret;
 use tokio::fs;
 use tracing::log;
 use url::Url;
@@ -22,6 +25,7 @@ use crate::opts::{
     DEPLOYMENT_ENV_NAME_ENV, HIPPO_PASSWORD, HIPPO_SERVER_URL_OPT, HIPPO_URL_ENV, HIPPO_USERNAME,
     INSECURE_OPT,
 };
+use crate::paths::{config_root_dir, login_file};
 
 // this is the client ID registered in the Cloud's backend
 const SPIN_CLIENT_ID: &str = "583e63e9-461f-4fbe-a246-23e0fb1cad10";
@@ -56,7 +60,7 @@ pub struct LoginCommand {
         env = BINDLE_PASSWORD,
         requires = BINDLE_USERNAME
     )]
-    pub bindle_password: Option<String>,
+    pub bindle_password: Option<Secret<String>>,
 
     /// Ignore server certificate errors from bindle and hippo
     #[clap(
@@ -93,7 +97,7 @@ pub struct LoginCommand {
         env = HIPPO_PASSWORD,
         requires = HIPPO_USERNAME,
     )]
-    pub hippo_password: Option<String>,
+    pub hippo_password: Option<Secret<String>>,
 
     /// Display login status
     #[clap(
@@ -127,6 +131,20 @@ pub struct LoginCommand {
     )]
     pub check_device_code: Option<String>,
 
+    /// Save the state of the device authorization flow to, and resume it
+    /// from, the specified file. The first run creates a device code and
+    /// saves it. Later runs check whether the device has been authorized,
+    /// and remove the file once login succeeds.
+    #[clap(
+        name = "device-code-file",
+        long = "device-code-file",
+        conflicts_with = "list",
+        conflicts_with = "status",
+        conflicts_with = "check-device-code",
+        conflicts_with = HIPPO_USERNAME
+    )]
+    pub device_code_file: Option<PathBuf>,
+
     // authentication method used for logging in (username|github)
     #[clap(
         name = "auth-method",
@@ -156,6 +174,35 @@ pub struct LoginCommand {
         conflicts_with = "check-device-code"
     )]
     pub list: bool,
+
+    /// The organization to deploy into, by name or ID. If omitted, the
+    /// default organization for the account is used.
+    #[clap(
+        name = "org",
+        long = "org",
+        conflicts_with = HIPPO_USERNAME,
+        conflicts_with = "list",
+        conflicts_with = "status",
+        conflicts_with = "get-device-code"
+    )]
+    pub organization: Option<String>,
+
+    /// Keep the saved login fresh until stopped, by refreshing the token
+    /// shortly before it expires. Use this alongside long-running pipelines
+    /// so that later `spin deploy` steps are still logged in.
+    #[clap(
+        name = "refresh-daemon",
+        long = "refresh-daemon",
+        takes_value = false,
+        conflicts_with = "list",
+        conflicts_with = "status",
+        conflicts_with = "get-device-code",
+        conflicts_with = "check-device-code",
+        conflicts_with = "device-code-file",
+        conflicts_with = "org",
+        conflicts_with = HIPPO_USERNAME
+    )]
+    pub refresh_daemon: bool,
 }
 
 fn parse_url(url: &str) -> Result<url::Url> {
@@ -186,7 +233,11 @@ impl LoginCommand {
             (false, false, false, Some(device_code)) => {
                 self.run_check_device_code(device_code).await
             }
-            (false, false, false, None) => self.run_interactive_login().await,
+            (false, false, false, None) if self.refresh_daemon => self.run_refresh_daemon().await,
+            (false, false, false, None) => match &self.device_code_file {
+                Some(path) => self.run_resume_device_flow(path).await,
+                None => self.run_interactive_login().await,
+            },
             _ => Err(anyhow::anyhow!("Invalid combination of options")), // Should never happen
         }
     }
@@ -217,16 +268,95 @@ impl LoginCommand {
         Ok(())
     }
 
+    async fn run_refresh_daemon(&self) -> Result<()> {
+        let path = self.config_file_path()?;
+        let mut login_connection = LoginConnection::load(self.deployment_env_id.as_deref()).await?;
+        let connection_config = login_connection.cloud_connection_config()?;
+
+        println!(
+            "Keeping login fresh until stopped. Token expires at {}",
+            login_connection.expiration
+        );
+        let mut tokens = Box::pin(TokenRefresher::new(connection_config).refreshed_tokens());
+        while let Some(result) = tokens.next().await {
+            let token_info = match result {
+                Ok(token_info) => token_info,
+                Err(e) => {
+                    eprintln!("Failed to refresh login token, will retry: {:#}", e);
+                    continue;
+                }
+            };
+            // The refresher guarantees that a token is present.
+            let token = token_info.token.unwrap_or_default();
+            login_connection.token_fingerprint = Some(token_fingerprint(&token));
+            login_connection.token = Secret::new(token);
+            if let Some(expiration) = token_info.expiration {
+                login_connection.expiration = expiration;
+            }
+            write_login_file(&path, &login_connection)?;
+            println!(
+                "Refreshed login token. New token expires at {}",
+                login_connection.expiration
+            );
+        }
+        Ok(())
+    }
+
     async fn run_get_device_code(&self) -> Result<()> {
         let connection_config = self.anon_connection_config();
         let device_code_info = create_device_code(&Client::new(connection_config)).await?;
 
         println!("{}", serde_json::to_string_pretty(&device_code_info)?);
 
+        if let Some(path) = &self.device_code_file {
+            let state = DeviceFlowState {
+                url: self.hippo_server_url.clone(),
+                device_code: device_code_info
+                    .device_code
+                    .context("Server did not return a device code")?,
+            };
+            std::fs::write(path, serde_json::to_string_pretty(&state)?)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    async fn run_resume_device_flow(&self, path: &Path) -> Result<()> {
+        if !path.exists() {
+            return self.run_get_device_code().await;
+        }
+
+        let state: DeviceFlowState = serde_json::from_str(
+            &std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?,
+        )
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        if state.url != self.hippo_server_url {
+            bail!(
+                "The device code in {} is for {}. Run the command again with `--url {}`",
+                path.display(),
+                state.url,
+                state.url
+            );
+        }
+
+        if self.check_device_code(&state.device_code).await? {
+            std::fs::remove_file(path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
         Ok(())
     }
 
     async fn run_check_device_code(&self, device_code: &str) -> Result<()> {
+        self.check_device_code(device_code).await?;
+        Ok(())
+    }
+
+    /// Checks whether the device code has been authorized, saving the login
+    /// if it has. Returns whether login succeeded.
+    async fn check_device_code(&self, device_code: &str) -> Result<bool> {
         let connection_config = self.anon_connection_config();
         let client = Client::new(connection_config);
         let token_info = client.login(device_code.to_owned()).await?;
@@ -240,16 +370,16 @@ impl LoginCommand {
         match token_readiness {
             TokenReadiness::Ready(token_info) => {
                 println!("{}", serde_json::to_string_pretty(&token_info)?);
-                let login_connection = self.login_connection_for_token(token_info);
+                let login_connection = self.login_connection_for_token(token_info).await?;
                 self.save_login_info(&login_connection)?;
+                Ok(true)
             }
             TokenReadiness::Unready => {
                 let waiting = json!({ "status": "waiting" });
                 println!("{}", serde_json::to_string_pretty(&waiting)?);
+                Ok(false)
             }
         }
-
-        Ok(())
     }
 
     async fn run_interactive_login(&self) -> Result<()> {
@@ -265,13 +395,13 @@ impl LoginCommand {
         let connection_config = self.anon_connection_config();
         let token_info = github_token(connection_config).await?;
 
-        Ok(self.login_connection_for_token(token_info))
+        self.login_connection_for_token(token_info).await
     }
 
     async fn run_interactive_basic_login(&self) -> Result<LoginConnection> {
         let username = prompt_if_not_provided(&self.hippo_username, "Hippo username")?;
         let password = match &self.hippo_password {
-            Some(password) => password.to_owned(),
+            Some(password) => password.expose().to_owned(),
             None => {
                 print!("Hippo password: ");
                 std::io::stdout().flush()?;
@@ -288,7 +418,7 @@ impl LoginCommand {
         // is unauthenticated.  If Bindle URL was prompted for, or Bindle username or password
         // is provided, ask the user.
         let mut bindle_username = self.bindle_username.clone();
-        let mut bindle_password = self.bindle_password.clone();
+        let mut bindle_password = self.bindle_password.clone().map(Secret::into_inner);
 
         let unauthenticated_bindle_server_provided = self.bindle_server_url.is_some()
             && self.bindle_username.is_none()
@@ -306,7 +436,7 @@ impl LoginCommand {
             bindle_password = match bindle_username {
                 None => None,
                 Some(_) => Some(prompt_if_not_provided(
-                    &self.bindle_password,
+                    &self.bindle_password.clone().map(Secret::into_inner),
                     "Bindle password",
                 )?),
             };
@@ -331,38 +461,75 @@ impl LoginCommand {
         Ok(LoginConnection {
             url: self.hippo_server_url.clone(),
             danger_accept_invalid_certs: self.insecure,
-            token: token.token.unwrap_or_default(),
-            expiration: token.expiration.unwrap_or_default(),
+            token: Secret::new(token.token.clone().unwrap_or_default()),
+            expiration: token.expiration.clone().unwrap_or_default(),
             bindle_url: Some(bindle_url),
             bindle_username,
-            bindle_password,
+            bindle_password: bindle_password.map(Secret::new),
+            organization: None,
+            machine_id: machine_id().ok(),
+            token_fingerprint: token.token.as_deref().map(token_fingerprint),
         })
     }
 
-    fn login_connection_for_token(&self, token_info: TokenInfo) -> LoginConnection {
-        LoginConnection {
+    async fn login_connection_for_token(&self, token_info: TokenInfo) -> Result<LoginConnection> {
+        let organization = match &self.organization {
+            Some(org) => Some(
+                self.find_organization(&token_info, org)
+                    .await?
+                    .id
+                    .to_string(),
+            ),
+            None => None,
+        };
+
+        let token_fingerprint = token_info.token.as_deref().map(token_fingerprint);
+
+        Ok(LoginConnection {
             url: self.hippo_server_url.clone(),
             danger_accept_invalid_certs: self.insecure,
-            token: token_info.token.unwrap_or_default(),
+            token: Secret::new(token_info.token.unwrap_or_default()),
             expiration: token_info.expiration.unwrap_or_default(),
             bindle_url: None,
             bindle_username: None,
             bindle_password: None,
-        }
+            organization,
+            machine_id: machine_id().ok(),
+            token_fingerprint,
+        })
     }
 
-    fn config_file_path(&self) -> Result<PathBuf> {
-        let root = config_root_dir()?;
+    async fn find_organization(&self, token_info: &TokenInfo, org: &str) -> Result<Organization> {
+        let client = Client::new(ConnectionConfig {
+            token: Secret::new(token_info.clone()),
+            ..self.anon_connection_config()
+        });
+        let organizations = client
+            .list_organizations()
+            .await
+            .context("Problem listing organizations")?;
 
-        ensure(&root)?;
+        let names = organizations
+            .iter()
+            .map(|o| o.name.clone())
+            .collect::<Vec<_>>();
+        match organizations
+            .into_iter()
+            .find(|o| o.name == org || o.id.to_string() == org)
+        {
+            Some(organization) => Ok(organization),
+            None => bail!(
+                "You are not a member of an organization named '{}'. Your organizations are: {}",
+                org,
+                names.join(", ")
+            ),
+        }
+    }
 
-        let file_stem = match &self.deployment_env_id {
-            None => "config",
-            Some(id) => id,
-        };
-        let file = format!("{}.json", file_stem);
+    fn config_file_path(&self) -> Result<PathBuf> {
+        ensure(&config_root_dir()?)?;
 
-        let path = root.join(file);
+        let path = login_file(self.deployment_env_id.as_deref())?;
 
         Ok(path)
     }
@@ -372,6 +539,9 @@ impl LoginCommand {
             url: self.hippo_server_url.to_string(),
             insecure: self.insecure,
             token: Default::default(),
+            organization: None,
+            machine_id: machine_id().ok(),
+            token_fingerprint: None,
         }
     }
 
@@ -393,16 +563,22 @@ impl LoginCommand {
 
     fn save_login_info(&self, login_connection: &LoginConnection) -> Result<(), anyhow::Error> {
         let path = self.config_file_path()?;
-        std::fs::write(path, serde_json::to_string_pretty(login_connection)?)?;
-        Ok(())
+        write_login_file(&path, login_connection)
     }
 }
 
-fn config_root_dir() -> Result<PathBuf, anyhow::Error> {
-    let root = dirs::config_dir()
-        .context("Cannot find configuration directory")?
-        .join("fermyon");
-    Ok(root)
+/// Writes login details so that concurrent readers, such as `spin deploy`
+/// steps running alongside `spin login --refresh-daemon`, see either the old
+/// or the new file but never a partially written one.
+fn write_login_file(path: &Path, login_connection: &LoginConnection) -> Result<()> {
+    let temp_path = path.with_extension(format!("json.{}.tmp", std::process::id()));
+    std::fs::write(&temp_path, serde_json::to_string_pretty(login_connection)?)
+        .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+    if let Err(e) = std::fs::rename(&temp_path, path) {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e).with_context(|| format!("Failed to replace {}", path.display()));
+    }
+    Ok(())
 }
 
 fn prompt_if_not_provided(provided: &Option<String>, prompt_text: &str) -> Result<String> {
@@ -426,48 +602,45 @@ async fn github_token(
     let client = Client::new(connection_config);
 
     // Generate a device code and a user code to activate it with
-    let device_code = create_device_code(&client).await?;
+    let authenticator =
+        DeviceFlowAuthenticator::new(client, Uuid::parse_str(SPIN_CLIENT_ID)?).await?;
 
     println!(
         "\nCopy your one-time code:\n\n{}\n",
-        device_code.user_code.clone().unwrap(),
+        authenticator.user_code(),
     );
 
     println!(
         "...and open the authorization page in your browser:\n\n{}\n",
-        device_code.verification_url.clone().unwrap(),
+        authenticator.verification_url(),
     );
 
-    // The OAuth library should theoretically handle waiting for the device to be authorized, but
-    // testing revealed that it doesn't work. So we manually poll every 10 seconds for fifteen minutes.
-    const POLL_INTERVAL_SECS: u64 = 10;
-    let mut seconds_elapsed = 0;
-    let timeout_seconds = 15 * 60;
+    let events = authenticator.wait_for_authorization();
+    futures::pin_mut!(events);
 
     // Loop while waiting for the device code to be authorized by the user
-    loop {
-        if seconds_elapsed > timeout_seconds {
-            bail!("Timed out waiting to authorize the device. Please execute `spin login` again and authorize the device with GitHub.");
-        }
-
-        match client.login(device_code.device_code.clone().unwrap()).await {
-            Ok(response) => {
-                if response.token.is_none() {
-                    println!("Waiting for device authorization...");
-                    tokio::time::sleep(Duration::from_secs(POLL_INTERVAL_SECS)).await;
-                    seconds_elapsed += POLL_INTERVAL_SECS;
-                } else {
-                    println!("Device authorized!");
-                    return Ok(response);
-                }
-            }
-            Err(_) => {
+    while let Some(event) = events.next().await {
+        match event {
+            Ok(DeviceFlowEvent::Waiting) | Ok(DeviceFlowEvent::SlowDown) => {
                 println!("Waiting for device authorization...");
-                tokio::time::sleep(Duration::from_secs(POLL_INTERVAL_SECS)).await;
-                seconds_elapsed += POLL_INTERVAL_SECS;
             }
-        };
+            Ok(DeviceFlowEvent::Authorized(token_info)) => {
+                println!("Device authorized!");
+                return Ok(token_info);
+            }
+            Err(e)
+                if matches!(
+                    e.downcast_ref::<AuthError>(),
+                    Some(AuthError::AuthorizationTimeout)
+                ) =>
+            {
+                bail!("Timed out waiting to authorize the device. Please execute `spin login` again and authorize the device with GitHub.");
+            }
+            Err(e) => return Err(e),
+        }
     }
+
+    bail!("Device authorization ended unexpectedly")
 }
 
 async fn create_device_code(client: &Client) -> Result<DeviceCodeItem> {
@@ -487,10 +660,123 @@ pub struct LoginConnection {
     pub bindle_username: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
-    pub bindle_password: Option<String>,
+    pub bindle_password: Option<Secret<String>>,
     pub danger_accept_invalid_certs: bool,
-    pub token: String,
+    pub token: Secret<String>,
     pub expiration: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub organization: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub machine_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub token_fingerprint: Option<String>,
+}
+
+impl LoginConnection {
+    /// Loads the saved login for the given environment, or the default
+    /// login if no environment is given. Fails if there is no login or it
+    /// has expired.
+    pub(crate) async fn load(environment: Option<&str>) -> Result<Self> {
+        let path = login_file(environment)?;
+        let login_command = match environment {
+            Some(name) => format!("spin login --environment-name {}", name),
+            None => "spin login".to_owned(),
+        };
+        let data = match fs::read_to_string(&path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                bail!("You are not logged in. Run `{}` to log in", login_command)
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let connection: Self = serde_json::from_str(&data)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        let expiration = chrono::DateTime::parse_from_rfc3339(&connection.expiration)?;
+        if chrono::Utc::now() > expiration {
+            bail!(
+                "Your login has expired. Run `{}` to log in again",
+                login_command
+            );
+        }
+        Ok(connection)
+    }
+
+    /// Creates a client for the Fermyon Cloud API using this login.
+    pub(crate) fn cloud_client(&self) -> Result<Client> {
+        Ok(Client::new(self.cloud_connection_config()?))
+    }
+
+    /// The connection details for the Fermyon Cloud API using this login.
+    pub(crate) fn cloud_connection_config(&self) -> Result<ConnectionConfig> {
+        if self.bindle_url.is_some() {
+            bail!("This command is only supported for Fermyon Cloud logins");
+        }
+        Ok(ConnectionConfig {
+            url: self.url.to_string(),
+            insecure: self.danger_accept_invalid_certs,
+            token: Secret::new(TokenInfo {
+                token: Some(self.token.expose().clone()),
+                expiration: Some(self.expiration.clone()),
+            }),
+            organization: self.organization.clone(),
+            machine_id: self.machine_id.clone(),
+            token_fingerprint: self.token_fingerprint.clone(),
+        })
+    }
+
+    /// Adds an explanation to errors caused by the platform rejecting the
+    /// login token, which otherwise surface as a bare 401.
+    pub fn explain_unauthorized(&self, error: anyhow::Error) -> anyhow::Error {
+        let unauthorized = error
+            .chain()
+            .any(|e| matches!(e.downcast_ref::<AuthError>(), Some(AuthError::Unauthorized)));
+        if !unauthorized {
+            return error;
+        }
+
+        let explanation = match (&self.machine_id, machine_id().ok()) {
+            (Some(saved), Some(current)) if *saved != current => {
+                "This login was created on another machine. The platform revokes login tokens which are used from a new machine, so run `spin login` to log in on this machine.".to_owned()
+            }
+            _ => format!(
+                "Your login token{} was not accepted. It may have expired, or been revoked because it was used from another machine. Run `spin login` to log in again.",
+                self.token_fingerprint
+                    .as_ref()
+                    .map(|f| format!(" ({})", f))
+                    .unwrap_or_default()
+            ),
+        };
+        error.context(explanation)
+    }
+}
+
+const MACHINE_ID_FILE: &str = "machine-id";
+
+/// A random identifier for this machine, created the first time it is needed.
+pub(crate) fn machine_id() -> Result<String> {
+    let path = config_root_dir()?.join(MACHINE_ID_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(id) => Ok(id.trim().to_owned()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let id = Uuid::from_bytes(rand::random()).to_string();
+            ensure(&config_root_dir()?)?;
+            std::fs::write(&path, &id)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            Ok(id)
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Identifies a token without revealing it.
+pub(crate) fn token_fingerprint(token: &str) -> String {
+    let digest = Sha256::digest(token.as_bytes());
+    let hex = format!("{:x}", digest);
+    format!("sha256:{}", &hex[..16])
 }
 
 #[derive(Deserialize, Serialize)]
@@ -572,6 +858,14 @@ fn prompt_for_auth_method() -> AuthMethod {
     }
 }
 
+/// The state of a device authorization flow which spans several runs of
+/// `spin login --device-code-file`.
+#[derive(Deserialize, Serialize)]
+struct DeviceFlowState {
+    url: Url,
+    device_code: String,
+}
+
 enum TokenReadiness {
     Ready(TokenInfo),
     Unready,
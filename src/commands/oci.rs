@@ -0,0 +1,1465 @@
+use std::{
+    ffi::OsString,
+    io::Read,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, Subcommand};
+use reqwest::Url;
+use spin_loader::local::parent_dir;
+use spin_publish::{
+    oci::{
+        normalize_registry, pinned_reference, read_locked_app, sbom_media_type, spdx_sbom, Cache,
+        Client, Compression, CredentialStore, ExistingTagPolicy, Inspection, Lockfile, Proxy,
+        RegistryConfig, ResumeToken, SigningKey, TrustPolicy, VerificationKey, DEFAULT_LOCKFILE,
+        DEFAULT_MAX_CONCURRENT_DOWNLOADS, SPDX_MEDIA_TYPE,
+    },
+    PublishError, PublishWarning, Secret, Staging, TemplateContext,
+};
+use spin_trigger::cli::{SPIN_LOCKED_URL, SPIN_WORKING_DIR};
+
+use crate::{
+    commands::{apps::OutputFormat, bindle::PublishFilterOptions, new::ParameterValue},
+    opts::*,
+    parse_rate_limit, provenance,
+};
+
+/// Commands for working with Spin applications in OCI registries.
+#[derive(Subcommand, Debug)]
+pub enum OciCommands {
+    /// Push a Spin application to a registry.
+    Push(Push),
+
+    /// Pull a Spin application from a registry into the local cache.
+    Pull(Pull),
+
+    /// Pull a Spin application from a registry and run it.
+    Run(Run),
+
+    /// Save a Spin application from a registry to an OCI image layout
+    /// directory.
+    Save(Save),
+
+    /// Load a Spin application from an OCI image layout into the local
+    /// cache, optionally pushing it to a registry.
+    Load(Load),
+
+    /// Copy a Spin application to another reference, which may be in
+    /// another registry.
+    Copy(Copy),
+
+    /// Delete a published application from a registry.
+    Delete(Delete),
+
+    /// Check whether a reference has been published. Exits with status 0
+    /// if it has and 1 if it has not.
+    Exists(Exists),
+
+    /// Fetch the SBOM attached to a published application.
+    Sbom(Sbom),
+
+    /// Describe a published application from its manifest and config,
+    /// without pulling it.
+    Inspect(Inspect),
+
+    /// List the repositories in a registry or registry namespace.
+    ListRemote(ListRemote),
+
+    /// List the tags in a repository.
+    Tags(Tags),
+
+    /// Log in to a registry, checking the credentials with it and storing
+    /// them for later pushes and pulls.
+    Login(Login),
+
+    /// Remove the credentials stored for a registry.
+    Logout(Logout),
+
+    /// Serve cached registry content to local Spin instances, pulling
+    /// through from an upstream registry.
+    #[clap(hide = true)]
+    Proxy(ProxyCommand),
+}
+
+impl OciCommands {
+    pub async fn run(self) -> Result<()> {
+        match self {
+            Self::Push(cmd) => cmd.run().await,
+            Self::Pull(cmd) => cmd.run().await,
+            Self::Run(cmd) => cmd.run().await,
+            Self::Save(cmd) => cmd.run().await,
+            Self::Load(cmd) => cmd.run().await,
+            Self::Copy(cmd) => cmd.run().await,
+            Self::Delete(cmd) => cmd.run().await,
+            Self::Exists(cmd) => cmd.run().await,
+            Self::Sbom(cmd) => cmd.run().await,
+            Self::Inspect(cmd) => cmd.run().await,
+            Self::ListRemote(cmd) => cmd.run().await,
+            Self::Tags(cmd) => cmd.run().await,
+            Self::Login(cmd) => cmd.run().await,
+            Self::Logout(cmd) => cmd.run().await,
+            Self::Proxy(cmd) => cmd.run().await,
+        }
+    }
+}
+
+/// TLS and connection settings for registries.
+#[derive(Parser, Debug)]
+pub struct TlsOptions {
+    /// Read settings for particular registries, such as registries to access
+    /// over plain HTTP, from this TOML file. The default is registries.toml
+    /// in Spin's configuration directory, if it exists
+    #[clap(
+        long = "registry-config",
+        value_name = "PATH",
+        env = "SPIN_REGISTRY_CONFIG"
+    )]
+    pub registry_config: Option<PathBuf>,
+
+    /// Trust the CA certificates in this PEM file, as well as the system's,
+    /// when connecting to registries
+    #[clap(long = "ca-cert", value_name = "PATH", env = "SPIN_OCI_CA_CERT")]
+    pub ca_cert: Option<PathBuf>,
+
+    /// Present the certificate in this PEM file to a registry which requires
+    /// client certificates (in registry=path format). Requires a
+    /// --client-key for the same registry
+    #[clap(
+        long = "client-cert",
+        value_name = "REGISTRY=PATH",
+        multiple_occurrences = true
+    )]
+    pub client_certs: Vec<RegistryFile>,
+
+    /// The PKCS #8 private key, in a PEM file, of the client certificate for
+    /// a registry (in registry=path format)
+    #[clap(
+        long = "client-key",
+        value_name = "REGISTRY=PATH",
+        multiple_occurrences = true
+    )]
+    pub client_keys: Vec<RegistryFile>,
+}
+
+impl TlsOptions {
+    /// A registry client with these settings.
+    pub fn client(&self, insecure: bool) -> Result<Client> {
+        let registry_config = match &self.registry_config {
+            Some(path) => RegistryConfig::load(path)?,
+            None => RegistryConfig::load_default()?,
+        };
+        let mut client = Client::new(insecure)?.with_registry_config(registry_config)?;
+        if let Some(path) = &self.ca_cert {
+            client = client.with_ca_certificates(path)?;
+        }
+        if let Some(key) = self
+            .client_keys
+            .iter()
+            .find(|key| !self.client_certs.iter().any(|c| c.registry == key.registry))
+        {
+            bail!("No --client-cert was given for {}", key.registry);
+        }
+        for cert in &self.client_certs {
+            let key = self
+                .client_keys
+                .iter()
+                .find(|key| key.registry == cert.registry)
+                .ok_or_else(|| anyhow!("No --client-key was given for {}", cert.registry))?;
+            client = client.with_client_certificate(&cert.registry, &cert.path, &key.path)?;
+        }
+        Ok(client)
+    }
+}
+
+/// A file for a registry, given as `<registry>=<path>`.
+#[derive(Debug)]
+pub struct RegistryFile {
+    pub registry: String,
+    pub path: PathBuf,
+}
+
+impl FromStr for RegistryFile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((registry, path)) if !registry.is_empty() && !path.is_empty() => Ok(Self {
+                registry: registry.to_owned(),
+                path: PathBuf::from(path),
+            }),
+            _ => Err(anyhow!("'{}' should be in the form registry=path", s)),
+        }
+    }
+}
+
+/// Push a Spin application to a registry.
+#[derive(Parser, Debug)]
+pub struct Push {
+    /// Path to spin.toml
+    #[clap(
+        name = APP_CONFIG_FILE_OPT,
+        short = 'f',
+        long = "file",
+        default_value = DEFAULT_MANIFEST_FILE
+    )]
+    pub app: PathBuf,
+
+    /// Push this locked application JSON file, such as one written by an
+    /// earlier build stage, instead of building one from spin.toml. Its
+    /// Wasm modules and asset files must still exist where it refers to.
+    #[clap(long = "locked-app", value_name = "PATH", conflicts_with = APP_CONFIG_FILE_OPT)]
+    pub locked_app: Option<PathBuf>,
+
+    /// Reference to push to (e.g. `ghcr.io/my-org/my-app:v1`). The
+    /// placeholders `{version}`, `{git_sha}` and `{date}` are replaced with
+    /// the application version, the abbreviated git commit hash and the
+    /// current date.
+    pub reference: String,
+
+    /// Connect to the registry over plain HTTP
+    #[clap(
+        name = INSECURE_OPT,
+        short = 'k',
+        long = "insecure",
+        takes_value = false,
+    )]
+    pub insecure: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsOptions,
+
+    /// Do nothing if the reference has already been published.
+    #[clap(long = "skip-existing")]
+    pub skip_existing: bool,
+
+    /// How to print the result: `text` or `json`. JSON results are printed
+    /// to standard output, and progress to standard error.
+    #[clap(long = "output", arg_enum, default_value = "text")]
+    pub output: OutputFormat,
+
+    /// Finish an earlier push to the reference which failed part way,
+    /// uploading only the layers it did not push.
+    #[clap(long = "resume")]
+    pub resume: bool,
+
+    /// What to do if the registry refuses to replace an existing tag, as
+    /// registries which enforce immutable tags do: `error`, `bump` to push
+    /// to `<tag>-1`, `<tag>-2` and so on, or `digest-only` to push without
+    /// a tag.
+    #[clap(long = "on-existing-tag", default_value = "error")]
+    pub on_existing_tag: ExistingTagPolicy,
+
+    /// Compress the layers holding static asset files: `none`, `gzip` or
+    /// `zstd`. Compressed layers are decompressed when pulled.
+    #[clap(long = "compression", default_value = "none")]
+    pub compression: Compression,
+
+    /// Pack each component's static asset files into a single layer, for
+    /// registries which limit how many layers an image may have.
+    #[clap(long = "archive")]
+    pub archive: bool,
+
+    /// Publish the application in an image index alongside this
+    /// platform-specific variant (e.g. a precompiled image for one host
+    /// architecture). May be given more than once.
+    #[clap(
+        long = "variant",
+        value_name = "REFERENCE",
+        multiple_occurrences = true
+    )]
+    pub variants: Vec<String>,
+
+    /// Push an OCI 1.1 artifact manifest with the Spin artifact type,
+    /// falling back to an image manifest if the registry does not support
+    /// it.
+    #[clap(long = "artifact-manifest")]
+    pub artifact_manifest: bool,
+
+    /// Push a Docker schema 2 manifest with container image media types,
+    /// for registries which reject anything else. Cannot be combined with
+    /// compression, archives or artifact manifests.
+    #[clap(
+        long = "docker-compatible",
+        conflicts_with_all = &["archive", "artifact_manifest"]
+    )]
+    pub docker_compatible: bool,
+
+    /// Generate an SPDX SBOM of the application's components and files,
+    /// and attach it to the pushed application.
+    #[clap(long = "sbom")]
+    pub sbom: bool,
+
+    /// Attach this SPDX or CycloneDX JSON SBOM to the pushed application
+    /// instead of generating one.
+    #[clap(long = "sbom-file", conflicts_with = "sbom")]
+    pub sbom_file: Option<PathBuf>,
+
+    /// Add an annotation to the pushed manifest, in the form `name=value`.
+    /// May be repeated. The `org.opencontainers.image.revision`, `created`
+    /// and `source` annotations are filled in from git when the application
+    /// is in a git repository, unless given here.
+    #[clap(long = "annotation", multiple_occurrences = true)]
+    pub annotations: Vec<ParameterValue>,
+
+    /// Sign the pushed application with this PEM encoded PKCS#8 ECDSA P-256
+    /// private key, attaching a signature which cosign can verify with the
+    /// matching public key.
+    #[clap(long = "sign-key", value_name = "PATH")]
+    pub sign_key: Option<PathBuf>,
+
+    /// Assemble the layers and print the manifest and config which would be
+    /// pushed, as JSON, without contacting the registry.
+    #[clap(
+        long = "dry-run",
+        conflicts_with_all = &["skip_existing", "resume", "variants", "sbom", "sbom_file", "sign_key"]
+    )]
+    pub dry_run: bool,
+
+    #[clap(flatten)]
+    pub filter: PublishFilterOptions,
+}
+
+impl Push {
+    pub async fn run(self) -> Result<()> {
+        let app_file = self.locked_app.as_ref().unwrap_or(&self.app);
+        let app_dir = parent_dir(app_file)?;
+        let working_dir = tempfile::tempdir()?;
+        let (locked_app, version) = match &self.locked_app {
+            Some(path) => {
+                let locked_app = read_locked_app(path)?;
+                let version = locked_app
+                    .metadata
+                    .get("version")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_owned();
+                (locked_app, version)
+            }
+            None => {
+                let app = spin_loader::from_file(&self.app, Some(working_dir.path()), &None)
+                    .await
+                    .with_context(|| format!("Failed to load {}", self.app.display()))?;
+                let version = app.info.version.clone();
+                let locked_app = spin_trigger::locked::build_locked_app(app, working_dir.path())?;
+                (locked_app, version)
+            }
+        };
+        let reference = TemplateContext::new(version, &app_dir).expand(&self.reference)?;
+        let sign_key = self
+            .sign_key
+            .as_deref()
+            .map(SigningKey::from_file)
+            .transpose()?;
+
+        let mut annotations = provenance::oci_annotations(&app_dir).unwrap_or_else(|e| {
+            tracing::debug!("Not annotating git provenance: {:?}", e);
+            Default::default()
+        });
+        annotations.extend(
+            self.annotations
+                .iter()
+                .map(|a| (a.name.clone(), a.value.clone())),
+        );
+
+        let client = self
+            .tls
+            .client(self.insecure)?
+            .with_annotations(annotations)
+            .with_staging(Staging::new(None).await?)
+            .with_filter(self.filter.filter(app_file)?)
+            .with_compression(self.compression)
+            .with_existing_tag_policy(self.on_existing_tag)
+            .with_asset_archives(self.archive)
+            .with_artifact_manifest(self.artifact_manifest)
+            .with_docker_compatibility(self.docker_compatible)
+            .with_operation_log(Cache::new(None).await?);
+        if self.dry_run {
+            let plan = client
+                .dry_run_push(&locked_app, &reference)
+                .await
+                .with_context(|| format!("Failed to assemble {}", reference))?;
+            let output = serde_json::json!({
+                "reference": reference,
+                "digest": plan.result.manifest_digest,
+                "mediaType": plan.manifest_media_type,
+                "manifest": serde_json::from_slice::<serde_json::Value>(&plan.manifest)?,
+                "config": serde_json::from_slice::<serde_json::Value>(&plan.config)?,
+            });
+            println!("{}", serde_json::to_string_pretty(&output)?);
+            return Ok(());
+        }
+        if self.skip_existing && client.exists(&reference).await? {
+            progress(
+                &self.output,
+                format_args!("{} has already been published", reference),
+            );
+            if self.output == OutputFormat::Json {
+                let output = serde_json::json!({ "reference": reference, "skipped": true });
+                println!("{}", serde_json::to_string_pretty(&output)?);
+            }
+            return Ok(());
+        }
+        let resume_dir = ResumeToken::default_dir()?;
+        let client = if self.resume {
+            match ResumeToken::load(&resume_dir, &reference).await? {
+                Some(token) => {
+                    progress(
+                        &self.output,
+                        format_args!(
+                            "Resuming push to {} ({} layers already pushed)...",
+                            reference,
+                            token.pushed.len()
+                        ),
+                    );
+                    client.with_resume_token(token)
+                }
+                None => {
+                    progress(
+                        &self.output,
+                        format_args!("No failed push to {} to resume", reference),
+                    );
+                    client
+                }
+            }
+        } else {
+            client
+        };
+        let started = Instant::now();
+        progress(
+            &self.output,
+            format_args!("Pushing app to {}...", reference),
+        );
+        let pushed = match client.push(&locked_app, &reference).await {
+            Ok(pushed) => pushed,
+            Err(PublishError::PushIncomplete { token, source }) => {
+                report_incomplete_push(&token);
+                token.save(&resume_dir).await?;
+                return Err(anyhow::Error::from(*source)).with_context(|| {
+                    format!(
+                        "Failed to push {}. Run the command again with --resume to push only the remaining layers",
+                        reference
+                    )
+                });
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to push {}", reference)),
+        };
+        ResumeToken::remove(&resume_dir, &reference).await?;
+        report_warnings(&pushed.warnings);
+        let existing = pushed.layers.iter().filter(|l| !l.uploaded).count();
+        progress(
+            &self.output,
+            format_args!(
+                "Pushed {} layers ({} bytes, {} already in the registry) with digest {}",
+                pushed.layers.len(),
+                pushed.total_bytes,
+                existing,
+                pushed.manifest_digest
+            ),
+        );
+        let pushed_reference = pushed.pushed_reference(&reference)?;
+        let reference = if pushed_reference != reference {
+            progress(
+                &self.output,
+                format_args!(
+                    "{} already exists and cannot be replaced; pushed {} instead",
+                    reference, pushed_reference
+                ),
+            );
+            pushed_reference
+        } else {
+            reference
+        };
+
+        let sbom = match &self.sbom_file {
+            Some(path) => {
+                let sbom = std::fs::read(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                let media_type = sbom_media_type(&sbom).with_context(|| {
+                    format!("{} is not an SPDX or CycloneDX JSON SBOM", path.display())
+                })?;
+                Some((sbom, media_type))
+            }
+            None if self.sbom => Some((
+                spdx_sbom(&reference, &pushed.manifest_digest, &pushed.layers),
+                SPDX_MEDIA_TYPE,
+            )),
+            None => None,
+        };
+        let mut sbom_digest = None;
+        if let Some((sbom, media_type)) = sbom {
+            let digest = client
+                .attach_sbom(&reference, sbom, media_type)
+                .await
+                .with_context(|| format!("Failed to attach SBOM to {}", reference))?;
+            progress(
+                &self.output,
+                format_args!("Attached SBOM with digest {}", digest),
+            );
+            sbom_digest = Some(digest);
+        }
+
+        let mut signature_digest = None;
+        if let Some(key) = &sign_key {
+            let digest = client
+                .sign(&reference, key)
+                .await
+                .with_context(|| format!("Failed to sign {}", reference))?;
+            progress(&self.output, format_args!("Signed {}", digest));
+            signature_digest = Some(digest);
+        }
+
+        let mut index_digest = None;
+        if !self.variants.is_empty() {
+            let mut entries = vec![client.index_entry(&reference).await?];
+            for variant in &self.variants {
+                let entry = client
+                    .index_entry(variant)
+                    .await
+                    .with_context(|| format!("Failed to describe variant {}", variant))?;
+                entries.push(entry);
+            }
+            let digest = client
+                .push_index(&reference, entries)
+                .await
+                .with_context(|| format!("Failed to push image index to {}", reference))?;
+            progress(
+                &self.output,
+                format_args!(
+                    "Pushed image index with {} variants with digest {}",
+                    self.variants.len() + 1,
+                    digest
+                ),
+            );
+            index_digest = Some(digest);
+        }
+
+        if self.output == OutputFormat::Json {
+            let output = serde_json::json!({
+                "reference": reference,
+                "digest": pushed.manifest_digest,
+                "layers": pushed.layers,
+                "total_bytes": pushed.total_bytes,
+                "uploaded_bytes": pushed.uploaded_bytes(),
+                "sbom_digest": sbom_digest,
+                "signature_digest": signature_digest,
+                "index_digest": index_digest,
+                "warnings": pushed.warnings,
+                "duration_ms": started.elapsed().as_millis() as u64,
+            });
+            println!("{}", serde_json::to_string_pretty(&output)?);
+        }
+        Ok(())
+    }
+}
+
+/// Prints the warnings from a push to standard error. Files excluded by the
+/// publish filter are counted rather than listed, as there may be many.
+fn report_warnings(warnings: &[PublishWarning]) {
+    let is_skipped_file = |w: &&PublishWarning| matches!(w, PublishWarning::SkippedFile { .. });
+    let skipped = warnings.iter().filter(is_skipped_file).count();
+    if skipped > 0 {
+        eprintln!(
+            "Warning: {} files were excluded by the publish filter and not pushed",
+            skipped
+        );
+    }
+    for warning in warnings.iter().filter(|w| !is_skipped_file(w)) {
+        eprintln!("Warning: {}", warning);
+    }
+}
+
+/// Prints progress to standard output or, if the result is to be printed
+/// to standard output as JSON, to standard error.
+fn progress(output: &OutputFormat, message: std::fmt::Arguments) {
+    match output {
+        OutputFormat::Json => eprintln!("{}", message),
+        OutputFormat::Text => println!("{}", message),
+    }
+}
+
+/// Reports which layers a failed push left in the registry, and which it
+/// failed to upload.
+fn report_incomplete_push(token: &ResumeToken) {
+    eprintln!(
+        "{} layers were pushed before the failure, and will not be uploaded again:",
+        token.pushed.len()
+    );
+    for digest in &token.pushed {
+        eprintln!("  {}", digest);
+    }
+    if let Some(digest) = &token.failed {
+        match &token.partial {
+            Some(partial) if &partial.digest == digest => {
+                eprintln!("Failed to upload {}; its upload will be continued", digest)
+            }
+            _ => eprintln!("Failed to upload {}", digest),
+        }
+    }
+}
+
+/// Pull a Spin application from a registry into the local cache.
+#[derive(Parser, Debug)]
+pub struct Pull {
+    /// Reference to pull (e.g. `ghcr.io/my-org/my-app:v1`). Pin the pull
+    /// to an exact version with a digest (e.g.
+    /// `ghcr.io/my-org/my-app@sha256:...`).
+    pub reference: String,
+
+    /// Directory of the cache to pull into. Defaults to the Spin registry
+    /// cache.
+    #[clap(long = "cache-dir")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Pull only the Wasm and static asset files of this component.
+    #[clap(long = "component")]
+    pub component: Option<String>,
+
+    /// How many layers to download at once.
+    #[clap(long = "concurrency", default_value_t = DEFAULT_MAX_CONCURRENT_DOWNLOADS)]
+    pub concurrency: usize,
+
+    /// How to print the result: `text` or `json`. JSON results are printed
+    /// to standard output, and progress to standard error.
+    #[clap(long = "output", arg_enum, default_value = "text")]
+    pub output: OutputFormat,
+
+    /// Refuse the application unless it has a cosign signature made with
+    /// the private key matching this PEM encoded public key (e.g.
+    /// `cosign.pub`). May be given more than once, in which case a
+    /// signature with any of the keys is accepted.
+    #[clap(
+        long = "verify-signature",
+        value_name = "KEY_FILE",
+        multiple_occurrences = true,
+        conflicts_with = "component"
+    )]
+    pub verify_signature: Vec<PathBuf>,
+
+    /// Refuse content which does not satisfy the content trust policy in
+    /// the specified file, including its signature requirements.
+    #[clap(long = "trust-policy", env = TRUST_POLICY_ENV)]
+    pub trust_policy: Option<PathBuf>,
+
+    /// Connect to the registry over plain HTTP
+    #[clap(
+        name = INSECURE_OPT,
+        short = 'k',
+        long = "insecure",
+        takes_value = false,
+    )]
+    pub insecure: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsOptions,
+}
+
+impl Pull {
+    pub async fn run(self) -> Result<()> {
+        let mut client = self
+            .tls
+            .client(self.insecure)?
+            .with_max_concurrent_downloads(self.concurrency);
+        if let Some(path) = &self.trust_policy {
+            client = client.with_trust_policy(TrustPolicy::load(path)?);
+        }
+        let keys = self
+            .verify_signature
+            .iter()
+            .map(|path| VerificationKey::from_file(path))
+            .collect::<Result<Vec<_>, _>>()?;
+        let cache = Cache::new(self.cache_dir).await?;
+        let started = Instant::now();
+        progress(&self.output, format_args!("Pulling {}...", self.reference));
+        let digest = if !keys.is_empty() {
+            client
+                .pull_verified(&self.reference, &cache, &keys)
+                .await
+                .with_context(|| format!("Failed to pull {}", self.reference))?;
+            progress(
+                &self.output,
+                format_args!("Pulled {} and verified its signature", self.reference),
+            );
+            None
+        } else if let Some(component) = &self.component {
+            client
+                .pull_component(&self.reference, component, &cache)
+                .await
+                .with_context(|| format!("Failed to pull {} from {}", component, self.reference))?;
+            progress(
+                &self.output,
+                format_args!("Pulled and verified component {}", component),
+            );
+            None
+        } else {
+            let digest = client
+                .pull_into_cache(&self.reference, &cache)
+                .await
+                .with_context(|| format!("Failed to pull {}", self.reference))?;
+            progress(&self.output, format_args!("Pulled and verified {}", digest));
+            Some(digest)
+        };
+
+        if self.output == OutputFormat::Json {
+            let output = serde_json::json!({
+                "reference": self.reference,
+                "digest": digest,
+                "component": self.component,
+                "signature_verified": !keys.is_empty(),
+                "cache_dir": cache.root(),
+                "duration_ms": started.elapsed().as_millis() as u64,
+            });
+            println!("{}", serde_json::to_string_pretty(&output)?);
+        }
+        Ok(())
+    }
+}
+
+/// Pull a Spin application from a registry and run it, optionally pinning
+/// it to a digest so that every run uses the same content.
+#[derive(Parser, Debug)]
+pub struct Run {
+    /// Reference to run (e.g. `ghcr.io/my-org/my-app:v1`)
+    pub reference: String,
+
+    /// Run the application only if the reference resolves to this digest
+    /// (e.g. `sha256:...`), failing if the tag has been moved.
+    #[clap(long = "digest", conflicts_with = "locked")]
+    pub digest: Option<String>,
+
+    /// Run the digest the reference is pinned to in the lockfile. The
+    /// first time the reference is run, the digest it resolves to is
+    /// recorded in the lockfile.
+    #[clap(long = "locked", takes_value = false)]
+    pub locked: bool,
+
+    /// The lockfile used with `--locked`.
+    #[clap(long = "lockfile", default_value = DEFAULT_LOCKFILE, requires = "locked")]
+    pub lockfile: PathBuf,
+
+    /// Directory of the cache to pull into. Defaults to the Spin registry
+    /// cache.
+    #[clap(long = "cache-dir")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Connect to the registry over plain HTTP
+    #[clap(
+        name = INSECURE_OPT,
+        short = 'k',
+        long = "insecure",
+        takes_value = false,
+    )]
+    pub insecure: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsOptions,
+
+    /// All other args, to be passed through to the trigger
+    #[clap(hide = true)]
+    pub trigger_args: Vec<OsString>,
+}
+
+impl Run {
+    pub async fn run(self) -> Result<()> {
+        let client = self.tls.client(self.insecure)?;
+        let cache = Cache::new(self.cache_dir.clone()).await?;
+        let failed = || format!("Failed to pull {}", self.reference);
+        // The application is pinned to a digest if one was given or is in
+        // the lockfile.
+        let app = if let Some(digest) = &self.digest {
+            println!("Pulling {} ({})...", self.reference, digest);
+            client
+                .pull_pinned(&self.reference, digest, &cache)
+                .await
+                .with_context(failed)?
+        } else if !self.locked {
+            println!("Pulling {}...", self.reference);
+            client
+                .pull(&self.reference, &cache)
+                .await
+                .with_context(failed)?
+        } else {
+            let mut lockfile = Lockfile::load(&self.lockfile)?;
+            match lockfile.pinned(&self.reference) {
+                // The tag may have moved on since; the pin is what counts.
+                Some(digest) => {
+                    println!("Pulling {} ({})...", self.reference, digest);
+                    let pinned = pinned_reference(&self.reference, digest)?;
+                    client.pull(&pinned, &cache).await.with_context(failed)?
+                }
+                None => {
+                    println!("Pulling {}...", self.reference);
+                    let (digest, app) = client
+                        .pull_resolved(&self.reference, &cache)
+                        .await
+                        .with_context(failed)?;
+                    lockfile.pin(&self.reference, &digest);
+                    lockfile.save(&self.lockfile)?;
+                    println!(
+                        "Pinned {} to {} in {}",
+                        self.reference,
+                        digest,
+                        self.lockfile.display()
+                    );
+                    app
+                }
+            }
+        };
+        let trigger_type = match app.triggers.first() {
+            Some(trigger) => trigger.trigger_type.clone(),
+            None => bail!("{} has no triggers", self.reference),
+        };
+
+        let working_dir = tempfile::tempdir()?;
+        let locked_path = working_dir.path().join("spin.lock");
+        std::fs::write(&locked_path, app.to_json()?)
+            .with_context(|| format!("Failed to write {}", locked_path.display()))?;
+        let locked_url = Url::from_file_path(&locked_path)
+            .map_err(|_| anyhow!("Cannot convert to file URL: {}", locked_path.display()))?;
+
+        let mut cmd = std::process::Command::new(std::env::current_exe()?);
+        cmd.arg("trigger")
+            .arg(trigger_type)
+            .env(SPIN_WORKING_DIR, working_dir.path())
+            .env(SPIN_LOCKED_URL, locked_url.as_str())
+            .args(&self.trigger_args);
+        tracing::trace!("Running trigger executor: {:?}", cmd);
+        let mut child = cmd.spawn().context("Failed to execute trigger")?;
+
+        // Terminate trigger executor if `spin oci run` itself receives a
+        // termination signal
+        #[cfg(not(windows))]
+        {
+            let pid = nix::unistd::Pid::from_raw(child.id() as i32);
+            ctrlc::set_handler(move || {
+                if let Err(err) = nix::sys::signal::kill(pid, nix::sys::signal::SIGTERM) {
+                    tracing::warn!("Failed to kill trigger handler process: {:?}", err)
+                }
+            })?;
+        }
+
+        let status = child.wait()?;
+        if !status.success() {
+            bail!(status);
+        }
+        Ok(())
+    }
+}
+
+/// Save a Spin application to an OCI image layout directory, which other
+/// OCI tools can read, for moving it where there is no registry.
+#[derive(Parser, Debug)]
+pub struct Save {
+    /// Reference to save (e.g. `ghcr.io/my-org/my-app:v1`)
+    pub reference: String,
+
+    /// The image layout directory to save into, which is created if it
+    /// does not exist.
+    #[clap(short = 'o', long = "output")]
+    pub output: PathBuf,
+
+    /// The name of the application in the image layout. Defaults to the
+    /// tag of the reference.
+    #[clap(long = "name")]
+    pub name: Option<String>,
+
+    /// Directory of the cache to save through. Defaults to the Spin
+    /// registry cache.
+    #[clap(long = "cache-dir")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Connect to the registry over plain HTTP
+    #[clap(
+        name = INSECURE_OPT,
+        short = 'k',
+        long = "insecure",
+        takes_value = false,
+    )]
+    pub insecure: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsOptions,
+}
+
+impl Save {
+    pub async fn run(self) -> Result<()> {
+        let client = self.tls.client(self.insecure)?;
+        let cache = Cache::new(self.cache_dir).await?;
+        println!("Saving {}...", self.reference);
+        let digest = client
+            .save(&self.reference, &cache, &self.output, self.name.as_deref())
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to save {} to {}",
+                    self.reference,
+                    self.output.display()
+                )
+            })?;
+        println!("Saved {} to {}", digest, self.output.display());
+        Ok(())
+    }
+}
+
+/// Load a Spin application from an OCI image layout, such as one written
+/// by `spin oci save`, into the local cache under a reference.
+#[derive(Parser, Debug)]
+pub struct Load {
+    /// The image layout directory, or a tarball of one, to load from
+    pub layout: PathBuf,
+
+    /// Reference to load the application as (e.g. `ghcr.io/my-org/my-app:v1`)
+    pub reference: String,
+
+    /// The name of the application in the image layout. May be omitted if
+    /// the layout holds only one.
+    #[clap(long = "name")]
+    pub name: Option<String>,
+
+    /// Push the loaded application to the reference
+    #[clap(long = "push", takes_value = false)]
+    pub push: bool,
+
+    /// Directory of the cache to load into. Defaults to the Spin registry
+    /// cache.
+    #[clap(long = "cache-dir")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Connect to the registry over plain HTTP
+    #[clap(
+        name = INSECURE_OPT,
+        short = 'k',
+        long = "insecure",
+        takes_value = false,
+    )]
+    pub insecure: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsOptions,
+}
+
+impl Load {
+    pub async fn run(self) -> Result<()> {
+        let client = self.tls.client(self.insecure)?;
+        let cache = Cache::new(self.cache_dir).await?;
+        let digest = client
+            .load(&self.layout, self.name.as_deref(), &self.reference, &cache)
+            .await
+            .with_context(|| format!("Failed to load {}", self.layout.display()))?;
+        println!("Loaded {} as {}", digest, self.reference);
+
+        if self.push {
+            println!("Pushing {}...", self.reference);
+            let digest = client
+                .push_cached(&self.reference, &cache)
+                .await
+                .with_context(|| format!("Failed to push {}", self.reference))?;
+            println!("Pushed {} ({})", self.reference, digest);
+        }
+        Ok(())
+    }
+}
+
+/// Copy a Spin application to another reference by way of the local cache.
+#[derive(Parser, Debug)]
+pub struct Copy {
+    /// Reference to copy from (e.g. `registry.staging.example/my-app:v1`)
+    pub source: String,
+
+    /// Reference to copy to (e.g. `registry.example/my-app:v1`)
+    pub destination: String,
+
+    /// Directory of the cache to copy through. Defaults to the Spin
+    /// registry cache.
+    #[clap(long = "cache-dir")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Connect to the registries over plain HTTP
+    #[clap(
+        name = INSECURE_OPT,
+        short = 'k',
+        long = "insecure",
+        takes_value = false,
+    )]
+    pub insecure: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsOptions,
+}
+
+impl Copy {
+    pub async fn run(self) -> Result<()> {
+        let client = self.tls.client(self.insecure)?;
+        let cache = Cache::new(self.cache_dir).await?;
+        println!("Copying {} to {}...", self.source, self.destination);
+        let digest = client
+            .copy(&self.source, &self.destination, &cache)
+            .await
+            .with_context(|| format!("Failed to copy {} to {}", self.source, self.destination))?;
+        println!("Copied with digest {}", digest);
+        Ok(())
+    }
+}
+
+/// Delete a published application from a registry.
+#[derive(Parser, Debug)]
+pub struct Delete {
+    /// Reference to delete (e.g. `ghcr.io/my-org/my-app:pr-123`). The
+    /// manifest the tag points to is deleted, along with any other tags
+    /// pointing to it.
+    pub reference: String,
+
+    /// Connect to the registry over plain HTTP
+    #[clap(
+        name = INSECURE_OPT,
+        short = 'k',
+        long = "insecure",
+        takes_value = false,
+    )]
+    pub insecure: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsOptions,
+}
+
+impl Delete {
+    pub async fn run(self) -> Result<()> {
+        let client = self.tls.client(self.insecure)?;
+        let digest = client
+            .delete(&self.reference)
+            .await
+            .with_context(|| format!("Failed to delete {}", self.reference))?;
+        println!("Deleted {} ({})", self.reference, digest);
+        Ok(())
+    }
+}
+
+/// Check whether a reference has been published. Exits with status 0 if it
+/// has and 1 if it has not.
+#[derive(Parser, Debug)]
+pub struct Exists {
+    /// Reference to check (e.g. `ghcr.io/my-org/my-app:v1`)
+    pub reference: String,
+
+    /// Remember references which do not exist for this many seconds, so
+    /// that repeated checks do not ask the registry again.
+    #[clap(long = "cache-missing", value_name = "SECONDS")]
+    pub cache_missing: Option<u64>,
+
+    /// Ask the registry even if the reference is remembered as missing.
+    #[clap(long = "no-cache", requires = "cache_missing")]
+    pub no_cache: bool,
+
+    /// Connect to the registry over plain HTTP
+    #[clap(
+        name = INSECURE_OPT,
+        short = 'k',
+        long = "insecure",
+        takes_value = false,
+    )]
+    pub insecure: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsOptions,
+}
+
+impl Exists {
+    pub async fn run(self) -> Result<()> {
+        let mut client = self.tls.client(self.insecure)?;
+        if let Some(ttl) = self.cache_missing {
+            let ttl = if self.no_cache { 0 } else { ttl };
+            client = client.with_negative_cache(Cache::new(None).await?, Duration::from_secs(ttl));
+        }
+        let exists = client
+            .exists(&self.reference)
+            .await
+            .with_context(|| format!("Failed to check {}", self.reference))?;
+        if exists {
+            println!("{} exists", self.reference);
+            Ok(())
+        } else {
+            println!("{} does not exist", self.reference);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Describe a published application from its manifest and config.
+#[derive(Parser, Debug)]
+pub struct Inspect {
+    /// Reference of the application (e.g. `ghcr.io/my-org/my-app:v1`)
+    pub reference: String,
+
+    /// How to print the description: `text` or `json`.
+    #[clap(long = "output", arg_enum, default_value = "text")]
+    pub output: OutputFormat,
+
+    /// Connect to the registry over plain HTTP
+    #[clap(
+        name = INSECURE_OPT,
+        short = 'k',
+        long = "insecure",
+        takes_value = false,
+    )]
+    pub insecure: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsOptions,
+}
+
+impl Inspect {
+    pub async fn run(self) -> Result<()> {
+        let client = self.tls.client(self.insecure)?;
+        let inspection = client
+            .inspect(&self.reference)
+            .await
+            .with_context(|| format!("Failed to inspect {}", self.reference))?;
+        match self.output {
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&inspection)?),
+            OutputFormat::Text => print_inspection(&inspection),
+        }
+        Ok(())
+    }
+}
+
+fn print_inspection(inspection: &Inspection) {
+    println!("Reference: {}", inspection.reference);
+    println!("Digest: {}", inspection.digest);
+    if let Some(index_digest) = &inspection.index_digest {
+        println!("Index digest: {}", index_digest);
+    }
+    println!("Media type: {}", inspection.media_type);
+    println!("Config digest: {}", inspection.config_digest);
+    println!("Triggers: {}", inspection.trigger_types.join(", "));
+    println!("Components:");
+    for component in &inspection.components {
+        println!(
+            "  {}  {}  {} assets",
+            component.id,
+            component.source_digest.as_deref().unwrap_or("-"),
+            component.asset_count
+        );
+    }
+    println!("Layers:");
+    for layer in &inspection.layers {
+        let place = match (&layer.component, &layer.guest_path) {
+            (Some(component), Some(path)) => format!("  {}:{}", component, path),
+            (Some(component), None) => format!("  {}", component),
+            _ => String::new(),
+        };
+        println!(
+            "  {}  {}  {} bytes{}",
+            layer.digest, layer.media_type, layer.size, place
+        );
+    }
+    if !inspection.annotations.is_empty() {
+        println!("Annotations:");
+        for (name, value) in &inspection.annotations {
+            println!("  {}={}", name, value);
+        }
+    }
+    println!("Total size: {} bytes", inspection.total_bytes);
+}
+
+/// Fetch the SBOM attached to a published application.
+#[derive(Parser, Debug)]
+pub struct Sbom {
+    /// Reference of the application (e.g. `ghcr.io/my-org/my-app:v1`)
+    pub reference: String,
+
+    /// Write the SBOM to this file rather than to standard output.
+    #[clap(short = 'o', long = "output")]
+    pub output: Option<PathBuf>,
+
+    /// Connect to the registry over plain HTTP
+    #[clap(
+        name = INSECURE_OPT,
+        short = 'k',
+        long = "insecure",
+        takes_value = false,
+    )]
+    pub insecure: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsOptions,
+}
+
+impl Sbom {
+    pub async fn run(self) -> Result<()> {
+        let client = self.tls.client(self.insecure)?;
+        let (media_type, sbom) = client
+            .fetch_sbom(&self.reference)
+            .await
+            .with_context(|| format!("Failed to fetch the SBOM of {}", self.reference))?
+            .with_context(|| format!("{} has no SBOM attached", self.reference))?;
+        match &self.output {
+            Some(path) => {
+                std::fs::write(path, &sbom)
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+                eprintln!("Wrote {} SBOM to {}", media_type, path.display());
+            }
+            None => {
+                use std::io::Write;
+                std::io::stdout().write_all(&sbom)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// List the repositories in a registry or registry namespace.
+#[derive(Parser, Debug)]
+pub struct ListRemote {
+    /// Registry to list, optionally followed by a namespace
+    /// (e.g. `ghcr.io/my-org`).
+    pub location: String,
+
+    /// Connect to the registry over plain HTTP
+    #[clap(
+        name = INSECURE_OPT,
+        short = 'k',
+        long = "insecure",
+        takes_value = false,
+    )]
+    pub insecure: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsOptions,
+}
+
+impl ListRemote {
+    pub async fn run(self) -> Result<()> {
+        let client = self.tls.client(self.insecure)?;
+        let repositories = client
+            .list_repositories(&self.location)
+            .await
+            .with_context(|| format!("Failed to list repositories in {}", self.location))?;
+
+        for repository in repositories {
+            println!("{}", repository);
+        }
+        Ok(())
+    }
+}
+
+/// Log in to a registry. The credentials are checked against the
+/// registry's authentication endpoint before they are stored.
+#[derive(Parser, Debug)]
+pub struct Login {
+    /// The registry to log in to (e.g. `ghcr.io`)
+    pub registry: String,
+
+    /// The username to log in with. Prompted for if not given.
+    #[clap(short = 'u', long = "username")]
+    pub username: Option<String>,
+
+    /// Read the password or token from standard input, rather than
+    /// prompting for it.
+    #[clap(long = "password-stdin")]
+    pub password_stdin: bool,
+
+    /// Store the credentials in the Docker configuration, where Docker and
+    /// other tools can use them, rather than in Spin's own store.
+    #[clap(long = "docker-config")]
+    pub docker_config: bool,
+
+    /// Connect to the registry over plain HTTP
+    #[clap(
+        name = INSECURE_OPT,
+        short = 'k',
+        long = "insecure",
+        takes_value = false,
+    )]
+    pub insecure: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsOptions,
+}
+
+impl Login {
+    pub async fn run(self) -> Result<()> {
+        let registry = normalize_registry(&self.registry);
+        let username = match &self.username {
+            Some(username) => username.clone(),
+            None => dialoguer::Input::new()
+                .with_prompt("Username")
+                .interact_text()?,
+        };
+        let password = if self.password_stdin {
+            let mut password = String::new();
+            std::io::stdin()
+                .read_to_string(&mut password)
+                .context("Failed to read the password from standard input")?;
+            password.trim_end_matches(&['\r', '\n'][..]).to_owned()
+        } else {
+            dialoguer::Password::new()
+                .with_prompt("Password")
+                .interact()?
+        };
+        let password = Secret::new(password);
+
+        self.tls
+            .client(self.insecure)?
+            .login(&registry, &username, &password)
+            .await
+            .with_context(|| format!("Failed to log in to {}", registry))?;
+
+        let store = credential_store(self.docker_config)?;
+        store.store(&registry, &username, &password)?;
+        println!(
+            "Logged in to {} as {}. Credentials stored in {}",
+            registry,
+            username,
+            store.path().display()
+        );
+        Ok(())
+    }
+}
+
+/// Log out of a registry, removing the credentials stored for it.
+#[derive(Parser, Debug)]
+pub struct Logout {
+    /// The registry to log out of (e.g. `ghcr.io`)
+    pub registry: String,
+
+    /// Remove the credentials from the Docker configuration, rather than
+    /// from Spin's own store.
+    #[clap(long = "docker-config")]
+    pub docker_config: bool,
+}
+
+impl Logout {
+    pub async fn run(self) -> Result<()> {
+        let registry = normalize_registry(&self.registry);
+        let store = credential_store(self.docker_config)?;
+        if store.remove(&registry)? {
+            println!("Logged out of {}", registry);
+        } else {
+            println!(
+                "Not logged in to {}: {} has no credentials for it",
+                registry,
+                store.path().display()
+            );
+        }
+        Ok(())
+    }
+}
+
+fn credential_store(docker_config: bool) -> Result<CredentialStore> {
+    Ok(if docker_config {
+        CredentialStore::docker()?
+    } else {
+        CredentialStore::spin()?
+    })
+}
+
+/// List the tags in a repository.
+#[derive(Parser, Debug)]
+pub struct Tags {
+    /// Repository to list (e.g. `ghcr.io/my-org/my-app`).
+    pub repository: String,
+
+    /// Connect to the registry over plain HTTP
+    #[clap(
+        name = INSECURE_OPT,
+        short = 'k',
+        long = "insecure",
+        takes_value = false,
+    )]
+    pub insecure: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsOptions,
+}
+
+impl Tags {
+    pub async fn run(self) -> Result<()> {
+        let client = self.tls.client(self.insecure)?;
+        let tags = client
+            .list_tags(&self.repository)
+            .await
+            .with_context(|| format!("Failed to list tags in {}", self.repository))?;
+
+        for tag in tags {
+            println!("{}", tag);
+        }
+        Ok(())
+    }
+}
+
+/// Serve cached registry content to local Spin instances, pulling through
+/// from an upstream registry.
+#[derive(Parser, Debug)]
+pub struct ProxyCommand {
+    /// Upstream registry host (e.g. `ghcr.io`).
+    pub upstream: String,
+
+    /// Address on which to serve the registry API.
+    #[clap(long = "listen", default_value = "127.0.0.1:5000")]
+    pub listen: SocketAddr,
+
+    /// Directory in which to cache registry content. Defaults to the Spin
+    /// registry cache.
+    #[clap(long = "cache-dir")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Connect to the upstream registry over plain HTTP
+    #[clap(
+        name = INSECURE_OPT,
+        short = 'k',
+        long = "insecure",
+        takes_value = false,
+    )]
+    pub insecure: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsOptions,
+
+    /// Limit the rate of downloads from the upstream registry, in bytes per
+    /// second. Accepts K, M and G suffixes (e.g. `512K`).
+    #[clap(
+        long = "download-limit",
+        env = DOWNLOAD_LIMIT_ENV,
+        parse(try_from_str = parse_rate_limit),
+    )]
+    pub download_limit: Option<u64>,
+
+    /// Refuse upstream content which does not satisfy the content trust
+    /// policy in the specified file.
+    #[clap(long = "trust-policy", env = TRUST_POLICY_ENV)]
+    pub trust_policy: Option<PathBuf>,
+
+    /// Check that layers claiming to be Wasm are valid Wasm modules or
+    /// components before caching them.
+    #[clap(long = "validate-wasm")]
+    pub validate_wasm: bool,
+}
+
+impl ProxyCommand {
+    pub async fn run(self) -> Result<()> {
+        let mut client = self
+            .tls
+            .client(self.insecure)?
+            .with_wasm_validation(self.validate_wasm);
+        if let Some(limit) = self.download_limit {
+            client = client.with_download_limit(limit);
+        }
+        if let Some(path) = &self.trust_policy {
+            client = client.with_trust_policy(TrustPolicy::load(path)?);
+        }
+        let cache = Cache::new(self.cache_dir).await?;
+        println!(
+            "Serving {} from {} on http://{}",
+            self.upstream,
+            cache.root().display(),
+            self.listen
+        );
+        Proxy::new(client, cache, self.upstream)
+            .serve(self.listen)
+            .await
+            .context("Registry proxy failed")
+    }
+}
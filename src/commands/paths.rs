@@ -0,0 +1,44 @@
+use anyhow::Result;
+use clap::Parser;
+
+use crate::{opts::*, paths::SpinPaths};
+
+/// Print the locations of the files and directories Spin uses.
+#[derive(Parser, Debug)]
+#[clap(about = "Print the locations of the files and directories Spin uses")]
+pub struct PathsCommand {
+    /// Print the paths as JSON.
+    #[clap(long = "json")]
+    pub json: bool,
+
+    /// Report the login file for the Fermyon instance saved under the
+    /// specified name, rather than the default instance.
+    #[clap(
+        name = "environment-name",
+        long = "environment-name",
+        env = DEPLOYMENT_ENV_NAME_ENV
+    )]
+    pub deployment_env_id: Option<String>,
+}
+
+impl PathsCommand {
+    pub async fn run(self) -> Result<()> {
+        let paths = SpinPaths::resolve(self.deployment_env_id.as_deref())?;
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&paths)?);
+            return Ok(());
+        }
+
+        let descriptions = paths.describe();
+        let width = descriptions
+            .iter()
+            .map(|(description, _)| description.len())
+            .max()
+            .unwrap_or_default();
+        for (description, path) in descriptions {
+            println!("{:width$}  {}", description, path.display(), width = width);
+        }
+        Ok(())
+    }
+}
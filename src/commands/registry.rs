@@ -0,0 +1,94 @@
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use spin_publish::oci::{Client, RegistryCheck};
+
+use crate::{
+    commands::{apps::OutputFormat, oci::TlsOptions},
+    opts::*,
+};
+
+/// Commands for working with OCI registries.
+#[derive(Subcommand, Debug)]
+pub enum RegistryCommands {
+    /// Check that the credentials for a registry work and, if a repository
+    /// is given, that they allow pushing to it.
+    Check(Check),
+}
+
+impl RegistryCommands {
+    pub async fn run(self) -> Result<()> {
+        match self {
+            Self::Check(cmd) => cmd.run().await,
+        }
+    }
+}
+
+/// Check the credentials for a registry before pushing to it. Exits with
+/// a failure status if they are rejected, or if they do not allow pushing
+/// to the repository.
+#[derive(Parser, Debug)]
+pub struct Check {
+    /// The registry to check (e.g. `ghcr.io`), optionally followed by a
+    /// repository whose push permission is checked (e.g.
+    /// `ghcr.io/my-org/my-app`).
+    pub location: String,
+
+    /// How to print the result: `text` or `json`.
+    #[clap(long = "output", arg_enum, default_value = "text")]
+    pub output: OutputFormat,
+
+    /// Connect to the registry over plain HTTP
+    #[clap(
+        name = INSECURE_OPT,
+        short = 'k',
+        long = "insecure",
+        takes_value = false,
+    )]
+    pub insecure: bool,
+
+    #[clap(flatten)]
+    pub tls: TlsOptions,
+}
+
+impl Check {
+    pub async fn run(self) -> Result<()> {
+        let check = self
+            .tls
+            .client(self.insecure)?
+            .check_registry(&self.location)
+            .await
+            .with_context(|| format!("Failed to check {}", self.location))?;
+        match self.output {
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&check)?),
+            OutputFormat::Text => print_check(&check),
+        }
+        if check.can_push == Some(false) {
+            bail!(
+                "The credentials for {} do not allow pushing to {}",
+                check.registry,
+                self.location
+            );
+        }
+        Ok(())
+    }
+}
+
+fn print_check(check: &RegistryCheck) {
+    println!("Registry: {}", check.registry);
+    println!("Credentials: {}", check.credentials);
+    if let Some(identity) = &check.identity {
+        println!("Identity: {}", identity);
+    }
+    if !check.scopes.is_empty() {
+        println!("Scopes:");
+        for scope in &check.scopes {
+            println!("  {}", scope);
+        }
+    }
+    if let Some(repository) = &check.repository {
+        match &check.push_error {
+            None => println!("Push to {}: allowed", repository),
+            Some(error) => println!("Push to {}: refused ({})", repository, error),
+        }
+    }
+}
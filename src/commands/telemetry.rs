@@ -0,0 +1,80 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+
+use crate::telemetry;
+
+/// Commands for the opt-in, local-only deployment statistics.
+#[derive(Subcommand, Debug)]
+pub enum TelemetryCommands {
+    /// Start recording anonymized deployment outcomes on this machine.
+    Enable,
+
+    /// Stop recording deployment outcomes.
+    Disable,
+
+    /// Show whether deployment outcomes are being recorded.
+    Status,
+
+    /// Export the recorded deployment outcomes as JSON.
+    Export(Export),
+
+    /// Delete the recorded deployment outcomes.
+    Clear,
+}
+
+impl TelemetryCommands {
+    pub async fn run(self) -> Result<()> {
+        match self {
+            Self::Enable => {
+                telemetry::set_enabled(true)?;
+                println!("Deployment outcomes will be recorded in {}. Nothing is sent anywhere: use `spin telemetry export` to share them.", telemetry::telemetry_dir()?.display());
+                Ok(())
+            }
+            Self::Disable => {
+                telemetry::set_enabled(false)?;
+                println!("Deployment outcomes will no longer be recorded. Use `spin telemetry clear` to delete existing records.");
+                Ok(())
+            }
+            Self::Status => {
+                let state = if telemetry::is_enabled() {
+                    "enabled"
+                } else {
+                    "disabled"
+                };
+                println!("Recording is {}", state);
+                println!(
+                    "{} deployment outcome(s) recorded",
+                    telemetry::load_records()?.len()
+                );
+                Ok(())
+            }
+            Self::Export(cmd) => cmd.run().await,
+            Self::Clear => telemetry::clear_records(),
+        }
+    }
+}
+
+/// Export the recorded deployment outcomes as JSON.
+#[derive(Parser, Debug)]
+pub struct Export {
+    /// Write the statistics to the specified file rather than to standard
+    /// output.
+    #[clap(short = 'o', long = "output")]
+    pub output: Option<PathBuf>,
+}
+
+impl Export {
+    pub async fn run(self) -> Result<()> {
+        let json = serde_json::to_string_pretty(&telemetry::load_records()?)?;
+        match &self.output {
+            Some(path) => std::fs::write(path, json)
+                .with_context(|| format!("Failed to write {}", path.display())),
+            None => {
+                println!("{}", json);
+                Ok(())
+            }
+        }
+    }
+}
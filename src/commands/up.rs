@@ -9,6 +9,7 @@ use clap::{CommandFactory, Parser};
 use reqwest::Url;
 use spin_loader::bindle::BindleConnectionInfo;
 use spin_manifest::ApplicationTrigger;
+use spin_publish::Secret;
 use spin_trigger::cli::{SPIN_LOCKED_URL, SPIN_WORKING_DIR};
 use tempfile::TempDir;
 
@@ -68,7 +69,7 @@ pub struct UpCommand {
         env = BINDLE_PASSWORD,
         requires = BINDLE_USERNAME
     )]
-    pub bindle_password: Option<String>,
+    pub bindle_password: Option<Secret<String>>,
 
     /// Ignore server certificate errors from bindle server
     #[clap(
@@ -229,7 +230,7 @@ impl UpCommand {
                 url,
                 self.insecure,
                 self.bindle_username.clone(),
-                self.bindle_password.clone(),
+                self.bindle_password.clone().map(Secret::into_inner),
             )
         })
     }
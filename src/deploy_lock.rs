@@ -0,0 +1,93 @@
+//! The deployment lockfile, which records exactly what `spin deploy` deployed
+//! for a given application version.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+pub(crate) const DEPLOY_LOCK_FILE: &str = "spin.deploy.lock";
+
+const DEPLOY_LOCK_HEADER: &str =
+    "# This file is generated by `spin deploy`. Commit it alongside spin.toml.\n";
+
+/// The resolved content of a deployed application version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DeployLock {
+    /// The application name from the manifest.
+    pub name: String,
+    /// The application version from the manifest.
+    pub version: String,
+    /// The digest of the application content.
+    pub digest: String,
+    /// The bindle which was most recently deployed for this content.
+    pub bindle_id: String,
+}
+
+impl DeployLock {
+    /// The path of the lockfile for the given application manifest.
+    pub fn path_for(app: &Path) -> Result<PathBuf> {
+        Ok(spin_loader::local::parent_dir(app)?.join(DEPLOY_LOCK_FILE))
+    }
+
+    /// Loads the lockfile at the given path, if there is one.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let lock =
+            toml::from_str(&text).with_context(|| format!("Failed to parse {}", path.display()))?;
+        Ok(Some(lock))
+    }
+
+    /// Saves the lockfile to the given path.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = format!("{}{}", DEPLOY_LOCK_HEADER, toml::to_string(self)?);
+        std::fs::write(path, text).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Checks that the given application content matches what was locked.
+    pub fn verify(&self, name: &str, version: &str, digest: &str) -> Result<()> {
+        if self.name != name || self.version != version {
+            bail!(
+                "{} is for {} version {}, but the application is {} version {}. Deploy without --locked to update it.",
+                DEPLOY_LOCK_FILE,
+                self.name,
+                self.version,
+                name,
+                version
+            );
+        }
+        if self.digest != digest {
+            bail!(
+                "The content of {} version {} has changed since it was locked (expected {}, found {}). Deploy without --locked to update {}.",
+                name,
+                version,
+                self.digest,
+                digest,
+                DEPLOY_LOCK_FILE
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verify_rejects_changed_content() {
+        let lock = DeployLock {
+            name: "app".to_owned(),
+            version: "1.0.0".to_owned(),
+            digest: "sha256:abc".to_owned(),
+            bindle_id: "app/1.0.0+qabc".to_owned(),
+        };
+        assert!(lock.verify("app", "1.0.0", "sha256:abc").is_ok());
+        assert!(lock.verify("app", "1.0.0", "sha256:def").is_err());
+        assert!(lock.verify("app", "1.0.1", "sha256:abc").is_err());
+    }
+}
@@ -0,0 +1,128 @@
+//! The summary of what a deployment changes relative to the revision which
+//! is currently active, shown before the channel is updated.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// The differences between the active revision of a channel and the one
+/// about to replace it.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct DeploySummary {
+    /// The old and new versions, if they differ.
+    pub version: Option<(String, String)>,
+    pub components_added: Vec<String>,
+    pub components_removed: Vec<String>,
+    pub variables_added: Vec<String>,
+    pub variables_removed: Vec<String>,
+    pub variables_changed: Vec<String>,
+}
+
+impl DeploySummary {
+    /// Compares the active revision and variables of a channel with those
+    /// being deployed. Variable values are compared but never shown, as
+    /// they may be secrets.
+    pub fn new<'a>(
+        old_version: Option<&str>,
+        new_version: &str,
+        old_components: impl IntoIterator<Item = &'a str>,
+        new_components: impl IntoIterator<Item = &'a str>,
+        old_variables: &BTreeMap<String, String>,
+        new_variables: &BTreeMap<String, String>,
+    ) -> Self {
+        let old_components: BTreeSet<_> = old_components.into_iter().collect();
+        let new_components: BTreeSet<_> = new_components.into_iter().collect();
+        let difference = |a: &BTreeSet<&str>, b: &BTreeSet<&str>| {
+            a.difference(b).map(|s| s.to_string()).collect()
+        };
+
+        Self {
+            version: match old_version {
+                Some(old) if old == new_version => None,
+                old => Some((old.unwrap_or("(none)").to_owned(), new_version.to_owned())),
+            },
+            components_added: difference(&new_components, &old_components),
+            components_removed: difference(&old_components, &new_components),
+            variables_added: new_variables
+                .keys()
+                .filter(|k| !old_variables.contains_key(*k))
+                .cloned()
+                .collect(),
+            variables_removed: old_variables
+                .keys()
+                .filter(|k| !new_variables.contains_key(*k))
+                .cloned()
+                .collect(),
+            variables_changed: new_variables
+                .iter()
+                .filter(|(k, v)| old_variables.get(*k).map_or(false, |old| old != *v))
+                .map(|(k, _)| k.clone())
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+impl fmt::Display for DeploySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No changes to the active revision");
+        }
+        if let Some((old, new)) = &self.version {
+            writeln!(f, "  version: {} -> {}", old, new)?;
+        }
+        let lists = [
+            ("+", "component", &self.components_added),
+            ("-", "component", &self.components_removed),
+            ("+", "variable", &self.variables_added),
+            ("-", "variable", &self.variables_removed),
+            ("~", "variable", &self.variables_changed),
+        ];
+        for (marker, kind, names) in lists {
+            for name in names {
+                writeln!(f, "  {} {} {}", marker, kind, name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn summarises_changes_without_values() {
+        let old_variables = BTreeMap::from([
+            ("kept".to_owned(), "1".to_owned()),
+            ("changed".to_owned(), "old".to_owned()),
+            ("removed".to_owned(), "x".to_owned()),
+        ]);
+        let new_variables = BTreeMap::from([
+            ("kept".to_owned(), "1".to_owned()),
+            ("changed".to_owned(), "new-secret".to_owned()),
+            ("added".to_owned(), "y".to_owned()),
+        ]);
+        let summary = DeploySummary::new(
+            Some("1.0.0"),
+            "1.1.0",
+            ["web", "api"],
+            ["web", "worker"],
+            &old_variables,
+            &new_variables,
+        );
+
+        assert_eq!(
+            Some(("1.0.0".to_owned(), "1.1.0".to_owned())),
+            summary.version
+        );
+        assert_eq!(vec!["worker"], summary.components_added);
+        assert_eq!(vec!["api"], summary.components_removed);
+        assert_eq!(vec!["added"], summary.variables_added);
+        assert_eq!(vec!["removed"], summary.variables_removed);
+        assert_eq!(vec!["changed"], summary.variables_changed);
+        assert!(!summary.to_string().contains("new-secret"));
+    }
+}
@@ -0,0 +1,346 @@
+//! Deployment manifests, which record the state `spin deploy` left an
+//! application in, so that the deployment can be reproduced declaratively,
+//! and the plans which bring the platform back into line with them.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use spin_loader::local::config::RawAppManifest;
+
+use crate::{commands::new::ParameterValue, variables::variable_env_name};
+
+const DEPLOYMENT_MANIFEST_HEADER: &str =
+    "# This file is generated by `spin deploy --export-manifest`. Apply it with `spin deploy --apply`.\n";
+
+/// What a deployment deployed, and the channel it deployed it to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DeploymentManifest {
+    /// The application name from the manifest.
+    pub name: String,
+    /// The application version from the manifest.
+    pub version: String,
+    /// The bindle deployed as the channel's active revision.
+    pub bindle_id: String,
+    /// The digest of the application content.
+    pub digest: String,
+    /// The names of secret application variables which were set. Their
+    /// values are never recorded.
+    #[serde(default)]
+    pub secret_variables: Vec<String>,
+    /// The channel the application was deployed to.
+    pub channel: DeployedChannel,
+    /// The values of non-secret application variables.
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+}
+
+/// The channel an application was deployed to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DeployedChannel {
+    /// The name of the channel.
+    pub name: String,
+    /// The domain the channel serves the application on.
+    pub domain: Option<String>,
+    /// The labels set on the channel.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl DeploymentManifest {
+    /// Records the given variable values, which are keyed by the
+    /// environment variable names the platform is given, under the names
+    /// the application manifest declares, leaving out secret values.
+    pub fn with_variables(mut self, cfg: &RawAppManifest, values: &[(String, String)]) -> Self {
+        for (name, variable) in &cfg.variables {
+            let env_name = variable_env_name(name);
+            let value = match values.iter().find(|(n, _)| n == &env_name) {
+                Some((_, value)) => value,
+                None => continue,
+            };
+            if variable.secret {
+                self.secret_variables.push(name.clone());
+            } else {
+                self.variables.insert(name.clone(), value.clone());
+            }
+        }
+        self.secret_variables.sort();
+        self
+    }
+
+    /// The revision number of the deployed bindle: the version in its ID.
+    pub fn revision(&self) -> &str {
+        self.bindle_id
+            .rsplit_once('/')
+            .map_or(self.bindle_id.as_str(), |(_, version)| version)
+    }
+
+    /// The environment variables the channel should have. Secret values
+    /// are taken from `secrets` or, failing that, kept from the channel.
+    pub fn desired_variables(
+        &self,
+        secrets: &[ParameterValue],
+        current: Option<&CurrentChannel>,
+    ) -> Result<BTreeMap<String, String>> {
+        let mut variables: BTreeMap<_, _> = self
+            .variables
+            .iter()
+            .map(|(name, value)| (variable_env_name(name), value.clone()))
+            .collect();
+        for name in &self.secret_variables {
+            let env_name = variable_env_name(name);
+            let value = match secrets.iter().rev().find(|v| &v.name == name) {
+                Some(v) => v.value.clone(),
+                None => match current.and_then(|c| c.variables.get(&env_name)) {
+                    Some(value) => value.clone(),
+                    None => bail!(
+                        "Secret variable '{}' is not set on the channel. Use `--variable {}=value` to provide it",
+                        name,
+                        name
+                    ),
+                },
+            };
+            variables.insert(env_name, value);
+        }
+        Ok(variables)
+    }
+
+    /// Lists the changes which bring the platform from its current state
+    /// to the one recorded, with the channel having the given variables.
+    pub fn plan(
+        &self,
+        current: &CurrentDeployment,
+        variables: &BTreeMap<String, String>,
+    ) -> Vec<Change> {
+        let mut changes = vec![];
+        let revision = self.revision().to_owned();
+        if !current.app_exists {
+            // Creating an app imports its revisions from the registry
+            changes.push(Change::CreateApp(self.name.clone()));
+        } else if !current.revision_registered {
+            changes.push(Change::RegisterRevision(revision.clone()));
+        }
+
+        let empty = CurrentChannel::default();
+        let channel = match &current.channel {
+            Some(channel) => {
+                if channel.active_revision.as_ref() != Some(&revision) {
+                    changes.push(Change::SetActiveRevision {
+                        from: channel.active_revision.clone(),
+                        to: revision,
+                    });
+                }
+                channel
+            }
+            None => {
+                changes.push(Change::CreateChannel {
+                    name: self.channel.name.clone(),
+                    revision,
+                });
+                &empty
+            }
+        };
+
+        // Setting variables keeps the channel's others, so only those
+        // recorded are compared
+        let changed: Vec<_> = variables
+            .iter()
+            .filter(|(name, value)| channel.variables.get(*name) != Some(*value))
+            .map(|(name, _)| name.clone())
+            .collect();
+        if !changed.is_empty() {
+            changes.push(Change::SetVariables(changed));
+        }
+        if self.channel.labels != channel.labels {
+            changes.push(Change::SetLabels(self.channel.labels.clone()));
+        }
+        changes
+    }
+
+    /// Loads the deployment manifest at the given path.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Saves the deployment manifest to the given path.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = format!("{}{}", DEPLOYMENT_MANIFEST_HEADER, toml::to_string(self)?);
+        std::fs::write(path, text).with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// What the platform has for the application in a deployment manifest.
+#[derive(Debug, Default)]
+pub(crate) struct CurrentDeployment {
+    /// Whether the application exists.
+    pub app_exists: bool,
+    /// Whether the recorded revision is registered for the application.
+    pub revision_registered: bool,
+    /// The recorded channel, if it exists.
+    pub channel: Option<CurrentChannel>,
+}
+
+/// The state of an existing channel.
+#[derive(Debug, Default)]
+pub(crate) struct CurrentChannel {
+    /// The revision number of the active revision, if any.
+    pub active_revision: Option<String>,
+    /// The domain the channel serves the application on.
+    pub domain: String,
+    /// The environment variables of the channel.
+    pub variables: BTreeMap<String, String>,
+    /// The labels of the channel.
+    pub labels: BTreeMap<String, String>,
+}
+
+/// A change needed to bring the platform into line with a deployment
+/// manifest, in the order it must be made.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Change {
+    /// Create the named app, importing its revisions.
+    CreateApp(String),
+    /// Register the revision with the app.
+    RegisterRevision(String),
+    /// Create the channel with the revision active.
+    CreateChannel { name: String, revision: String },
+    /// Change the channel's active revision.
+    SetActiveRevision { from: Option<String>, to: String },
+    /// Set the channel's variables, of which those named change.
+    SetVariables(Vec<String>),
+    /// Replace the channel's labels.
+    SetLabels(BTreeMap<String, String>),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CreateApp(name) => write!(f, "Create app {}", name),
+            Self::RegisterRevision(revision) => write!(f, "Register revision {}", revision),
+            Self::CreateChannel { name, revision } => {
+                write!(f, "Create channel {} with revision {}", name, revision)
+            }
+            Self::SetActiveRevision { from, to } => write!(
+                f,
+                "Change the active revision from {} to {}",
+                from.as_deref().unwrap_or("none"),
+                to
+            ),
+            Self::SetVariables(names) => write!(f, "Set variables {}", names.join(", ")),
+            Self::SetLabels(labels) => {
+                let labels: Vec<_> = labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                write!(f, "Set labels {}", labels.join(", "))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use spin_loader::local::config::RawAppManifestAnyVersion;
+
+    fn manifest() -> DeploymentManifest {
+        DeploymentManifest {
+            name: "app".to_owned(),
+            version: "1.0.0".to_owned(),
+            bindle_id: "app/1.0.0+qabc".to_owned(),
+            digest: "sha256:abc".to_owned(),
+            secret_variables: vec![],
+            channel: DeployedChannel {
+                name: "spin-deploy".to_owned(),
+                domain: Some("app.example.com".to_owned()),
+                labels: BTreeMap::new(),
+            },
+            variables: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn secret_variable_values_are_not_recorded() {
+        let cfg: RawAppManifestAnyVersion = toml::from_str(
+            r#"
+            spin_version = "1"
+            name = "app"
+            version = "1.0.0"
+            trigger = { type = "http", base = "/" }
+            [variables]
+            greeting = { default = "hello" }
+            api_key = { required = true, secret = true }
+            [[component]]
+            id = "app"
+            source = "app.wasm"
+            [component.trigger]
+            route = "/..."
+            "#,
+        )
+        .unwrap();
+        let RawAppManifestAnyVersion::V1(cfg) = cfg;
+        let manifest = manifest().with_variables(
+            &cfg,
+            &[
+                ("SPIN_APP_GREETING".to_owned(), "hi".to_owned()),
+                ("SPIN_APP_API_KEY".to_owned(), "hunter2".to_owned()),
+            ],
+        );
+
+        assert_eq!(
+            Some("hi"),
+            manifest.variables.get("greeting").map(String::as_str)
+        );
+        assert_eq!(vec!["api_key"], manifest.secret_variables);
+        let text = toml::to_string(&manifest).unwrap();
+        assert!(!text.contains("hunter2"));
+        assert_eq!(manifest, toml::from_str(&text).unwrap());
+    }
+
+    #[test]
+    fn plans_only_the_changes_needed() {
+        let mut manifest = manifest();
+        manifest
+            .variables
+            .insert("greeting".to_owned(), "hi".to_owned());
+        manifest.secret_variables.push("api_key".to_owned());
+        let current = CurrentDeployment {
+            app_exists: true,
+            revision_registered: true,
+            channel: Some(CurrentChannel {
+                active_revision: Some("0.9.0+qdef".to_owned()),
+                domain: "app.example.com".to_owned(),
+                variables: BTreeMap::from([
+                    ("SPIN_APP_GREETING".to_owned(), "hello".to_owned()),
+                    ("SPIN_APP_API_KEY".to_owned(), "hunter2".to_owned()),
+                ]),
+                labels: BTreeMap::new(),
+            }),
+        };
+
+        let variables = manifest
+            .desired_variables(&[], current.channel.as_ref())
+            .unwrap();
+        assert_eq!(
+            Some("hunter2"),
+            variables.get("SPIN_APP_API_KEY").map(String::as_str)
+        );
+        assert_eq!(
+            vec![
+                Change::SetActiveRevision {
+                    from: Some("0.9.0+qdef".to_owned()),
+                    to: "1.0.0+qabc".to_owned(),
+                },
+                Change::SetVariables(vec!["SPIN_APP_GREETING".to_owned()]),
+            ],
+            manifest.plan(&current, &variables)
+        );
+
+        let missing = CurrentDeployment::default();
+        assert!(manifest.desired_variables(&[], None).is_err());
+        assert_eq!(
+            Change::CreateApp("app".to_owned()),
+            manifest.plan(&missing, &BTreeMap::new())[0]
+        );
+    }
+}
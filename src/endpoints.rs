@@ -0,0 +1,136 @@
+//! Records the remote endpoints contacted during an operation, so that
+//! users on restricted networks can find out what they need to allow.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use reqwest::redirect::Policy;
+use url::Url;
+
+/// The number of redirects followed for a request, as reqwest's default
+/// policy does.
+const MAX_REDIRECTS: usize = 10;
+/// The port git uses for remotes given in the `[user@]host:path` form.
+const SSH_PORT: u16 = 22;
+
+/// The `host:port` endpoints contacted during an operation, each with the
+/// purposes for which it was contacted.
+#[derive(Debug, Default)]
+pub(crate) struct EndpointRecorder {
+    endpoints: Mutex<BTreeMap<String, BTreeSet<&'static str>>>,
+}
+
+impl EndpointRecorder {
+    /// Records that the given URL is about to be contacted.
+    pub fn record(&self, url: &Url, purpose: &'static str) {
+        let host = match url.host_str() {
+            Some(host) => host,
+            None => return,
+        };
+        let endpoint = match url.port_or_known_default() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_owned(),
+        };
+        self.endpoints
+            .lock()
+            .unwrap()
+            .entry(endpoint)
+            .or_default()
+            .insert(purpose);
+    }
+
+    /// Records that the given URL string is about to be contacted, if it is a
+    /// valid URL.
+    pub fn record_str(&self, url: &str, purpose: &'static str) {
+        if let Ok(url) = Url::parse(url) {
+            self.record(&url, purpose);
+        }
+    }
+
+    /// Records that the given git remote is about to be fetched. Remotes may
+    /// be URLs or, as for SSH, of the form `[user@]host:path`; local paths
+    /// are not recorded.
+    pub fn record_git_remote(&self, remote: &str) {
+        if let Ok(url) = Url::parse(remote) {
+            if url.has_host() {
+                self.record(&url, "git remote");
+            }
+            return;
+        }
+        let host = match remote.split_once(':') {
+            Some((host, _)) if !host.contains('/') => host,
+            _ => return,
+        };
+        let host = host.rsplit('@').next().unwrap_or(host);
+        self.record_str(&format!("ssh://{}:{}/", host, SSH_PORT), "git remote");
+    }
+
+    /// A redirect policy which records where requests are redirected before
+    /// following the redirects, so that the endpoints they lead to are
+    /// reported along with those contacted directly.
+    pub fn redirect_policy(self: &Arc<Self>, purpose: &'static str) -> Policy {
+        let recorder = self.clone();
+        Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error("too many redirects");
+            }
+            recorder.record(attempt.url(), purpose);
+            attempt.follow()
+        })
+    }
+
+    /// Writes a report of the recorded endpoints, one per line.
+    pub fn write_report(&self, path: &Path) -> Result<()> {
+        let report = self
+            .endpoints
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(endpoint, purposes)| {
+                let purposes = purposes.iter().copied().collect::<Vec<_>>().join(", ");
+                format!("{}\t{}\n", endpoint, purposes)
+            })
+            .collect::<String>();
+        std::fs::write(path, report)
+            .with_context(|| format!("Failed to write endpoint report {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records_endpoints_with_default_ports() {
+        let recorder = EndpointRecorder::default();
+        recorder.record_str("https://cloud.fermyon.com/api", "platform API");
+        recorder.record_str("http://localhost:8080/v1", "Bindle server");
+        recorder.record_str("https://cloud.fermyon.com/api/registry", "registry");
+
+        let endpoints = recorder.endpoints.lock().unwrap();
+        assert_eq!(
+            vec!["cloud.fermyon.com:443", "localhost:8080"],
+            endpoints.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(2, endpoints["cloud.fermyon.com:443"].len());
+    }
+
+    #[test]
+    fn records_git_remotes_in_either_form() {
+        let recorder = EndpointRecorder::default();
+        recorder.record_git_remote("https://github.com/fermyon/spin.git");
+        recorder.record_git_remote("git@gitlab.com:fermyon/spin.git");
+        recorder.record_git_remote("/home/me/src/spin");
+        recorder.record_git_remote("file:///home/me/src/spin");
+
+        let endpoints = recorder.endpoints.lock().unwrap();
+        assert_eq!(
+            vec!["github.com:443", "gitlab.com:22"],
+            endpoints.keys().collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! Fetching an application's source from a remote git repository, so that
+//! it can be deployed without a checked out working tree.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tempfile::TempDir;
+
+use crate::provenance::git;
+
+/// A shallow clone of a git repository at a single ref, in a temporary
+/// directory which is removed when this is dropped.
+pub(crate) struct GitSource {
+    dir: TempDir,
+}
+
+impl GitSource {
+    /// Fetches the given branch, tag or commit of the repository, or the
+    /// remote's default branch if no ref is given, without its history.
+    pub fn fetch(url: &str, git_ref: Option<&str>) -> Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let git_ref = git_ref.unwrap_or("HEAD");
+        // Fetching into an empty repository, unlike `git clone --branch`,
+        // accepts commit hashes as well as branches and tags
+        for args in [
+            vec!["init", "--quiet"],
+            vec!["remote", "add", "origin", url],
+            vec!["fetch", "--quiet", "--depth", "1", "origin", git_ref],
+            vec!["checkout", "--quiet", "--detach", "FETCH_HEAD"],
+        ] {
+            git(dir.path(), &args)
+                .with_context(|| format!("Failed to fetch {} from {}", git_ref, url))?;
+        }
+        Ok(Self { dir })
+    }
+
+    /// The root of the checked out working tree.
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn commit(repo: &Path, content: &str) {
+        std::fs::write(repo.join("spin.toml"), content).unwrap();
+        git(repo, &["add", "spin.toml"]).unwrap();
+        git(
+            repo,
+            &[
+                "-c",
+                "user.name=test",
+                "-c",
+                "user.email=test@example.com",
+                "commit",
+                "--quiet",
+                "-m",
+                content,
+            ],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn fetches_the_given_ref_or_the_default_branch() {
+        let repo = tempfile::tempdir().unwrap();
+        git(repo.path(), &["init", "--quiet"]).unwrap();
+        commit(repo.path(), "first");
+        git(repo.path(), &["tag", "v1"]).unwrap();
+        commit(repo.path(), "second");
+        let url = url::Url::from_directory_path(repo.path())
+            .unwrap()
+            .to_string();
+
+        let read =
+            |source: &GitSource| std::fs::read_to_string(source.path().join("spin.toml")).unwrap();
+        assert_eq!("first", read(&GitSource::fetch(&url, Some("v1")).unwrap()));
+        assert_eq!("second", read(&GitSource::fetch(&url, None).unwrap()));
+        assert!(GitSource::fetch(&url, Some("no-such-ref")).is_err());
+    }
+}
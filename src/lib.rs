@@ -1,6 +1,18 @@
 pub mod commands;
+mod deploy_lock;
+mod deploy_summary;
+mod deployment_manifest;
+mod endpoints;
+mod git_source;
+mod local_check;
 pub(crate) mod opts;
+mod paths;
+mod provenance;
 mod sloth;
+mod staging_dirs;
+mod telemetry;
+mod variables;
+mod version_bump;
 
 use anyhow::{anyhow, Result};
 use semver::BuildMetadata;
@@ -27,3 +39,36 @@ pub(crate) fn wrap_prepare_bindle_error(err: PublishError) -> anyhow::Error {
 pub(crate) fn parse_buildinfo(buildinfo: &str) -> Result<BuildMetadata> {
     Ok(BuildMetadata::new(buildinfo)?)
 }
+
+/// Parses a transfer rate in bytes per second, with an optional `K`, `M` or
+/// `G` suffix (e.g. `512K`).
+pub(crate) fn parse_rate_limit(limit: &str) -> Result<u64> {
+    let limit = limit.trim();
+    let (number, multiplier) = match limit.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&limit[..i], 1024),
+        Some((i, 'm' | 'M')) => (&limit[..i], 1024 * 1024),
+        Some((i, 'g' | 'G')) => (&limit[..i], 1024 * 1024 * 1024),
+        _ => (limit, 1),
+    };
+    let rate: u64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid rate limit '{}': expected bytes per second, optionally with a K, M or G suffix", limit))?;
+    if rate == 0 {
+        return Err(anyhow!("Rate limit must be greater than zero"));
+    }
+    Ok(rate * multiplier)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_rate_limit_suffixes() {
+        assert_eq!(1000, parse_rate_limit("1000").unwrap());
+        assert_eq!(512 * 1024, parse_rate_limit("512K").unwrap());
+        assert_eq!(2 * 1024 * 1024, parse_rate_limit("2m").unwrap());
+        assert!(parse_rate_limit("0").is_err());
+        assert!(parse_rate_limit("fast").is_err());
+    }
+}
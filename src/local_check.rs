@@ -0,0 +1,139 @@
+//! Checks that an application runs locally before it is deployed: that each
+//! component compiles and instantiates and, optionally, that the HTTP
+//! trigger answers a health route.
+
+use std::{
+    net::TcpListener,
+    path::Path,
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::Url;
+use spin_manifest::ApplicationTrigger;
+use spin_trigger::{
+    async_trait,
+    cli::{SPIN_LOCKED_URL, SPIN_WORKING_DIR},
+    config::TriggerExecutorBuilderConfig,
+    loader::TriggerLoader,
+    TriggerAppEngine, TriggerExecutor, TriggerExecutorBuilder,
+};
+
+/// How long to wait for the HTTP trigger to answer the health route.
+const HEALTH_ROUTE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Loads the application as `spin up` would, compiles and instantiates each
+/// of its components, and, if a health route is given, runs the HTTP trigger
+/// on a free local port until the route answers successfully.
+pub(crate) async fn verify_locally(app_file: &Path, health_route: Option<&str>) -> Result<()> {
+    let working_dir_holder = tempfile::tempdir()?;
+    let working_dir = working_dir_holder.path().canonicalize()?;
+
+    let app = spin_loader::from_file(app_file, Some(&working_dir), &None)
+        .await
+        .with_context(|| format!("Failed to load {}", app_file.display()))?;
+    let is_http = matches!(app.info.trigger, ApplicationTrigger::Http(_));
+    let locked_app = spin_trigger::locked::build_locked_app(app, &working_dir)?;
+    let locked_path = working_dir.join("spin.lock");
+    let locked_app_contents =
+        serde_json::to_vec_pretty(&locked_app).context("failed to serialize locked app")?;
+    std::fs::write(&locked_path, locked_app_contents)
+        .with_context(|| format!("failed to write {:?}", locked_path))?;
+    let locked_url = Url::from_file_path(&locked_path)
+        .map_err(|_| anyhow!("cannot convert to file URL: {locked_path:?}"))?
+        .to_string();
+
+    let loader = TriggerLoader::new(&working_dir, false);
+    let check = TriggerExecutorBuilder::<InstantiationCheck>::new(loader)
+        .build(locked_url.clone(), TriggerExecutorBuilderConfig::default())
+        .await
+        .context("Application failed to compile")?;
+    check.run(()).await?;
+
+    if let Some(route) = health_route {
+        if !is_http {
+            bail!("A health route can only be checked for HTTP applications");
+        }
+        check_health_route(&working_dir, &locked_url, route).await?;
+    }
+    Ok(())
+}
+
+/// A trigger executor which handles no triggers, but instantiates each
+/// component once, running any initialization it does.
+struct InstantiationCheck {
+    engine: TriggerAppEngine<Self>,
+}
+
+#[async_trait]
+impl TriggerExecutor for InstantiationCheck {
+    const TRIGGER_TYPE: &'static str = "instantiation-check";
+    type RuntimeData = ();
+    type TriggerConfig = ();
+    type RunConfig = ();
+
+    fn new(engine: TriggerAppEngine<Self>) -> Result<Self> {
+        Ok(Self { engine })
+    }
+
+    async fn run(self, _config: Self::RunConfig) -> Result<()> {
+        let component_ids: Vec<_> = self
+            .engine
+            .app()
+            .components()
+            .map(|c| c.id().to_owned())
+            .collect();
+        for id in component_ids {
+            self.engine.prepare_instance(&id).await?;
+            println!("Component {} instantiated", id);
+        }
+        Ok(())
+    }
+}
+
+/// Runs the HTTP trigger on a free local port and waits for the route to
+/// answer successfully, stopping the trigger afterwards.
+async fn check_health_route(working_dir: &Path, locked_url: &str, route: &str) -> Result<()> {
+    let address = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let mut child = tokio::process::Command::new(std::env::current_exe()?)
+        .arg("trigger")
+        .arg("http")
+        .arg("--listen")
+        .arg(address.to_string())
+        .env(SPIN_WORKING_DIR, working_dir)
+        .env(SPIN_LOCKED_URL, locked_url)
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to execute trigger")?;
+
+    let url = format!("http://{}/{}", address, route.trim_start_matches('/'));
+    let http = reqwest::Client::new();
+    let deadline = Instant::now() + HEALTH_ROUTE_TIMEOUT;
+    let result = loop {
+        if let Some(status) = child.try_wait()? {
+            break Err(anyhow!(
+                "The HTTP trigger exited before {} answered: {}",
+                route,
+                status
+            ));
+        }
+        match http.get(&url).send().await {
+            Ok(response) if response.status().is_success() => {
+                println!("{} answered {}", route, response.status());
+                break Ok(());
+            }
+            Ok(response) => break Err(anyhow!("{} answered {}", route, response.status())),
+            Err(e) if Instant::now() >= deadline => {
+                break Err(anyhow!(e).context(format!("Timed out waiting for {}", route)))
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(250)).await,
+        }
+    };
+
+    if let Err(e) = child.kill().await {
+        tracing::warn!("Failed to stop trigger process: {:?}", e);
+    }
+    result
+}
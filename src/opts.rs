@@ -7,6 +7,9 @@ pub const BINDLE_USERNAME: &str = "BINDLE_USERNAME";
 pub const BINDLE_PASSWORD: &str = "BINDLE_PASSWORD";
 pub const BUILDINFO_OPT: &str = "BUILDINFO";
 pub const INSECURE_OPT: &str = "INSECURE";
+pub const UPLOAD_LIMIT_ENV: &str = "SPIN_UPLOAD_LIMIT";
+pub const DOWNLOAD_LIMIT_ENV: &str = "SPIN_DOWNLOAD_LIMIT";
+pub const TRUST_POLICY_ENV: &str = "SPIN_TRUST_POLICY";
 pub const STAGING_DIR_OPT: &str = "STAGING_DIR";
 pub const HIPPO_SERVER_URL_OPT: &str = "HIPPO_SERVER_URL";
 pub const HIPPO_URL_ENV: &str = "HIPPO_URL";
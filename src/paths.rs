@@ -0,0 +1,70 @@
+//! The locations of the files and directories which Spin reads and writes.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+/// The directory in which logins and other user configuration are stored.
+pub(crate) fn config_root_dir() -> Result<PathBuf> {
+    let root = dirs::config_dir()
+        .context("Cannot find configuration directory")?
+        .join("fermyon");
+    Ok(root)
+}
+
+/// The file in which the login for the given environment is stored, or the
+/// default login if no environment is given.
+pub(crate) fn login_file(environment: Option<&str>) -> Result<PathBuf> {
+    let stem = environment.unwrap_or("config");
+    Ok(config_root_dir()?.join(format!("{}.json", stem)))
+}
+
+/// All the paths Spin resolves, as reported by `spin paths`.
+#[derive(Debug, Serialize)]
+pub(crate) struct SpinPaths {
+    pub config_dir: PathBuf,
+    pub login_file: PathBuf,
+    pub variables_dir: PathBuf,
+    pub registry_cache_dir: PathBuf,
+    pub staging_dir: PathBuf,
+    pub plugins_dir: PathBuf,
+    pub templates_dir: PathBuf,
+    pub telemetry_dir: PathBuf,
+}
+
+impl SpinPaths {
+    pub fn resolve(environment: Option<&str>) -> Result<Self> {
+        let config_dir = config_root_dir()?;
+        Ok(Self {
+            login_file: login_file(environment)?,
+            variables_dir: config_dir.join("variables"),
+            config_dir,
+            registry_cache_dir: spin_publish::oci::Cache::default_root()?,
+            // Bindles are staged in a fresh temporary directory unless
+            // --staging-dir is given
+            staging_dir: std::env::temp_dir(),
+            plugins_dir: spin_plugins::PluginStore::try_default()?
+                .get_plugins_directory()
+                .to_owned(),
+            templates_dir: spin_templates::TemplateManager::try_default()?
+                .directory()
+                .to_owned(),
+            telemetry_dir: crate::telemetry::telemetry_dir()?,
+        })
+    }
+
+    /// The paths with descriptions, in display order.
+    pub fn describe(&self) -> Vec<(&'static str, &PathBuf)> {
+        vec![
+            ("Configuration directory", &self.config_dir),
+            ("Login file", &self.login_file),
+            ("Saved variables directory", &self.variables_dir),
+            ("Registry cache directory", &self.registry_cache_dir),
+            ("Default staging directory", &self.staging_dir),
+            ("Plugins directory", &self.plugins_dir),
+            ("Templates directory", &self.templates_dir),
+            ("Deployment statistics directory", &self.telemetry_dir),
+        ]
+    }
+}
@@ -0,0 +1,247 @@
+//! Tracking of the directories in which bindles are staged, so that those
+//! left behind by crashed or interrupted publishes can be cleaned up.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tempfile::TempDir;
+
+const STATE_FILE: &str = "staging-dirs.json";
+
+/// How long after it was last used a staging directory is considered
+/// abandoned even if the process which used it appears to be running, in
+/// case its process ID has been reused.
+const STALE_AFTER_SECS: i64 = 24 * 60 * 60;
+
+/// The staging directories which Spin has created or been asked to use,
+/// recorded in a small state file in the cache directory.
+#[derive(Clone, Debug)]
+pub(crate) struct StagingDirs {
+    state_file: PathBuf,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct StagingDirRecord {
+    path: PathBuf,
+    pid: u32,
+    /// When the directory was last used, in seconds since the Unix epoch.
+    used: i64,
+    /// Whether Spin created the directory, rather than it being given
+    /// with `--staging-dir`.
+    temporary: bool,
+}
+
+impl StagingDirs {
+    pub fn open() -> Result<Self> {
+        let cache_dir = dirs::cache_dir().context("Cannot find cache directory")?;
+        Ok(Self {
+            state_file: cache_dir.join("spin").join(STATE_FILE),
+        })
+    }
+
+    /// Removes the staging directories whose publishes finished without
+    /// cleaning up after themselves. Directories given with
+    /// `--staging-dir` are only removed if `include_given` is set. Returns
+    /// the directories removed.
+    pub fn remove_abandoned(&self, include_given: bool) -> Result<Vec<PathBuf>> {
+        let now = chrono::Utc::now().timestamp();
+        let (abandoned, kept): (Vec<_>, Vec<_>) = self
+            .load()?
+            .into_iter()
+            .partition(|r| (r.temporary || include_given) && r.is_abandoned(now));
+
+        let mut removed = vec![];
+        for record in abandoned {
+            match std::fs::remove_dir_all(&record.path) {
+                Ok(()) => removed.push(record.path),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed to remove staging directory {}: {}",
+                        record.path.display(),
+                        e
+                    );
+                }
+            }
+        }
+        self.save(&kept)?;
+        Ok(removed)
+    }
+
+    fn track(&self, path: &Path, temporary: bool) -> Result<()> {
+        let mut records = self.load()?;
+        records.retain(|r| r.path != path);
+        records.push(StagingDirRecord {
+            path: path.to_owned(),
+            pid: std::process::id(),
+            used: chrono::Utc::now().timestamp(),
+            temporary,
+        });
+        self.save(&records)
+    }
+
+    fn untrack(&self, path: &Path) -> Result<()> {
+        let mut records = self.load()?;
+        records.retain(|r| r.path != path);
+        self.save(&records)
+    }
+
+    fn load(&self) -> Result<Vec<StagingDirRecord>> {
+        match std::fs::read(&self.state_file) {
+            Ok(data) => Ok(serde_json::from_slice(&data).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring invalid {}: {}", self.state_file.display(), e);
+                vec![]
+            })),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => {
+                Err(e).with_context(|| format!("Failed to read {}", self.state_file.display()))
+            }
+        }
+    }
+
+    fn save(&self, records: &[StagingDirRecord]) -> Result<()> {
+        if let Some(dir) = self.state_file.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        // Written via a temporary file so that a concurrent publish never
+        // reads a partial state file.
+        let temp = self
+            .state_file
+            .with_extension(format!("{}.tmp", std::process::id()));
+        std::fs::write(&temp, serde_json::to_vec_pretty(records)?)
+            .with_context(|| format!("Failed to write {}", temp.display()))?;
+        std::fs::rename(&temp, &self.state_file)
+            .with_context(|| format!("Failed to write {}", self.state_file.display()))
+    }
+}
+
+impl StagingDirRecord {
+    fn is_abandoned(&self, now: i64) -> bool {
+        if self.pid == std::process::id() {
+            return false;
+        }
+        now - self.used > STALE_AFTER_SECS || !is_running(self.pid)
+    }
+}
+
+#[cfg(not(windows))]
+fn is_running(pid: u32) -> bool {
+    let pid = nix::unistd::Pid::from_raw(pid as i32);
+    !matches!(
+        nix::sys::signal::kill(pid, None),
+        Err(nix::errno::Errno::ESRCH)
+    )
+}
+
+#[cfg(windows)]
+fn is_running(_pid: u32) -> bool {
+    // Without a portable liveness check, directories are only considered
+    // abandoned once they are stale.
+    true
+}
+
+/// A directory in which to stage a bindle: either the directory given with
+/// `--staging-dir`, or a temporary directory which is removed when this is
+/// dropped.
+pub(crate) struct StagingDir {
+    path: PathBuf,
+    temp: Option<TempDir>,
+    tracker: Option<StagingDirs>,
+}
+
+impl StagingDir {
+    /// Prepares the directory to stage a bindle in, after removing any
+    /// temporary staging directories abandoned by earlier publishes.
+    /// Tracking is best effort, so failures are logged but do not prevent
+    /// publishing.
+    pub fn prepare(given: Option<&Path>) -> Result<Self> {
+        let tracker = StagingDirs::open()
+            .map_err(|e| tracing::warn!("Cannot track staging directories: {:#}", e))
+            .ok();
+        if let Some(tracker) = &tracker {
+            match tracker.remove_abandoned(false) {
+                Ok(removed) if !removed.is_empty() => {
+                    tracing::info!("Removed {} abandoned staging directories", removed.len())
+                }
+                Ok(_) => {}
+                Err(e) => tracing::warn!("Failed to clean up staging directories: {:#}", e),
+            }
+        }
+
+        let (path, temp) = match given {
+            Some(path) => (path.to_owned(), None),
+            None => {
+                let temp = tempfile::tempdir()?;
+                (temp.path().to_owned(), Some(temp))
+            }
+        };
+        if let Some(tracker) = &tracker {
+            if let Err(e) = tracker.track(&path, temp.is_some()) {
+                tracing::warn!("Failed to track staging directory: {:#}", e);
+            }
+        }
+        Ok(Self {
+            path,
+            temp,
+            tracker,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        // A given directory stays tracked, so that `spin cache prune
+        // --staging` can reclaim it once it is no longer in use.
+        if let (Some(_), Some(tracker)) = (&self.temp, &self.tracker) {
+            if let Err(e) = tracker.untrack(&self.path) {
+                tracing::warn!("Failed to untrack staging directory: {:#}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn removes_only_abandoned_directories() {
+        let temp = tempfile::tempdir().unwrap();
+        let tracker = StagingDirs {
+            state_file: temp.path().join(STATE_FILE),
+        };
+        let stale = temp.path().join("stale");
+        let given = temp.path().join("given");
+        let current = temp.path().join("current");
+        for dir in [&stale, &given, &current] {
+            std::fs::create_dir(dir).unwrap();
+        }
+        let record = |path: &Path, pid, temporary| StagingDirRecord {
+            path: path.to_owned(),
+            pid,
+            used: 0,
+            temporary,
+        };
+        tracker
+            .save(&[
+                record(&stale, u32::MAX, true),
+                record(&given, u32::MAX, false),
+                record(&current, std::process::id(), true),
+            ])
+            .unwrap();
+
+        assert_eq!(
+            vec![stale.clone()],
+            tracker.remove_abandoned(false).unwrap()
+        );
+        assert!(!stale.exists() && given.exists() && current.exists());
+        assert_eq!(vec![given.clone()], tracker.remove_abandoned(true).unwrap());
+        assert!(current.exists());
+    }
+}
@@ -0,0 +1,173 @@
+//! Opt-in, local-only statistics about deployment outcomes. Nothing is
+//! recorded unless the user has run `spin telemetry enable`, and nothing is
+//! ever sent anywhere: users export the statistics themselves to share them
+//! with their platform operators.
+//!
+//! Records are anonymized: they contain an outcome category, a duration and
+//! the Spin version, but no application names, URLs or error messages.
+
+use std::{
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use cloud::client::AuthError;
+use serde::{Deserialize, Serialize};
+use spin_publish::PublishError;
+
+use crate::paths::config_root_dir;
+
+const ENABLED_FILE: &str = "enabled";
+const DEPLOYS_FILE: &str = "deploys.jsonl";
+
+/// The directory in which statistics are stored.
+pub(crate) fn telemetry_dir() -> Result<PathBuf> {
+    Ok(config_root_dir()?.join("telemetry"))
+}
+
+/// Whether the user has opted in to recording statistics.
+pub(crate) fn is_enabled() -> bool {
+    telemetry_dir()
+        .map(|dir| dir.join(ENABLED_FILE).exists())
+        .unwrap_or(false)
+}
+
+/// Opts in to or out of recording statistics. Opting out does not delete
+/// statistics which have already been recorded.
+pub(crate) fn set_enabled(enabled: bool) -> Result<()> {
+    let dir = telemetry_dir()?;
+    let marker = dir.join(ENABLED_FILE);
+    if enabled {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        std::fs::write(&marker, "").with_context(|| format!("Failed to write {}", marker.display()))
+    } else {
+        match std::fs::remove_file(&marker) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to remove {}", marker.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The outcome of a single deployment.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DeployRecord {
+    /// The UTC date of the deployment, without the time of day.
+    pub date: String,
+    /// `success`, or the category of the failure.
+    pub outcome: String,
+    /// How long the deployment took, to the nearest second.
+    pub duration_secs: u64,
+    /// The version of Spin which performed the deployment.
+    pub spin_version: String,
+}
+
+impl DeployRecord {
+    fn new(duration: Duration, result: &Result<()>) -> Self {
+        Self {
+            date: chrono::Utc::now().format("%Y-%m-%d").to_string(),
+            outcome: match result {
+                Ok(()) => "success".to_owned(),
+                Err(e) => failure_category(e).to_owned(),
+            },
+            duration_secs: duration.as_secs_f64().round() as u64,
+            spin_version: env!("CARGO_PKG_VERSION").to_owned(),
+        }
+    }
+}
+
+/// Records the outcome of a deployment which began at `started`, if the
+/// user has opted in. Failure to record is never an error for the caller.
+pub(crate) fn record_deploy(started: Instant, result: &Result<()>) {
+    if !is_enabled() {
+        return;
+    }
+    let record = DeployRecord::new(started.elapsed(), result);
+    if let Err(e) = append_record(&record) {
+        tracing::debug!("Failed to record deploy statistics: {:?}", e);
+    }
+}
+
+fn append_record(record: &DeployRecord) -> Result<()> {
+    let path = telemetry_dir()?.join(DEPLOYS_FILE);
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?;
+    writeln!(file, "{}", serde_json::to_string(record)?)?;
+    Ok(())
+}
+
+/// Loads all the recorded deployment outcomes.
+pub(crate) fn load_records() -> Result<Vec<DeployRecord>> {
+    let path = telemetry_dir()?.join(DEPLOYS_FILE);
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    // Skip lines which cannot be parsed, e.g. if a write was interrupted
+    Ok(text
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// Deletes all the recorded deployment outcomes.
+pub(crate) fn clear_records() -> Result<()> {
+    let path = telemetry_dir()?.join(DEPLOYS_FILE);
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to remove {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn failure_category(error: &anyhow::Error) -> &'static str {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<AuthError>() {
+            return match e {
+                AuthError::Timeout { .. } => "platform-timeout",
+                AuthError::Unauthorized
+                | AuthError::AuthorizationTimeout
+                | AuthError::AuthorizationPending
+                | AuthError::SlowDown => "unauthorized",
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<PublishError>() {
+            return match e {
+                PublishError::BindleAlreadyExists(_) => "already-exists",
+                PublishError::MissingBuildArtifact(_) => "missing-build",
+                PublishError::PolicyViolation(_) => "policy-violation",
+                PublishError::BindleClient(_) => "bindle-upload",
+                _ => "publish",
+            };
+        }
+        if cause.downcast_ref::<reqwest::Error>().is_some() {
+            return "network";
+        }
+    }
+    "other"
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn failures_are_categorized_without_details() {
+        let error = anyhow::Error::from(PublishError::BindleAlreadyExists("app/1.0.0".into()))
+            .context("Failed to push bindle");
+        let record = DeployRecord::new(Duration::from_millis(2600), &Err(error));
+        assert_eq!("already-exists", record.outcome);
+        assert_eq!(3, record.duration_secs);
+
+        let record = DeployRecord::new(Duration::ZERO, &Err(anyhow::anyhow!("secret.example.com")));
+        assert_eq!("other", record.outcome);
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use is_terminal::IsTerminal;
+use spin_loader::local::config::RawAppManifest;
+
+use crate::commands::new::ParameterValue;
+
+// The Spin runtime reads application variables from environment variables
+// with this prefix (see the `EnvProvider` set up by the trigger).
+const SPIN_VARIABLE_ENV_PREFIX: &str = "SPIN_APP";
+
+/// Resolves values for the application variables which must be supplied at
+/// deploy time, returning them as environment variables for the platform.
+///
+/// Values come from the command line first, then from answers saved for
+/// the deployment environment, and finally from prompting the user. Secret
+/// values are never saved.
+pub(crate) fn resolve_variables(
+    cfg: &RawAppManifest,
+    provided: &[ParameterValue],
+    store: &VariableStore,
+) -> Result<Vec<(String, String)>> {
+    for value in provided {
+        if !cfg.variables.contains_key(&value.name) {
+            bail!(
+                "Variable '{}' is not declared in the application manifest",
+                value.name
+            );
+        }
+    }
+
+    let mut saved = store.load()?;
+    let mut resolved = vec![];
+    let mut missing = vec![];
+
+    let mut names = cfg.variables.keys().collect::<Vec<_>>();
+    names.sort();
+
+    for name in names {
+        let variable = &cfg.variables[name];
+        let value = match provided.iter().rev().find(|v| &v.name == name) {
+            Some(v) => Some(v.value.clone()),
+            None if !variable.required => None,
+            None if variable.secret => None,
+            None => saved.get(name).cloned(),
+        };
+        match value {
+            Some(value) => resolved.push((name.clone(), value)),
+            None if variable.required => missing.push((name.clone(), variable.secret)),
+            None => {}
+        }
+    }
+
+    if !missing.is_empty() {
+        if !std::io::stdin().is_terminal() {
+            let names = missing.into_iter().map(|(n, _)| n).collect::<Vec<_>>();
+            bail!(
+                "Missing values for required variables: {}. Use `--variable name=value` to provide them",
+                names.join(", ")
+            );
+        }
+        for (name, secret) in missing {
+            let value = prompt_variable(&name, secret)?;
+            resolved.push((name, value));
+        }
+    }
+
+    for (name, value) in &resolved {
+        if !cfg.variables[name].secret {
+            saved.insert(name.clone(), value.clone());
+        }
+    }
+    store.save(&saved)?;
+
+    Ok(resolved
+        .into_iter()
+        .map(|(name, value)| (variable_env_name(&name), value))
+        .collect())
+}
+
+fn prompt_variable(name: &str, secret: bool) -> Result<String> {
+    let prompt = format!("Value for required variable '{}'", name);
+    let value = if secret {
+        dialoguer::Password::new().with_prompt(prompt).interact()?
+    } else {
+        dialoguer::Input::<String>::new()
+            .with_prompt(prompt)
+            .interact_text()?
+    };
+    Ok(value)
+}
+
+pub(crate) fn variable_env_name(name: &str) -> String {
+    format!("{}_{}", SPIN_VARIABLE_ENV_PREFIX, name.to_ascii_uppercase())
+}
+
+/// Non-secret variable values saved per deployment environment and application.
+pub(crate) struct VariableStore {
+    path: PathBuf,
+}
+
+impl VariableStore {
+    pub fn new(config_root: &Path, environment: &str, app_name: &str) -> Self {
+        let path = config_root
+            .join("variables")
+            .join(environment)
+            .join(format!("{}.json", app_name));
+        Self { path }
+    }
+
+    fn load(&self) -> Result<HashMap<String, String>> {
+        match std::fs::read(&self.path) {
+            Ok(data) => serde_json::from_slice(&data).with_context(|| {
+                format!("Failed to parse saved variables {}", self.path.display())
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(e)
+                .with_context(|| format!("Failed to read saved variables {}", self.path.display())),
+        }
+    }
+
+    fn save(&self, values: &HashMap<String, String>) -> Result<()> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+        }
+        std::fs::write(&self.path, serde_json::to_string_pretty(values)?)
+            .with_context(|| format!("Failed to save variables to {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use spin_loader::local::config::RawAppManifestAnyVersion;
+
+    use super::*;
+
+    fn manifest() -> RawAppManifest {
+        let cfg: RawAppManifestAnyVersion = toml::from_str(
+            r#"
+            spin_version = "1"
+            name = "app"
+            version = "1.0.0"
+            trigger = { type = "http", base = "/" }
+            [variables]
+            greeting = { default = "hello" }
+            region = { required = true }
+            api_key = { required = true, secret = true }
+            [[component]]
+            id = "app"
+            source = "app.wasm"
+            [component.trigger]
+            route = "/..."
+            "#,
+        )
+        .unwrap();
+        let RawAppManifestAnyVersion::V1(cfg) = cfg;
+        cfg
+    }
+
+    fn value(name: &str, value: &str) -> ParameterValue {
+        ParameterValue {
+            name: name.to_owned(),
+            value: value.to_owned(),
+        }
+    }
+
+    #[test]
+    fn variable_env_names_match_runtime_convention() {
+        assert_eq!("SPIN_APP_API_KEY", variable_env_name("api_key"));
+    }
+
+    #[test]
+    fn saves_answers_for_required_variables_but_not_secrets() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = VariableStore::new(dir.path(), "cloud", "app");
+        let cfg = manifest();
+
+        // Variables with defaults are left to the runtime unless given
+        let resolved = resolve_variables(
+            &cfg,
+            &[value("region", "eu"), value("api_key", "hunter2")],
+            &store,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                ("SPIN_APP_API_KEY".to_owned(), "hunter2".to_owned()),
+                ("SPIN_APP_REGION".to_owned(), "eu".to_owned()),
+            ],
+            resolved
+        );
+        assert_eq!(
+            HashMap::from([("region".to_owned(), "eu".to_owned())]),
+            store.load().unwrap()
+        );
+
+        // A saved answer is used when no value is given, but a secret must
+        // be given again
+        let resolved = resolve_variables(
+            &cfg,
+            &[value("api_key", "hunter3"), value("greeting", "hi")],
+            &store,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                ("SPIN_APP_API_KEY".to_owned(), "hunter3".to_owned()),
+                ("SPIN_APP_GREETING".to_owned(), "hi".to_owned()),
+                ("SPIN_APP_REGION".to_owned(), "eu".to_owned()),
+            ],
+            resolved
+        );
+        assert!(!std::fs::read_to_string(&store.path)
+            .unwrap()
+            .contains("hunter"));
+    }
+
+    #[test]
+    fn rejects_undeclared_variables() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = VariableStore::new(dir.path(), "cloud", "app");
+        let err = resolve_variables(&manifest(), &[value("colour", "blue")], &store).unwrap_err();
+        assert!(err.to_string().contains("colour"));
+    }
+}
@@ -0,0 +1,150 @@
+//! Bumping the version in an application manifest, so that `spin deploy`
+//! can take care of the usual release loop.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use semver::Version;
+use spin_loader::local::parent_dir;
+
+use crate::provenance::git;
+
+/// Which part of the version to increment.
+#[derive(clap::ArgEnum, Clone, Debug, Eq, PartialEq)]
+pub enum BumpLevel {
+    #[clap(name = "patch")]
+    Patch,
+    #[clap(name = "minor")]
+    Minor,
+    #[clap(name = "major")]
+    Major,
+}
+
+impl BumpLevel {
+    /// The version following `version` at this level. A prerelease is
+    /// bumped to its release.
+    pub fn apply(&self, version: &Version) -> Version {
+        match self {
+            Self::Major if version.minor == 0 && version.patch == 0 && !version.pre.is_empty() => {
+                Version::new(version.major, 0, 0)
+            }
+            Self::Major => Version::new(version.major + 1, 0, 0),
+            Self::Minor if version.patch == 0 && !version.pre.is_empty() => {
+                Version::new(version.major, version.minor, 0)
+            }
+            Self::Minor => Version::new(version.major, version.minor + 1, 0),
+            Self::Patch if !version.pre.is_empty() => {
+                Version::new(version.major, version.minor, version.patch)
+            }
+            Self::Patch => Version::new(version.major, version.minor, version.patch + 1),
+        }
+    }
+}
+
+/// Bumps the version in the manifest file, leaving the rest of the file as
+/// it was. Returns the old and new versions.
+pub(crate) fn bump_manifest_version(
+    app_file: &Path,
+    level: &BumpLevel,
+) -> Result<(Version, Version)> {
+    let manifest = std::fs::read_to_string(app_file)
+        .with_context(|| format!("Failed to read {}", app_file.display()))?;
+    let (bumped, old, new) = rewrite_version(&manifest, level)
+        .with_context(|| format!("Cannot bump the version in {}", app_file.display()))?;
+    std::fs::write(app_file, bumped)
+        .with_context(|| format!("Failed to write {}", app_file.display()))?;
+    Ok((old, new))
+}
+
+/// Commits the manifest file and tags the commit `v<version>`.
+pub(crate) fn commit_and_tag(app_file: &Path, version: &Version) -> Result<()> {
+    let app_dir = parent_dir(app_file)?;
+    let file = app_file
+        .file_name()
+        .context("The manifest path has no file name")?
+        .to_string_lossy()
+        .into_owned();
+    let message = format!("Release {}", version);
+    let tag = format!("v{}", version);
+    for args in [
+        vec!["add", "--", file.as_str()],
+        vec!["commit", "-m", message.as_str(), "--", file.as_str()],
+        vec!["tag", tag.as_str()],
+    ] {
+        if git(&app_dir, &args)?.is_none() {
+            bail!(
+                "Cannot commit the bumped version because {} is not in a git repository",
+                app_dir.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the value of the top-level `version` key in a manifest's text,
+/// preserving its quoting and everything around it. Returns the new text
+/// and the old and new versions.
+fn rewrite_version(manifest: &str, level: &BumpLevel) -> Result<(String, Version, Version)> {
+    let mut output = String::with_capacity(manifest.len());
+    let mut versions = None;
+    let mut in_table = false;
+
+    for line in manifest.split_inclusive('\n') {
+        if line.trim_start().starts_with('[') {
+            in_table = true;
+        }
+        match split_version_line(line) {
+            Some((prefix, value, suffix)) if !in_table && versions.is_none() => {
+                let old = Version::parse(value)
+                    .with_context(|| format!("Version '{}' is not a semantic version", value))?;
+                let new = level.apply(&old);
+                output.push_str(prefix);
+                output.push_str(&new.to_string());
+                output.push_str(suffix);
+                versions = Some((old, new));
+            }
+            _ => output.push_str(line),
+        }
+    }
+
+    match versions {
+        Some((old, new)) => Ok((output, old, new)),
+        None => bail!("The manifest has no version"),
+    }
+}
+
+/// Splits a `version = "..."` line into the text before the version, the
+/// version and the text after it.
+fn split_version_line(line: &str) -> Option<(&str, &str, &str)> {
+    let rest = line.trim_start().strip_prefix("version")?;
+    let value = rest.trim_start().strip_prefix('=')?.trim_start();
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let body = &value[1..];
+    let end = body.find(quote)?;
+    let start = line.len() - body.len();
+    Some((&line[..start], &body[..end], &line[start + end..]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bumps_version_preserving_formatting() {
+        let manifest = "spin_manifest_version = \"1\"\nname = \"app\"\nversion   =  '1.2.3'  # release\n\n[[component]]\nversion = \"9.9.9\"\n";
+        let (bumped, old, new) = rewrite_version(manifest, &BumpLevel::Minor).unwrap();
+        assert_eq!(Version::new(1, 2, 3), old);
+        assert_eq!(Version::new(1, 3, 0), new);
+        assert_eq!(
+            "spin_manifest_version = \"1\"\nname = \"app\"\nversion   =  '1.3.0'  # release\n\n[[component]]\nversion = \"9.9.9\"\n",
+            bumped
+        );
+
+        let rc = Version::parse("2.0.0-rc.1").unwrap();
+        assert_eq!(Version::new(2, 0, 0), BumpLevel::Major.apply(&rc));
+        assert_eq!(
+            Version::new(1, 2, 4),
+            BumpLevel::Patch.apply(&Version::new(1, 2, 3))
+        );
+    }
+}